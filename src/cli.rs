@@ -7,16 +7,34 @@ use hancock::ops::*;
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// Configuration file [default: hancock/hancock.toml in the XDG config directories]
+    #[arg(long, global = true, env = "HANCOCK_CONFIG")]
+    pub config: Option<String>,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    Init(Init),
-    Issue(Issue),
+    Init(Box<Init>),
+    Issue(Box<Issue>),
     List(List),
     Renew(Renew),
+    History(History),
+    Rollback(Rollback),
+    Revoke(Revoke),
+    Crl(Crl),
+    Ocsp(Ocsp),
+    Migrate(Migrate),
+    Key(Key),
+    Agent(Agent),
+    Backup(Backup),
+    Request(Request),
+    SignBundle(SignBundle),
+    ImportSigned(ImportSigned),
+    Root(Root),
+    Config(Config),
 }
 
 fn main() {
@@ -49,10 +67,29 @@ fn main() {
     #[cfg(debug_assertions)]
     let cli = dbg!(Cli::parse());
 
+    let config = match cli.command {
+        Commands::Config(_) => hancock::config::Config::default(),
+        _ => hancock::config::Config::load(&cli.config),
+    };
+
     match cli.command {
-        Commands::Init(args) => init(args),
-        Commands::Issue(args) => issue(args),
-        Commands::List(args) => list(args),
-        Commands::Renew(args) => renew(args),
+        Commands::Init(args) => init(*args, &config),
+        Commands::Issue(args) => issue(*args, &config),
+        Commands::List(args) => list(args, &config),
+        Commands::Renew(args) => renew(args, &config),
+        Commands::History(args) => history(args, &config),
+        Commands::Rollback(args) => rollback(args, &config),
+        Commands::Revoke(args) => revoke(args, &config),
+        Commands::Crl(args) => crl(args, &config),
+        Commands::Ocsp(args) => ocsp(args, &config),
+        Commands::Migrate(args) => migrate(args, &config),
+        Commands::Key(args) => key(args, &config),
+        Commands::Agent(args) => agent(args, &config),
+        Commands::Backup(args) => backup(args, &config),
+        Commands::Request(args) => request(args, &config),
+        Commands::SignBundle(args) => sign_bundle(args, &config),
+        Commands::ImportSigned(args) => import_signed(args, &config),
+        Commands::Root(args) => root(args, &config),
+        Commands::Config(args) => hancock::ops::config(args, &cli.config),
     }
 }
@@ -0,0 +1,979 @@
+//! `hancock agent` holds a store's CA keys decrypted in locked memory and
+//! signs with them for hancock commands run by permitted users, so the keys
+//! are decrypted once a day instead of by every issue and renew.
+//!
+//! It speaks the socket protocol of external signers with the key file's
+//! path as KEY. Its own user can also send `UNLOCK TYPE LENGTH [INTERMEDIATE]`
+//! followed by LENGTH bytes of password, `LOCK` to drop every key, and `LIST`
+//! to see which keys are unlocked and when they lock again.
+//!
+//! The agent's own user, who can read the key files anyway, may sign
+//! anything. Every other user is limited by a policy checked against what
+//! they send to be signed: only certificates whose basicConstraints say they
+//! are not CAs, with no other extension letting them sign certificates or
+//! CRLs, and that expire within the agent's maximum lifetime, and CRLs and OCSP responses
+//! only for users also allowed to sign those. They may only sign with
+//! SHA-256, SHA-384 or SHA-512, and what they send must name the signature
+//! algorithm of that digest and the key. Anything else, such as a request
+//! signed with a CA's key, is refused. Other users connect through
+//! the socket's group, so the socket is then kept outside the store, which
+//! only its owner can enter, in a directory open to that group.
+//!
+//! Each connection is served on its own thread, so a client that connects and
+//! sends nothing holds up only itself until its read times out, and each user
+//! has a limit of its own on open connections, so one user opening many
+//! cannot lock out the others.
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, read};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::der;
+use crate::external;
+use crate::path;
+use crate::pkey;
+use crate::signer::{self, Signer};
+use crate::validity;
+use crate::KeyType;
+
+/// Socket of the agent that hancock commands sign through, if it is set
+pub const SOCKET_ENV: &str = "HANCOCK_AGENT_SOCK";
+
+/// basicConstraints
+const BASIC_CONSTRAINTS: &str = "2.5.29.19";
+/// keyUsage
+const KEY_USAGE: &str = "2.5.29.15";
+/// Netscape's nsCertType
+const NS_CERT_TYPE: &str = "2.16.840.1.113730.1.1";
+
+/// keyCertSign and cRLSign in the first byte of keyUsage's bits
+const KEY_CERT_SIGN_CRL_SIGN: u8 = 0x04 | 0x02;
+/// sslCA, emailCA and objCA in nsCertType's bits
+const NS_CERT_TYPE_CA: u8 = 0x04 | 0x02 | 0x01;
+
+/// The digests other users may sign with, and the signature algorithms that
+/// go with them for RSA and EC keys
+const DIGESTS: [(&str, &str, &str); 3] = [
+    ("sha256", "1.2.840.113549.1.1.11", "1.2.840.10045.4.3.2"),
+    ("sha384", "1.2.840.113549.1.1.12", "1.2.840.10045.4.3.3"),
+    ("sha512", "1.2.840.113549.1.1.13", "1.2.840.10045.4.3.4"),
+];
+
+/// Connections served at once for each user. Every thread's stack is locked
+/// in memory along with the keys, so there must not be too many
+const MAX_CONNECTIONS: usize = 8;
+
+/// A user allowed to sign with the agent's keys, from `USER[=KEY,...]` where
+/// USER is a name or uid and each KEY is `root` or an intermediate's name.
+/// Without keys every key may be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allow {
+    pub uid: u32,
+    pub keys: Option<Vec<String>>,
+}
+
+impl FromStr for Allow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, keys) = match s.split_once('=') {
+            Some((user, keys)) => (
+                user,
+                Some(keys.split(',').map(|key| key.trim().to_string()).collect()),
+            ),
+            None => (s, None),
+        };
+        Ok(Allow {
+            uid: uid(user)?,
+            keys,
+        })
+    }
+}
+
+/// What users other than the agent's own may sign
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Longest a certificate may be valid for from when it is signed
+    pub max_lifetime: validity::Duration,
+    /// Users who may also sign CRLs and OCSP responses
+    pub revokers: Vec<u32>,
+}
+
+/// The structure in a SIGN request, as far as the policy cares
+#[derive(Debug, PartialEq, Eq)]
+enum Signing {
+    /// With why it could act as a CA, if it could. The algorithms are the
+    /// OIDs of the signature algorithms the structures say they are signed
+    /// with
+    Certificate {
+        ca: Option<&'static str>,
+        not_after: i64,
+        algorithm: Vec<u8>,
+    },
+    Crl {
+        algorithm: Vec<u8>,
+    },
+    OcspResponse,
+}
+
+impl Policy {
+    /// Check what another user sends to be signed with a key of the given
+    /// type and digest. Only strong digests are allowed, and a certificate or
+    /// CRL must name the signature algorithm it is about to be signed with,
+    /// so a weak digest cannot be slipped in under a strong one's name
+    fn check(&self, uid: u32, digest: &str, key: Id, tbs: &[u8]) -> Result<&'static str, String> {
+        let expected = DIGESTS
+            .iter()
+            .find(|(name, _, _)| name.eq_ignore_ascii_case(digest))
+            .map(|(_, rsa, ec)| match key {
+                Id::EC => der::oid(ec),
+                _ => der::oid(rsa),
+            })
+            .ok_or_else(|| {
+                format!("other users may only sign with sha256, sha384 or sha512, not {digest}")
+            })?;
+        let signing = signing(tbs);
+        match &signing {
+            Some(Signing::Certificate { algorithm, .. } | Signing::Crl { algorithm })
+                if *algorithm != expected =>
+            {
+                return Err(format!(
+                    "the signature algorithm in what is to be signed is not {digest} with a {} key",
+                    match key {
+                        Id::EC => "EC",
+                        _ => "RSA",
+                    }
+                ));
+            }
+            _ => {}
+        }
+        match signing {
+            Some(Signing::Certificate { ca: Some(why), .. }) => Err(format!(
+                "only the agent's own user may sign certificates that could act as a CA, and this one {why}"
+            )),
+            Some(Signing::Certificate { not_after, .. })
+                if not_after > validity::now() + self.max_lifetime.seconds() =>
+            {
+                Err(format!(
+                    "certificates signed for other users may be valid for at most {}",
+                    self.max_lifetime.describe()
+                ))
+            }
+            Some(Signing::Certificate { .. }) => Ok("a certificate"),
+            Some(Signing::Crl { .. }) if self.revokers.contains(&uid) => Ok("a CRL"),
+            Some(Signing::OcspResponse) if self.revokers.contains(&uid) => Ok("an OCSP response"),
+            Some(_) => Err(format!("uid {uid} may not sign CRLs or OCSP responses")),
+            None => Err(String::from(
+                "only certificates, CRLs and OCSP responses may be signed for other users",
+            )),
+        }
+    }
+}
+
+/// Tell what a DER structure to be signed is by the tags of its fields
+fn signing(tbs: &[u8]) -> Option<Signing> {
+    let (tbs, rest) = der::split(tbs)?;
+    if tbs.tag != der::SEQUENCE || !rest.is_empty() {
+        return None;
+    }
+    let mut fields = Vec::new();
+    let mut rest = tbs.content;
+    while !rest.is_empty() {
+        let (field, next) = der::split(rest)?;
+        fields.push(field);
+        rest = next;
+    }
+    let tags: Vec<u8> = fields.iter().map(|field| field.tag).collect();
+    match tags.as_slice() {
+        // A v3 TBSCertificate, which is all hancock signs
+        [0xa0, der::INTEGER, der::SEQUENCE, der::SEQUENCE, der::SEQUENCE, der::SEQUENCE, der::SEQUENCE, ..] => {
+            certificate(&fields)
+        }
+        // A TBSCertList
+        [der::INTEGER, der::SEQUENCE, der::SEQUENCE, der::UTC_TIME | der::GENERALIZED_TIME, ..] => {
+            Some(Signing::Crl {
+                algorithm: algorithm(&fields[1])?,
+            })
+        }
+        // An OCSP ResponseData, whose version is left out as the default
+        [0xa1 | 0xa2, der::GENERALIZED_TIME, der::SEQUENCE, ..] => Some(Signing::OcspResponse),
+        _ => None,
+    }
+}
+
+/// The encoded OID of an AlgorithmIdentifier
+fn algorithm(identifier: &der::Element) -> Option<Vec<u8>> {
+    let (oid, _) = der::split(identifier.content)?;
+    match oid.tag {
+        der::OBJECT_IDENTIFIER => Some(oid.encoded.to_vec()),
+        _ => None,
+    }
+}
+
+fn certificate(fields: &[der::Element]) -> Option<Signing> {
+    let algorithm = algorithm(&fields[2])?;
+    let (_, validity) = der::split(fields[4].content)?;
+    let (not_after, _) = der::split(validity)?;
+    let not_after = der::read_time(&not_after)?;
+
+    // The extensions are [3] EXPLICIT, after the optional unique IDs. A
+    // certificate only counts as not a CA when it says so, so one without
+    // basicConstraints or with two of an extension is taken for one
+    let mut seen: Vec<&[u8]> = Vec::new();
+    let mut ca = Some("has no basicConstraints with cA FALSE");
+    let mut signs = None;
+    if let Some(extensions) = fields[7..].iter().find(|field| field.tag == 0xa3) {
+        let (extensions, _) = der::split(extensions.content)?;
+        let mut rest = extensions.content;
+        while !rest.is_empty() {
+            let (extension, next) = der::split(rest)?;
+            rest = next;
+            let (oid, value) = der::split(extension.content)?;
+            if seen.contains(&oid.encoded) {
+                return Some(Signing::Certificate {
+                    ca: Some("repeats an extension"),
+                    not_after,
+                    algorithm,
+                });
+            }
+            seen.push(oid.encoded);
+
+            // Skip the critical flag to the OCTET STRING holding the value
+            let (mut value, after) = der::split(value)?;
+            if value.tag != der::OCTET_STRING {
+                value = der::split(after)?.0;
+            }
+            let (value, _) = der::split(value.content)?;
+            if oid.encoded == der::oid(BASIC_CONSTRAINTS).as_slice() {
+                // cA is the first field when it is TRUE, as it defaults to FALSE
+                let ca_true = der::split(value.content)
+                    .is_some_and(|(first, _)| first.tag == 0x01 && first.content != [0]);
+                ca = ca_true.then_some("has basicConstraints with cA TRUE");
+            } else if oid.encoded == der::oid(KEY_USAGE).as_slice()
+                && bits(&value) & KEY_CERT_SIGN_CRL_SIGN != 0
+            {
+                signs = Some("has keyUsage keyCertSign or cRLSign");
+            } else if oid.encoded == der::oid(NS_CERT_TYPE).as_slice()
+                && bits(&value) & NS_CERT_TYPE_CA != 0
+            {
+                signs = Some("has an nsCertType CA bit");
+            }
+        }
+    }
+    Some(Signing::Certificate {
+        ca: ca.or(signs),
+        not_after,
+        algorithm,
+    })
+}
+
+/// The first byte of a BIT STRING's flags, where keyUsage keeps keyCertSign
+/// and cRLSign and nsCertType its CA types. Anything else has every flag set
+fn bits(value: &der::Element) -> u8 {
+    match value.content {
+        [_, first, ..] if value.tag == der::BIT_STRING => *first,
+        [_] if value.tag == der::BIT_STRING => 0,
+        _ => 0xff,
+    }
+}
+
+impl Allow {
+    fn permits(&self, uid: u32, name: &str) -> bool {
+        self.uid == uid
+            && self
+                .keys
+                .as_ref()
+                .is_none_or(|keys| keys.iter().any(|key| key == name))
+    }
+}
+
+struct Unlocked {
+    /// `root` or the intermediate's name, as used in Allow
+    name: String,
+    key: PKey<Private>,
+    until: Instant,
+}
+
+struct Agent {
+    store_dir: String,
+    uid: u32,
+    allow: Vec<Allow>,
+    policy: Policy,
+    unlock_for: Duration,
+    keys: HashMap<String, Unlocked>,
+}
+
+/// Listen on a socket and serve requests until killed
+pub fn serve(
+    socket: &str,
+    store_dir: &str,
+    allow: Vec<Allow>,
+    policy: Policy,
+    unlock_for: Duration,
+) {
+    // Keep the keys out of swap and core dumps, and away from debuggers run
+    // by the same user
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        eprintln!(
+            "Unable to lock memory, keys may be swapped to disk: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) };
+
+    let listener = listen(socket, !allow.is_empty());
+    println!("{SOCKET_ENV}={socket}; export {SOCKET_ENV};");
+    std::io::stdout().flush().unwrap();
+
+    let agent = Arc::new(Mutex::new(Agent {
+        store_dir: store_dir.to_string(),
+        uid: unsafe { libc::geteuid() },
+        allow,
+        policy,
+        unlock_for,
+        keys: HashMap::new(),
+    }));
+    let connections: Arc<Mutex<HashMap<u32, usize>>> = Arc::default();
+    loop {
+        // Wake up every second so keys are dropped when they expire even if
+        // nothing connects
+        let mut poll = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut poll, 1, 1000) };
+        lock_agent(&agent).expire();
+        if poll.revents & libc::POLLIN == 0 {
+            continue;
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Unable to accept a connection: {e}");
+                continue;
+            }
+        };
+        let uid = match peer_uid(&stream) {
+            Some(uid) => uid,
+            None => continue,
+        };
+        if !opened(&connections, uid) {
+            eprintln!("Refused a connection from uid {uid}, which has {MAX_CONNECTIONS} open");
+            continue;
+        }
+        let (agent, open) = (Arc::clone(&agent), Arc::clone(&connections));
+        let spawned = thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                handle(&agent, uid, stream);
+                closed(&open, uid);
+            });
+        if let Err(e) = spawned {
+            closed(&connections, uid);
+            eprintln!("Unable to serve a connection: {e}");
+        }
+    }
+}
+
+/// Count a new connection from a user, unless it has too many open
+fn opened(connections: &Mutex<HashMap<u32, usize>>, uid: u32) -> bool {
+    let mut connections = connections.lock().unwrap();
+    let open = connections.entry(uid).or_default();
+    if *open >= MAX_CONNECTIONS {
+        return false;
+    }
+    *open += 1;
+    true
+}
+
+fn closed(connections: &Mutex<HashMap<u32, usize>>, uid: u32) {
+    let mut connections = connections.lock().unwrap();
+    if let Some(open) = connections.get_mut(&uid) {
+        *open -= 1;
+        if *open == 0 {
+            connections.remove(&uid);
+        }
+    }
+}
+
+/// The agent's state, which a thread that panicked while holding it leaves
+/// as consistent as ever
+fn lock_agent(agent: &Mutex<Agent>) -> std::sync::MutexGuard<'_, Agent> {
+    agent
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Read one request and answer it. The whole request is read before the
+/// agent is locked, so a slow client never keeps others waiting
+fn handle(agent: &Mutex<Agent>, uid: u32, stream: UnixStream) {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let line = line.trim_end();
+    let reply = body(line, &mut reader).and_then(|body| lock_agent(agent).reply(uid, line, body));
+    let mut stream = &stream;
+    let _ = match reply {
+        Ok(data) => stream
+            .write_all(format!("OK {}\n", data.len()).as_bytes())
+            .and_then(|_| stream.write_all(&data)),
+        Err(message) => {
+            eprintln!("Refused {} from uid {uid}: {message}", command(line));
+            stream.write_all(format!("ERROR {message}\n").as_bytes())
+        }
+    };
+}
+
+/// The bytes following a request line, whose length is the second word of
+/// SIGN and UNLOCK requests
+fn body(line: &str, reader: &mut impl Read) -> Result<Vec<u8>, String> {
+    match command(line) {
+        "SIGN" | "UNLOCK" => {
+            let length: usize = line
+                .split(' ')
+                .nth(2)
+                .and_then(|length| length.parse().ok())
+                .ok_or("no length given")?;
+            read_exact(reader, length)
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn listen(socket: &str, shared: bool) -> UnixListener {
+    if let Ok(metadata) = fs::symlink_metadata(socket) {
+        if !metadata.file_type().is_socket() {
+            panic!("{socket} exists and is not a socket, refusing to replace it");
+        }
+        if UnixStream::connect(socket).is_ok() {
+            panic!("An agent is already listening on {socket}");
+        }
+        // Left behind by an agent that was killed
+        fs::remove_file(socket).unwrap_or_else(|e| panic!("Unable to remove {socket}: {e}"));
+    }
+    match shared {
+        true => shared_dir(socket),
+        false => path::ensure_dir(socket),
+    }
+
+    // Bind with a restrictive umask so there is no window where others can
+    // connect, then open it to the socket's group if other users are allowed
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(umask) };
+    let listener = listener.unwrap_or_else(|e| panic!("Unable to listen on {socket}: {e}"));
+    if shared {
+        fs::set_permissions(socket, fs::Permissions::from_mode(0o660)).unwrap();
+    }
+    listener
+}
+
+/// Allowed users reach a shared socket through its group, so the directory
+/// it is in has to let the group in, unlike the store's own directory. A
+/// new directory is made group accessible, and an existing one must be
+fn shared_dir(socket: &str) {
+    let dir = match std::path::Path::new(socket).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o750)
+            .create(dir)
+            .unwrap_or_else(|e| panic!("Unable to create {}: {e}", dir.display()));
+    }
+    let mode = fs::metadata(dir)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {e}", dir.display()))
+        .permissions()
+        .mode();
+    if mode & 0o010 == 0 {
+        panic!(
+            "{} is not open to its group, so the users given --allow cannot reach {socket}. \
+             Put the socket in a directory of a group those users are in, with group access",
+            dir.display()
+        );
+    }
+}
+
+impl Agent {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.keys.retain(|path, unlocked| {
+            if unlocked.until > now {
+                return true;
+            }
+            eprintln!("Locked {} ({path})", unlocked.name);
+            false
+        });
+    }
+
+    fn reply(&mut self, uid: u32, line: &str, body: Vec<u8>) -> Result<Vec<u8>, String> {
+        match command(line) {
+            "PUBLIC-KEY" => {
+                let (_, path) = line.split_once(' ').ok_or("no key given")?;
+                let unlocked = self.permitted(uid, path)?;
+                Ok(unlocked.key.public_key_to_pem().unwrap())
+            }
+            "SIGN" => {
+                // The length was used to read the body
+                let mut words = line.splitn(4, ' ').skip(1);
+                let name = words.next().ok_or("no digest given")?;
+                let path = words.nth(1).ok_or("no key given")?;
+                let digest = MessageDigest::from_name(name)
+                    .ok_or_else(|| format!("{name} is not a known digest"))?;
+                let unlocked = self.permitted(uid, path)?;
+                match uid == self.uid {
+                    true => eprintln!("Signed with {} for uid {uid}", unlocked.name),
+                    false => {
+                        let signing = self.policy.check(uid, name, unlocked.key.id(), &body)?;
+                        eprintln!("Signed {signing} with {} for uid {uid}", unlocked.name);
+                    }
+                }
+                Ok(Signer::sign(&unlocked.key, digest, &body))
+            }
+            "UNLOCK" => {
+                self.owner(uid)?;
+                let mut words = line.splitn(4, ' ').skip(1);
+                let key_type = match words.next().map(str::to_lowercase).as_deref() {
+                    Some("rsa") => KeyType::Rsa(0),
+                    Some("ecdsa") => KeyType::Ecdsa,
+                    _ => return Err(String::from("no key type given")),
+                };
+                let intermediate = words.nth(1);
+                let password = String::from_utf8(body).map_err(|_| "the password is not UTF-8")?;
+                self.unlock(key_type, intermediate, password)?;
+                Ok(Vec::new())
+            }
+            "LOCK" => {
+                self.owner(uid)?;
+                self.keys.clear();
+                eprintln!("Locked every key");
+                Ok(Vec::new())
+            }
+            "LIST" => {
+                self.owner(uid)?;
+                let now = Instant::now();
+                let mut list: Vec<String> = self
+                    .keys
+                    .iter()
+                    .map(|(path, unlocked)| {
+                        let left = unlocked.until.saturating_duration_since(now).as_secs();
+                        format!(
+                            "{}\t{path}\tlocks in {}h{:02}m\n",
+                            unlocked.name,
+                            left / 3600,
+                            left % 3600 / 60
+                        )
+                    })
+                    .collect();
+                list.sort();
+                Ok(list.concat().into_bytes())
+            }
+            other => Err(format!("{other} is not a known request")),
+        }
+    }
+
+    fn owner(&self, uid: u32) -> Result<(), String> {
+        match uid == self.uid {
+            true => Ok(()),
+            false => Err(String::from("only the agent's own user may do this")),
+        }
+    }
+
+    fn permitted(&self, uid: u32, path: &str) -> Result<&Unlocked, String> {
+        let unlocked = fs::canonicalize(path)
+            .ok()
+            .and_then(|path| self.keys.get(path.to_str()?))
+            .ok_or_else(|| format!("{path} is not unlocked"))?;
+        if uid != self.uid
+            && !self
+                .allow
+                .iter()
+                .any(|allow| allow.permits(uid, &unlocked.name))
+        {
+            return Err(format!("uid {uid} may not use {}", unlocked.name));
+        }
+        Ok(unlocked)
+    }
+
+    fn unlock(
+        &mut self,
+        key_type: KeyType,
+        intermediate: Option<&str>,
+        password: String,
+    ) -> Result<(), String> {
+        if let Some(name) = intermediate {
+            path::validate_name(name)?;
+        }
+        let (name, pkey_path) = match intermediate {
+            Some(name) => (
+                name.to_string(),
+                path::intermediate_pkey(&self.store_dir, name, key_type),
+            ),
+            None => (
+                String::from("root"),
+                path::ca_pkey(&self.store_dir, key_type),
+            ),
+        };
+        if signer::is_external(&pkey_path) {
+            return Err(format!("{pkey_path} is not kept by hancock"));
+        }
+        let pem = read(&pkey_path).map_err(|e| format!("Unable to read {pkey_path}: {e}"))?;
+        let password = match pkey::is_encrypted(&pkey_path) {
+            true => Some(password),
+            false => None,
+        };
+        let key = pkey::decode_pkey(&pem, &password)?;
+        let canonical = fs::canonicalize(&pkey_path).unwrap();
+        eprintln!("Unlocked {name} ({pkey_path})");
+        self.keys.insert(
+            canonical.to_string_lossy().to_string(),
+            Unlocked {
+                name,
+                key,
+                until: Instant::now() + self.unlock_for,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// The agent's key for a key file, if an agent is running, holds it, and lets
+/// this user sign with it
+pub fn key(path: &str) -> Option<external::Key> {
+    let socket = std::env::var(SOCKET_ENV).ok()?;
+    let path = fs::canonicalize(path).ok()?;
+    external::Key::from_socket(&socket, Some(path.to_string_lossy().to_string())).ok()
+}
+
+/// Whether the agent can sign with the key in a key file
+pub fn holds(path: &str) -> bool {
+    key(path).is_some()
+}
+
+/// Have the agent decrypt and hold the root's or an intermediate's key
+pub fn unlock(
+    socket: &str,
+    key_type: KeyType,
+    intermediate: &Option<String>,
+    password: &str,
+) -> Result<(), String> {
+    let key_type = match key_type {
+        KeyType::Rsa(_) => "rsa",
+        KeyType::Ecdsa => "ecdsa",
+    };
+    let mut line = format!("UNLOCK {key_type} {}", password.len());
+    if let Some(intermediate) = intermediate {
+        line = format!("{line} {intermediate}");
+    }
+    external::request(socket, &line, password.as_bytes()).map(|_| ())
+}
+
+pub fn lock(socket: &str) -> Result<(), String> {
+    external::request(socket, "LOCK", &[]).map(|_| ())
+}
+
+pub fn list(socket: &str) -> Result<String, String> {
+    external::request(socket, "LIST", &[]).map(|list| String::from_utf8_lossy(&list).to_string())
+}
+
+fn command(line: &str) -> &str {
+    line.split(' ').next().unwrap_or_default()
+}
+
+fn read_exact(reader: &mut impl Read, length: usize) -> Result<Vec<u8>, String> {
+    // Requests carry a signed structure or a password, never more
+    if length > 1 << 20 {
+        return Err(format!("{length} bytes is too large"));
+    }
+    let mut data = vec![0; length];
+    reader
+        .read_exact(&mut data)
+        .map_err(|e| format!("Unable to read the request: {e}"))?;
+    Ok(data)
+}
+
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match result {
+        0 => Some(cred.uid),
+        _ => None,
+    }
+}
+
+/// The uid of a user given by name or uid
+pub fn uid(user: &str) -> Result<u32, String> {
+    match user.parse() {
+        Ok(uid) => Ok(uid),
+        Err(_) => uid_of(user).ok_or_else(|| format!("{user} is not a known user")),
+    }
+}
+
+fn uid_of(user: &str) -> Option<u32> {
+    let name = CString::new(user).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    match passwd.is_null() {
+        true => None,
+        false => Some(unsafe { (*passwd).pw_uid }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crl;
+    use crate::validity::Validity;
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::x509::extension::{BasicConstraints, KeyUsage};
+    use openssl::x509::{X509Extension, X509NameBuilder, X509};
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn cert(key: &PKey<Private>, days: u32, extensions: Vec<X509Extension>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "test").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        for extension in extensions {
+            builder.append_extension(extension).unwrap();
+        }
+        builder.sign(key, MessageDigest::sha384()).unwrap();
+        builder.build()
+    }
+
+    /// The signed part of a certificate or CRL
+    fn tbs(signed: &[u8]) -> Vec<u8> {
+        let (outer, _) = der::split(signed).unwrap();
+        der::split(outer.content).unwrap().0.encoded.to_vec()
+    }
+
+    fn not_ca() -> X509Extension {
+        BasicConstraints::new().critical().build().unwrap()
+    }
+
+    /// Whether a certificate with these extensions could act as a CA
+    fn could_be_ca(extensions: Vec<X509Extension>) -> bool {
+        let cert = cert(&key(), 30, extensions);
+        match signing(&tbs(&cert.to_der().unwrap())) {
+            Some(Signing::Certificate { ca, .. }) => ca.is_some(),
+            other => panic!("{other:?} is not a certificate"),
+        }
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            max_lifetime: validity::Duration::days(90),
+            revokers: vec![1001],
+        }
+    }
+
+    #[test]
+    fn shares_sockets_only_through_group_directories() {
+        let dir = path::TestDir::new("agent-shared");
+        let socket = format!("{}/run/agent.sock", dir.0);
+        let _listener = listen(&socket, true);
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&format!("{}/run", dir.0)), 0o750);
+        assert_eq!(mode(&socket), 0o660);
+
+        let private = format!("{}/private", dir.0);
+        fs::create_dir(&private).unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+        let refused = std::panic::catch_unwind(|| listen(&format!("{private}/agent.sock"), true));
+        assert!(refused.is_err());
+    }
+
+    #[test]
+    fn tells_certificates_apart() {
+        assert!(!could_be_ca(vec![not_ca()]));
+        let mut constraints = BasicConstraints::new();
+        constraints.critical().ca().pathlen(0);
+        assert!(could_be_ca(vec![constraints.build().unwrap()]));
+        assert_eq!(signing(&[]), None);
+        assert_eq!(signing(&der::sequence(&[der::integer(&[1])])), None);
+    }
+
+    #[test]
+    fn takes_certificates_for_cas_unless_they_say_otherwise() {
+        assert!(could_be_ca(vec![]));
+        let signs = |usage: &mut KeyUsage| usage.build().unwrap();
+        assert!(!could_be_ca(vec![
+            not_ca(),
+            signs(KeyUsage::new().digital_signature().key_encipherment()),
+        ]));
+        assert!(could_be_ca(vec![
+            not_ca(),
+            signs(KeyUsage::new().digital_signature().key_cert_sign()),
+        ]));
+        assert!(could_be_ca(vec![
+            not_ca(),
+            signs(KeyUsage::new().crl_sign()),
+        ]));
+
+        // nsCertType is a BIT STRING: server alone, then server and sslCA
+        let ns_cert_type = |bits: u8| {
+            X509Extension::new_from_der(
+                &Asn1Object::from_str(NS_CERT_TYPE).unwrap(),
+                false,
+                &Asn1OctetString::new_from_bytes(&der::tlv(der::BIT_STRING, &[0, bits])).unwrap(),
+            )
+            .unwrap()
+        };
+        assert!(!could_be_ca(vec![not_ca(), ns_cert_type(0x40)]));
+        assert!(could_be_ca(vec![not_ca(), ns_cert_type(0x40 | 0x04)]));
+
+        // A second basicConstraints could undo the first
+        let mut constraints = BasicConstraints::new();
+        constraints.critical().ca();
+        assert!(could_be_ca(vec![not_ca(), constraints.build().unwrap()]));
+        assert!(could_be_ca(vec![not_ca(), not_ca()]));
+    }
+
+    #[test]
+    fn limits_other_users() {
+        let key = key();
+        let leaf = tbs(&cert(&key, 30, vec![not_ca()]).to_der().unwrap());
+        assert_eq!(
+            policy().check(1000, "sha384", Id::EC, &leaf),
+            Ok("a certificate")
+        );
+        let long = tbs(&cert(&key, 365, vec![not_ca()]).to_der().unwrap());
+        assert!(policy().check(1000, "sha384", Id::EC, &long).is_err());
+        let mut constraints = BasicConstraints::new();
+        constraints.ca();
+        let ca = tbs(&cert(&key, 30, vec![constraints.build().unwrap()])
+            .to_der()
+            .unwrap());
+        assert!(policy().check(1000, "sha384", Id::EC, &ca).is_err());
+        let bare = tbs(&cert(&key, 30, vec![]).to_der().unwrap());
+        assert!(policy().check(1000, "sha384", Id::EC, &bare).is_err());
+
+        let validity =
+            Validity::from_now(validity::Duration::days(7), validity::Duration::ZERO).unwrap();
+        let crl = crl::generate_crl(&cert(&key, 30, vec![]), &[], 1, &validity, &key);
+        let crl = tbs(&crl.to_der().unwrap());
+        assert!(policy().check(1000, "sha384", Id::EC, &crl).is_err());
+        assert_eq!(policy().check(1001, "sha384", Id::EC, &crl), Ok("a CRL"));
+
+        let response = der::sequence(&[
+            der::tlv(0xa2, &der::tlv(der::OCTET_STRING, &[0; 20])),
+            der::generalized_time(validity::now()),
+            der::sequence(&[]),
+        ]);
+        assert!(policy().check(1000, "sha384", Id::EC, &response).is_err());
+        assert_eq!(
+            policy().check(1001, "sha384", Id::EC, &response),
+            Ok("an OCSP response")
+        );
+    }
+
+    #[test]
+    fn holds_other_users_to_strong_matching_digests() {
+        let leaf = tbs(&cert(&key(), 30, vec![not_ca()]).to_der().unwrap());
+        assert_eq!(
+            policy().check(1000, "SHA384", Id::EC, &leaf),
+            Ok("a certificate")
+        );
+        for (digest, key) in [
+            ("sha1", Id::EC),
+            ("md5", Id::EC),
+            ("sha256", Id::EC),
+            ("sha384", Id::RSA),
+        ] {
+            assert!(
+                policy().check(1000, digest, key, &leaf).is_err(),
+                "{digest} {key:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_weak_or_mismatched_digests_from_other_users() {
+        let dir = path::TestDir::new("agent-digests");
+        let key_path = format!("{}/ca.key", dir.0);
+        fs::write(&key_path, "").unwrap();
+        let key = key();
+        let leaf = tbs(&cert(&key, 30, vec![not_ca()]).to_der().unwrap());
+        let agent = Mutex::new(Agent {
+            store_dir: dir.0.clone(),
+            uid: 0,
+            allow: vec![Allow {
+                uid: 1000,
+                keys: None,
+            }],
+            policy: policy(),
+            unlock_for: Duration::from_secs(60),
+            keys: HashMap::from([(
+                fs::canonicalize(&key_path)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+                Unlocked {
+                    name: String::from("root"),
+                    key,
+                    until: Instant::now() + Duration::from_secs(60),
+                },
+            )]),
+        });
+        let sign = |uid: u32, digest: &str| {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            client
+                .write_all(format!("SIGN {digest} {} {key_path}\n", leaf.len()).as_bytes())
+                .and_then(|_| client.write_all(&leaf))
+                .unwrap();
+            handle(&agent, uid, server);
+            external::read_reply(&mut BufReader::new(client))
+        };
+
+        assert!(sign(1000, "sha384").is_ok());
+        assert!(sign(1000, "sha1").is_err());
+        assert!(sign(1000, "sha256").is_err());
+        // The owner signs whatever it asks for
+        assert!(sign(0, "sha1").is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+//! Bundles carried between an online store and an air-gapped root, so the
+//! root key never has to leave the offline machine: a request for the root
+//! to sign an intermediate or its CRL, and what it signed to bring back
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+
+use crate::crl::Revoked;
+use crate::file;
+use crate::validity::Duration;
+
+pub const REQUEST: &str = "intermediate-request";
+pub const SIGNED: &str = "intermediate-signed";
+pub const CRL_REQUEST: &str = "crl-request";
+pub const CRL_SIGNED: &str = "crl-signed";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub kind: String,
+    /// Name of the intermediate in the requesting store
+    pub name: String,
+    pub key_type: String,
+    /// Requested lifetime, as a duration or a number of days
+    pub lifetime: Duration,
+    /// Profile from the root's configuration to sign with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub csr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signed {
+    pub kind: String,
+    pub name: String,
+    pub key_type: String,
+    pub certificate: String,
+    /// The root that signed it, to check against the requesting store's
+    pub issuer: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrlRequest {
+    pub kind: String,
+    pub key_type: String,
+    pub crl_number: u64,
+    /// How long until the next CRL is due
+    pub lifetime: Duration,
+    #[serde(default)]
+    pub revoked: Vec<Revoked>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrlSigned {
+    pub kind: String,
+    pub key_type: String,
+    pub crl: String,
+    pub issuer: String,
+}
+
+pub fn save<T: Serialize>(path: &str, bundle: &T) {
+    println!("{}", path);
+    file::write_private(path, toml::to_string(bundle).unwrap().as_bytes());
+}
+
+/// What a bundle holds, one of the kinds above
+pub fn kind(path: &str) -> String {
+    #[derive(Deserialize)]
+    struct Kind {
+        kind: String,
+    }
+    read::<Kind>(path).kind
+}
+
+pub fn read_request(path: &str) -> Request {
+    read_kind(path, REQUEST)
+}
+
+pub fn read_signed(path: &str) -> Signed {
+    read_kind(path, SIGNED)
+}
+
+pub fn read_crl_request(path: &str) -> CrlRequest {
+    read_kind(path, CRL_REQUEST)
+}
+
+pub fn read_crl_signed(path: &str) -> CrlSigned {
+    read_kind(path, CRL_SIGNED)
+}
+
+fn read_kind<T: for<'de> Deserialize<'de>>(path: &str, expected: &str) -> T {
+    let kind = kind(path);
+    if kind != expected {
+        panic!("{path} is a {kind} bundle, not a {expected}");
+    }
+    read(path)
+}
+
+fn read<T: for<'de> Deserialize<'de>>(path: &str) -> T {
+    let contents = read_to_string(shellexpand::tilde(path).to_string())
+        .unwrap_or_else(|e| panic!("Unable to read {path}: {e}"));
+    toml::from_str(&contents).unwrap_or_else(|e| panic!("Unable to parse {path}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+
+    fn request() -> Request {
+        Request {
+            kind: REQUEST.to_string(),
+            name: "web".to_string(),
+            key_type: "ecdsa".to_string(),
+            lifetime: Duration::days(365),
+            profile: None,
+            csr: "-----BEGIN CERTIFICATE REQUEST-----".to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_back_a_saved_bundle() {
+        let dir = TestDir::new("bundle-saved");
+        let path = format!("{}/web.toml", dir.0);
+        save(&path, &request());
+
+        assert_eq!(kind(&path), REQUEST);
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("profile"));
+        let read = read_request(&path);
+        assert_eq!(read.name, "web");
+        assert_eq!(read.key_type, "ecdsa");
+        assert_eq!(read.lifetime, Duration::days(365));
+        assert_eq!(read.profile, None);
+        assert_eq!(read.csr, request().csr);
+    }
+
+    #[test]
+    fn takes_lifetimes_in_days_or_as_durations() {
+        let dir = TestDir::new("bundle-lifetimes");
+        let path = format!("{}/web.toml", dir.0);
+        let bundle = |lifetime: &str| {
+            format!("kind = \"{REQUEST}\"\nname = \"web\"\nkey_type = \"rsa\"\nlifetime = {lifetime}\ncsr = \"\"\n")
+        };
+
+        std::fs::write(&path, bundle("90")).unwrap();
+        assert_eq!(read_request(&path).lifetime, Duration::days(90));
+        std::fs::write(&path, bundle("\"12h\"")).unwrap();
+        assert_eq!(read_request(&path).lifetime, Duration(12 * 60 * 60));
+    }
+
+    #[test]
+    #[should_panic(expected = "is a intermediate-request bundle, not a intermediate-signed")]
+    fn refuses_a_bundle_of_another_kind() {
+        let dir = TestDir::new("bundle-kind");
+        let path = format!("{}/web.toml", dir.0);
+        save(&path, &request());
+        read_signed(&path);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to parse")]
+    fn refuses_a_bundle_missing_fields() {
+        let dir = TestDir::new("bundle-fields");
+        let path = format!("{}/web.toml", dir.0);
+        std::fs::write(&path, format!("kind = \"{SIGNED}\"\nname = \"web\"\n")).unwrap();
+        read_signed(&path);
+    }
+}
@@ -1,29 +1,170 @@
-use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
 use openssl::hash::MessageDigest;
-use openssl::pkey::{Id, PKey, Private};
+use openssl::pkey::Id;
 use openssl::x509::extension::*;
 use openssl::x509::*;
 
-use crate::path;
-use std::fs::{read, write, File};
-use std::os::unix::prelude::PermissionsExt;
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+
+use crate::der;
+use crate::file;
+use crate::signer::{self, Signer};
+use crate::validity::Validity;
+use std::fs::read;
+use std::str::FromStr;
+
+/// Usages and URLs to put in issued certificates. Empty usage lists keep the
+/// defaults for a leaf or intermediate
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub key_usage: Vec<String>,
+    pub extended_key_usage: Vec<String>,
+    pub crl_urls: Vec<String>,
+    pub ocsp_urls: Vec<String>,
+    pub issuer_urls: Vec<String>,
+}
+
+impl Profile {
+    /// Check that every usage name is known and every URL is usable
+    pub fn validate(&self) -> Result<(), String> {
+        key_usage(&self.key_usage)?;
+        extended_key_usage(&self.extended_key_usage)?;
+        for url in self
+            .crl_urls
+            .iter()
+            .chain(&self.ocsp_urls)
+            .chain(&self.issuer_urls)
+        {
+            let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+            if !matches!(scheme, Some("http") | Some("https") | Some("ldap"))
+                || !url.is_ascii()
+                || url.contains(char::is_whitespace)
+            {
+                return Err(format!("{url} is not a valid http, https or ldap URL"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do when a certificate would outlive the CA signing it, which makes
+/// it stop validating early
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IssuerExpiry {
+    /// Shorten the certificate to end when the CA does
+    #[default]
+    Truncate,
+    /// Refuse to issue the certificate
+    Fail,
+    /// Renew the CA with its existing key first
+    Renew,
+}
+
+impl FromStr for IssuerExpiry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "truncate" => Ok(IssuerExpiry::Truncate),
+            "fail" => Ok(IssuerExpiry::Fail),
+            "renew" => Ok(IssuerExpiry::Renew),
+            _ => Err(format!(
+                "{s} is not a valid issuer expiry policy ['truncate', 'fail', 'renew']"
+            )),
+        }
+    }
+}
+
+pub fn key_usage(names: &[String]) -> Result<KeyUsage, String> {
+    let mut key_usage = KeyUsage::new();
+    key_usage.critical();
+    for name in names {
+        match name.to_lowercase().replace(['_', '-'], "").as_str() {
+            "digitalsignature" => key_usage.digital_signature(),
+            "nonrepudiation" | "contentcommitment" => key_usage.non_repudiation(),
+            "keyencipherment" => key_usage.key_encipherment(),
+            "dataencipherment" => key_usage.data_encipherment(),
+            "keyagreement" => key_usage.key_agreement(),
+            "keycertsign" => key_usage.key_cert_sign(),
+            "crlsign" => key_usage.crl_sign(),
+            "encipheronly" => key_usage.encipher_only(),
+            "decipheronly" => key_usage.decipher_only(),
+            _ => return Err(format!("{name} is not a known key usage")),
+        };
+    }
+    Ok(key_usage)
+}
+
+pub fn extended_key_usage(names: &[String]) -> Result<ExtendedKeyUsage, String> {
+    let mut extended_key_usage = ExtendedKeyUsage::new();
+    for name in names {
+        let openssl_name = match name.to_lowercase().replace(['_', '-'], "").as_str() {
+            "serverauth" => "serverAuth",
+            "clientauth" => "clientAuth",
+            "codesigning" => "codeSigning",
+            "emailprotection" => "emailProtection",
+            "timestamping" => "timeStamping",
+            "ocspsigning" => "OCSPSigning",
+            _ => name.as_str(),
+        };
+        if Asn1Object::from_str(openssl_name).is_err() {
+            return Err(format!("{name} is not a known extended key usage"));
+        }
+        extended_key_usage.other(openssl_name);
+    }
+    Ok(extended_key_usage)
+}
+
+fn crl_distribution_points(urls: &[String]) -> X509Extension {
+    let points: Vec<Vec<u8>> = urls
+        .iter()
+        .map(|url| {
+            // DistributionPoint { distributionPoint [0] { fullName [0] { URI } } }
+            let full_name = der::tlv(0xa0, &der::uri_general_name(url));
+            der::sequence(&[der::tlv(0xa0, &full_name)])
+        })
+        .collect();
+    X509Extension::new_from_der(
+        &Asn1Object::from_str("2.5.29.31").unwrap(),
+        false,
+        &Asn1OctetString::new_from_bytes(&der::sequence(&points)).unwrap(),
+    )
+    .unwrap()
+}
+
+fn authority_info_access(ocsp_urls: &[String], issuer_urls: &[String]) -> X509Extension {
+    let descriptions: Vec<Vec<u8>> = ocsp_urls
+        .iter()
+        .map(|url| ("1.3.6.1.5.5.7.48.1", url))
+        .chain(issuer_urls.iter().map(|url| ("1.3.6.1.5.5.7.48.2", url)))
+        .map(|(method, url)| der::sequence(&[der::oid(method), der::uri_general_name(url)]))
+        .collect();
+    X509Extension::new_from_der(
+        &Asn1Object::from_str("1.3.6.1.5.5.7.1.1").unwrap(),
+        false,
+        &Asn1OctetString::new_from_bytes(&der::sequence(&descriptions)).unwrap(),
+    )
+    .unwrap()
+}
 
 pub fn generate_cert(
-    lifetime_days: u32,
+    validity: &Validity,
     signing_request: &X509Req,
     intermediate: bool,
+    profile: &Profile,
     ca_cert: &X509,
-    ca_key_pair: &PKey<Private>,
+    ca_signer: &dyn Signer,
 ) -> X509 {
     let mut x509_builder = X509::builder().unwrap();
     x509_builder.set_version(2).unwrap();
 
+    // A certificate never outlives the CA that signs it
+    let validity = validity.within(ca_cert).unwrap_or_else(|e| panic!("{e}"));
     x509_builder
-        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .set_not_before(&validity.not_before_time())
         .unwrap();
     x509_builder
-        .set_not_after(&Asn1Time::days_from_now(lifetime_days).unwrap())
+        .set_not_after(&validity.not_after_time())
         .unwrap();
 
     let mut serial = BigNum::new().unwrap();
@@ -50,6 +191,9 @@ pub fn generate_cert(
     x509_builder.append_extension(basic_constraints).unwrap();
 
     let key_usage = match intermediate {
+        _ if !profile.key_usage.is_empty() => {
+            key_usage(&profile.key_usage).unwrap().build().unwrap()
+        }
         true => KeyUsage::new()
             .critical()
             .key_cert_sign()
@@ -65,13 +209,23 @@ pub fn generate_cert(
     };
     x509_builder.append_extension(key_usage).unwrap();
 
-    if !intermediate {
-        let extended_key_usage = ExtendedKeyUsage::new()
-            .client_auth()
-            .server_auth()
+    if !profile.extended_key_usage.is_empty() {
+        let extended_key_usage = extended_key_usage(&profile.extended_key_usage)
+            .unwrap()
             .build()
             .unwrap();
         x509_builder.append_extension(extended_key_usage).unwrap();
+    }
+
+    if !intermediate {
+        if profile.extended_key_usage.is_empty() {
+            let extended_key_usage = ExtendedKeyUsage::new()
+                .client_auth()
+                .server_auth()
+                .build()
+                .unwrap();
+            x509_builder.append_extension(extended_key_usage).unwrap();
+        }
 
         // TODO: It'd be cool if this ignored anything but SANs
         // but I'm not sure if that's possible
@@ -98,26 +252,149 @@ pub fn generate_cert(
         .append_extension(authority_key_identifier)
         .unwrap();
 
+    if !profile.crl_urls.is_empty() {
+        x509_builder
+            .append_extension(crl_distribution_points(&profile.crl_urls))
+            .unwrap();
+    }
+    if !profile.ocsp_urls.is_empty() || !profile.issuer_urls.is_empty() {
+        x509_builder
+            .append_extension(authority_info_access(
+                &profile.ocsp_urls,
+                &profile.issuer_urls,
+            ))
+            .unwrap();
+    }
+
     let digest_algorithm = match signing_request.public_key().unwrap().id() {
         Id::RSA => MessageDigest::sha256(),
         Id::EC => MessageDigest::sha384(),
         _ => MessageDigest::sha256(),
     };
 
-    x509_builder.sign(ca_key_pair, digest_algorithm).unwrap();
-
-    x509_builder.build()
+    signer::sign_cert(x509_builder, ca_signer, digest_algorithm)
 }
 
 pub fn save_cert(path: &str, cert: &X509) {
-    path::ensure_dir(path);
-    let file = File::create(path).unwrap();
-    let mut permissions = file.metadata().unwrap().permissions();
-    permissions.set_mode(0o600);
-    std::fs::set_permissions(path, permissions).unwrap();
-    write(path, cert.to_pem().unwrap()).unwrap();
+    file::write_private(path, &cert.to_pem().unwrap());
 }
 
 pub fn read_cert(path: &str) -> X509 {
     X509::from_pem(&read(path).unwrap()).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain;
+    use crate::validity::{self, Duration, Timestamp};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn name(common_name: &str) -> X509Name {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        name.build()
+    }
+
+    /// A self-signed CA that expires some days from now
+    fn ca(key: &PKey<Private>, days: i64) -> X509 {
+        let now = validity::now();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name("Root")).unwrap();
+        builder.set_issuer_name(&name("Root")).unwrap();
+        builder
+            .set_not_before(&Asn1Time::from_unix((now - DAY) as libc::time_t).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix((now + days * DAY) as libc::time_t).unwrap())
+            .unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        let subject_key_identifier = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_key_identifier).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn request(common_name: &str) -> X509Req {
+        let key = key();
+        let mut builder = X509Req::builder().unwrap();
+        builder.set_subject_name(&name(common_name)).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn issue(
+        validity: &validity::Validity,
+        intermediate: bool,
+        ca_cert: &X509,
+        ca_key: &PKey<Private>,
+    ) -> X509 {
+        generate_cert(
+            validity,
+            &request("web"),
+            intermediate,
+            &Profile::default(),
+            ca_cert,
+            ca_key,
+        )
+    }
+
+    #[test]
+    fn ends_certificates_with_their_issuer() {
+        let ca_key = key();
+        let ca_cert = ca(&ca_key, 10);
+        let ca_end = validity::unix(ca_cert.not_after());
+
+        let long = validity::Validity::from_now(Duration::days(30), Duration::ZERO).unwrap();
+        assert!(long.outlives(&ca_cert));
+        let cert = issue(&long, false, &ca_cert, &ca_key);
+        assert_eq!(validity::unix(cert.not_after()), ca_end);
+        assert!(!chain::is_ca(&cert));
+
+        let short = validity::Validity::from_now(Duration::days(5), Duration::ZERO).unwrap();
+        assert!(!short.outlives(&ca_cert));
+        let cert = issue(&short, true, &ca_cert, &ca_key);
+        assert_eq!(validity::unix(cert.not_after()), short.not_after);
+        assert!(chain::is_ca(&cert));
+        assert!(chain::verify(&[cert, ca_cert]).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "would only start once its issuer has expired")]
+    fn refuses_certificates_starting_after_their_issuer_ends() {
+        let ca_key = key();
+        let ca_cert = ca(&ca_key, 10);
+        let start = Timestamp(validity::now() + 20 * DAY);
+        let validity =
+            validity::Validity::between(Some(start), None, Duration::days(30), Duration::ZERO)
+                .unwrap();
+        issue(&validity, false, &ca_cert, &ca_key);
+    }
+
+    #[test]
+    fn parses_issuer_expiry_policies() {
+        assert_eq!("truncate".parse(), Ok(IssuerExpiry::Truncate));
+        assert_eq!("Fail".parse(), Ok(IssuerExpiry::Fail));
+        assert_eq!("RENEW".parse(), Ok(IssuerExpiry::Renew));
+        assert_eq!(IssuerExpiry::default(), IssuerExpiry::Truncate);
+        assert!("extend".parse::<IssuerExpiry>().is_err());
+    }
+}
@@ -0,0 +1,397 @@
+//! Issuer certificates written alongside a certificate, so clients that only
+//! trust an external root can build a path from it to the root
+use openssl::asn1::Asn1Time;
+use openssl::x509::{X509VerifyResult, X509};
+use std::cmp::Ordering;
+use std::fs::{read, read_dir};
+use std::path::Path;
+
+use crate::cert;
+use crate::der;
+use crate::path;
+use crate::subject::Subject;
+use crate::KeyType;
+
+const BASIC_CONSTRAINTS: &str = "2.5.29.19";
+const BOOLEAN: u8 = 0x01;
+
+/// Read certificates from PEM, or a single DER certificate as Windows CAs
+/// often give them
+pub fn read_certs(path: &str) -> Vec<X509> {
+    let contents = read(path).unwrap_or_else(|e| panic!("Unable to read {path}: {e}"));
+    match X509::stack_from_pem(&contents) {
+        Ok(certs) if !certs.is_empty() => certs,
+        _ => vec![X509::from_der(&contents)
+            .unwrap_or_else(|_| panic!("{path} does not hold PEM or DER certificates"))],
+    }
+}
+
+/// PEM of a certificate followed by its issuers
+pub fn encode(cert: &X509, issuers: &[X509]) -> Vec<u8> {
+    let mut pem = cert.to_pem().unwrap();
+    pem.extend(encode_all(issuers));
+    pem
+}
+
+pub fn encode_all(certs: &[X509]) -> Vec<u8> {
+    certs.iter().flat_map(|c| c.to_pem().unwrap()).collect()
+}
+
+/// Certificates to send after one signed by the root or an intermediate:
+/// the intermediate, then for a subordinate root the root itself and the
+/// chain it was imported with. A self-signed root is left out, since clients
+/// must already trust it
+pub fn issuers(base_dir: &str, key_type: KeyType, intermediate: Option<&str>) -> Vec<X509> {
+    let mut issuers = Vec::new();
+    if let Some(name) = intermediate {
+        issuers.push(cert::read_cert(&path::intermediate_crt(
+            base_dir, name, key_type,
+        )));
+    }
+    let root = cert::read_cert(&path::ca_crt(base_dir, key_type));
+    match is_self_signed(&root) {
+        true => issuers.extend(cross_certs(base_dir, &root)),
+        false => {
+            issuers.push(root);
+            let chain_path = path::ca_chain(base_dir, key_type);
+            if Path::new(&chain_path).exists() {
+                issuers.extend(read_certs(&chain_path));
+            }
+        }
+    }
+    issuers
+}
+
+/// Unexpired certificates for a root's subject and key signed by other roots
+pub fn cross_certs(base_dir: &str, root: &X509) -> Vec<X509> {
+    let entries = match read_dir(path::cross_dir(base_dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut certs: Vec<X509> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| cert::read_cert(&entry.path().to_string_lossy()))
+        .filter(|cert| is_cross_cert(cert, root))
+        .collect();
+    certs.sort_by_key(|cert| cert.to_der().unwrap());
+    certs
+}
+
+/// Whether a certificate is an unexpired one for a root's subject and key
+/// signed by another root
+pub fn is_cross_cert(cert: &X509, root: &X509) -> bool {
+    cert.public_key()
+        .unwrap()
+        .public_eq(&root.public_key().unwrap())
+        && matches!(
+            cert.subject_name().try_cmp(root.subject_name()),
+            Ok(Ordering::Equal)
+        )
+        && !is_self_signed(cert)
+        && cert.not_after() > Asn1Time::days_from_now(0).unwrap()
+}
+
+pub fn is_self_signed(cert: &X509) -> bool {
+    cert.issued(cert) == X509VerifyResult::OK
+        && cert.verify(&cert.public_key().unwrap()).unwrap_or(false)
+}
+
+/// Check that each certificate is issued and signed by the one after it
+pub fn verify(certs: &[X509]) -> Result<(), String> {
+    for pair in certs.windows(2) {
+        let (subject, issuer) = (&pair[0], &pair[1]);
+        let issued = issuer.issued(subject);
+        if issued != X509VerifyResult::OK {
+            return Err(format!(
+                "{} is not issued by {}: {}",
+                describe(subject),
+                describe(issuer),
+                issued.error_string()
+            ));
+        }
+        if !subject
+            .verify(&issuer.public_key().unwrap())
+            .unwrap_or(false)
+        {
+            return Err(format!(
+                "The signature on {} does not verify with {}",
+                describe(subject),
+                describe(issuer)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a certificate's BasicConstraints mark it as a CA
+pub fn is_ca(cert: &X509) -> bool {
+    basic_constraints_ca(&cert.to_der().unwrap()).unwrap_or(false)
+}
+
+fn basic_constraints_ca(cert: &[u8]) -> Option<bool> {
+    let (cert, _) = der::split(cert)?;
+    let (tbs, _) = der::split(cert.content)?;
+
+    // Extensions are the only field of the TBSCertificate tagged [3]
+    let mut fields = tbs.content;
+    let extensions = loop {
+        let (field, rest) = der::split(fields)?;
+        if field.tag == 0xa3 {
+            break der::split(field.content)?.0;
+        }
+        fields = rest;
+    };
+
+    // Each extension is a SEQUENCE of its OID, an optional critical BOOLEAN
+    // and an OCTET STRING holding its value
+    let mut rest = extensions.content;
+    while !rest.is_empty() {
+        let (extension, next) = der::split(rest)?;
+        rest = next;
+        let (oid, value) = der::split(extension.content)?;
+        if oid.encoded != der::oid(BASIC_CONSTRAINTS) {
+            continue;
+        }
+        let (mut octets, after) = der::split(value)?;
+        if octets.tag == BOOLEAN {
+            octets = der::split(after)?.0;
+        }
+        // BasicConstraints is a SEQUENCE whose cA BOOLEAN is left out when false
+        let (constraints, _) = der::split(octets.content)?;
+        return Some(match der::split(constraints.content) {
+            Some((first, _)) => first.tag == BOOLEAN && first.content != [0],
+            None => false,
+        });
+    }
+    Some(false)
+}
+
+fn describe(cert: &X509) -> String {
+    Subject::from_name(cert.subject_name()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::X509NameBuilder;
+    use std::fs::create_dir_all;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// A certificate for a key, signed by an issuer or by itself, ending some
+    /// days from now
+    fn build(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        ca: Option<bool>,
+        days: i64,
+    ) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let now = crate::validity::now();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        match issuer {
+            Some((cert, _)) => builder.set_issuer_name(cert.subject_name()).unwrap(),
+            None => builder.set_issuer_name(&subject).unwrap(),
+        }
+        builder
+            .set_not_before(&Asn1Time::from_unix((now - 2 * DAY) as libc::time_t).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix((now + days * DAY) as libc::time_t).unwrap())
+            .unwrap();
+        builder.set_pubkey(key).unwrap();
+        match ca {
+            Some(true) => builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap(),
+            Some(false) => builder
+                .append_extension(BasicConstraints::new().critical().build().unwrap())
+                .unwrap(),
+            None => {}
+        }
+        let signing_key = issuer.map(|(_, key)| key).unwrap_or(key);
+        builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn verifies_each_certificate_against_the_next() {
+        let (root_key, intermediate_key, leaf_key) = (key(), key(), key());
+        let root = build("Root", &root_key, None, Some(true), 30);
+        let intermediate = build(
+            "Intermediate",
+            &intermediate_key,
+            Some((&root, &root_key)),
+            Some(true),
+            30,
+        );
+        let leaf = build(
+            "web",
+            &leaf_key,
+            Some((&intermediate, &intermediate_key)),
+            Some(false),
+            30,
+        );
+
+        assert!(verify(&[leaf.clone(), intermediate.clone(), root.clone()]).is_ok());
+        assert!(verify(std::slice::from_ref(&leaf)).is_ok());
+        let out_of_order = verify(&[leaf.clone(), root.clone(), intermediate]).unwrap_err();
+        assert!(
+            out_of_order.starts_with("/CN=web is not issued by /CN=Root"),
+            "{out_of_order}"
+        );
+
+        // Same name as the intermediate, but a different key
+        let impostor = build(
+            "Intermediate",
+            &key(),
+            Some((&root, &root_key)),
+            Some(true),
+            30,
+        );
+        let forged = verify(&[leaf, impostor]).unwrap_err();
+        assert!(forged.contains("does not verify"), "{forged}");
+    }
+
+    #[test]
+    fn tells_cas_from_leaves() {
+        let root_key = key();
+        let root = build("Root", &root_key, None, Some(true), 30);
+        let leaf = build("web", &key(), Some((&root, &root_key)), Some(false), 30);
+        let unconstrained = build("old", &key(), Some((&root, &root_key)), None, 30);
+
+        assert!(is_ca(&root));
+        assert!(!is_ca(&leaf));
+        assert!(!is_ca(&unconstrained));
+        assert!(is_self_signed(&root));
+        assert!(!is_self_signed(&leaf));
+    }
+
+    #[test]
+    fn reads_pem_and_der_certificates() {
+        let dir = TestDir::new("chain-read");
+        let root_key = key();
+        let root = build("Root", &root_key, None, Some(true), 30);
+        let leaf = build("web", &key(), Some((&root, &root_key)), Some(false), 30);
+
+        let pem = format!("{}/chain.pem", dir.0);
+        std::fs::write(&pem, encode(&leaf, std::slice::from_ref(&root))).unwrap();
+        let certs = read_certs(&pem);
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].to_der().unwrap(), leaf.to_der().unwrap());
+        assert_eq!(certs[1].to_der().unwrap(), root.to_der().unwrap());
+
+        let der = format!("{}/root.cer", dir.0);
+        std::fs::write(&der, root.to_der().unwrap()).unwrap();
+        assert_eq!(read_certs(&der).len(), 1);
+    }
+
+    #[test]
+    fn sends_intermediates_and_current_cross_certificates() {
+        let dir = TestDir::new("chain-issuers");
+        let base_dir = &dir.0;
+        let (root_key, other_key, intermediate_key) = (key(), key(), key());
+        let root = build("Root", &root_key, None, Some(true), 30);
+        let other = build("Other Root", &other_key, None, Some(true), 30);
+        let intermediate = build(
+            "web",
+            &intermediate_key,
+            Some((&root, &root_key)),
+            Some(true),
+            30,
+        );
+        create_dir_all(format!("{base_dir}/intermediates/web")).unwrap();
+        create_dir_all(path::cross_dir(base_dir)).unwrap();
+        cert::save_cert(&path::ca_crt(base_dir, KeyType::Ecdsa), &root);
+        cert::save_cert(
+            &path::intermediate_crt(base_dir, "web", KeyType::Ecdsa),
+            &intermediate,
+        );
+
+        let cross = build(
+            "Root",
+            &root_key,
+            Some((&other, &other_key)),
+            Some(true),
+            30,
+        );
+        let expired = build(
+            "Root",
+            &root_key,
+            Some((&other, &other_key)),
+            Some(true),
+            -1,
+        );
+        let other_subject = build(
+            "Not Root",
+            &root_key,
+            Some((&other, &other_key)),
+            Some(true),
+            30,
+        );
+        let other_key_cross = build("Root", &key(), Some((&other, &other_key)), Some(true), 30);
+        for (name, cert) in [
+            ("cross", &cross),
+            ("expired", &expired),
+            ("subject", &other_subject),
+            ("key", &other_key_cross),
+            ("self", &root),
+        ] {
+            cert::save_cert(&format!("{}/{name}.crt", path::cross_dir(base_dir)), cert);
+        }
+
+        let ders = |certs: Vec<X509>| -> Vec<Vec<u8>> {
+            certs.iter().map(|cert| cert.to_der().unwrap()).collect()
+        };
+        assert_eq!(
+            ders(issuers(base_dir, KeyType::Ecdsa, Some("web"))),
+            ders(vec![intermediate.clone(), cross.clone()])
+        );
+        assert_eq!(
+            ders(issuers(base_dir, KeyType::Ecdsa, None)),
+            ders(vec![cross.clone()])
+        );
+
+        // A subordinate root is sent along with the chain it was imported with
+        let subordinate = build(
+            "Root",
+            &root_key,
+            Some((&other, &other_key)),
+            Some(true),
+            30,
+        );
+        cert::save_cert(&path::ca_crt(base_dir, KeyType::Ecdsa), &subordinate);
+        std::fs::write(
+            path::ca_chain(base_dir, KeyType::Ecdsa),
+            encode_all(std::slice::from_ref(&other)),
+        )
+        .unwrap();
+        assert_eq!(
+            ders(issuers(base_dir, KeyType::Ecdsa, Some("web"))),
+            ders(vec![intermediate, subordinate, other])
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cert::{IssuerExpiry, Profile};
+use crate::path;
+use crate::pkey;
+use crate::policy;
+use crate::subject::Subject;
+use crate::validity::Duration;
+
+pub const DEFAULT_BASE_DIR: &str = "~/.hancock";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// CA used when `--ca` is not given
+    pub default_ca: Option<String>,
+
+    #[serde(default)]
+    pub ca: BTreeMap<String, CaConfig>,
+
+    #[serde(default)]
+    pub profile: BTreeMap<String, ProfileConfig>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaConfig {
+    pub base_dir: Option<String>,
+
+    /// CA store within the base directory
+    pub store: Option<String>,
+
+    pub key_type: Option<String>,
+    pub key_length: Option<u32>,
+
+    /// Cipher and KDF for private keys given a password, as accepted by
+    /// `--cipher` and `--kdf`
+    pub key_cipher: Option<String>,
+    pub key_kdf: Option<String>,
+
+    /// Intermediate that signs leaves issued from this CA
+    pub intermediate: Option<String>,
+
+    /// Lifetimes as durations like "8h" or "30d", or numbers of days
+    pub root_lifetime: Option<Duration>,
+    pub intermediate_lifetime: Option<Duration>,
+    pub lifetime: Option<Duration>,
+
+    /// How long each CRL is valid for until the next one is due
+    pub crl_lifetime: Option<Duration>,
+
+    /// How far before now issued certificates start, for clients with slow
+    /// clocks
+    pub backdate: Option<Duration>,
+
+    /// What to do when a certificate would outlive its issuer, as accepted
+    /// by `--issuer-expiry`
+    pub issuer_expiry: Option<String>,
+
+    /// Default subject attributes, keyed by short name
+    #[serde(default)]
+    pub subject: toml::Table,
+
+    #[serde(default)]
+    pub crl_urls: Vec<String>,
+    #[serde(default)]
+    pub ocsp_urls: Vec<String>,
+    #[serde(default)]
+    pub issuer_urls: Vec<String>,
+
+    /// Profile used when `--profile` is not given
+    pub profile: Option<String>,
+
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    /// Whether the CA was named with `--ca` rather than being the default
+    #[serde(skip)]
+    pub explicit: bool,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub key_type: Option<String>,
+    pub key_length: Option<u32>,
+    pub lifetime: Option<Duration>,
+    #[serde(default)]
+    pub key_usage: Vec<String>,
+    #[serde(default)]
+    pub extended_key_usage: Vec<String>,
+}
+
+/// Shell commands run after a certificate is written. Each is run with
+/// `sh -c` and `HANCOCK_*` variables describing the certificate
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default)]
+    pub post_issue: Vec<String>,
+    #[serde(default)]
+    pub post_renew: Vec<String>,
+}
+
+impl Config {
+    /// Load the file given with `--config`, or the first `hancock/hancock.toml`
+    /// found in the XDG config directories. No file gives an empty config
+    pub fn load(explicit: &Option<String>) -> Config {
+        match find(explicit) {
+            Some(path) => Config::load_from(&path).unwrap_or_else(|e| panic!("{e}")),
+            None => Config::default(),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config, String> {
+        let contents =
+            read_to_string(path).map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+        toml::from_str(&contents).map_err(|e| format!("Unable to parse {}: {e}", path.display()))
+    }
+
+    /// The named CA, or the default CA if no name is given
+    pub fn ca(&self, explicit: &Option<String>) -> CaConfig {
+        match explicit.as_ref().or(self.default_ca.as_ref()) {
+            Some(name) => match self.ca.get(name) {
+                Some(ca) => CaConfig {
+                    explicit: explicit.is_some(),
+                    ..ca.clone()
+                },
+                None => panic!("No CA named {name} in the configuration file"),
+            },
+            None => CaConfig::default(),
+        }
+    }
+
+    pub fn profile(&self, name: &str) -> ProfileConfig {
+        match self.profile.get(name) {
+            Some(profile) => profile.clone(),
+            None => panic!("No profile named {name} in the configuration file"),
+        }
+    }
+
+    /// Every problem found in the configuration
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(ref name) = self.default_ca {
+            if !self.ca.contains_key(name) {
+                errors.push(format!("default_ca {name} is not a configured CA"));
+            }
+        }
+
+        for (name, ca) in &self.ca {
+            if let Some(ref key_type) = ca.key_type {
+                if let Err(e) = check_key_type(key_type) {
+                    errors.push(format!("ca.{name}: {e}"));
+                }
+            }
+            if let Some(ref store) = ca.store {
+                if let Err(e) = path::validate_store_name(store) {
+                    errors.push(format!("ca.{name}: {e}"));
+                }
+            }
+            if let Some(ref cipher) = ca.key_cipher {
+                if let Err(e) = cipher.parse::<pkey::Cipher>() {
+                    errors.push(format!("ca.{name}: {e}"));
+                }
+            }
+            if let Some(ref kdf) = ca.key_kdf {
+                if let Err(e) = kdf.parse::<pkey::Kdf>() {
+                    errors.push(format!("ca.{name}: {e}"));
+                }
+            }
+            if let Some(ref issuer_expiry) = ca.issuer_expiry {
+                if let Err(e) = issuer_expiry.parse::<IssuerExpiry>() {
+                    errors.push(format!("ca.{name}: {e}"));
+                }
+            }
+            if let Err(e) = policy::subject_from_table(&ca.subject) {
+                errors.push(format!("ca.{name}.subject: {e}"));
+            }
+            if let Err(e) = ca.profile_extensions(&ProfileConfig::default()).validate() {
+                errors.push(format!("ca.{name}: {e}"));
+            }
+            if let Some(ref profile) = ca.profile {
+                if !self.profile.contains_key(profile) {
+                    errors.push(format!("ca.{name}: profile {profile} is not configured"));
+                }
+            }
+            for (lifetime, duration) in [
+                ("root_lifetime", ca.root_lifetime),
+                ("intermediate_lifetime", ca.intermediate_lifetime),
+                ("lifetime", ca.lifetime),
+                ("crl_lifetime", ca.crl_lifetime),
+            ] {
+                if duration.is_some_and(|d| d <= Duration::ZERO) {
+                    errors.push(format!("ca.{name}: {lifetime} must be positive"));
+                }
+            }
+        }
+
+        for (name, profile) in &self.profile {
+            if let Some(ref key_type) = profile.key_type {
+                if let Err(e) = check_key_type(key_type) {
+                    errors.push(format!("profile.{name}: {e}"));
+                }
+            }
+            if profile.lifetime.is_some_and(|d| d <= Duration::ZERO) {
+                errors.push(format!("profile.{name}: lifetime must be positive"));
+            }
+            if let Err(e) = CaConfig::default().profile_extensions(profile).validate() {
+                errors.push(format!("profile.{name}: {e}"));
+            }
+        }
+
+        errors
+    }
+}
+
+impl CaConfig {
+    /// The base directory from `--base-dir`, falling back to this CA's if it
+    /// was named with `--ca`, then CA_BASE_DIR, then the default CA's and
+    /// finally the default
+    pub fn base_dir(&self, base_dir: &Option<String>) -> String {
+        let env = std::env::var("CA_BASE_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty());
+        let configured = match self.explicit {
+            true => self.base_dir.clone().or(env),
+            false => env.or(self.base_dir.clone()),
+        };
+        path::base_dir(
+            base_dir
+                .as_deref()
+                .or(configured.as_deref())
+                .unwrap_or(DEFAULT_BASE_DIR),
+        )
+    }
+
+    /// This CA's key encryption, with anything not configured left at the
+    /// default
+    pub fn key_encryption(&self) -> pkey::Encryption {
+        let default = pkey::Encryption::default();
+        pkey::Encryption {
+            cipher: match self.key_cipher {
+                Some(ref cipher) => cipher.parse().unwrap_or_else(|e| panic!("{e}")),
+                None => default.cipher,
+            },
+            kdf: match self.key_kdf {
+                Some(ref kdf) => kdf.parse().unwrap_or_else(|e| panic!("{e}")),
+                None => default.kdf,
+            },
+        }
+    }
+
+    /// This CA's issuer expiry policy, truncating by default
+    pub fn issuer_expiry(&self) -> IssuerExpiry {
+        match self.issuer_expiry {
+            Some(ref policy) => policy.parse().unwrap_or_else(|e| panic!("{e}")),
+            None => IssuerExpiry::default(),
+        }
+    }
+
+    /// How far before now this CA's certificates start, none by default
+    pub fn backdate(&self) -> Duration {
+        self.backdate.unwrap_or(Duration::ZERO)
+    }
+
+    pub fn subject(&self) -> Subject {
+        policy::subject_from_table(&self.subject).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Combine a profile's usages with this CA's distribution URLs
+    pub fn profile_extensions(&self, profile: &ProfileConfig) -> Profile {
+        Profile {
+            key_usage: profile.key_usage.clone(),
+            extended_key_usage: profile.extended_key_usage.clone(),
+            crl_urls: self.crl_urls.clone(),
+            ocsp_urls: self.ocsp_urls.clone(),
+            issuer_urls: self.issuer_urls.clone(),
+        }
+    }
+}
+
+/// The configuration file that would be loaded, if any
+pub fn find(explicit: &Option<String>) -> Option<PathBuf> {
+    let mut dirs: Vec<PathBuf> = dirs::config_dir().into_iter().collect();
+    let system_dirs = std::env::var("XDG_CONFIG_DIRS").unwrap_or_else(|_| String::from("/etc/xdg"));
+    dirs.extend(
+        system_dirs
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+    );
+    find_in(explicit, &dirs)
+}
+
+/// The file given explicitly, or else `hancock/hancock.toml` in the first of
+/// the directories that has one
+fn find_in(explicit: &Option<String>, dirs: &[PathBuf]) -> Option<PathBuf> {
+    if let Some(path) = explicit {
+        return Some(PathBuf::from(shellexpand::tilde(path).to_string()));
+    }
+    dirs.iter()
+        .map(|dir| dir.join("hancock").join("hancock.toml"))
+        .find(|path| path.is_file())
+}
+
+pub fn run_hooks(hooks: &[String], env: &[(&str, &str)]) {
+    for hook in hooks {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(hook)
+            .envs(env.iter().copied())
+            .status()
+            .unwrap_or_else(|e| panic!("Unable to run hook {hook}: {e}"));
+        if !status.success() {
+            eprintln!("Hook {hook} exited with {status}");
+        }
+    }
+}
+
+fn check_key_type(key_type: &str) -> Result<(), String> {
+    match key_type.to_uppercase().as_str() {
+        "RSA" | "ECDSA" => Ok(()),
+        _ => Err(format!(
+            "{key_type} is not a valid key type ['RSA', 'ECDSA']"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_valid_configuration() {
+        let config = parse(
+            r#"
+            default_ca = "internal"
+
+            [ca.internal]
+            base_dir = "~/internal"
+            key_type = "ecdsa"
+            lifetime = 30
+            profile = "server"
+            crl_urls = ["http://crl.example.com/root.crl"]
+
+            [ca.internal.subject]
+            O = "Acme"
+            OU = ["Web", "Ops"]
+
+            [profile.server]
+            key_usage = ["digitalSignature"]
+            extended_key_usage = ["serverAuth"]
+            "#,
+        );
+        assert_eq!(config.check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn finds_every_problem() {
+        let config = parse(
+            r#"
+            default_ca = "missing"
+
+            [ca.internal]
+            key_type = "dsa"
+            lifetime = 0
+            profile = "missing"
+            ocsp_urls = ["ftp://ocsp.example.com"]
+
+            [ca.internal.subject]
+            XX = "foo"
+
+            [profile.server]
+            lifetime = 0
+            key_usage = ["everything"]
+            "#,
+        );
+        let errors = config.check();
+        for expected in [
+            "default_ca missing is not a configured CA",
+            "ca.internal: dsa is not a valid key type",
+            "ca.internal: lifetime must be positive",
+            "ca.internal: profile missing is not configured",
+            "ca.internal: ftp://ocsp.example.com is not a valid",
+            "ca.internal.subject: ",
+            "profile.server: lifetime must be positive",
+            "profile.server: ",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.starts_with(expected)),
+                "{expected} not in {errors:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("[ca.internal]\nbasedir = \"/tmp\"").is_err());
+        assert!(toml::from_str::<Config>("default = \"internal\"").is_err());
+    }
+
+    #[test]
+    fn looks_up_named_and_default_cas() {
+        let config = parse(
+            r#"
+            default_ca = "internal"
+            [ca.internal]
+            key_type = "ecdsa"
+            [ca.external]
+            key_type = "rsa"
+            "#,
+        );
+        assert_eq!(config.ca(&None).key_type.as_deref(), Some("ecdsa"));
+        assert_eq!(
+            config.ca(&Some("external".to_string())).key_type.as_deref(),
+            Some("rsa")
+        );
+        assert_eq!(Config::default().ca(&None).key_type, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "No CA named missing")]
+    fn refuses_an_unknown_ca() {
+        Config::default().ca(&Some("missing".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "No profile named missing")]
+    fn refuses_an_unknown_profile() {
+        Config::default().profile("missing");
+    }
+
+    #[test]
+    fn finds_the_first_configuration_file() {
+        let dir = TestDir::new("config-find");
+        let (user, system, empty) = (
+            PathBuf::from(format!("{}/user", dir.0)),
+            PathBuf::from(format!("{}/system", dir.0)),
+            PathBuf::from(format!("{}/empty", dir.0)),
+        );
+        for config_dir in [&user, &system] {
+            std::fs::create_dir_all(config_dir.join("hancock")).unwrap();
+            std::fs::write(config_dir.join("hancock").join("hancock.toml"), "").unwrap();
+        }
+
+        let dirs = [empty.clone(), user.clone(), system.clone()];
+        assert_eq!(
+            find_in(&None, &dirs),
+            Some(user.join("hancock").join("hancock.toml"))
+        );
+        let dirs = [system.clone(), user.clone()];
+        assert_eq!(
+            find_in(&None, &dirs),
+            Some(system.join("hancock").join("hancock.toml"))
+        );
+        // An explicit file wins even over one that exists
+        assert_eq!(
+            find_in(&Some("/etc/hancock.toml".to_string()), &dirs),
+            Some(PathBuf::from("/etc/hancock.toml"))
+        );
+        assert_eq!(find_in(&None, &[empty]), None);
+    }
+}
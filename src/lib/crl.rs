@@ -0,0 +1,401 @@
+//! Certificate revocation lists. Each CA keeps the certificates it revoked in
+//! a file next to its certificate, and signs a CRL listing them. OpenSSL can
+//! read CRLs but not build them, so they are built here and signed like
+//! anything else, wherever the CA's key is kept
+use openssl::bn::BigNum;
+use openssl::x509::{X509Crl, X509CrlRef, X509};
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::der;
+use crate::file;
+use crate::history;
+use crate::signer::{self, Signer};
+use crate::validity::{Timestamp, Validity};
+
+const CRL_NUMBER: &str = "2.5.29.20";
+const REASON_CODE: &str = "2.5.29.21";
+const AUTHORITY_KEY_IDENTIFIER: &str = "2.5.29.35";
+
+/// Why a certificate was revoked, as a CRL entry's reasonCode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Reason {
+    #[default]
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    PrivilegeWithdrawn,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Unspecified => "unspecified",
+            Reason::KeyCompromise => "key-compromise",
+            Reason::CaCompromise => "ca-compromise",
+            Reason::AffiliationChanged => "affiliation-changed",
+            Reason::Superseded => "superseded",
+            Reason::CessationOfOperation => "cessation-of-operation",
+            Reason::CertificateHold => "certificate-hold",
+            Reason::PrivilegeWithdrawn => "privilege-withdrawn",
+        }
+    }
+
+    /// The CRLReason value
+    pub fn code(&self) -> u8 {
+        match self {
+            Reason::Unspecified => 0,
+            Reason::KeyCompromise => 1,
+            Reason::CaCompromise => 2,
+            Reason::AffiliationChanged => 3,
+            Reason::Superseded => 4,
+            Reason::CessationOfOperation => 5,
+            Reason::CertificateHold => 6,
+            Reason::PrivilegeWithdrawn => 9,
+        }
+    }
+}
+
+impl FromStr for Reason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // keyCompromise, key-compromise and key_compromise are all accepted
+        match s.trim().to_lowercase().replace(['-', '_'], "").as_str() {
+            "unspecified" => Ok(Reason::Unspecified),
+            "keycompromise" => Ok(Reason::KeyCompromise),
+            "cacompromise" => Ok(Reason::CaCompromise),
+            "affiliationchanged" => Ok(Reason::AffiliationChanged),
+            "superseded" => Ok(Reason::Superseded),
+            "cessationofoperation" => Ok(Reason::CessationOfOperation),
+            "certificatehold" => Ok(Reason::CertificateHold),
+            "privilegewithdrawn" => Ok(Reason::PrivilegeWithdrawn),
+            _ => Err(format!(
+                "{s} is not a valid revocation reason ['unspecified', 'key-compromise', 'ca-compromise', 'affiliation-changed', 'superseded', 'cessation-of-operation', 'certificate-hold', 'privilege-withdrawn']"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Reason {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Reason> for String {
+    fn from(reason: Reason) -> String {
+        reason.as_str().to_string()
+    }
+}
+
+/// A revoked certificate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revoked {
+    /// Serial number in lowercase hex
+    pub serial: String,
+    /// ID of the certificate or name of the intermediate, for people reading
+    /// the list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub revoked: Timestamp,
+    #[serde(default)]
+    pub reason: Reason,
+}
+
+/// Everything a CA has revoked, and the number of the last CRL it signed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Revocations {
+    #[serde(default)]
+    pub crl_number: u64,
+    #[serde(default)]
+    pub revoked: Vec<Revoked>,
+}
+
+impl Revocations {
+    /// Add a certificate, unless it is already revoked
+    pub fn revoke(&mut self, crt: &X509, name: &str, reason: Reason) -> bool {
+        let serial = history::serial(crt);
+        if self.revoked.iter().any(|revoked| revoked.serial == serial) {
+            return false;
+        }
+        self.revoked.push(Revoked {
+            serial,
+            name: Some(name.to_string()),
+            revoked: Timestamp::now(),
+            reason,
+        });
+        true
+    }
+}
+
+/// A CA's revocations, which are empty if it never revoked anything
+pub fn read_revocations(path: &str) -> Revocations {
+    if !Path::new(path).is_file() {
+        return Revocations::default();
+    }
+    toml::from_str(&read_to_string(path).unwrap())
+        .unwrap_or_else(|e| panic!("Unable to parse {path}: {e}"))
+}
+
+pub fn encode_revocations(revocations: &Revocations) -> Vec<u8> {
+    toml::to_string(revocations).unwrap().into_bytes()
+}
+
+pub fn save_revocations(path: &str, revocations: &Revocations) {
+    file::write_private(path, &encode_revocations(revocations));
+}
+
+/// Sign a CRL for a CA listing the revoked certificates, valid from the
+/// start of the validity until its end, when the next one is due
+pub fn generate_crl(
+    ca_cert: &X509,
+    revoked: &[Revoked],
+    number: u64,
+    validity: &Validity,
+    signer: &dyn Signer,
+) -> X509Crl {
+    let public_key = signer.public_key();
+    let (digest, algorithm) = signer::algorithm(&public_key);
+
+    let mut tbs = vec![
+        // v2, which CRLs with extensions must be
+        der::integer(&[1]),
+        algorithm.clone(),
+        ca_cert.subject_name().to_der().unwrap(),
+        der::time(validity.not_before),
+        der::time(validity.not_after),
+    ];
+    if !revoked.is_empty() {
+        let entries: Vec<Vec<u8>> = revoked.iter().map(entry).collect();
+        tbs.push(der::sequence(&entries));
+    }
+    let mut extensions = vec![extension(CRL_NUMBER, &der::integer(&number.to_be_bytes()))];
+    if let Some(key_id) = ca_cert.subject_key_id() {
+        // AuthorityKeyIdentifier with only keyIdentifier, [0] IMPLICIT
+        extensions.push(extension(
+            AUTHORITY_KEY_IDENTIFIER,
+            &der::sequence(&[der::tlv(0x80, key_id.as_slice())]),
+        ));
+    }
+    tbs.push(der::tlv(0xa0, &der::sequence(&extensions)));
+    let tbs = der::sequence(&tbs);
+
+    let crl = X509Crl::from_der(&signer::sign_der(&tbs, &algorithm, signer, digest)).unwrap();
+    if !crl.verify(&public_key).unwrap() {
+        panic!("The signature on the CRL does not verify with the signing key");
+    }
+    crl
+}
+
+fn entry(revoked: &Revoked) -> Vec<u8> {
+    let serial = BigNum::from_hex_str(&revoked.serial)
+        .unwrap_or_else(|_| panic!("{} is not a serial number in hex", revoked.serial));
+    let mut fields = vec![der::integer(&serial.to_vec()), der::time(revoked.revoked.0)];
+    // An unspecified reason is left out rather than given
+    if revoked.reason != Reason::Unspecified {
+        fields.push(der::sequence(&[extension(
+            REASON_CODE,
+            &der::tlv(der::ENUMERATED, &[revoked.reason.code()]),
+        )]));
+    }
+    der::sequence(&fields)
+}
+
+fn extension(oid: &str, value: &[u8]) -> Vec<u8> {
+    der::sequence(&[der::oid(oid), der::tlv(der::OCTET_STRING, value)])
+}
+
+/// The CRL number of a CRL, which OpenSSL does not give
+pub fn number(crl: &X509CrlRef) -> Option<u64> {
+    let signed = crl.to_der().ok()?;
+    let (outer, _) = der::split(&signed)?;
+    let (tbs, _) = der::split(outer.content)?;
+
+    // The extensions are the only [0] in the TBSCertList
+    let mut rest = tbs.content;
+    let extensions = loop {
+        let (element, next) = der::split(rest)?;
+        if element.tag == 0xa0 {
+            break der::split(element.content)?.0;
+        }
+        rest = next;
+    };
+    let mut rest = extensions.content;
+    while !rest.is_empty() {
+        let (extension, next) = der::split(rest)?;
+        let (oid, value) = der::split(extension.content)?;
+        if oid.encoded == der::oid(CRL_NUMBER).as_slice() {
+            // The value is the last field, after an optional critical flag
+            let mut value = value;
+            let octets = loop {
+                let (element, next) = der::split(value)?;
+                if element.tag == der::OCTET_STRING {
+                    break element;
+                }
+                value = next;
+            };
+            let (number, _) = der::split(octets.content)?;
+            if number.tag != der::INTEGER || number.content.len() > 9 {
+                return None;
+            }
+            return Some(
+                number
+                    .content
+                    .iter()
+                    .fold(0u64, |number, b| (number << 8) | *b as u64),
+            );
+        }
+        rest = next;
+    }
+    None
+}
+
+pub fn save_crl(path: &str, crl: &X509CrlRef) {
+    file::write_private(path, &crl.to_pem().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use crate::validity::Duration;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::SubjectKeyIdentifier;
+    use openssl::x509::X509NameBuilder;
+
+    /// A CA and its key, with a serial number
+    fn ca(serial: u32) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Test Root")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        let subject_key_identifier = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_key_identifier).unwrap();
+        builder.sign(&key, MessageDigest::sha384()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn parses_reasons() {
+        for reason in [
+            Reason::Unspecified,
+            Reason::KeyCompromise,
+            Reason::CaCompromise,
+            Reason::AffiliationChanged,
+            Reason::Superseded,
+            Reason::CessationOfOperation,
+            Reason::CertificateHold,
+            Reason::PrivilegeWithdrawn,
+        ] {
+            assert_eq!(reason.as_str().parse(), Ok(reason));
+        }
+        assert_eq!("keyCompromise".parse(), Ok(Reason::KeyCompromise));
+        assert_eq!("key_compromise".parse(), Ok(Reason::KeyCompromise));
+        assert_eq!(Reason::PrivilegeWithdrawn.code(), 9);
+        assert!("removeFromCRL".parse::<Reason>().is_err());
+    }
+
+    #[test]
+    fn keeps_each_revocation_once() {
+        let dir = TestDir::new("crl-revocations");
+        let path = format!("{}/authority.revoked.toml", dir.0);
+        assert!(read_revocations(&path).revoked.is_empty());
+
+        let (first, _) = ca(0x1a);
+        let (second, _) = ca(0x2b);
+        let mut revocations = Revocations::default();
+        assert!(revocations.revoke(&first, "web-1", Reason::KeyCompromise));
+        assert!(revocations.revoke(&second, "web-2", Reason::Unspecified));
+        assert!(!revocations.revoke(&first, "web-1", Reason::Superseded));
+        revocations.crl_number = 3;
+        save_revocations(&path, &revocations);
+
+        let read = read_revocations(&path);
+        assert_eq!(read.crl_number, 3);
+        assert_eq!(read.revoked, revocations.revoked);
+        assert_eq!(read.revoked[0].serial, "1a");
+        assert_eq!(read.revoked[0].reason, Reason::KeyCompromise);
+    }
+
+    #[test]
+    fn signs_crls_openssl_reads() {
+        let (ca_cert, ca_key) = ca(1);
+        let revoked = vec![
+            Revoked {
+                serial: String::from("1a"),
+                name: Some(String::from("web-1")),
+                revoked: Timestamp(1_717_243_200),
+                reason: Reason::KeyCompromise,
+            },
+            Revoked {
+                serial: String::from("ff00"),
+                name: None,
+                revoked: Timestamp(1_717_243_200),
+                reason: Reason::Unspecified,
+            },
+        ];
+        let validity = Validity::from_now(Duration::days(7), Duration::ZERO).unwrap();
+        let crl = generate_crl(&ca_cert, &revoked, 258, &validity, &ca_key);
+
+        assert!(crl.verify(&ca_cert.public_key().unwrap()).unwrap());
+        assert_eq!(
+            crl.issuer_name().to_der().unwrap(),
+            ca_cert.subject_name().to_der().unwrap()
+        );
+        assert_eq!(number(&crl), Some(258));
+        let next_update = crl.next_update().unwrap();
+        assert_eq!(crate::validity::unix(next_update), validity.not_after);
+        let serials: Vec<String> = crl
+            .get_revoked()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                entry
+                    .serial_number()
+                    .to_bn()
+                    .unwrap()
+                    .to_hex_str()
+                    .unwrap()
+                    .to_lowercase()
+            })
+            .collect();
+        assert_eq!(serials, ["1a", "ff00"]);
+
+        // The number is read back whole, however large
+        let crl = generate_crl(&ca_cert, &[], u64::MAX, &validity, &ca_key);
+        assert!(crl.get_revoked().is_none());
+        assert_eq!(number(&crl), Some(u64::MAX));
+    }
+}
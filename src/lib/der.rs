@@ -0,0 +1,142 @@
+//! Just enough DER to build the structures OpenSSL has no builder for, and to
+//! take apart signed structures for re-signing
+use openssl::asn1::{Asn1Object, Asn1Time};
+
+use crate::validity;
+
+pub const SEQUENCE: u8 = 0x30;
+pub const UTF8_STRING: u8 = 0x0c;
+pub const IA5_STRING: u8 = 0x16;
+pub const OBJECT_IDENTIFIER: u8 = 0x06;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const INTEGER: u8 = 0x02;
+pub const ENUMERATED: u8 = 0x0a;
+pub const UTC_TIME: u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+
+pub fn length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes: Vec<u8> = len
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|b| *b == 0)
+        .collect();
+    let mut der = vec![0x80 | bytes.len() as u8];
+    der.extend(bytes);
+    der
+}
+
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    der.extend(length(content.len()));
+    der.extend(content);
+    der
+}
+
+pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &items.concat())
+}
+
+/// An INTEGER holding a non-negative number given as big-endian bytes
+pub fn integer(magnitude: &[u8]) -> Vec<u8> {
+    let mut content: Vec<u8> = magnitude.iter().copied().skip_while(|b| *b == 0).collect();
+    // A leading zero keeps a number with its top bit set positive
+    if content.first().is_none_or(|b| b & 0x80 != 0) {
+        content.insert(0, 0);
+    }
+    tlv(INTEGER, &content)
+}
+
+/// A Time, which is a UTCTime until 2049 and a GeneralizedTime from 2050
+pub fn time(seconds: i64) -> Vec<u8> {
+    let (year, month, day, hour, minute, second) = validity::utc(seconds);
+    match (1950..2050).contains(&year) {
+        true => tlv(
+            UTC_TIME,
+            format!(
+                "{:02}{month:02}{day:02}{hour:02}{minute:02}{second:02}Z",
+                year % 100
+            )
+            .as_bytes(),
+        ),
+        false => generalized_time(seconds),
+    }
+}
+
+/// A GeneralizedTime, which OCSP uses whatever the year
+pub fn generalized_time(seconds: i64) -> Vec<u8> {
+    let (year, month, day, hour, minute, second) = validity::utc(seconds);
+    tlv(
+        GENERALIZED_TIME,
+        format!("{year:04}{month:02}{day:02}{hour:02}{minute:02}{second:02}Z").as_bytes(),
+    )
+}
+
+pub fn utf8_string(value: &str) -> Vec<u8> {
+    tlv(UTF8_STRING, value.as_bytes())
+}
+
+/// A GeneralName uniformResourceIdentifier, `[6] IMPLICIT IA5String`
+pub fn uri_general_name(uri: &str) -> Vec<u8> {
+    tlv(0x86, uri.as_bytes())
+}
+
+pub fn oid(oid: &str) -> Vec<u8> {
+    tlv(
+        OBJECT_IDENTIFIER,
+        Asn1Object::from_str(oid).unwrap().as_slice(),
+    )
+}
+
+/// One element read back from some DER
+pub struct Element<'a> {
+    pub tag: u8,
+    pub content: &'a [u8],
+    /// The element including its tag and length
+    pub encoded: &'a [u8],
+}
+
+/// Split the first element off some DER, giving it and whatever follows it.
+/// Only single byte tags are handled
+pub fn split(der: &[u8]) -> Option<(Element<'_>, &[u8])> {
+    let tag = *der.first()?;
+    let first = *der.get(1)? as usize;
+    let (header, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > std::mem::size_of::<usize>() {
+            return None;
+        }
+        let bytes = der.get(2..2 + count)?;
+        (
+            2 + count,
+            bytes.iter().fold(0usize, |len, b| (len << 8) | *b as usize),
+        )
+    };
+    let end = header.checked_add(len)?;
+    if end > der.len() {
+        return None;
+    }
+    Some((
+        Element {
+            tag,
+            content: &der[header..end],
+            encoded: &der[..end],
+        },
+        &der[end..],
+    ))
+}
+
+/// The seconds since the Unix epoch a UTCTime or GeneralizedTime holds
+pub fn read_time(element: &Element) -> Option<i64> {
+    if element.tag != UTC_TIME && element.tag != GENERALIZED_TIME {
+        return None;
+    }
+    let time = Asn1Time::from_str(std::str::from_utf8(element.content).ok()?).ok()?;
+    Some(validity::unix(&time))
+}
@@ -0,0 +1,306 @@
+//! CA keys held by another program that signs on hancock's behalf, such as a
+//! cloud KMS or HSM integration. A key file refers to one as `exec:COMMAND`
+//! or `unix:SOCKET`.
+//!
+//! A command is run through the shell as `COMMAND public-key`, printing the
+//! PEM public key, and `COMMAND sign DIGEST`, reading the data to sign on
+//! stdin and printing the signature, where DIGEST is sha256, sha384 or
+//! sha512. Signatures are PKCS#1 v1.5 for RSA keys and DER for ECDSA keys, as
+//! `openssl dgst -sign` gives them.
+//!
+//! A socket takes one request per connection: a line `PUBLIC-KEY [KEY]`, or a
+//! line `SIGN DIGEST LENGTH [KEY]` followed by LENGTH bytes of data to sign,
+//! where KEY picks one of several keys a signer such as the hancock agent
+//! holds. It replies with a line `OK LENGTH` followed by LENGTH bytes, or
+//! `ERROR MESSAGE`
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+
+use crate::signer::Signer;
+
+pub const EXEC_SCHEME: &str = "exec:";
+pub const UNIX_SCHEME: &str = "unix:";
+
+/// Where requests are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Exec(String),
+    Socket { socket: String, key: Option<String> },
+}
+
+/// A key held by an external signer
+pub struct Key {
+    target: Target,
+    public: PKey<Public>,
+}
+
+impl Key {
+    pub fn open(uri: &str) -> Key {
+        if let Some(command) = uri.strip_prefix(EXEC_SCHEME) {
+            let pem = run(command, &["public-key"], &[]);
+            let public = PKey::public_key_from_pem(&pem)
+                .unwrap_or_else(|e| panic!("{uri} gave an invalid public key: {e}"));
+            return Key {
+                target: Target::Exec(command.to_string()),
+                public,
+            };
+        }
+        match uri.strip_prefix(UNIX_SCHEME) {
+            Some(socket) => Key::from_socket(&shellexpand::tilde(socket), None)
+                .unwrap_or_else(|e| panic!("{e}")),
+            None => panic!(
+                "{uri} is not an external signer, it must start with {EXEC_SCHEME} or {UNIX_SCHEME}"
+            ),
+        }
+    }
+
+    /// A key held by the signer listening on a socket, picked by KEY if it
+    /// holds several
+    pub fn from_socket(socket: &str, key: Option<String>) -> Result<Key, String> {
+        let pem = request(socket, &with_key("PUBLIC-KEY", &key), &[])?;
+        let public = PKey::public_key_from_pem(&pem)
+            .map_err(|e| format!("{socket} gave an invalid public key: {e}"))?;
+        Ok(Key {
+            target: Target::Socket {
+                socket: socket.to_string(),
+                key,
+            },
+            public,
+        })
+    }
+}
+
+impl Signer for Key {
+    fn public_key(&self) -> PKey<Public> {
+        self.public.clone()
+    }
+
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> Vec<u8> {
+        let digest = digest_name(digest);
+        match &self.target {
+            Target::Exec(command) => run(command, &["sign", &digest], data),
+            Target::Socket { socket, key } => request(
+                socket,
+                &with_key(&format!("SIGN {digest} {}", data.len()), key),
+                data,
+            )
+            .unwrap_or_else(|e| panic!("{e}")),
+        }
+    }
+}
+
+/// Name of a digest as it is given to external signers
+pub fn digest_name(digest: MessageDigest) -> String {
+    digest.type_().short_name().unwrap().to_lowercase()
+}
+
+fn run(command: &str, args: &[&str], input: &[u8]) -> Vec<u8> {
+    // The arguments are passed positionally so the command needs no quoting
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} \"$@\""))
+        .arg("hancock-signer")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Unable to run {command}: {e}"));
+    // The data is written from another thread while the output is read, so
+    // a command replying before it has read everything cannot block on a
+    // full pipe. One that never reads it closes the pipe, which is no error
+    let mut stdin = child.stdin.take().unwrap();
+    let output = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || match stdin.write_all(input) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e),
+            _ => Ok(()),
+        });
+        let output = child.wait_with_output();
+        (output, writer.join().unwrap())
+    });
+    let output = match output {
+        (Ok(output), Ok(())) => output,
+        (Err(e), _) | (_, Err(e)) => panic!("Unable to run {command}: {e}"),
+    };
+    if !output.status.success() {
+        panic!("{command} {} exited with {}", args.join(" "), output.status);
+    }
+    output.stdout
+}
+
+fn with_key(line: &str, key: &Option<String>) -> String {
+    match key {
+        Some(key) => format!("{line} {key}"),
+        None => line.to_string(),
+    }
+}
+
+/// Send a request line and its data to a socket and read the reply
+pub fn request(socket: &str, line: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| format!("Unable to connect to {socket}: {e}"))?;
+    stream
+        .write_all(format!("{line}\n").as_bytes())
+        .and_then(|_| stream.write_all(data))
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("Unable to write to {socket}: {e}"))?;
+    read_reply(&mut BufReader::new(stream)).map_err(|e| format!("{socket} refused {line}: {e}"))
+}
+
+/// Read an `OK LENGTH` reply and its data, or the message of an `ERROR`
+pub fn read_reply(reader: &mut impl BufRead) -> Result<Vec<u8>, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let line = line.trim_end();
+    if let Some(message) = line.strip_prefix("ERROR") {
+        return Err(message.trim().to_string());
+    }
+    let length: usize = line
+        .strip_prefix("OK ")
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| format!("invalid reply {line}"))?;
+    // Replies carry a public key, a signature or a list of keys, never more
+    if length > 1 << 20 {
+        return Err(format!("a reply of {length} bytes is too large"));
+    }
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::{self, Profile};
+    use crate::path::TestDir;
+    use crate::pkey;
+    use crate::req;
+    use crate::root;
+    use crate::subject::Subject;
+    use crate::validity::{Duration, Validity};
+    use crate::KeyType;
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    fn subject(common_name: &str) -> Subject {
+        let mut subject = Subject::new();
+        subject.push(Nid::COMMONNAME, common_name).unwrap();
+        subject
+    }
+
+    /// Issue a root with the external signer's key, then a leaf under it,
+    /// checking both verify with the key the signer holds
+    fn issue_through(uri: &str, ca_key: &PKey<Private>) {
+        let signer = Key::open(uri);
+        let public_key = signer.public_key();
+        assert!(public_key.public_eq(ca_key));
+
+        let validity = Validity::from_now(Duration::days(30), Duration::ZERO).unwrap();
+        let root = root::generate_root_cert(&validity, &subject("Root"), false, &signer);
+        assert!(root.verify(ca_key).unwrap());
+
+        let leaf_key = pkey::generate_pkey(KeyType::Ecdsa);
+        let request = req::generate_req(&subject("leaf.example.com"), false, &[], &leaf_key);
+        let leaf = cert::generate_cert(
+            &validity,
+            &request,
+            false,
+            &Profile::default(),
+            &root,
+            &signer,
+        );
+        assert!(leaf.verify(ca_key).unwrap());
+
+        // A subordinate CA's request is signed the same way
+        let request = root::generate_root_req(&subject("Subordinate"), false, &signer);
+        assert!(request.verify(ca_key).unwrap());
+    }
+
+    fn save_key(dir: &TestDir, key_type: KeyType) -> (PKey<Private>, String) {
+        let key = pkey::generate_pkey(match key_type {
+            KeyType::Rsa(_) => KeyType::Rsa(2048),
+            KeyType::Ecdsa => KeyType::Ecdsa,
+        });
+        let path = format!("{}/{key_type}.key", dir.0);
+        std::fs::write(&path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (key, path)
+    }
+
+    #[test]
+    fn signs_through_a_command() {
+        let dir = TestDir::new("external-exec");
+        let script = format!("{}/signer.sh", dir.0);
+        std::fs::write(
+            &script,
+            "case \"$2\" in\n\
+             public-key) openssl pkey -in \"$1\" -pubout ;;\n\
+             sign) openssl dgst -\"$3\" -sign \"$1\" ;;\n\
+             *) exit 1 ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        for key_type in [KeyType::Rsa(2048), KeyType::Ecdsa] {
+            let (key, path) = save_key(&dir, key_type);
+            issue_through(&format!("{EXEC_SCHEME}sh {script} {path}"), &key);
+        }
+    }
+
+    /// Serve the socket protocol with a key until the test ends
+    fn serve(socket: &str, key: PKey<Private>) {
+        let listener = UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let words: Vec<&str> = line.split_whitespace().collect();
+                let reply = match words.as_slice() {
+                    ["PUBLIC-KEY"] => key.public_key_to_pem().unwrap(),
+                    ["SIGN", digest, length] => {
+                        let mut data = vec![0; length.parse().unwrap()];
+                        reader.read_exact(&mut data).unwrap();
+                        Signer::sign(&key, MessageDigest::from_name(digest).unwrap(), &data)
+                    }
+                    _ => {
+                        (&stream).write_all(b"ERROR unknown request\n").unwrap();
+                        continue;
+                    }
+                };
+                (&stream)
+                    .write_all(format!("OK {}\n", reply.len()).as_bytes())
+                    .and_then(|_| (&stream).write_all(&reply))
+                    .unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn signs_through_a_socket() {
+        let dir = TestDir::new("external-socket");
+        for key_type in [KeyType::Rsa(2048), KeyType::Ecdsa] {
+            let (key, _) = save_key(&dir, key_type);
+            let socket = format!("{}/{key_type}.sock", dir.0);
+            serve(&socket, key.clone());
+            issue_through(&format!("{UNIX_SCHEME}{socket}"), &key);
+        }
+    }
+
+    #[test]
+    fn limits_replies() {
+        let read = |reply: &str| read_reply(&mut BufReader::new(reply.as_bytes()));
+        assert_eq!(read("OK 3\nabc"), Ok(b"abc".to_vec()));
+        assert_eq!(
+            read("ERROR no such key\n"),
+            Err(String::from("no such key"))
+        );
+        assert!(read("OK 3\nab").is_err());
+        assert!(read(&format!("OK {}\n", usize::MAX)).is_err());
+        assert!(read(&format!("OK {}\n", (1 << 20) + 1)).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use openssl::rand::rand_bytes;
+use std::fs::{hard_link, remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::path;
+
+/// Write a file readable only by its owner so that it is either completely
+/// written or left untouched. The contents go to a temporary file created with
+/// mode 0600 next to the destination, which is synced and renamed into place
+pub fn write_private(path: &str, contents: &[u8]) {
+    let mut transaction = Transaction::new();
+    transaction.write(path, contents);
+    transaction.commit();
+}
+
+/// A set of files written together. Every file is fully written and synced
+/// to a temporary path before any of them replaces its destination, and the
+/// temporary files are removed if the transaction is dropped without commit.
+/// The files are renamed into place one at a time, so if one of the renames
+/// fails, those already done are put back as they were. A crash part way
+/// through the renames can still leave some files replaced and others not
+#[derive(Debug, Default)]
+pub struct Transaction {
+    staged: Vec<(String, String)>,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    pub fn write(&mut self, path: &str, contents: &[u8]) {
+        path::ensure_dir(path);
+        let temp_path = temp_path(path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .unwrap_or_else(|e| panic!("Unable to create {temp_path}: {e}"));
+        self.staged.push((temp_path.clone(), path.to_string()));
+
+        file.write_all(contents)
+            .unwrap_or_else(|e| panic!("Unable to write {temp_path}: {e}"));
+        file.sync_all()
+            .unwrap_or_else(|e| panic!("Unable to sync {temp_path}: {e}"));
+    }
+
+    pub fn commit(mut self) {
+        // Keep a link to each file about to be replaced, to put it back if a
+        // later rename fails
+        let mut backups: Vec<Option<String>> = Vec::new();
+        for (_, path) in &self.staged {
+            if !Path::new(path).exists() {
+                backups.push(None);
+                continue;
+            }
+            let backup_path = temp_path(path);
+            if let Err(e) = hard_link(path, &backup_path) {
+                remove_all(backups.iter().flatten());
+                panic!("Unable to keep {path} as {backup_path}: {e}");
+            }
+            backups.push(Some(backup_path));
+        }
+
+        let mut dirs: Vec<String> = Vec::new();
+        for (i, (temp_path, path)) in self.staged.iter().enumerate() {
+            if let Err(e) = rename(temp_path, path) {
+                for ((_, path), backup_path) in self.staged[..i].iter().zip(&backups) {
+                    match backup_path {
+                        Some(backup_path) => rename(backup_path, path).ok(),
+                        None => remove_file(path).ok(),
+                    };
+                }
+                remove_all(backups[i..].iter().flatten());
+                panic!("Unable to move {temp_path} to {path}: {e}");
+            }
+            let dir = parent(path);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        self.staged.clear();
+        remove_all(backups.iter().flatten());
+
+        // The renames are only durable once the directories are synced
+        for dir in dirs {
+            if let Ok(dir) = File::open(&dir) {
+                dir.sync_all().ok();
+            }
+        }
+    }
+}
+
+fn remove_all<'a>(paths: impl Iterator<Item = &'a String>) {
+    for path in paths {
+        remove_file(path).ok();
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        for (temp_path, _) in &self.staged {
+            remove_file(temp_path).ok();
+        }
+    }
+}
+
+fn temp_path(path: &str) -> String {
+    let mut suffix = [0; 6];
+    rand_bytes(&mut suffix).unwrap();
+    let suffix: String = suffix.iter().map(|b| format!("{b:02x}")).collect();
+    let file_name = Path::new(path)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    format!("{}/.{file_name}.{suffix}.tmp", parent(path))
+}
+
+fn parent(path: &str) -> String {
+    Path::new(path)
+        .parent()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| String::from("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use std::fs::{read_dir, read_to_string, write};
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Names of the files in a directory, temporary ones included
+    fn files(dir: &str) -> Vec<String> {
+        let mut files: Vec<String> = read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn writes_private_files() {
+        let dir = TestDir::new("file-private");
+        let path = format!("{}/keys/ca.pem", dir.0);
+        write_private(&path, b"first");
+        write_private(&path, b"second");
+        assert_eq!(read_to_string(&path).unwrap(), "second");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(files(&format!("{}/keys", dir.0)), ["ca.pem"]);
+    }
+
+    #[test]
+    fn commits_every_file_together() {
+        let dir = TestDir::new("file-commit");
+        let (pkey, crt) = (format!("{}/ca.pem", dir.0), format!("{}/ca.crt", dir.0));
+        write(&crt, "old certificate").unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.write(&pkey, b"new key");
+        transaction.write(&crt, b"new certificate");
+        // Nothing is replaced until the commit
+        assert_eq!(read_to_string(&crt).unwrap(), "old certificate");
+        assert!(!Path::new(&pkey).exists());
+        transaction.commit();
+
+        assert_eq!(read_to_string(&pkey).unwrap(), "new key");
+        assert_eq!(read_to_string(&crt).unwrap(), "new certificate");
+        assert_eq!(files(&dir.0), ["ca.crt", "ca.pem"]);
+    }
+
+    #[test]
+    fn leaves_files_alone_when_dropped() {
+        let dir = TestDir::new("file-drop");
+        let crt = format!("{}/ca.crt", dir.0);
+        write(&crt, "old certificate").unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.write(&crt, b"new certificate");
+        transaction.write(&format!("{}/ca.pem", dir.0), b"new key");
+        assert_eq!(files(&dir.0).len(), 3);
+        drop(transaction);
+
+        assert_eq!(read_to_string(&crt).unwrap(), "old certificate");
+        assert_eq!(files(&dir.0), ["ca.crt"]);
+    }
+
+    #[test]
+    fn puts_files_back_when_a_rename_fails() {
+        let dir = TestDir::new("file-rollback");
+        let crt = format!("{}/ca.crt", dir.0);
+        write(&crt, "old certificate").unwrap();
+        // A file cannot replace a directory with something in it
+        let chain = format!("{}/ca.chain.pem", dir.0);
+        std::fs::create_dir_all(format!("{chain}/in-the-way")).unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.write(&crt, b"new certificate");
+        transaction.write(&format!("{}/ca.pem", dir.0), b"new key");
+        transaction.write(&chain, b"new chain");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            transaction.commit();
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(read_to_string(&crt).unwrap(), "old certificate");
+        assert_eq!(files(&dir.0), ["ca.chain.pem", "ca.crt"]);
+    }
+}
@@ -0,0 +1,323 @@
+use openssl::x509::X509;
+use std::fs::{read, read_dir};
+use std::path::Path;
+
+use crate::cert;
+use crate::file;
+use crate::path;
+use crate::KeyType;
+
+pub fn serial(cert: &X509) -> String {
+    cert.serial_number()
+        .to_bn()
+        .unwrap()
+        .to_hex_str()
+        .unwrap()
+        .to_lowercase()
+}
+
+/// Keep a copy of a certificate, and the key it was issued for, under the
+/// certificate's history directory. Versions already archived are left alone
+pub fn archive(base_dir: &str, id: &str, key_type: KeyType, crt: &X509) {
+    let mut transaction = file::Transaction::new();
+    stage_archive(&mut transaction, base_dir, id, key_type, crt);
+    transaction.commit();
+}
+
+/// Archive a certificate as part of a transaction, so the copy is written
+/// along with whatever replaces it
+pub fn stage_archive(
+    transaction: &mut file::Transaction,
+    base_dir: &str,
+    id: &str,
+    key_type: KeyType,
+    crt: &X509,
+) {
+    let pkey = read(path::cert_pkey(base_dir, id, key_type)).ok();
+    stage_archive_with_key(transaction, base_dir, id, key_type, crt, pkey.as_deref());
+}
+
+/// Archive a certificate issued along with a new key in the same transaction,
+/// which is not yet where the key is read from
+pub fn stage_archive_with_key(
+    transaction: &mut file::Transaction,
+    base_dir: &str,
+    id: &str,
+    key_type: KeyType,
+    crt: &X509,
+    pkey: Option<&[u8]>,
+) {
+    let serial = serial(crt);
+    archive_to(
+        transaction,
+        crt,
+        &path::history_crt(base_dir, id, &serial, key_type),
+        pkey,
+        &path::history_pkey(base_dir, id, &serial, key_type),
+    );
+}
+
+/// Keep a copy of an intermediate's certificate and key under its history
+/// directory, written along with whatever replaces it
+pub fn stage_archive_intermediate(
+    transaction: &mut file::Transaction,
+    base_dir: &str,
+    name: &str,
+    key_type: KeyType,
+    crt: &X509,
+) {
+    let serial = serial(crt);
+    archive_to(
+        transaction,
+        crt,
+        &path::intermediate_history_crt(base_dir, name, &serial, key_type),
+        read(path::intermediate_pkey(base_dir, name, key_type))
+            .ok()
+            .as_deref(),
+        &path::intermediate_history_pkey(base_dir, name, &serial, key_type),
+    );
+}
+
+fn archive_to(
+    transaction: &mut file::Transaction,
+    crt: &X509,
+    crt_path: &str,
+    pkey: Option<&[u8]>,
+    archived_pkey_path: &str,
+) {
+    if Path::new(crt_path).exists() {
+        return;
+    }
+    if let Some(pkey) = pkey {
+        transaction.write(archived_pkey_path, pkey);
+    }
+    transaction.write(crt_path, &crt.to_pem().unwrap());
+}
+
+/// A serial number as given on the command line, in lowercase hex without a
+/// leading 0x
+pub fn parse_serial(input: &str) -> Result<String, String> {
+    let serial = input.trim().trim_start_matches("0x").to_lowercase();
+    match !serial.is_empty() && serial.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Ok(serial),
+        false => Err(format!("{input} is not a serial number in hex")),
+    }
+}
+
+/// Every archived version of a certificate, oldest first
+pub fn versions(base_dir: &str, id: &str, key_type: KeyType) -> Vec<(String, X509)> {
+    let entries = match read_dir(path::cert_history(base_dir, id)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut versions: Vec<(String, X509)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let serial = file_name.split('.').next()?.to_string();
+            match path::history_crt(base_dir, id, &serial, key_type)
+                == entry.path().to_string_lossy()
+            {
+                true => Some((serial, cert::read_cert(&entry.path().to_string_lossy()))),
+                false => None,
+            }
+        })
+        .collect();
+    versions.sort_by(|(_, a), (_, b)| a.not_before().compare(b.not_before()).unwrap());
+    versions
+}
+
+/// Make an archived version the current certificate again, restoring its key
+/// if it differs from the current one. The current version is archived first
+pub fn rollback(base_dir: &str, id: &str, key_type: KeyType, serial: &str) {
+    let serial = parse_serial(serial).unwrap_or_else(|e| panic!("{e}"));
+    let archived_crt = path::history_crt(base_dir, id, &serial, key_type);
+    if !Path::new(&archived_crt).exists() {
+        panic!("No version {serial} of {id} in its history");
+    }
+
+    // The key and certificate are swapped together, so they always match, and
+    // along with the current version's archive
+    let mut transaction = file::Transaction::new();
+    let crt_path = path::cert_crt(base_dir, id, key_type);
+    if Path::new(&crt_path).exists() {
+        stage_archive(
+            &mut transaction,
+            base_dir,
+            id,
+            key_type,
+            &cert::read_cert(&crt_path),
+        );
+    }
+    let archived_pkey = path::history_pkey(base_dir, id, &serial, key_type);
+    if Path::new(&archived_pkey).exists() {
+        transaction.write(
+            &path::cert_pkey(base_dir, id, key_type),
+            &read(&archived_pkey).unwrap(),
+        );
+    }
+    transaction.write(&crt_path, &cert::read_cert(&archived_crt).to_pem().unwrap());
+    transaction.commit();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509NameBuilder;
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    /// A certificate with a serial number, valid for a day from a start
+    fn cert(serial: u32, not_before: i64) -> X509 {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "www.example.com")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::from_unix(not_before).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(not_before + 86400).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha384()).unwrap();
+        builder.build()
+    }
+
+    /// Install a certificate and a stand-in for its key as the current version
+    fn install(base_dir: &str, crt: &X509, pkey: &str) {
+        let pkey_path = path::cert_pkey(base_dir, "web", KeyType::Ecdsa);
+        create_dir_all(Path::new(&pkey_path).parent().unwrap()).unwrap();
+        write(&pkey_path, pkey).unwrap();
+        cert::save_cert(&path::cert_crt(base_dir, "web", KeyType::Ecdsa), crt);
+    }
+
+    fn serials(base_dir: &str, key_type: KeyType) -> Vec<String> {
+        versions(base_dir, "web", key_type)
+            .into_iter()
+            .map(|(serial, _)| serial)
+            .collect()
+    }
+
+    #[test]
+    fn archives_each_version_once() {
+        let dir = TestDir::new("history-archive");
+        let base_dir = dir.0.as_str();
+        let (first, second) = (cert(0x1a, 1_700_000_000), cert(0x2b, 1_600_000_000));
+
+        install(base_dir, &first, "first key");
+        archive(base_dir, "web", KeyType::Ecdsa, &first);
+        install(base_dir, &second, "second key");
+        archive(base_dir, "web", KeyType::Ecdsa, &second);
+        // Archiving a version again keeps the key it was archived with
+        archive(base_dir, "web", KeyType::Ecdsa, &first);
+        assert_eq!(
+            read_to_string(path::history_pkey(base_dir, "web", "1a", KeyType::Ecdsa)).unwrap(),
+            "first key"
+        );
+
+        // Oldest first, and only of the key type asked for
+        assert_eq!(serials(base_dir, KeyType::Ecdsa), ["2b", "1a"]);
+        assert!(serials(base_dir, KeyType::Rsa(0)).is_empty());
+        assert!(versions(base_dir, "other", KeyType::Ecdsa).is_empty());
+    }
+
+    #[test]
+    fn archives_along_with_what_replaces_it() {
+        let dir = TestDir::new("history-stage");
+        let base_dir = dir.0.as_str();
+        let (first, second) = (cert(0x1a, 1_600_000_000), cert(0x2b, 1_700_000_000));
+        install(base_dir, &first, "first key");
+
+        // The old version keeps the key on disk, the new one the key the
+        // transaction writes for it
+        let mut transaction = file::Transaction::new();
+        stage_archive(&mut transaction, base_dir, "web", KeyType::Ecdsa, &first);
+        transaction.write(
+            &path::cert_pkey(base_dir, "web", KeyType::Ecdsa),
+            b"second key",
+        );
+        stage_archive_with_key(
+            &mut transaction,
+            base_dir,
+            "web",
+            KeyType::Ecdsa,
+            &second,
+            Some(b"second key"),
+        );
+        assert!(serials(base_dir, KeyType::Ecdsa).is_empty());
+        transaction.commit();
+
+        assert_eq!(serials(base_dir, KeyType::Ecdsa), ["1a", "2b"]);
+        for (serial, key) in [("1a", "first key"), ("2b", "second key")] {
+            assert_eq!(
+                read_to_string(path::history_pkey(base_dir, "web", serial, KeyType::Ecdsa))
+                    .unwrap(),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn rolls_back_the_certificate_and_its_key() {
+        let dir = TestDir::new("history-rollback");
+        let base_dir = dir.0.as_str();
+        let (first, second) = (cert(0x1a, 1_600_000_000), cert(0x2b, 1_700_000_000));
+        install(base_dir, &first, "first key");
+        archive(base_dir, "web", KeyType::Ecdsa, &first);
+        install(base_dir, &second, "second key");
+
+        rollback(base_dir, "web", KeyType::Ecdsa, "0x1A");
+        let current = cert::read_cert(&path::cert_crt(base_dir, "web", KeyType::Ecdsa));
+        assert_eq!(current.to_der().unwrap(), first.to_der().unwrap());
+        assert_eq!(
+            read_to_string(path::cert_pkey(base_dir, "web", KeyType::Ecdsa)).unwrap(),
+            "first key"
+        );
+        // The version rolled back from is kept, so it can be restored again
+        assert_eq!(serials(base_dir, KeyType::Ecdsa), ["1a", "2b"]);
+        assert_eq!(
+            read_to_string(path::history_pkey(base_dir, "web", "2b", KeyType::Ecdsa)).unwrap(),
+            "second key"
+        );
+    }
+
+    #[test]
+    fn parses_serials() {
+        assert_eq!(parse_serial("0x1A2b"), Ok("1a2b".to_string()));
+        assert_eq!(parse_serial(" 00ff "), Ok("00ff".to_string()));
+        for invalid in ["", "0x", "12g4", "../1a", "1a.ecdsa"] {
+            assert!(parse_serial(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a serial number in hex")]
+    fn refuses_a_serial_that_is_not_hex() {
+        let dir = TestDir::new("history-invalid");
+        rollback(&dir.0, "web", KeyType::Ecdsa, "../../authority");
+    }
+
+    #[test]
+    #[should_panic(expected = "No version 3c of web in its history")]
+    fn refuses_an_unknown_version() {
+        let dir = TestDir::new("history-unknown");
+        install(&dir.0, &cert(0x1a, 1_600_000_000), "first key");
+        rollback(&dir.0, "web", KeyType::Ecdsa, "3c");
+    }
+}
@@ -0,0 +1,346 @@
+use openssl::rand::rand_bytes;
+use std::fs::read_to_string;
+use std::path::Path;
+use toml::{Table, Value};
+
+use crate::file;
+use crate::path;
+use crate::KeyType;
+
+/// A certificate directory, keyed by a stable ID rather than its CommonName
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub id: String,
+    pub common_name: String,
+    pub aliases: Vec<String>,
+    /// Profile the certificate was issued with, reused when renewing it
+    pub profile: Option<String>,
+}
+
+/// A new ID for a certificate, a readable slug of the CommonName followed by
+/// random hex so certificates sharing a CommonName do not collide
+pub fn new_id(common_name: &str) -> String {
+    let mut suffix = [0; 8];
+    rand_bytes(&mut suffix).unwrap();
+    let suffix: String = suffix.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{}-{suffix}", slug(common_name))
+}
+
+pub fn slug(common_name: &str) -> String {
+    let mut slug = String::new();
+    for c in common_name.to_lowercase().chars() {
+        match c {
+            '*' => slug.push_str("wildcard"),
+            'a'..='z' | '0'..='9' | '.' => slug.push(c),
+            _ => {
+                if !slug.ends_with('-') {
+                    slug.push('-')
+                }
+            }
+        }
+    }
+    let slug: String = slug
+        .trim_matches(|c| c == '-' || c == '.')
+        .chars()
+        .take(48)
+        .collect();
+    match slug.is_empty() {
+        true => String::from("cert"),
+        false => slug,
+    }
+}
+
+pub fn read_index(path: &str) -> Vec<Entry> {
+    if !Path::new(path).is_file() {
+        return Vec::new();
+    }
+    let table: Table = read_to_string(path)
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|e| panic!("Unable to parse {path}: {e}"));
+
+    let mut entries = Vec::new();
+    for (id, entry) in table {
+        let entry = entry
+            .as_table()
+            .unwrap_or_else(|| panic!("{path}: entry {id} is not a table"));
+        entries.push(Entry {
+            id: id.clone(),
+            common_name: entry
+                .get("common_name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            aliases: entry
+                .get("aliases")
+                .and_then(Value::as_array)
+                .map(|aliases| {
+                    aliases
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            profile: entry
+                .get("profile")
+                .and_then(Value::as_str)
+                .map(String::from),
+        });
+    }
+    entries
+}
+
+pub fn save_index(path: &str, entries: &[Entry]) {
+    file::write_private(path, &encode_index(entries));
+}
+
+fn encode_index(entries: &[Entry]) -> Vec<u8> {
+    let mut table = Table::new();
+    for entry in entries {
+        let mut fields = Table::new();
+        fields.insert(
+            String::from("common_name"),
+            Value::String(entry.common_name.clone()),
+        );
+        if !entry.aliases.is_empty() {
+            fields.insert(
+                String::from("aliases"),
+                Value::Array(entry.aliases.iter().cloned().map(Value::String).collect()),
+            );
+        }
+        if let Some(ref profile) = entry.profile {
+            fields.insert(String::from("profile"), Value::String(profile.clone()));
+        }
+        table.insert(entry.id.clone(), Value::Table(fields));
+    }
+    toml::to_string(&table).unwrap().into_bytes()
+}
+
+/// Add an entry or extend an existing one with new aliases, recording the
+/// profile it was last issued with. Aliases must be unique within a store
+pub fn record(path: &str, id: &str, common_name: &str, aliases: &[String], profile: Option<&str>) {
+    let mut transaction = file::Transaction::new();
+    stage_record(&mut transaction, path, id, common_name, aliases, profile);
+    transaction.commit();
+}
+
+/// Record an entry as part of a transaction, so a certificate can be found
+/// by its names as soon as it is written
+pub fn stage_record(
+    transaction: &mut file::Transaction,
+    path: &str,
+    id: &str,
+    common_name: &str,
+    aliases: &[String],
+    profile: Option<&str>,
+) {
+    let mut entries = read_index(path);
+    for alias in aliases {
+        if let Some(other) = entries
+            .iter()
+            .find(|e| e.id != id && e.aliases.contains(alias))
+        {
+            panic!("Alias {alias} already refers to {}", other.id);
+        }
+    }
+    match entries.iter_mut().find(|e| e.id == id) {
+        Some(entry) => {
+            for alias in aliases {
+                if !entry.aliases.contains(alias) {
+                    entry.aliases.push(alias.clone());
+                }
+            }
+            entry.profile = profile.map(String::from);
+        }
+        None => entries.push(Entry {
+            id: id.to_string(),
+            common_name: common_name.to_string(),
+            aliases: aliases.to_vec(),
+            profile: profile.map(String::from),
+        }),
+    }
+    transaction.write(path, &encode_index(&entries));
+}
+
+/// IDs of the certificates a name refers to, matching an ID, alias or
+/// CommonName in that order. Directories from before IDs existed are named
+/// after their CommonName and are found by it
+pub fn lookup(base_dir: &str, name: &str) -> Vec<String> {
+    let entries = read_index(&path::cert_index(base_dir));
+
+    if entries.iter().any(|e| e.id == name) {
+        return vec![name.to_string()];
+    }
+    if let Some(entry) = entries.iter().find(|e| e.aliases.iter().any(|a| a == name)) {
+        return vec![entry.id.clone()];
+    }
+    let mut ids: Vec<String> = entries
+        .iter()
+        .filter(|e| e.common_name == name)
+        .map(|e| e.id.clone())
+        .collect();
+    if is_legacy_dir(base_dir, name) {
+        ids.push(name.to_string());
+    }
+    ids
+}
+
+/// Whether a name is a certificate directory from before IDs existed, which
+/// holds a certificate named after it
+fn is_legacy_dir(base_dir: &str, name: &str) -> bool {
+    path::validate_name(name).is_ok()
+        && !path::STORE_DIRS.contains(&name)
+        && [KeyType::Rsa(0), KeyType::Ecdsa]
+            .into_iter()
+            .any(|key_type| Path::new(&path::cert_crt(base_dir, name, key_type)).is_file())
+}
+
+/// Like lookup, but the name must refer to exactly one certificate
+pub fn resolve(base_dir: &str, name: &str) -> String {
+    let ids = lookup(base_dir, name);
+    match ids.len() {
+        1 => ids[0].clone(),
+        0 => panic!("No certificate named {name}"),
+        _ => panic!(
+            "{name} refers to more than one certificate, use one of the IDs: {}",
+            ids.join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+
+    #[test]
+    fn makes_readable_ids() {
+        assert_eq!(slug("www.example.com"), "www.example.com");
+        assert_eq!(slug("*.Example.com"), "wildcard.example.com");
+        assert_eq!(slug("John Smith / Ops"), "john-smith-ops");
+        assert_eq!(slug("../../etc"), "etc");
+        assert_eq!(slug("日本"), "cert");
+        assert_eq!(slug(&"a".repeat(100)).len(), 48);
+
+        let id = new_id("www.example.com");
+        assert!(id.starts_with("www.example.com-"));
+        assert_ne!(id, new_id("www.example.com"));
+        assert_eq!(path::validate_name(&id), Ok(()));
+    }
+
+    #[test]
+    fn looks_up_ids_aliases_and_common_names() {
+        let dir = TestDir::new("index-lookup");
+        let base_dir = dir.0.as_str();
+        let index = path::cert_index(base_dir);
+        record(
+            &index,
+            "web-1",
+            "www.example.com",
+            &["web".to_string()],
+            None,
+        );
+        record(&index, "web-2", "www.example.com", &[], None);
+        record(&index, "api-1", "api.example.com", &[], None);
+
+        assert_eq!(lookup(base_dir, "web-2"), ["web-2"]);
+        assert_eq!(lookup(base_dir, "web"), ["web-1"]);
+        assert_eq!(lookup(base_dir, "www.example.com"), ["web-1", "web-2"]);
+        assert_eq!(resolve(base_dir, "api.example.com"), "api-1");
+        assert!(lookup(base_dir, "missing").is_empty());
+
+        // Directories from before the index are found by their name, as long
+        // as they hold a certificate
+        std::fs::create_dir_all(format!("{base_dir}/old.example.com")).unwrap();
+        assert!(lookup(base_dir, "old.example.com").is_empty());
+        std::fs::write(
+            path::cert_crt(base_dir, "old.example.com", KeyType::Ecdsa),
+            "",
+        )
+        .unwrap();
+        assert_eq!(resolve(base_dir, "old.example.com"), "old.example.com");
+    }
+
+    #[test]
+    fn never_takes_store_directories_for_certificates() {
+        let dir = TestDir::new("index-store-dirs");
+        let base_dir = dir.0.as_str();
+        record(&path::cert_index(base_dir), "roots-1", "roots", &[], None);
+        for name in path::STORE_DIRS {
+            std::fs::create_dir_all(format!("{base_dir}/{name}")).unwrap();
+            std::fs::write(path::cert_crt(base_dir, name, KeyType::Rsa(0)), "").unwrap();
+            assert!(!is_legacy_dir(base_dir, name));
+        }
+        assert_eq!(resolve(base_dir, "roots"), "roots-1");
+        assert!(lookup(base_dir, "intermediates").is_empty());
+    }
+
+    #[test]
+    fn records_entries_only_when_committed() {
+        let dir = TestDir::new("index-staged");
+        let index = path::cert_index(&dir.0);
+        let mut transaction = file::Transaction::new();
+        stage_record(
+            &mut transaction,
+            &index,
+            "web-1",
+            "www.example.com",
+            &[],
+            None,
+        );
+        assert!(lookup(&dir.0, "web-1").is_empty());
+        drop(transaction);
+        assert!(!Path::new(&index).exists());
+
+        let mut transaction = file::Transaction::new();
+        stage_record(
+            &mut transaction,
+            &index,
+            "web-1",
+            "www.example.com",
+            &[],
+            None,
+        );
+        transaction.commit();
+        assert_eq!(resolve(&dir.0, "www.example.com"), "web-1");
+    }
+
+    #[test]
+    #[should_panic(expected = "refers to more than one certificate")]
+    fn refuses_an_ambiguous_name() {
+        let dir = TestDir::new("index-ambiguous");
+        let index = path::cert_index(&dir.0);
+        record(&index, "web-1", "www.example.com", &[], None);
+        record(&index, "web-2", "www.example.com", &[], None);
+        resolve(&dir.0, "www.example.com");
+    }
+
+    #[test]
+    #[should_panic(expected = "No certificate named missing")]
+    fn refuses_an_unknown_name() {
+        let dir = TestDir::new("index-unknown");
+        resolve(&dir.0, "missing");
+    }
+
+    #[test]
+    #[should_panic(expected = "Alias web already refers to web-1")]
+    fn keeps_aliases_unique() {
+        let dir = TestDir::new("index-aliases");
+        let index = path::cert_index(&dir.0);
+        record(
+            &index,
+            "web-1",
+            "www.example.com",
+            &["web".to_string()],
+            None,
+        );
+        record(
+            &index,
+            "web-2",
+            "www.example.com",
+            &["web".to_string()],
+            None,
+        );
+    }
+}
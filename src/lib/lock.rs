@@ -0,0 +1,144 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::path;
+
+/// An exclusive advisory lock on a base directory, held until dropped. Every
+/// hancock invocation that changes a store takes it first, so a scheduled
+/// renew and a manual issue run one after the other instead of interleaving.
+/// There are no locks per certificate: nearly every change also touches
+/// something shared, such as the index, the revocation list or a renewed
+/// issuer, so they would need this lock anyway, and runs are short
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// How long an invocation waits for another to finish unless told otherwise,
+/// long enough for a renew run to get through a store
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+
+/// How long to wait for another invocation to release the lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    /// Fail straight away if the lock is held
+    No,
+    /// Wait at most this long
+    For(Duration),
+    /// Wait for as long as it takes
+    Forever,
+}
+
+impl Wait {
+    /// From a `--wait [SECONDS]` flag, where a bare `--wait` waits forever,
+    /// `--wait 0` not at all, and no flag the default
+    pub fn from_arg(arg: Option<Option<u64>>) -> Wait {
+        match arg {
+            None => Wait::For(DEFAULT_WAIT),
+            Some(None) => Wait::Forever,
+            Some(Some(0)) => Wait::No,
+            Some(Some(seconds)) => Wait::For(Duration::from_secs(seconds)),
+        }
+    }
+}
+
+pub fn acquire(base_dir: &str, wait: Wait) -> Lock {
+    let lock_path = path::lock(base_dir);
+    path::ensure_dir(&lock_path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&lock_path)
+        .unwrap_or_else(|e| panic!("Unable to open {lock_path}: {e}"));
+
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        // flock locks belong to the open file, so they are released when the
+        // file is closed, including when the process dies
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Lock { _file: file };
+        }
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            panic!("Unable to lock {lock_path}: {error}");
+        }
+
+        match wait {
+            Wait::No => {
+                panic!("{base_dir} is in use by another hancock process")
+            }
+            Wait::For(timeout) if started.elapsed() >= timeout => panic!(
+                "Timed out after {}s waiting for another hancock process to release {base_dir}, use --wait to wait longer",
+                timeout.as_secs()
+            ),
+            _ => {}
+        }
+        if !waiting {
+            eprintln!("Waiting for another hancock process to release {base_dir}");
+            waiting = true;
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+
+    #[test]
+    fn waits_a_while_by_default() {
+        assert_eq!(Wait::from_arg(None), Wait::For(DEFAULT_WAIT));
+        assert_eq!(Wait::from_arg(Some(None)), Wait::Forever);
+        assert_eq!(Wait::from_arg(Some(Some(0))), Wait::No);
+        assert_eq!(
+            Wait::from_arg(Some(Some(5))),
+            Wait::For(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn excludes_a_second_holder() {
+        let dir = TestDir::new("lock-exclusive");
+        let lock = acquire(&dir.0, Wait::No);
+        let refused = std::panic::catch_unwind(|| acquire(&dir.0, Wait::No));
+        let message = *refused.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.ends_with("is in use by another hancock process"),
+            "{message}"
+        );
+
+        drop(lock);
+        acquire(&dir.0, Wait::No);
+    }
+
+    #[test]
+    fn gives_up_after_waiting() {
+        let dir = TestDir::new("lock-timeout");
+        let _lock = acquire(&dir.0, Wait::No);
+        let started = Instant::now();
+        let refused =
+            std::panic::catch_unwind(|| acquire(&dir.0, Wait::For(Duration::from_millis(300))));
+        let message = *refused.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("Timed out"), "{message}");
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn waits_for_the_holder_to_finish() {
+        let dir = TestDir::new("lock-wait");
+        let lock = acquire(&dir.0, Wait::No);
+        let holder = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+        acquire(&dir.0, Wait::For(Duration::from_secs(10)));
+        holder.join().unwrap();
+    }
+}
@@ -1,9 +1,31 @@
+use std::fmt;
+
+pub mod agent;
+pub mod bundle;
 pub mod cert;
+pub mod chain;
+pub mod config;
+pub mod crl;
+pub mod der;
+pub mod external;
+pub mod file;
+pub mod history;
+pub mod index;
+pub mod lock;
+pub mod ocsp;
 pub mod ops;
+pub mod password;
 pub mod path;
+pub mod pkcs11;
 pub mod pkey;
+pub mod policy;
 pub mod req;
 pub mod root;
+pub mod san;
+pub mod shamir;
+pub mod signer;
+pub mod subject;
+pub mod validity;
 
 #[derive(Debug, Clone, Copy)]
 pub enum KeyType {
@@ -11,11 +33,11 @@ pub enum KeyType {
     Rsa(u32),
 }
 
-impl ToString for KeyType {
-    fn to_string(&self) -> String {
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            KeyType::Rsa(_) => String::from("rsa"),
-            KeyType::Ecdsa => String::from("ecdsa"),
+            KeyType::Rsa(_) => write!(f, "rsa"),
+            KeyType::Ecdsa => write!(f, "ecdsa"),
         }
     }
 }
@@ -0,0 +1,418 @@
+//! OCSP responses, answering whether the certificates in a request are
+//! revoked from the same list CRLs are signed from. Like CRLs, OpenSSL can
+//! read them but not build them, so they are built here and signed with the
+//! CA's key wherever it is kept
+use openssl::bn::BigNum;
+use openssl::hash::{hash, MessageDigest};
+use openssl::ocsp::OcspResponse;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+
+use crate::crl::{Reason, Revoked};
+use crate::der;
+use crate::signer::{self, Signer};
+use crate::validity::{self, Validity};
+
+const BASIC_RESPONSE: &str = "1.3.6.1.5.5.7.48.1.1";
+const NONCE: &str = "1.3.6.1.5.5.7.48.1.2";
+
+/// One certificate asked about, by the hashes of its issuer and its serial
+pub struct CertId {
+    /// The CertID as it was requested, which the response repeats
+    encoded: Vec<u8>,
+    digest: Option<MessageDigest>,
+    name_hash: Vec<u8>,
+    key_hash: Vec<u8>,
+    /// Serial number in lowercase hex
+    pub serial: String,
+}
+
+impl CertId {
+    /// Whether the certificate is one the CA would have issued
+    pub fn is_for(&self, ca_cert: &X509) -> bool {
+        self.digest.is_some_and(|digest| {
+            hash(digest, &ca_cert.subject_name().to_der().unwrap()).unwrap()[..] == self.name_hash
+                && hash(digest, &key_bits(ca_cert)).unwrap()[..] == self.key_hash
+        })
+    }
+}
+
+/// The certificates an OCSP request asks about, and the nonce extension it
+/// carries for the response to repeat
+pub struct Request {
+    pub cert_ids: Vec<CertId>,
+    nonce: Option<Vec<u8>>,
+}
+
+/// What the response says about a certificate
+pub enum Status<'a> {
+    Good,
+    Revoked(&'a Revoked),
+    Unknown,
+}
+
+impl Status<'_> {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Good => "good",
+            Status::Revoked(_) => "revoked",
+            Status::Unknown => "unknown",
+        }
+    }
+}
+
+/// Read a DER OCSPRequest, such as `openssl ocsp -reqout` writes
+pub fn parse_request(request: &[u8]) -> Result<Request, String> {
+    let invalid = || "not a valid OCSP request".to_string();
+    let (outer, _) = der::split(request).ok_or_else(invalid)?;
+    let (tbs, _) = der::split(outer.content).ok_or_else(invalid)?;
+
+    // An optional version [0] and requestor name [1] come before the list,
+    // and optional extensions [2] after it
+    let mut rest = tbs.content;
+    let mut cert_ids = None;
+    let mut nonce = None;
+    while !rest.is_empty() {
+        let (element, next) = der::split(rest).ok_or_else(invalid)?;
+        match element.tag {
+            der::SEQUENCE => cert_ids = Some(parse_list(element.content).ok_or_else(invalid)?),
+            0xa2 => nonce = find_nonce(element.content),
+            _ => {}
+        }
+        rest = next;
+    }
+    let cert_ids = cert_ids.ok_or_else(invalid)?;
+    if cert_ids.is_empty() {
+        return Err("The OCSP request asks about no certificates".to_string());
+    }
+    Ok(Request { cert_ids, nonce })
+}
+
+fn parse_list(mut list: &[u8]) -> Option<Vec<CertId>> {
+    let mut cert_ids = Vec::new();
+    while !list.is_empty() {
+        let (request, next) = der::split(list)?;
+        let (cert_id, _) = der::split(request.content)?;
+        let (algorithm, rest) = der::split(cert_id.content)?;
+        let (name_hash, rest) = der::split(rest)?;
+        let (key_hash, rest) = der::split(rest)?;
+        let (serial, _) = der::split(rest)?;
+        if serial.tag != der::INTEGER {
+            return None;
+        }
+        let (oid, _) = der::split(algorithm.content)?;
+        cert_ids.push(CertId {
+            encoded: cert_id.encoded.to_vec(),
+            digest: digest(oid.encoded),
+            name_hash: name_hash.content.to_vec(),
+            key_hash: key_hash.content.to_vec(),
+            serial: BigNum::from_slice(serial.content)
+                .ok()?
+                .to_hex_str()
+                .ok()?
+                .to_lowercase(),
+        });
+        list = next;
+    }
+    Some(cert_ids)
+}
+
+/// The digest a request identifies certificates with, of those it may use
+fn digest(oid: &[u8]) -> Option<MessageDigest> {
+    [
+        ("1.3.14.3.2.26", MessageDigest::sha1()),
+        ("2.16.840.1.101.3.4.2.1", MessageDigest::sha256()),
+        ("2.16.840.1.101.3.4.2.2", MessageDigest::sha384()),
+        ("2.16.840.1.101.3.4.2.3", MessageDigest::sha512()),
+    ]
+    .into_iter()
+    .find(|(digest_oid, _)| der::oid(digest_oid) == oid)
+    .map(|(_, digest)| digest)
+}
+
+/// The nonce extension among a request's extensions, `[2] EXPLICIT`
+fn find_nonce(explicit: &[u8]) -> Option<Vec<u8>> {
+    let (extensions, _) = der::split(explicit)?;
+    let mut rest = extensions.content;
+    while !rest.is_empty() {
+        let (extension, next) = der::split(rest)?;
+        let (oid, _) = der::split(extension.content)?;
+        if oid.encoded == der::oid(NONCE).as_slice() {
+            return Some(extension.encoded.to_vec());
+        }
+        rest = next;
+    }
+    None
+}
+
+/// Sign a successful OCSP response giving the status of each certificate
+/// asked about, valid from the start of the validity until its end. The CA
+/// signs it itself rather than a delegated responder
+pub fn generate_response(
+    ca_cert: &X509,
+    request: &Request,
+    statuses: &[Status],
+    validity: &Validity,
+    signer: &dyn Signer,
+) -> Vec<u8> {
+    let public_key = signer.public_key();
+    if !ca_cert.public_key().unwrap().public_eq(&public_key) {
+        panic!("The signing key is not the key of the CA's certificate");
+    }
+    let (digest, algorithm) = signer::algorithm(&public_key);
+
+    let responses: Vec<Vec<u8>> = request
+        .cert_ids
+        .iter()
+        .zip(statuses)
+        .map(|(cert_id, status)| {
+            der::sequence(&[
+                cert_id.encoded.clone(),
+                cert_status(status),
+                der::generalized_time(validity.not_before),
+                der::tlv(0xa0, &der::generalized_time(validity.not_after)),
+            ])
+        })
+        .collect();
+    let mut response_data = vec![
+        // The responder by the SHA-1 hash of its key, byKey [2] EXPLICIT
+        der::tlv(
+            0xa2,
+            &der::tlv(
+                der::OCTET_STRING,
+                &hash(MessageDigest::sha1(), &key_bits(ca_cert)).unwrap(),
+            ),
+        ),
+        der::generalized_time(validity::now()),
+        der::sequence(&responses),
+    ];
+    if let Some(nonce) = &request.nonce {
+        response_data.push(der::tlv(0xa1, &der::sequence(std::slice::from_ref(nonce))));
+    }
+    let signed = signer::sign_der(&der::sequence(&response_data), &algorithm, signer, digest);
+    verify(&signed, &public_key);
+
+    // The CA's certificate goes along in certs [0] EXPLICIT, since clients
+    // such as openssl ocsp look for the signer among them
+    let (signed, _) = der::split(&signed).unwrap();
+    let basic = der::tlv(
+        der::SEQUENCE,
+        &[
+            signed.content,
+            &der::tlv(0xa0, &der::sequence(&[ca_cert.to_der().unwrap()])),
+        ]
+        .concat(),
+    );
+
+    let response = der::sequence(&[
+        // successful
+        der::tlv(der::ENUMERATED, &[0]),
+        der::tlv(
+            0xa0,
+            &der::sequence(&[
+                der::oid(BASIC_RESPONSE),
+                der::tlv(der::OCTET_STRING, &basic),
+            ]),
+        ),
+    ]);
+    if OcspResponse::from_der(&response)
+        .and_then(|response| response.basic())
+        .is_err()
+    {
+        panic!("OpenSSL cannot read the OCSP response back");
+    }
+    response
+}
+
+/// The CertStatus CHOICE, whose alternatives are all IMPLICIT
+fn cert_status(status: &Status) -> Vec<u8> {
+    match status {
+        Status::Good => der::tlv(0x80, &[]),
+        Status::Revoked(revoked) => {
+            let mut info = der::generalized_time(revoked.revoked.0);
+            if revoked.reason != Reason::Unspecified {
+                info.extend(der::tlv(
+                    0xa0,
+                    &der::tlv(der::ENUMERATED, &[revoked.reason.code()]),
+                ));
+            }
+            der::tlv(0xa1, &info)
+        }
+        Status::Unknown => der::tlv(0x82, &[]),
+    }
+}
+
+/// The subjectPublicKey bits of a certificate, which issuer key hashes and
+/// the responder ID are taken over
+fn key_bits(cert: &X509) -> Vec<u8> {
+    let spki = cert.public_key().unwrap().public_key_to_der().unwrap();
+    let (spki, _) = der::split(&spki).unwrap();
+    let (_, rest) = der::split(spki.content).unwrap();
+    let (bits, _) = der::split(rest).unwrap();
+    // Without the count of unused bits, which is always 0 for keys
+    bits.content[1..].to_vec()
+}
+
+fn verify(basic: &[u8], public_key: &PKey<Public>) {
+    let (outer, _) = der::split(basic).unwrap();
+    let (tbs, rest) = der::split(outer.content).unwrap();
+    let (_, rest) = der::split(rest).unwrap();
+    let (signature, _) = der::split(rest).unwrap();
+    let (digest, _) = signer::algorithm(public_key);
+    let mut verifier = Verifier::new(digest, public_key).unwrap();
+    if !verifier
+        .verify_oneshot(&signature.content[1..], tbs.encoded)
+        .unwrap_or(false)
+    {
+        panic!("The signature on the OCSP response does not verify with the signing key");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validity::{Duration, Timestamp};
+    use openssl::asn1::{Asn1Integer, Asn1Time};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspRevokedStatus};
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Name, X509NameBuilder};
+
+    fn name(common_name: &str) -> X509Name {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        name.build()
+    }
+
+    fn cert(
+        subject: &str,
+        serial: u32,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(serial).unwrap()).unwrap())
+            .unwrap();
+        builder.set_subject_name(&name(subject)).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                builder.set_issuer_name(issuer.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name(subject)).unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        builder.build()
+    }
+
+    fn request(ca: &X509, leaves: &[&X509], digest: MessageDigest) -> Vec<u8> {
+        let mut request = OcspRequest::new().unwrap();
+        for leaf in leaves {
+            request
+                .add_id(OcspCertId::from_cert(digest, leaf, ca).unwrap())
+                .unwrap();
+        }
+        request.to_der().unwrap()
+    }
+
+    fn check(ca_key: PKey<Private>) {
+        let ca = cert("Test CA", 1, &ca_key, None);
+        let leaf_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let good = cert("good", 0x1001, &leaf_key, Some((&ca, &ca_key)));
+        let revoked = cert("revoked", 0x1002, &leaf_key, Some((&ca, &ca_key)));
+        let unknown = cert("unknown", 0x1003, &leaf_key, Some((&ca, &ca_key)));
+
+        let der = request(&ca, &[&good, &revoked, &unknown], MessageDigest::sha256());
+        let parsed = parse_request(&der).unwrap();
+        let serials: Vec<&str> = parsed
+            .cert_ids
+            .iter()
+            .map(|id| id.serial.as_str())
+            .collect();
+        assert_eq!(serials, ["1001", "1002", "1003"]);
+        assert!(parsed.cert_ids.iter().all(|id| id.is_for(&ca)));
+
+        let entry = Revoked {
+            serial: "1002".to_string(),
+            name: None,
+            revoked: Timestamp::now(),
+            reason: Reason::KeyCompromise,
+        };
+        let validity = Validity::from_now(Duration::days(1), Duration::ZERO).unwrap();
+        let response = generate_response(
+            &ca,
+            &parsed,
+            &[Status::Good, Status::Revoked(&entry), Status::Unknown],
+            &validity,
+            &ca_key,
+        );
+
+        // OpenSSL reads the response back, finds the CA's certificate in it
+        // and gives the status of each certificate
+        let basic = OcspResponse::from_der(&response).unwrap().basic().unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca.clone()).unwrap();
+        basic
+            .verify(&Stack::new().unwrap(), &store.build(), OcspFlag::empty())
+            .unwrap();
+        for (leaf, expected) in [
+            (&good, OcspCertStatus::GOOD),
+            (&revoked, OcspCertStatus::REVOKED),
+            (&unknown, OcspCertStatus::UNKNOWN),
+        ] {
+            let id = OcspCertId::from_cert(MessageDigest::sha256(), leaf, &ca).unwrap();
+            let status = basic.find_status(&id).unwrap();
+            assert_eq!(status.status, expected);
+            if expected == OcspCertStatus::REVOKED {
+                assert_eq!(status.reason, OcspRevokedStatus::KEY_COMPROMISE);
+            }
+        }
+    }
+
+    #[test]
+    fn answers_for_an_rsa_ca() {
+        check(PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn answers_for_an_ecdsa_ca() {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        check(PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn tells_apart_other_issuers() {
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca = cert("Test CA", 1, &ca_key, None);
+        let other = cert("Other CA", 2, &ca_key, None);
+        let leaf = cert("leaf", 3, &ca_key, Some((&ca, &ca_key)));
+
+        let parsed = parse_request(&request(&ca, &[&leaf], MessageDigest::sha1())).unwrap();
+        assert!(parsed.cert_ids[0].is_for(&ca));
+        assert!(!parsed.cert_ids[0].is_for(&other));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert!(parse_request(b"").is_err());
+        assert!(parse_request(&der::sequence(&[der::sequence(&[])])).is_err());
+        assert!(parse_request(&der::sequence(&[der::sequence(&[der::sequence(&[])])])).is_err());
+    }
+}
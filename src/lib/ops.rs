@@ -1,36 +1,22 @@
-use clap::Args;
-use openssl::asn1::Asn1Time;
+use clap::{Args, Subcommand};
 use openssl::nid::Nid;
+use std::cell::OnceCell;
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 
+use crate::cert::IssuerExpiry;
+use crate::san::AltName;
+use crate::signer::Signer;
+use crate::subject::Subject;
+use crate::validity::{Duration, Timestamp, Validity};
 use crate::KeyType;
 use crate::*;
 
 #[derive(Args, Debug)]
-#[command(about = "Generate a new root certificate")]
-pub struct Init {
-    /// Base directory to store certificates
-    #[arg(long, default_value = "~/.hancock", env = "CA_BASE_DIR")]
-    pub base_dir: String,
-
-    /// Algorithm to generate private keys ('RSA' or 'ECDSA')
-    #[arg(long, short = 't', default_value = "RSA", value_parser = type_parser)]
-    pub key_type: String,
-
-    /// Length to use when generating an RSA key. Ignored for ECDSA
-    #[arg(long, short = 'b', default_value_t = 4096)]
-    pub key_length: u32,
-
-    /// Lifetime in days of the generated certificate
-    #[arg(long, short = 'd', default_value_t = 365 * 10)]
-    pub lifetime: u32,
-
-    /// Certificate CommonName
-    #[arg(long, short = 'n')]
-    pub common_name: Option<String>,
-
+pub struct SubjectArgs {
     /// Certificate Country
     #[arg(long, short = 'c')]
     pub country: Option<String>,
@@ -47,365 +33,3620 @@ pub struct Init {
     #[arg(long, short = 'o')]
     pub organization: Option<String>,
 
-    /// Certificate Organizational Unit
+    /// Certificate Organizational Unit. May be repeated
     #[arg(long, short = 'u')]
-    pub organizational_unit: Option<String>,
+    pub organizational_unit: Vec<String>,
+
+    /// Certificate Email Address
+    #[arg(long)]
+    pub email_address: Option<String>,
+
+    /// Certificate Serial Number attribute (not the certificate's serial)
+    #[arg(long)]
+    pub serial_number: Option<String>,
+
+    /// Certificate Title
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Certificate Given Name
+    #[arg(long)]
+    pub given_name: Option<String>,
+
+    /// Certificate Surname
+    #[arg(long)]
+    pub surname: Option<String>,
+
+    /// Certificate Domain Component, most significant first. May be repeated
+    #[arg(long)]
+    pub domain_component: Vec<String>,
+
+    /// Full subject as '/C=US/O=Acme/CN=foo' or RFC 4514 'CN=foo,O=Acme,C=US', kept in the given order
+    #[arg(
+        long,
+        value_parser = subject_parser,
+        conflicts_with_all = [
+            "country", "state", "locality", "organization", "organizational_unit", "email_address",
+            "serial_number", "title", "given_name", "surname", "domain_component",
+        ],
+    )]
+    pub subject: Option<Subject>,
+
+    /// Encode attributes as PrintableString where the value allows it instead of UTF8String
+    #[arg(long)]
+    pub printable: bool,
+}
+
+impl SubjectArgs {
+    /// Build the subject, either from --subject or from the individual
+    /// attributes in conventional most-significant-first order. Attributes
+    /// that were not given are taken from the issuing CA's defaults
+    pub fn to_subject(&self, common_name: &Option<String>, defaults: &Subject) -> Subject {
+        if let Some(ref subject) = self.subject {
+            let mut subject = subject.clone();
+            match (subject.common_name(), common_name) {
+                (Some(ref a), Some(b)) if a != b => {
+                    panic!("CommonName {b} does not match the CN {a} given in --subject")
+                }
+                (None, Some(cn)) => subject.push(Nid::COMMONNAME, cn).unwrap(),
+                _ => {}
+            }
+            return subject;
+        }
+
+        let given: Vec<(Nid, Vec<&String>)> = vec![
+            (Nid::DOMAINCOMPONENT, self.domain_component.iter().collect()),
+            (Nid::COUNTRYNAME, self.country.iter().collect()),
+            (Nid::STATEORPROVINCENAME, self.state.iter().collect()),
+            (Nid::LOCALITYNAME, self.locality.iter().collect()),
+            (Nid::ORGANIZATIONNAME, self.organization.iter().collect()),
+            (
+                Nid::ORGANIZATIONALUNITNAME,
+                self.organizational_unit.iter().collect(),
+            ),
+            (Nid::TITLE, self.title.iter().collect()),
+            (Nid::SURNAME, self.surname.iter().collect()),
+            (Nid::GIVENNAME, self.given_name.iter().collect()),
+            (Nid::COMMONNAME, common_name.iter().collect()),
+            (Nid::SERIALNUMBER, self.serial_number.iter().collect()),
+            (Nid::PKCS9_EMAILADDRESS, self.email_address.iter().collect()),
+        ];
+
+        let mut subject = Subject::new();
+        let mut push = |nid: Nid, value: &str| {
+            if let Err(e) = subject.push(nid, value) {
+                panic!("{e}");
+            }
+        };
+        for (nid, values) in &given {
+            // Defaults without a matching flag go just ahead of the CommonName
+            if *nid == Nid::COMMONNAME {
+                for (default_nid, value) in defaults.entries() {
+                    if !given.iter().any(|(n, _)| n == default_nid) {
+                        push(*default_nid, value);
+                    }
+                }
+            }
+            match values.is_empty() {
+                true => defaults
+                    .entries()
+                    .iter()
+                    .filter(|(n, _)| n == nid && *n != Nid::COMMONNAME)
+                    .for_each(|(n, value)| push(*n, value)),
+                false => values.iter().for_each(|value| push(*nid, value)),
+            }
+        }
+        subject
+    }
+}
+
+/// The flags and variable one of a command's passwords is given with
+#[derive(Debug)]
+pub struct PasswordNames {
+    pub value: &'static str,
+    pub short: Option<char>,
+    pub env: &'static str,
+    pub file: &'static str,
+    pub fd: &'static str,
+    pub command: &'static str,
+    /// Help for the password flag itself
+    pub help: &'static str,
+    /// The password, as the help for the other flags refers to it
+    pub what: &'static str,
+    /// Whether --allow-password-argument comes with this password
+    pub allow_flag: bool,
+}
+
+pub trait PasswordKind {
+    const NAMES: PasswordNames;
+}
+
+/// The root private key's password, or the CA key's in general
+#[derive(Debug)]
+pub enum RootPassword {}
+
+impl PasswordKind for RootPassword {
+    const NAMES: PasswordNames = PasswordNames {
+        value: "password",
+        short: Some('p'),
+        env: "CA_PASSWORD",
+        file: "password-file",
+        fd: "password-fd",
+        command: "password-command",
+        help: "Password for the root private key",
+        what: "the root private key's password",
+        allow_flag: true,
+    };
+}
+
+#[derive(Debug)]
+pub enum IntermediatePassword {}
+
+impl PasswordKind for IntermediatePassword {
+    const NAMES: PasswordNames = PasswordNames {
+        value: "intermediate-password",
+        short: None,
+        env: "CA_INTERMEDIATE_PASSWORD",
+        file: "intermediate-password-file",
+        fd: "intermediate-password-fd",
+        command: "intermediate-password-command",
+        help: "Password for the intermediate's private key",
+        what: "the intermediate private key's password",
+        allow_flag: false,
+    };
+}
+
+#[derive(Debug)]
+pub enum KeyPassword {}
+
+impl PasswordKind for KeyPassword {
+    const NAMES: PasswordNames = PasswordNames {
+        value: "key-password",
+        short: None,
+        env: "HANCOCK_KEY_PASSWORD",
+        file: "key-password-file",
+        fd: "key-password-fd",
+        command: "key-password-command",
+        help: "Password to encrypt the certificate's private key with",
+        what: "the certificate private key's password",
+        allow_flag: false,
+    };
+}
+
+#[derive(Debug)]
+pub enum NewPassword {}
+
+impl PasswordKind for NewPassword {
+    const NAMES: PasswordNames = PasswordNames {
+        value: "new-password",
+        short: None,
+        env: "HANCOCK_NEW_PASSWORD",
+        file: "new-password-file",
+        fd: "new-password-fd",
+        command: "new-password-command",
+        help: "New password, or the PIN of a new key on a token",
+        what: "the new password",
+        allow_flag: false,
+    };
+}
+
+/// A password given directly, in the environment, in a file, on a file
+/// descriptor or by a command, with flags named after its kind
+pub struct PasswordArgs<K: PasswordKind = RootPassword> {
+    pub value: Option<String>,
+    /// Whether the value came from the command line rather than the environment
+    pub value_on_command_line: bool,
+    pub file: Option<String>,
+    pub fd: Option<i32>,
+    pub command: Option<String>,
+    /// Only ever set for the root password, which carries the flag
+    pub allow_password_argument: bool,
+    kind: std::marker::PhantomData<K>,
+}
+
+impl<K: PasswordKind> std::fmt::Debug for PasswordArgs<K> {
+    /// Shows where the password comes from, never the password itself
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordArgs")
+            .field("value", &self.value.as_ref().map(|_| "<redacted>"))
+            .field("value_on_command_line", &self.value_on_command_line)
+            .field("file", &self.file)
+            .field("fd", &self.fd)
+            .field("command", &self.command)
+            .field("allow_password_argument", &self.allow_password_argument)
+            .finish()
+    }
+}
+
+impl<K: PasswordKind> Args for PasswordArgs<K> {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        let names = K::NAMES;
+        let cmd = cmd
+            .arg(
+                clap::Arg::new(names.value)
+                    .long(names.value)
+                    .value_name("PASSWORD")
+                    .short(names.short)
+                    .env(names.env)
+                    .help(format!(
+                        "{}. Only read from {} unless --allow-password-argument is given",
+                        names.help, names.env
+                    )),
+            )
+            .arg(
+                clap::Arg::new(names.file)
+                    .long(names.file)
+                    .value_name("FILE")
+                    .conflicts_with_all([names.fd, names.command])
+                    .help(format!("File with {} on its first line", names.what)),
+            )
+            .arg(
+                clap::Arg::new(names.fd)
+                    .long(names.fd)
+                    .value_name("FD")
+                    .value_parser(clap::value_parser!(i32))
+                    .conflicts_with(names.command)
+                    .help(format!("File descriptor to read {} from", names.what)),
+            )
+            .arg(
+                clap::Arg::new(names.command)
+                    .long(names.command)
+                    .value_name("COMMAND")
+                    .help(match names.allow_flag {
+                        true => format!("Command that prints {} (e.g. 'pass show ca')", names.what),
+                        false => format!("Command that prints {}", names.what),
+                    }),
+            );
+        match names.allow_flag {
+            true => cmd.arg(
+                clap::Arg::new("allow-password-argument")
+                    .long("allow-password-argument")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Accept passwords given on the command line, where other users may see them"),
+            ),
+            false => cmd,
+        }
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+impl<K: PasswordKind> clap::FromArgMatches for PasswordArgs<K> {
+    fn from_arg_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let names = K::NAMES;
+        Ok(PasswordArgs {
+            value: matches.get_one::<String>(names.value).cloned(),
+            value_on_command_line: matches.value_source(names.value)
+                == Some(clap::parser::ValueSource::CommandLine),
+            file: matches.get_one::<String>(names.file).cloned(),
+            fd: matches.get_one::<i32>(names.fd).copied(),
+            command: matches.get_one::<String>(names.command).cloned(),
+            allow_password_argument: names.allow_flag
+                && matches.get_flag("allow-password-argument"),
+            kind: std::marker::PhantomData,
+        })
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl<K: PasswordKind> PasswordArgs<K> {
+    /// The password from whichever source was given, which is read only once.
+    /// A value given on the command line is refused unless allowed
+    pub fn read(&self, allow_password_argument: bool) -> Option<String> {
+        let names = K::NAMES;
+        let source = if let Some(ref path) = self.file {
+            password::Source::File(path.clone())
+        } else if let Some(fd) = self.fd {
+            password::Source::Fd(fd)
+        } else if let Some(ref command) = self.command {
+            password::Source::Command(command.clone())
+        } else {
+            password::check_argument(
+                self.value_on_command_line,
+                names.env,
+                &format!("--{}", names.value),
+                allow_password_argument,
+            );
+            password::Source::Value(self.value.clone()?)
+        };
+        Some(source.read())
+    }
+}
+
+impl PasswordArgs {
+    /// The root password, allowed on the command line with the command's
+    /// --allow-password-argument
+    pub fn get(&self) -> Option<String> {
+        self.read(self.allow_password_argument)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct EncryptionArgs {
+    /// Cipher to encrypt private keys given a password with ('aes-128-cbc', 'aes-192-cbc', 'aes-256-cbc', 'aes-128-gcm' or 'aes-256-gcm') [default: aes-256-cbc]
+    #[arg(long, value_parser = cipher_parser)]
+    pub cipher: Option<pkey::Cipher>,
+
+    /// Function deriving the encryption key from the password, as 'pbkdf2[:iterations=N]' or 'scrypt[:n=N,r=R,p=P]' [default: pbkdf2:iterations=600000]
+    #[arg(long, value_parser = kdf_parser)]
+    pub kdf: Option<pkey::Kdf>,
+}
+
+impl EncryptionArgs {
+    /// The encryption given on the command line, falling back to the CA's
+    pub fn to_encryption(&self, ca: &config::CaConfig) -> pkey::Encryption {
+        let configured = ca.key_encryption();
+        pkey::Encryption {
+            cipher: self.cipher.unwrap_or(configured.cipher),
+            kdf: self.kdf.unwrap_or(configured.kdf),
+        }
+    }
+}
+
+/// The configured CA, base directory and store a command works on
+#[derive(Args, Debug)]
+pub struct StoreArgs {
+    /// Named CA from the configuration file
+    #[arg(long)]
+    pub ca: Option<String>,
+
+    /// Base directory to store certificates, overriding the CA's and CA_BASE_DIR [default: ~/.hancock]
+    #[arg(long)]
+    pub base_dir: Option<String>,
+
+    /// Seconds to wait for other hancock processes using the base directory, 0 to fail straight away, or without a value for as long as it takes [default: 60]
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1)]
+    pub wait: Option<Option<u64>>,
+
+    /// Name of the CA store within the base directory, as given to init --name
+    #[arg(long, value_parser = store_parser)]
+    pub store: Option<String>,
+}
+
+impl StoreArgs {
+    /// The CA these name, with its base directory locked against other
+    /// hancock processes until the lock is dropped
+    pub fn lock(&self, config: &config::Config) -> (config::CaConfig, lock::Lock) {
+        let ca = config.ca(&self.ca);
+        let lock = lock::acquire(&self.base_dir(&ca), lock::Wait::from_arg(self.wait));
+        (ca, lock)
+    }
+
+    /// Like lock, along with the directory of the store
+    pub fn open(&self, config: &config::Config) -> (config::CaConfig, String, lock::Lock) {
+        let (ca, lock) = self.lock(config);
+        let store_dir = path::store_dir(&self.base_dir(&ca), &self.name(&ca));
+        (ca, store_dir, lock)
+    }
+
+    pub fn base_dir(&self, ca: &config::CaConfig) -> String {
+        ca.base_dir(&self.base_dir)
+    }
+
+    /// The store given, or else the CA's
+    pub fn name(&self, ca: &config::CaConfig) -> Option<String> {
+        self.store.clone().or(ca.store.clone())
+    }
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Generate a new root certificate")]
+#[command(mut_arg("store", |arg| arg.long("name").help("Name of the CA store to create within the base directory [default: default]")))]
+pub struct Init {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Algorithm to generate private keys ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Length to use when generating an RSA key. Ignored for ECDSA [default: 4096]
+    #[arg(long, short = 'b')]
+    pub key_length: Option<u32>,
+
+    /// Lifetime of the generated certificate, as a duration like 8h or 30d, or a number of days [default: 3650d]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    /// Start of the certificate's validity, as a timestamp like 2024-06-01T12:00:00Z or a date [default: now, less the backdate]
+    #[arg(long, value_parser = timestamp_parser)]
+    pub not_before: Option<Timestamp>,
+
+    /// End of the certificate's validity, as a timestamp like 2024-06-01T12:00:00Z or a date, instead of --lifetime
+    #[arg(long, value_parser = timestamp_parser, conflicts_with = "lifetime")]
+    pub not_after: Option<Timestamp>,
+
+    /// How far before now to start the certificate's validity, to allow for clients with slow clocks [default: 0s]
+    #[arg(long, value_parser = backdate_parser)]
+    pub backdate: Option<Duration>,
+
+    /// Certificate CommonName
+    #[arg(long, short = 'n')]
+    pub common_name: Option<String>,
+
+    #[command(flatten)]
+    pub subject: SubjectArgs,
+
+    /// Policy for issued certificates' subjects as 'ATTR=supplied|match|optional'. May be repeated
+    #[arg(long, value_parser = policy_parser)]
+    pub policy: Vec<(Nid, policy::Rule)>,
+
+    /// Root key kept outside hancock: a PKCS#11 URI of a token key, generated on the token if it does not exist, or an external signer as exec:COMMAND or unix:SOCKET. The password is the token PIN
+    #[arg(long, value_parser = key_uri_parser)]
+    pub key: Option<String>,
+
+    /// Write a request for an external root to sign instead of a self-signed certificate
+    #[arg(long, conflicts_with = "import_cert")]
+    pub subordinate: bool,
+
+    /// Install the certificate an external root signed for a subordinate CA, in PEM or DER
+    #[arg(long, value_name = "FILE")]
+    pub import_cert: Option<String>,
+
+    /// Certificates from the subordinate CA's issuer up to the external root, sent along with issued certificates
+    #[arg(long, value_name = "FILE", requires = "import_cert")]
+    pub chain: Option<String>,
 
-    /// Password for private key
-    #[arg(long, short = 'p', env = "CA_PASSWORD")]
-    pub password: Option<String>,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
 }
 
 #[derive(Args, Debug)]
 #[command(about = "Issue a new certificate")]
 pub struct Issue {
-    /// Base directory to store certificates
-    #[arg(long, default_value = "~/.hancock", env = "CA_BASE_DIR")]
-    pub base_dir: String,
+    #[command(flatten)]
+    pub store: StoreArgs,
 
-    /// Algorithm to generate private keys ('RSA' or 'ECDSA')
-    #[arg(long, short = 't', default_value = "RSA")]
-    pub key_type: String,
+    /// Algorithm to generate private keys ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Length to use when generating an RSA key. Ignored for ECDSA [default: 2048]
+    #[arg(long, short = 'b')]
+    pub key_length: Option<u32>,
+
+    /// Profile from the configuration file to issue with
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Lifetime of the generated certificate, as a duration like 15m, 8h or 30d, or a number of days (default 730d for intermediates or 90d for certificates)
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
 
-    /// Length to use when generating an RSA key. Ignored for ECDSA
-    #[arg(long, short = 'b', default_value_t = 2048)]
-    pub key_length: u32,
+    /// Start of the certificate's validity, as a timestamp like 2024-06-01T12:00:00Z or a date [default: now, less the backdate]
+    #[arg(long, value_parser = timestamp_parser)]
+    pub not_before: Option<Timestamp>,
 
-    /// Lifetime in days of the generated certificate (default 2 years for intermediates or 90 days for certificates)
-    #[arg(long, short = 'd')]
-    pub lifetime: Option<u32>,
+    /// End of the certificate's validity, as a timestamp like 2024-06-01T12:00:00Z or a date, instead of --lifetime
+    #[arg(long, value_parser = timestamp_parser, conflicts_with = "lifetime")]
+    pub not_after: Option<Timestamp>,
+
+    /// How far before now to start the certificate's validity, to allow for clients with slow clocks [default: 0s]
+    #[arg(long, value_parser = backdate_parser)]
+    pub backdate: Option<Duration>,
 
     // Certificate Intermediate to generate or use
-    #[arg(long, short = 'i')]
+    #[arg(long, short = 'i', value_parser = name_parser)]
     pub intermediate: Option<String>,
 
+    /// ID of an existing certificate to issue again, reusing its key, instead of creating a new one
+    #[arg(long, value_parser = name_parser, requires = "common_name")]
+    pub id: Option<String>,
+
+    /// Alias to look the certificate up by instead of its ID or CommonName. May be repeated
+    #[arg(long, value_parser = name_parser, requires = "common_name")]
+    pub alias: Vec<String>,
+
     /// Certificate CommonName
     #[arg(long, short = 'n')]
     pub common_name: Option<String>,
 
-    /// Certificate Country
-    #[arg(long, short = 'c')]
-    pub country: Option<String>,
+    #[command(flatten)]
+    pub subject: SubjectArgs,
 
-    /// Certificate State or Province
-    #[arg(long, short = 's')]
-    pub state: Option<String>,
+    /// Comma separated Subject Alternative Names, optionally typed (e.g. 'dns:a.example.com,ip:10.0.0.1')
+    #[arg(long, value_parser = san_list_parser)]
+    pub subject_alt_names: Option<::std::vec::Vec<AltName>>,
 
-    /// Certificate Locality
-    #[arg(long, short = 'l')]
-    pub locality: Option<String>,
+    /// Subject Alternative Name of the form 'type:value' where type is one of dns, ip, email, uri, upn or rid. May be repeated
+    #[arg(long = "san", value_parser = san_parser)]
+    pub san: Vec<AltName>,
 
-    /// Certificate Organization
-    #[arg(long, short = 'o')]
-    pub organization: Option<String>,
+    /// Do not fill in subject attributes from the issuing CA's defaults
+    #[arg(long)]
+    pub no_inherit: bool,
 
-    /// Certificate Organizational Unit
-    #[arg(long, short = 'u')]
-    pub organizational_unit: Option<String>,
+    /// Policy for a new intermediate's issued certificates as 'ATTR=supplied|match|optional'. May be repeated
+    #[arg(long, value_parser = policy_parser)]
+    pub policy: Vec<(Nid, policy::Rule)>,
 
-    /// Subject Alternative Names
-    #[arg(long)]
-    pub subject_alt_names: Option<String>,
+    /// New intermediate's key kept outside hancock: a PKCS#11 URI of a token key, generated on the token if it does not exist, or an external signer as exec:COMMAND or unix:SOCKET. The intermediate password is the token PIN
+    #[arg(long, value_parser = key_uri_parser, requires = "intermediate", conflicts_with = "common_name")]
+    pub key: Option<String>,
 
-    /// Password for private key
-    #[arg(long, short = 'p', env = "CA_PASSWORD")]
-    pub password: Option<String>,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+
+    /// Password for the intermediate's private key, to encrypt a new intermediate or sign with an existing one
+    #[command(flatten)]
+    pub intermediate_password: PasswordArgs<IntermediatePassword>,
+
+    #[command(flatten)]
+    pub key_password: PasswordArgs<KeyPassword>,
+
+    /// When the certificate would outlive its issuer: 'truncate' it, 'fail', or 'renew' the issuer with its key [default: truncate]
+    #[arg(long, value_parser = issuer_expiry_parser)]
+    pub issuer_expiry: Option<IssuerExpiry>,
 }
 
 #[derive(Args, Debug)]
 #[command(about = "List all known certificates")]
+#[command(mut_arg("store", |arg| arg.help("Name of the CA store within the base directory [default: all stores]")))]
 pub struct List {
-    #[arg(long, default_value = "~/.hancock", env = "CA_BASE_DIR")]
-    pub base_dir: String,
+    #[command(flatten)]
+    pub store: StoreArgs,
 }
 
 #[derive(Args, Debug)]
-#[command(about = "Renew a certificate or all if no Common Name is specified")]
-pub struct Renew {
-    /// Base directory to store certificates
-    #[arg(long, default_value = "~/.hancock", env = "CA_BASE_DIR")]
-    pub base_dir: String,
+#[command(about = "List the archived versions of a certificate")]
+pub struct History {
+    #[command(flatten)]
+    pub store: StoreArgs,
 
-    /// Certificate CommonName
+    /// Certificate CommonName, alias or ID
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Restore an archived version of a certificate")]
+pub struct Rollback {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Certificate CommonName, alias or ID
+    pub name: String,
+
+    /// Serial number, in hex, of the version to restore
+    #[arg(long, value_parser = serial_parser)]
+    pub to: String,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Revoke a certificate or an intermediate, to be listed in its issuer's next CRL")]
+pub struct Revoke {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Certificate CommonName, alias or ID
+    #[arg(
+        required_unless_present = "intermediate",
+        conflicts_with = "intermediate"
+    )]
+    pub name: Option<String>,
+
+    /// Intermediate to revoke instead of a certificate
+    #[arg(long, short = 'i', value_parser = name_parser)]
+    pub intermediate: Option<String>,
+
+    /// Only revoke the certificate of this type ('RSA' or 'ECDSA') [default: both]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Serial number, in hex, of an archived version to revoke instead of the current one
+    #[arg(long, value_parser = serial_parser)]
+    pub serial: Option<String>,
+
+    /// Why it is revoked: 'unspecified', 'key-compromise', 'ca-compromise', 'affiliation-changed', 'superseded', 'cessation-of-operation', 'certificate-hold' or 'privilege-withdrawn' [default: unspecified]
+    #[arg(long, value_parser = reason_parser)]
+    pub reason: Option<crl::Reason>,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Sign a certificate revocation list for the root or an intermediate")]
+pub struct Crl {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Intermediate whose CRL to sign, instead of the root's
+    #[arg(long, short = 'i', value_parser = name_parser)]
+    pub intermediate: Option<String>,
+
+    /// Which CA's CRL to sign when there are both ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// How long until the next CRL is due, as a duration like 12h or 7d [default: 7d]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub intermediate_password: PasswordArgs<IntermediatePassword>,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Answer an OCSP request for the root or an intermediate with a signed response")]
+pub struct Ocsp {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Intermediate to answer for, instead of the root
+    #[arg(long, short = 'i', value_parser = name_parser)]
+    pub intermediate: Option<String>,
+
+    /// Which CA to answer for when there are both ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// How long the response is valid, as a duration like 12h or 7d [default: the CRL lifetime]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    /// DER OCSP request, such as openssl ocsp -reqout writes
+    pub request: String,
+
+    /// Where to write the DER OCSP response
+    pub response: String,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub intermediate_password: PasswordArgs<IntermediatePassword>,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Move a single-CA base directory into the per-CA store layout")]
+#[command(mut_arg("store", |arg| arg.long("name").help("Name of the CA store to move the existing CA into [default: default]")))]
+pub struct Migrate {
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Inspect the configuration file")]
+pub struct Config {
+    #[command(subcommand)]
+    pub command: ConfigCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Validate the configuration file
+    Check,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Manage private keys")]
+pub struct Key {
+    #[command(subcommand)]
+    pub command: KeyCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommands {
+    /// Change, add or remove the password of the root's, an intermediate's or a certificate's key
+    Passwd(KeyPasswd),
+}
+
+#[derive(Args, Debug)]
+pub struct KeyPasswd {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Intermediate whose key to change, instead of the root's
+    #[arg(long, short = 'i', value_parser = name_parser, conflicts_with = "common_name")]
+    pub intermediate: Option<String>,
+
+    /// Certificate CommonName, alias or ID whose key to change, instead of the root's
     #[arg(long, short = 'n')]
     pub common_name: Option<String>,
 
-    /// Password for private key
-    #[arg(long, short = 'p', env = "CA_PASSWORD")]
-    pub password: Option<String>,
+    /// Which key to change when there are both ('RSA' or 'ECDSA')
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// The key's current password is given like the root key's
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub new_password: PasswordArgs<NewPassword>,
+
+    /// Store the key without a password
+    #[arg(long, conflicts_with_all = ["new-password-file", "new-password-fd", "new-password-command"])]
+    pub remove: bool,
+
+    /// Remove the password without asking for confirmation
+    #[arg(long, requires = "remove")]
+    pub yes: bool,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Hold decrypted CA keys for other hancock commands, like ssh-agent")]
+pub struct Agent {
+    #[command(subcommand)]
+    pub command: AgentCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AgentCommands {
+    /// Run the agent in the foreground. Hancock commands sign through it when HANCOCK_AGENT_SOCK is set
+    Start(AgentStart),
+    /// Decrypt the root's or an intermediate's key into the agent
+    Unlock(AgentUnlock),
+    /// Drop every key the agent holds
+    Lock(AgentSocket),
+    /// List the keys the agent holds and when they are dropped
+    List(AgentSocket),
+}
+
+#[derive(Args, Debug)]
+pub struct AgentStart {
+    /// Named CA from the configuration file
+    #[arg(long)]
+    pub ca: Option<String>,
+
+    /// Base directory to store certificates, overriding the CA's and CA_BASE_DIR [default: ~/.hancock]
+    #[arg(long)]
+    pub base_dir: Option<String>,
+
+    /// Name of the CA store within the base directory, as given to init --name
+    #[arg(long, value_parser = store_parser)]
+    pub store: Option<String>,
+
+    /// Socket to listen on [default: agent.sock in the store]
+    #[arg(long)]
+    pub socket: Option<String>,
+
+    /// Other user allowed to sign, as 'USER' or 'USER=KEY,...' where KEY is 'root' or an intermediate's name. Other users can only sign certificates marked as not CAs. Needs a --socket outside the store, in a directory of a group the users are in with group access. May be repeated
+    #[arg(long, value_parser = allow_parser, requires = "socket")]
+    pub allow: Vec<agent::Allow>,
+
+    /// Longest a certificate signed for another user may be valid for, as a duration like 90d
+    #[arg(long, value_parser = lifetime_parser, default_value = "398d")]
+    pub max_lifetime: Duration,
+
+    /// Allowed user who may also sign CRLs and OCSP responses, which can mark any certificate as good or revoked. May be repeated
+    #[arg(long, value_parser = uid_parser)]
+    pub allow_revocation: Vec<u32>,
+
+    /// Hours an unlocked key is held before it has to be unlocked again
+    #[arg(long, default_value_t = 24)]
+    pub unlock_hours: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct AgentUnlock {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Agent socket
+    #[arg(long, env = "HANCOCK_AGENT_SOCK")]
+    pub socket: String,
+
+    /// Intermediate whose key to unlock, instead of the root's
+    #[arg(long, short = 'i', value_parser = name_parser)]
+    pub intermediate: Option<String>,
+
+    /// Which key to unlock when there are both ('RSA' or 'ECDSA')
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct AgentSocket {
+    /// Agent socket
+    #[arg(long, env = "HANCOCK_AGENT_SOCK")]
+    pub socket: String,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Back up the root key as shares kept by several custodians")]
+pub struct Backup {
+    #[command(subcommand)]
+    pub command: BackupCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BackupCommands {
+    /// Split the root's key file, as it is stored, into shares any THRESHOLD of which restore it
+    Split(BackupSplit),
+    /// Restore the root's key from shares and check it against the root certificate
+    Combine(BackupCombine),
+}
+
+#[derive(Args, Debug)]
+pub struct BackupSplit {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Which root key to split when there are both ('RSA' or 'ECDSA')
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Number of shares to make
+    #[arg(long)]
+    pub shares: u8,
+
+    /// Number of shares needed to restore the key
+    #[arg(long)]
+    pub threshold: u8,
+
+    /// Directory to write one file per share to, instead of printing them
+    #[arg(long)]
+    pub out_dir: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct BackupCombine {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Files holding shares. Shares are read from stdin if none are given
+    pub files: Vec<String>,
+
+    /// Write the restored key here instead of into the store
+    #[arg(long)]
+    pub out: Option<String>,
+
+    /// The restored key's password is given like the root key's, if it is encrypted
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Request a certificate from a root kept offline")]
+pub struct Request {
+    #[command(subcommand)]
+    pub command: RequestCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RequestCommands {
+    /// Generate an intermediate's key and a bundle for the offline root to sign with sign-bundle
+    Intermediate(Box<RequestIntermediate>),
+    /// Write a bundle of the root's revocations for the offline root to sign as a CRL with sign-bundle
+    Crl(RequestCrl),
+}
+
+#[derive(Args, Debug)]
+pub struct RequestIntermediate {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Algorithm to generate private keys ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Length to use when generating an RSA key. Ignored for ECDSA [default: 2048]
+    #[arg(long, short = 'b')]
+    pub key_length: Option<u32>,
+
+    /// Lifetime to ask the root for, as a duration like 30d or a number of days [default: 730d]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    /// Profile from the offline root's configuration to sign with
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Name of the intermediate
+    #[arg(long, short = 'i', value_parser = name_parser)]
+    pub intermediate: String,
+
+    #[command(flatten)]
+    pub subject: SubjectArgs,
+
+    /// Do not fill in subject attributes from the root's defaults
+    #[arg(long)]
+    pub no_inherit: bool,
+
+    /// Policy for the intermediate's issued certificates as 'ATTR=supplied|match|optional'. May be repeated
+    #[arg(long, value_parser = policy_parser)]
+    pub policy: Vec<(Nid, policy::Rule)>,
+
+    /// Intermediate's key kept outside hancock, as for issue --key
+    #[arg(long, value_parser = key_uri_parser)]
+    pub key: Option<String>,
+
+    /// Where to write the bundle [default: next to the intermediate's key]
+    #[arg(long)]
+    pub out: Option<String>,
+
+    /// The new key's password, or the token PIN, is given like the root key's
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RequestCrl {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Which root's CRL to request when there are both ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// How long until the next CRL is due, as a duration like 12h or 7d [default: 7d]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    /// Where to write the bundle [default: next to the root's certificate]
+    #[arg(long)]
+    pub out: Option<String>,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Sign a bundle from request intermediate or request crl with this store's root")]
+pub struct SignBundle {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Request bundle to sign
+    pub bundle: String,
+
+    /// Where to write the signed bundle [default: the request's name ending in .signed.toml]
+    #[arg(long)]
+    pub out: Option<String>,
+
+    /// Sign without asking for confirmation
+    #[arg(long)]
+    pub yes: bool,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(
+    about = "Install an intermediate certificate or a CRL signed by an offline root with sign-bundle"
+)]
+pub struct ImportSigned {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Signed bundle to install
+    pub bundle: String,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Manage root certificates")]
+pub struct Root {
+    #[command(subcommand)]
+    pub command: RootCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RootCommands {
+    /// Replace the root with a new key and certificate, cross-signed with the old root so either one is trusted during the transition
+    Rollover(Box<RootRollover>),
+    /// Sign the RSA root with the ECDSA root or the other way around, so clients trusting only the signing root also trust what the other one issues
+    CrossSign(RootCrossSign),
+    /// Sign the root again with the same key, subject and key identifier for a new validity period
+    Renew(RootRenew),
+}
+
+#[derive(Args, Debug)]
+pub struct RootRenew {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Root to renew ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Lifetime of the renewed certificate, as a duration like 30d or a number of days [default: the current one's]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RootCrossSign {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Root to cross-sign ('RSA' or 'ECDSA'), which the other root signs
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: String,
+
+    /// Password for the signing root's private key
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RootRollover {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Algorithm of the root to replace ('RSA' or 'ECDSA') [default: RSA]
+    #[arg(long, short = 't', value_parser = type_parser)]
+    pub key_type: Option<String>,
+
+    /// Length of the new RSA key. Ignored for ECDSA [default: 4096]
+    #[arg(long, short = 'b')]
+    pub key_length: Option<u32>,
+
+    /// Lifetime of the new root certificate, as a duration like 30d or a number of days [default: 3650d]
+    #[arg(long, short = 'd', value_parser = lifetime_parser)]
+    pub lifetime: Option<Duration>,
+
+    /// New root's CommonName [default: the old root's]
+    #[arg(long, short = 'n')]
+    pub common_name: Option<String>,
+
+    #[command(flatten)]
+    pub subject: SubjectArgs,
+
+    /// New root key kept outside hancock, as for init --key
+    #[arg(long, value_parser = key_uri_parser)]
+    pub key: Option<String>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Password to encrypt the new root key with, or the new token's PIN
+    #[command(flatten)]
+    pub new_password: PasswordArgs<NewPassword>,
+
+    #[command(flatten)]
+    pub encryption: EncryptionArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(about = "Renew a certificate or all if no Common Name is specified")]
+#[command(mut_arg("store", |arg| arg.help("Name of the CA store within the base directory [default: all stores]")))]
+pub struct Renew {
+    #[command(flatten)]
+    pub store: StoreArgs,
+
+    /// Certificate CommonName, alias or ID
+    #[arg(long, short = 'n')]
+    pub common_name: Option<String>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Password for the private keys of intermediates that issued the certificates
+    #[command(flatten)]
+    pub intermediate_password: PasswordArgs<IntermediatePassword>,
+
+    /// When a certificate would outlive its issuer: 'truncate' it, 'fail', or 'renew' the issuer with its key [default: truncate]
+    #[arg(long, value_parser = issuer_expiry_parser)]
+    pub issuer_expiry: Option<IssuerExpiry>,
+
+    /// How far before now to start renewed certificates' validity, to allow for clients with slow clocks [default: 0s]
+    #[arg(long, value_parser = backdate_parser)]
+    pub backdate: Option<Duration>,
+}
+
+pub fn init(args: Init, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+
+    let key_type = key_type(
+        args.key_type.as_ref().or(ca.key_type.as_ref()),
+        args.key_length.or(ca.key_length).unwrap_or(4096),
+    );
+
+    if let Some(ref cert_path) = args.import_cert {
+        import_ca_cert(&base_dir, key_type, cert_path, &args.chain, &args.policy);
+        return;
+    }
+
+    let pkey_path = path::ca_pkey(&base_dir, key_type);
+    let cert_path = path::ca_crt(&base_dir, key_type);
+    let csr_path = path::ca_csr(&base_dir, key_type);
+    if !args.subordinate && !Path::new(&cert_path).exists() && Path::new(&csr_path).exists() {
+        panic!("{csr_path} is waiting for an external root to sign it, install the certificate with --import-cert");
+    }
+
+    let pkey: Box<dyn Signer> = match (Path::new(&pkey_path).exists(), &args.key) {
+        (true, Some(uri)) if signer::key_uri(&pkey_path) != Some(signer::stored_uri(uri)) => {
+            panic!("{pkey_path} already exists and does not refer to {uri}")
+        }
+        (true, _) => signer::load(
+            &pkey_path,
+            password::for_key(args.password.get(), &pkey_path),
+        ),
+        (false, Some(uri)) => {
+            let pkey = signer::open_or_generate(
+                uri,
+                password::for_pin(args.password.get(), uri),
+                key_type,
+            );
+            signer::save_key_uri(&pkey_path, uri);
+            pkey
+        }
+        (false, None) => {
+            let pkey = pkey::generate_pkey(key_type);
+            pkey::save_pkey(
+                &pkey_path,
+                &pkey,
+                password::for_new_key(args.password.get(), "root"),
+                &args.encryption.to_encryption(&ca),
+            );
+            Box::new(pkey)
+        }
+    };
+
+    if args.subordinate {
+        if Path::new(&cert_path).exists() {
+            panic!("{cert_path} already exists");
+        }
+        let subject = args.subject.to_subject(&args.common_name, &ca.subject());
+        let req = root::generate_root_req(&subject, args.subject.printable, pkey.as_ref());
+        req::save_req(&csr_path, &req);
+
+        let policy_path = path::ca_policy(&base_dir, key_type);
+        if !Path::new(&policy_path).exists() {
+            policy::save_policy(
+                &policy_path,
+                &policy::Policy::capture(&subject, &args.policy),
+            );
+        }
+        eprintln!("Have {csr_path} signed by the external root, then install the certificate with init --import-cert");
+        return;
+    }
+
+    let cert = match Path::new(&cert_path).exists() {
+        true => cert::read_cert(&cert_path),
+        false => {
+            let validity = Validity::between(
+                args.not_before,
+                args.not_after,
+                args.lifetime
+                    .or(ca.root_lifetime)
+                    .unwrap_or(Duration::days(365 * 10)),
+                args.backdate.unwrap_or(ca.backdate()),
+            )
+            .unwrap_or_else(|e| panic!("{e}"));
+            let cert = root::generate_root_cert(
+                &validity,
+                &args.subject.to_subject(&args.common_name, &ca.subject()),
+                args.subject.printable,
+                pkey.as_ref(),
+            );
+            cert::save_cert(&cert_path, &cert);
+            cert
+        }
+    };
+
+    let policy_path = path::ca_policy(&base_dir, key_type);
+    if !Path::new(&policy_path).exists() {
+        let policy =
+            policy::Policy::capture(&Subject::from_name(cert.subject_name()), &args.policy);
+        policy::save_policy(&policy_path, &policy);
+    }
+}
+
+pub fn issue(args: Issue, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+
+    let profile = match args.profile.as_ref().or(ca.profile.as_ref()) {
+        Some(name) => config.profile(name),
+        None => config::ProfileConfig::default(),
+    };
+
+    let key_type = key_type(
+        args.key_type
+            .as_ref()
+            .or(profile.key_type.as_ref())
+            .or(ca.key_type.as_ref()),
+        args.key_length
+            .or(profile.key_length)
+            .or(ca.key_length)
+            .unwrap_or(2048),
+    );
+
+    // Leaves from a configured CA are signed by its intermediate by default
+    let intermediate = match args.common_name {
+        Some(_) => args.intermediate.clone().or(ca.intermediate.clone()),
+        None => args.intermediate.clone(),
+    };
+
+    let cn = match args.common_name {
+        Some(ref cn) => cn.clone(),
+        None => match intermediate {
+            Some(ref i) => i.clone(),
+            None => panic!("At least one of common-name or intermediate must be set"),
+        },
+    };
+    let mut alt_names = args.subject_alt_names.clone().unwrap_or_default();
+    alt_names.extend(args.san.iter().cloned());
+    // A leaf's CommonName is also a SAN when it names a host or a mailbox,
+    // while a CA's name never is
+    let alt_names = match args.common_name.is_some() {
+        true => san::collect(&alt_names, Some(&cn)),
+        false => alt_names,
+    };
+    if args.common_name.is_some() && alt_names.is_empty() {
+        eprintln!("CommonName {cn} is not a DNS name, IP address or email address, so the certificate has no SANs, add them with --san");
+    }
+
+    // Each password is only read if its key is used, as reading from a
+    // descriptor or command can only be done once
+    let allow_password_argument = args.password.allow_password_argument;
+    let intermediate_password = match intermediate {
+        Some(_) => args.intermediate_password.read(allow_password_argument),
+        None => None,
+    };
+    let key_password = match args.common_name {
+        Some(_) => args.key_password.read(allow_password_argument),
+        None => None,
+    };
+
+    // Certificates are stored under a generated ID so names that are not
+    // safe paths or that repeat cannot collide on disk
+    let cert_id = match (&args.common_name, &args.id) {
+        (Some(_), Some(id)) => Some(index::resolve(&base_dir, id)),
+        (Some(cn), None) => Some(index::new_id(cn)),
+        _ => None,
+    };
+
+    // If both CN and Int are set, use the specified Int CA
+    // If only one is set, use the Root CA
+    let (ca_pkey_path, ca_password) = if args.common_name.is_some() && intermediate.is_some() {
+        (
+            path::intermediate_pkey(&base_dir, &intermediate.clone().unwrap(), key_type),
+            intermediate_password.clone(),
+        )
+    } else {
+        (path::ca_pkey(&base_dir, key_type), args.password.get())
+    };
+
+    let ca_pkey: Box<dyn Signer> = match Path::new(&ca_pkey_path).exists() {
+        true => signer::load(&ca_pkey_path, password::for_key(ca_password, &ca_pkey_path)),
+        false => {
+            let pkey = pkey::generate_pkey(key_type);
+            pkey::save_pkey(
+                &ca_pkey_path,
+                &pkey,
+                ca_password,
+                &args.encryption.to_encryption(&ca),
+            );
+            Box::new(pkey)
+        }
+    };
+
+    // If both CN and Int are set, use the specified Int CA
+    // If only one is set, use the Root CA
+    let ca_cert_path = if args.common_name.is_some() && intermediate.is_some() {
+        path::intermediate_crt(&base_dir, &intermediate.clone().unwrap(), key_type)
+    } else {
+        path::ca_crt(&base_dir, key_type)
+    };
+    let mut ca_cert = cert::read_cert(&ca_cert_path);
+
+    let ca_policy_path = if args.common_name.is_some() && intermediate.is_some() {
+        path::intermediate_policy(&base_dir, &intermediate.clone().unwrap(), key_type)
+    } else {
+        path::ca_policy(&base_dir, key_type)
+    };
+    let ca_policy = issuer_policy(&ca, &ca_policy_path);
+
+    let subject = match args.no_inherit {
+        true => args.subject.to_subject(&Some(cn.clone()), &Subject::new()),
+        false => args
+            .subject
+            .to_subject(&Some(cn.clone()), &ca_policy.defaults),
+    };
+    if let Err(e) = ca_policy.check(&subject, ca_cert.subject_name()) {
+        panic!("{e}");
+    }
+
+    let is_intermediate = intermediate.is_some() && args.common_name.is_none();
+
+    let lifetime = match args.lifetime.or(profile.lifetime) {
+        Some(d) => d,
+        None => {
+            if is_intermediate {
+                ca.intermediate_lifetime.unwrap_or(Duration::days(365 * 2))
+            } else {
+                ca.lifetime.unwrap_or(Duration::days(90))
+            }
+        }
+    };
+    let validity = Validity::between(
+        args.not_before,
+        args.not_after,
+        lifetime,
+        args.backdate.unwrap_or(ca.backdate()),
+    )
+    .unwrap_or_else(|e| panic!("{e}"));
+    // A certificate outliving its issuer stops validating when the issuer
+    // expires, so it is shortened unless the issuer is renewed first
+    let issuer_expiry = args.issuer_expiry.unwrap_or(ca.issuer_expiry());
+    if issuer_expiry == IssuerExpiry::Renew && validity.outlives(&ca_cert) {
+        let signing_intermediate = match args.common_name {
+            Some(_) => intermediate.as_deref(),
+            None => None,
+        };
+        ca_cert = match signing_intermediate {
+            None => renew_issuer(&ca, &base_dir, key_type, None, ca_pkey.as_ref()),
+            Some(name) => {
+                let root_pkey_path = path::ca_pkey(&base_dir, key_type);
+                let root_pkey = signer::load(
+                    &root_pkey_path,
+                    password::for_key(args.password.get(), &root_pkey_path),
+                );
+                renew_issuer(&ca, &base_dir, key_type, Some(name), root_pkey.as_ref())
+            }
+        };
+    }
+    if validity.outlives(&ca_cert) {
+        if let Err(e) = validity.within(&ca_cert) {
+            panic!("{e}, {ca_cert_path} expires {}", expires(&ca_cert));
+        }
+        match issuer_expiry {
+            IssuerExpiry::Fail => panic!(
+                "The certificate would outlive {ca_cert_path}, which expires {}",
+                expires(&ca_cert)
+            ),
+            _ => eprintln!(
+                "{ca_cert_path} expires {}, the certificate will end with it",
+                expires(&ca_cert)
+            ),
+        }
+    }
+
+    // If Int is set but CN is not set, generate a new Int PKey
+    // Else If CN is set, generate a new Cert PKey
+    let (pkey_path, pkey_password) = if intermediate.is_some() && args.common_name.is_none() {
+        (
+            path::intermediate_pkey(&base_dir, &intermediate.clone().unwrap(), key_type),
+            intermediate_password,
+        )
+    } else if args.common_name.is_some() {
+        (
+            path::cert_pkey(&base_dir, cert_id.as_ref().unwrap(), key_type),
+            key_password,
+        )
+    } else {
+        panic!("unexpected case");
+    };
+
+    // The new key, request, certificate and index entry are written together,
+    // so an issue that fails part way leaves the old ones in place. Only a
+    // crash in the middle of the renames can still leave a key without its
+    // certificate
+    let mut transaction = file::Transaction::new();
+    let mut new_pkey: Option<Vec<u8>> = None;
+    let pkey: Box<dyn Signer> = match (Path::new(&pkey_path).exists(), &args.key) {
+        (true, Some(uri)) if signer::key_uri(&pkey_path) != Some(signer::stored_uri(uri)) => {
+            panic!("{pkey_path} already exists and does not refer to {uri}")
+        }
+        (true, _) => signer::load(&pkey_path, password::for_key(pkey_password, &pkey_path)),
+        (false, Some(uri)) => {
+            let pkey =
+                signer::open_or_generate(uri, password::for_pin(pkey_password, uri), key_type);
+            println!("{}", pkey_path);
+            let encoded = signer::encode_key_uri(uri);
+            transaction.write(&pkey_path, &encoded);
+            new_pkey = Some(encoded);
+            pkey
+        }
+        (false, None) => {
+            let pkey = pkey::generate_pkey(key_type);
+            // Leaf keys are usually loaded by services without a password, so
+            // only a new intermediate's is asked for
+            let pkey_password = match is_intermediate {
+                true => password::for_new_key(pkey_password, "intermediate"),
+                false => pkey_password,
+            };
+            println!("{}", pkey_path);
+            let encoded =
+                pkey::encode_pkey(&pkey, pkey_password, &args.encryption.to_encryption(&ca));
+            transaction.write(&pkey_path, &encoded);
+            new_pkey = Some(encoded);
+            Box::new(pkey)
+        }
+    };
+
+    // If Int is set but CN is not set, generate a new Int CSR
+    // Else If CN is set, generate a new Cert CSR
+    let x509_req_path = if intermediate.is_some() && args.common_name.is_none() {
+        path::intermediate_csr(&base_dir, &intermediate.clone().unwrap(), key_type)
+    } else if args.common_name.is_some() {
+        path::cert_csr(&base_dir, cert_id.as_ref().unwrap(), key_type)
+    } else {
+        panic!("unexpected case");
+    };
+    for alt_name in &alt_names {
+        println!("{alt_name}");
+    }
+
+    let x509_req = {
+        let req = req::generate_req(&subject, args.subject.printable, &alt_names, pkey.as_ref());
+        println!("{}", x509_req_path);
+        transaction.write(&x509_req_path, &req.to_pem().unwrap());
+        req
+    };
+
+    // If Int is set but CN is not set, generate a new Int Cert
+    // Else If CN is set, generate a new Cert
+    let cert_path = if intermediate.is_some() && args.common_name.is_none() {
+        path::intermediate_crt(&base_dir, &intermediate.clone().unwrap(), key_type)
+    } else if args.common_name.is_some() {
+        path::cert_crt(&base_dir, cert_id.as_ref().unwrap(), key_type)
+    } else {
+        panic!("unexpected case");
+    };
+    let cert = cert::generate_cert(
+        &validity,
+        &x509_req,
+        is_intermediate,
+        &match is_intermediate {
+            true => ca.profile_extensions(&config::ProfileConfig::default()),
+            false => ca.profile_extensions(&profile),
+        },
+        &ca_cert,
+        ca_pkey.as_ref(),
+    );
+    // A certificate issued again under its ID, or an intermediate issued
+    // again, keeps its previous version in its history
+    if Path::new(&cert_path).is_file() {
+        let previous = cert::read_cert(&cert_path);
+        match cert_id {
+            Some(ref id) => {
+                history::stage_archive(&mut transaction, &base_dir, id, key_type, &previous)
+            }
+            None => history::stage_archive_intermediate(
+                &mut transaction,
+                &base_dir,
+                intermediate.as_ref().unwrap(),
+                key_type,
+                &previous,
+            ),
+        }
+    }
+    transaction.write(&cert_path, &cert.to_pem().unwrap());
+    if let Some(ref id) = cert_id {
+        match new_pkey {
+            Some(ref pkey) => history::stage_archive_with_key(
+                &mut transaction,
+                &base_dir,
+                id,
+                key_type,
+                &cert,
+                Some(pkey),
+            ),
+            None => history::stage_archive(&mut transaction, &base_dir, id, key_type, &cert),
+        }
+    }
+
+    // Clients are sent the issuers up to the root along with the certificate
+    let issuers = chain::issuers(
+        &base_dir,
+        key_type,
+        match is_intermediate {
+            true => None,
+            false => intermediate.as_deref(),
+        },
+    );
+    if !issuers.is_empty() {
+        let chain_path = match is_intermediate {
+            true => path::intermediate_chain(&base_dir, &intermediate.clone().unwrap(), key_type),
+            false => path::cert_chain(&base_dir, cert_id.as_ref().unwrap(), key_type),
+        };
+        println!("{}", chain_path);
+        transaction.write(&chain_path, &chain::encode(&cert, &issuers));
+    }
+    // The index is written along with the certificate, so it can always be
+    // found by its names
+    if let Some(ref id) = cert_id {
+        index::stage_record(
+            &mut transaction,
+            &path::cert_index(&base_dir),
+            id,
+            &cn,
+            &args.alias,
+            args.profile.as_deref().or(ca.profile.as_deref()),
+        );
+    }
+    transaction.commit();
+    if let Some(ref id) = cert_id {
+        println!("{id}");
+    }
+
+    config::run_hooks(
+        &ca.hooks.post_issue,
+        &[
+            ("HANCOCK_EVENT", "issue"),
+            ("HANCOCK_NAME", &cn),
+            ("HANCOCK_CERT", &cert_path),
+            ("HANCOCK_KEY", &pkey_path),
+            ("HANCOCK_CSR", &x509_req_path),
+        ],
+    );
+
+    // A new intermediate passes its own subject on as defaults, keeping the
+    // issuer's rules unless others were given
+    if is_intermediate {
+        let policy_path =
+            path::intermediate_policy(&base_dir, &intermediate.clone().unwrap(), key_type);
+        if !Path::new(&policy_path).exists() {
+            let rules = match args.policy.is_empty() {
+                true => ca_policy.rules.clone(),
+                false => args.policy.clone(),
+            };
+            policy::save_policy(&policy_path, &policy::Policy::capture(&subject, &rules));
+        }
+    }
+}
+
+pub fn list(args: List, config: &config::Config) {
+    let (ca, _lock) = args.store.lock(config);
+    let stores = path::stores(&args.store.base_dir(&ca), &args.store.name(&ca));
+
+    for (store, base_dir) in stores {
+        if let Some(store) = store {
+            println!("[{store}]");
+        }
+
+        let rsa_ca_crt_path = path::ca_crt(&base_dir, KeyType::Rsa(0));
+        if Path::new(&rsa_ca_crt_path).is_file() {
+            let crt = cert::read_cert(&rsa_ca_crt_path);
+            let crosses = cross_info(&base_dir, &crt);
+            let warnings = root_warnings(&base_dir, KeyType::Rsa(0), &crt);
+            println!("{}", cert_info(crt));
+            for line in crosses {
+                println!("  {line}");
+            }
+            for warning in warnings {
+                println!("  warning: {warning}");
+            }
+        }
+
+        let ecda_ca_crt_path = path::ca_crt(&base_dir, KeyType::Ecdsa);
+        if Path::new(&ecda_ca_crt_path).is_file() {
+            let crt = cert::read_cert(&ecda_ca_crt_path);
+            let crosses = cross_info(&base_dir, &crt);
+            let warnings = root_warnings(&base_dir, KeyType::Ecdsa, &crt);
+            println!("{}", cert_info(crt));
+            for line in crosses {
+                println!("  {line}");
+            }
+            for warning in warnings {
+                println!("  warning: {warning}");
+            }
+        }
+
+        for name in fs::read_dir(&base_dir).unwrap() {
+            let name = name.unwrap();
+            if name.file_type().unwrap().is_dir() {
+                let name = format!("{}", name.file_name().to_string_lossy());
+                for key_type in [KeyType::Rsa(0), KeyType::Ecdsa] {
+                    let crt_path = path::cert_crt(&base_dir, &name, key_type);
+                    if Path::new(&crt_path).is_file() {
+                        let crt = cert::read_cert(&crt_path);
+                        let warning = chain_warning(&base_dir, key_type, &crt);
+                        println!("{} [{name}]", cert_info(crt));
+                        if let Some(warning) = warning {
+                            println!("  warning: {warning}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn renew(args: Renew, config: &config::Config) {
+    let (ca, _lock) = args.store.lock(config);
+    let stores = path::stores(&args.store.base_dir(&ca), &args.store.name(&ca));
+    // Each password is read or asked for once, however many certificates
+    // its key renews
+    let root_password = OnceCell::new();
+    let intermediate_password = OnceCell::new();
+    let mut passwords: HashMap<String, Option<String>> = HashMap::new();
+    let backdate = args.backdate.unwrap_or(ca.backdate());
+
+    for (store, base_dir) in stores {
+        if let Some(store) = store {
+            println!("[{store}]");
+        }
+
+        let rsa_ca_crt_path = path::ca_crt(&base_dir, KeyType::Rsa(0));
+        if Path::new(&rsa_ca_crt_path).is_file() {
+            let crt = cert::read_cert(&rsa_ca_crt_path);
+            let warnings = root_warnings(&base_dir, KeyType::Rsa(0), &crt);
+            println!("{}", cert_info(crt));
+            for warning in warnings {
+                println!("  warning: {warning}");
+            }
+        }
+
+        let ecda_ca_crt_path = path::ca_crt(&base_dir, KeyType::Ecdsa);
+        if Path::new(&ecda_ca_crt_path).is_file() {
+            let crt = cert::read_cert(&ecda_ca_crt_path);
+            let warnings = root_warnings(&base_dir, KeyType::Ecdsa, &crt);
+            println!("{}", cert_info(crt));
+            for warning in warnings {
+                println!("  warning: {warning}");
+            }
+        }
+
+        let entries = index::read_index(&path::cert_index(&base_dir));
+
+        // A CommonName, alias or ID limits renewal to the certificates it names
+        let targets = args
+            .common_name
+            .as_ref()
+            .map(|name| index::lookup(&base_dir, name));
+
+        for name in fs::read_dir(&base_dir).unwrap() {
+            let name = name.unwrap();
+            if name.file_type().unwrap().is_dir() {
+                let name = format!("{}", name.file_name().to_string_lossy());
+                if let Some(ref targets) = targets {
+                    if !targets.contains(&name) {
+                        continue;
+                    }
+                }
+                for key_type in [KeyType::Rsa(0), KeyType::Ecdsa] {
+                    let crt_path = path::cert_crt(&base_dir, &name, key_type);
+                    if Path::new(&crt_path).is_file() {
+                        let crt = cert::read_cert(&crt_path);
+                        if let Some(warning) = chain_warning(&base_dir, key_type, &crt) {
+                            println!(
+                                "warning: {} [{name}]: {warning}",
+                                get_cn(&crt).unwrap_or_else(|| String::from("Unknown CN"))
+                            );
+                        }
+                        // Renewed once it is within 30 days of expiring.
+                        // Certificates issued for under a day would always
+                        // be within that, so they wait until a third of
+                        // their lifetime is left
+                        let duration = Validity::of(&crt).duration();
+                        let threshold = match duration < Duration::days(1) {
+                            true => duration.seconds() / 3,
+                            false => Duration::days(30).seconds(),
+                        };
+                        let remaining = Validity::of(&crt).not_after - validity::now();
+
+                        if remaining < threshold {
+                            // Renewed for exactly as long as it was issued for
+                            let validity = match Validity::renewing(&crt, backdate) {
+                                Ok(validity) => validity,
+                                Err(e) => {
+                                    println!("{e}, not renewing");
+                                    continue;
+                                }
+                            };
+                            // TODO: handle expirations in the past
+                            println!(
+                                "{} expires {}, renewing for {}",
+                                get_cn(&crt).unwrap_or_else(|| String::from("Unknown CN")),
+                                expires(&crt),
+                                validity.duration().describe()
+                            );
+
+                            // Certificates keep the profile they were issued
+                            // with, or the CA's if none was recorded
+                            let profile = match entries
+                                .iter()
+                                .find(|entry| entry.id == name)
+                                .and_then(|entry| entry.profile.clone())
+                                .or(ca.profile.clone())
+                            {
+                                Some(profile) if !config.profile.contains_key(&profile) => {
+                                    println!(
+                                        "Profile {profile} it was issued with is not configured, not renewing"
+                                    );
+                                    continue;
+                                }
+                                Some(profile) => config.profile(&profile),
+                                None => config::ProfileConfig::default(),
+                            };
+
+                            // Renew with the intermediate that issued the
+                            // certificate, if it was not the root
+                            let issuer = issuing_intermediate(&base_dir, &crt, key_type);
+                            let (ca_pkey_path, ca_cert_path) = match issuer {
+                                Some(ref intermediate) => (
+                                    path::intermediate_pkey(&base_dir, intermediate, key_type),
+                                    path::intermediate_crt(&base_dir, intermediate, key_type),
+                                ),
+                                None => (
+                                    path::ca_pkey(&base_dir, key_type),
+                                    path::ca_crt(&base_dir, key_type),
+                                ),
+                            };
+
+                            let ca_pkey = match Path::new(&ca_pkey_path).exists() {
+                                true => {
+                                    let ca_password = passwords
+                                        .entry(ca_pkey_path.clone())
+                                        .or_insert_with(|| {
+                                            let given = match issuer {
+                                                Some(_) => intermediate_password
+                                                    .get_or_init(|| {
+                                                        args.intermediate_password.read(
+                                                            args.password.allow_password_argument,
+                                                        )
+                                                    })
+                                                    .clone(),
+                                                None => root_password
+                                                    .get_or_init(|| args.password.get())
+                                                    .clone(),
+                                            };
+                                            password::for_key(given, &ca_pkey_path)
+                                        })
+                                        .clone();
+                                    signer::load(&ca_pkey_path, ca_password)
+                                }
+                                false => {
+                                    panic!("No private key for type {} found", key_type);
+                                }
+                            };
+
+                            let mut ca_cert = cert::read_cert(&ca_cert_path);
+                            let issuer_expiry = args.issuer_expiry.unwrap_or(ca.issuer_expiry());
+                            if issuer_expiry == IssuerExpiry::Renew && validity.outlives(&ca_cert) {
+                                let root_pkey = match issuer {
+                                    Some(_) => {
+                                        let root_pkey_path = path::ca_pkey(&base_dir, key_type);
+                                        let root_password = passwords
+                                            .entry(root_pkey_path.clone())
+                                            .or_insert_with(|| {
+                                                password::for_key(
+                                                    root_password
+                                                        .get_or_init(|| args.password.get())
+                                                        .clone(),
+                                                    &root_pkey_path,
+                                                )
+                                            })
+                                            .clone();
+                                        Some(signer::load(&root_pkey_path, root_password))
+                                    }
+                                    None => None,
+                                };
+                                ca_cert = renew_issuer(
+                                    &ca,
+                                    &base_dir,
+                                    key_type,
+                                    issuer.as_deref(),
+                                    root_pkey.as_deref().unwrap_or(ca_pkey.as_ref()),
+                                );
+                            }
+                            if validity.outlives(&ca_cert) {
+                                if issuer_expiry == IssuerExpiry::Fail {
+                                    println!(
+                                        "{ca_cert_path} expires {}, not renewing",
+                                        expires(&ca_cert)
+                                    );
+                                    continue;
+                                }
+                                if let Err(e) = validity.within(&ca_cert) {
+                                    println!(
+                                        "{e}, {ca_cert_path} expires {}, not renewing",
+                                        expires(&ca_cert)
+                                    );
+                                    continue;
+                                }
+                                println!(
+                                    "{ca_cert_path} expires {}, renewing until then",
+                                    expires(&ca_cert)
+                                );
+                            }
+
+                            let x509_req =
+                                req::read_req(&path::cert_csr(&base_dir, &name, key_type));
+                            let cert = cert::generate_cert(
+                                &validity,
+                                &x509_req,
+                                false,
+                                &ca.profile_extensions(&profile),
+                                &ca_cert,
+                                ca_pkey.as_ref(),
+                            );
+                            // The old version's archive, the new certificate,
+                            // its own archive and its chain land together
+                            let cert_path = path::cert_crt(&base_dir, &name, key_type);
+                            let mut transaction = file::Transaction::new();
+                            history::stage_archive(
+                                &mut transaction,
+                                &base_dir,
+                                &name,
+                                key_type,
+                                &crt,
+                            );
+                            transaction.write(&cert_path, &cert.to_pem().unwrap());
+                            history::stage_archive(
+                                &mut transaction,
+                                &base_dir,
+                                &name,
+                                key_type,
+                                &cert,
+                            );
+                            let issuers = chain::issuers(&base_dir, key_type, issuer.as_deref());
+                            if !issuers.is_empty() {
+                                transaction.write(
+                                    &path::cert_chain(&base_dir, &name, key_type),
+                                    &chain::encode(&cert, &issuers),
+                                );
+                            }
+                            transaction.commit();
+
+                            config::run_hooks(
+                                &ca.hooks.post_renew,
+                                &[
+                                    ("HANCOCK_EVENT", "renew"),
+                                    ("HANCOCK_NAME", &get_cn(&crt).unwrap()),
+                                    ("HANCOCK_CERT", &cert_path),
+                                    ("HANCOCK_KEY", &path::cert_pkey(&base_dir, &name, key_type)),
+                                ],
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn history(args: History, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+    let id = index::resolve(&base_dir, &args.name);
+
+    for key_type in [KeyType::Rsa(0), KeyType::Ecdsa] {
+        let current = match Path::new(&path::cert_crt(&base_dir, &id, key_type)).is_file() {
+            true => Some(history::serial(&cert::read_cert(&path::cert_crt(
+                &base_dir, &id, key_type,
+            )))),
+            false => None,
+        };
+        for (serial, crt) in history::versions(&base_dir, &id, key_type) {
+            let marker = match current.as_ref() == Some(&serial) {
+                true => " (current)",
+                false => "",
+            };
+            println!(
+                "{serial} {key_type} {} - {}{marker}",
+                crt.not_before(),
+                crt.not_after()
+            );
+        }
+    }
+}
+
+pub fn rollback(args: Rollback, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+    let id = index::resolve(&base_dir, &args.name);
+    let serial = args.to.clone();
+
+    let key_type = [KeyType::Rsa(0), KeyType::Ecdsa]
+        .into_iter()
+        .find(|key_type| Path::new(&path::history_crt(&base_dir, &id, &serial, *key_type)).exists())
+        .unwrap_or_else(|| panic!("No version {serial} of {id} in its history"));
+    history::rollback(&base_dir, &id, key_type, &serial);
+    println!("{id} restored to {serial}");
+}
+
+pub fn revoke(args: Revoke, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+    let name = match args.intermediate {
+        Some(ref intermediate) => intermediate.clone(),
+        None => index::resolve(&base_dir, args.name.as_ref().unwrap()),
+    };
+    let key_types = match args.key_type {
+        Some(ref key_type) => vec![self::key_type(Some(key_type), 0)],
+        None => vec![KeyType::Rsa(0), KeyType::Ecdsa],
+    };
+
+    let mut found = false;
+    for key_type in key_types {
+        let crt_path = match args.intermediate {
+            Some(_) => path::intermediate_crt(&base_dir, &name, key_type),
+            None => path::cert_crt(&base_dir, &name, key_type),
+        };
+        if !Path::new(&crt_path).is_file() {
+            continue;
+        }
+        let current = cert::read_cert(&crt_path);
+        let crt = match args.serial {
+            Some(ref serial) if *serial != history::serial(&current) => {
+                let archived_path = match args.intermediate {
+                    Some(_) => path::intermediate_history_crt(&base_dir, &name, serial, key_type),
+                    None => path::history_crt(&base_dir, &name, serial, key_type),
+                };
+                if !Path::new(&archived_path).is_file() {
+                    continue;
+                }
+                cert::read_cert(&archived_path)
+            }
+            _ => current,
+        };
+        found = true;
+
+        // Listed by whichever CA issued it: the root for intermediates, and
+        // the root or an intermediate for certificates
+        let issuer = match args.intermediate {
+            Some(_) => None,
+            None => issuing_intermediate(&base_dir, &crt, key_type),
+        };
+        let (issuer_path, revoked_path, crl_command) = match issuer {
+            Some(ref intermediate) => (
+                path::intermediate_crt(&base_dir, intermediate, key_type),
+                path::intermediate_revoked(&base_dir, intermediate, key_type),
+                format!("crl -i {intermediate} -t {key_type}"),
+            ),
+            None => (
+                path::ca_crt(&base_dir, key_type),
+                path::ca_revoked(&base_dir, key_type),
+                format!(
+                    "crl -t {key_type}, or request crl -t {key_type} if the root is kept offline"
+                ),
+            ),
+        };
+        let serial = history::serial(&crt);
+        if cert::read_cert(&issuer_path).issued(&crt) != openssl::x509::X509VerifyResult::OK {
+            panic!("{serial} was not issued by {issuer_path}, so its CRL cannot list it");
+        }
+
+        let mut revocations = crl::read_revocations(&revoked_path);
+        match revocations.revoke(&crt, &name, args.reason.unwrap_or_default()) {
+            false => println!("{serial} ({name}) is already revoked"),
+            true => {
+                println!("{}", revoked_path);
+                crl::save_revocations(&revoked_path, &revocations);
+                println!("Revoked {serial} ({name})");
+                eprintln!("Publish it in a new CRL with {crl_command}");
+            }
+        }
+    }
+    if !found {
+        match args.serial {
+            Some(ref serial) => panic!("No version {serial} of {name} to revoke"),
+            None => panic!("No certificate for {name} to revoke"),
+        }
+    }
+}
+
+pub fn crl(args: Crl, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+    let key_type = key_type(args.key_type.as_ref().or(ca.key_type.as_ref()), 0);
+
+    let (pkey_path, crt_path, revoked_path, crl_path) = match args.intermediate {
+        Some(ref name) => (
+            path::intermediate_pkey(&base_dir, name, key_type),
+            path::intermediate_crt(&base_dir, name, key_type),
+            path::intermediate_revoked(&base_dir, name, key_type),
+            path::intermediate_crl(&base_dir, name, key_type),
+        ),
+        None => (
+            path::ca_pkey(&base_dir, key_type),
+            path::ca_crt(&base_dir, key_type),
+            path::ca_revoked(&base_dir, key_type),
+            path::ca_crl(&base_dir, key_type),
+        ),
+    };
+    let ca_cert = cert::read_cert(&crt_path);
+    if !Path::new(&pkey_path).exists() {
+        panic!(
+            "{pkey_path} does not exist, for a root kept offline use request crl and sign-bundle"
+        );
+    }
+    let given = match args.intermediate {
+        Some(_) => args
+            .intermediate_password
+            .read(args.password.allow_password_argument),
+        None => args.password.get(),
+    };
+    let signer = signer::load(&pkey_path, password::for_key(given, &pkey_path));
+
+    let mut revocations = crl::read_revocations(&revoked_path);
+    revocations.crl_number += 1;
+    let validity = Validity::from_now(
+        args.lifetime
+            .or(ca.crl_lifetime)
+            .unwrap_or(Duration::days(7)),
+        ca.backdate(),
+    )
+    .unwrap_or_else(|e| panic!("{e}"));
+    let crl = crl::generate_crl(
+        &ca_cert,
+        &revocations.revoked,
+        revocations.crl_number,
+        &validity,
+        signer.as_ref(),
+    );
+
+    // The number is only used up once the CRL is written
+    let mut transaction = file::Transaction::new();
+    println!("{}", crl_path);
+    transaction.write(&crl_path, &crl.to_pem().unwrap());
+    transaction.write(&revoked_path, &crl::encode_revocations(&revocations));
+    transaction.commit();
+    println!(
+        "CRL {} lists {} revoked, the next is due in {}",
+        revocations.crl_number,
+        revocations.revoked.len(),
+        validity::describe(validity.not_after - validity::now(), 2)
+    );
+}
+
+pub fn ocsp(args: Ocsp, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+    let key_type = key_type(args.key_type.as_ref().or(ca.key_type.as_ref()), 0);
+
+    let (pkey_path, crt_path, revoked_path) = match args.intermediate {
+        Some(ref name) => (
+            path::intermediate_pkey(&base_dir, name, key_type),
+            path::intermediate_crt(&base_dir, name, key_type),
+            path::intermediate_revoked(&base_dir, name, key_type),
+        ),
+        None => (
+            path::ca_pkey(&base_dir, key_type),
+            path::ca_crt(&base_dir, key_type),
+            path::ca_revoked(&base_dir, key_type),
+        ),
+    };
+    let ca_cert = cert::read_cert(&crt_path);
+    let request =
+        fs::read(&args.request).unwrap_or_else(|e| panic!("Unable to read {}: {e}", args.request));
+    let request = ocsp::parse_request(&request).unwrap_or_else(|e| panic!("{}: {e}", args.request));
+    if !Path::new(&pkey_path).exists() {
+        panic!("{pkey_path} does not exist, an offline root cannot answer OCSP requests");
+    }
+    let given = match args.intermediate {
+        Some(_) => args
+            .intermediate_password
+            .read(args.password.allow_password_argument),
+        None => args.password.get(),
+    };
+    let signer = signer::load(&pkey_path, password::for_key(given, &pkey_path));
+
+    // Only certificates this CA issued are good, anything else is unknown
+    let revocations = crl::read_revocations(&revoked_path);
+    let issued = issued_serials(&base_dir, &ca_cert);
+    let statuses: Vec<ocsp::Status> = request
+        .cert_ids
+        .iter()
+        .map(|cert_id| {
+            if !cert_id.is_for(&ca_cert) {
+                eprintln!(
+                    "{} is asked about for another issuer than {crt_path}",
+                    cert_id.serial
+                );
+                return ocsp::Status::Unknown;
+            }
+            match revocations
+                .revoked
+                .iter()
+                .find(|revoked| revoked.serial == cert_id.serial)
+            {
+                Some(revoked) => ocsp::Status::Revoked(revoked),
+                None if issued.contains(&cert_id.serial) => ocsp::Status::Good,
+                None => ocsp::Status::Unknown,
+            }
+        })
+        .collect();
+    let validity = Validity::from_now(
+        args.lifetime
+            .or(ca.crl_lifetime)
+            .unwrap_or(Duration::days(7)),
+        ca.backdate(),
+    )
+    .unwrap_or_else(|e| panic!("{e}"));
+    let response =
+        ocsp::generate_response(&ca_cert, &request, &statuses, &validity, signer.as_ref());
+
+    println!("{}", args.response);
+    file::write_private(&args.response, &response);
+    for (cert_id, status) in request.cert_ids.iter().zip(&statuses) {
+        println!("{} {}", cert_id.serial, status.as_str());
+    }
+}
+
+pub fn migrate(args: Migrate, config: &config::Config) {
+    let (ca, _lock) = args.store.lock(config);
+    let base_dir = args.store.base_dir(&ca);
+    let store = args
+        .store
+        .store
+        .clone()
+        .unwrap_or_else(|| path::DEFAULT_STORE.to_string());
+    if !path::is_legacy(&base_dir) {
+        println!("{base_dir} has no CA outside of a store, nothing to migrate");
+        return;
+    }
+    let store_dir = path::store_dir(&base_dir, &Some(store.clone()));
+    if Path::new(&store_dir).exists() {
+        panic!("Store {store} already exists at {store_dir}");
+    }
+    // An agent holds keys by path, which moving them would break, so only a
+    // socket left behind by one that stopped is cleaned up
+    let socket = path::agent_socket(&base_dir);
+    if fs::symlink_metadata(&socket).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(&socket).is_ok() {
+            panic!("An agent is listening on {socket}, stop it before migrating");
+        }
+        fs::remove_file(&socket).unwrap_or_else(|e| panic!("Unable to remove {socket}: {e}"));
+    }
+    fs::create_dir_all(&store_dir).unwrap();
+    path::ensure_dir(&store_dir);
+
+    for entry in fs::read_dir(&base_dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_dir = entry.file_type().unwrap().is_dir();
+        let belongs_to_ca = match is_dir {
+            true => {
+                ["intermediates", "roots", "cross"].contains(&name.as_str())
+                    || [KeyType::Rsa(0), KeyType::Ecdsa].iter().any(|key_type| {
+                        Path::new(&path::cert_crt(&base_dir, &name, *key_type)).exists()
+                            || Path::new(&path::cert_pkey(&base_dir, &name, *key_type)).exists()
+                    })
+            }
+            false => name.starts_with("authority.") || name == "certs.toml",
+        };
+        if belongs_to_ca {
+            println!("{base_dir}/{name} -> {store_dir}/{name}");
+            fs::rename(entry.path(), format!("{store_dir}/{name}")).unwrap();
+        }
+    }
+}
+
+pub fn key(args: Key, config: &config::Config) {
+    match args.command {
+        KeyCommands::Passwd(args) => key_passwd(args, config),
+    }
+}
+
+pub fn agent(args: Agent, config: &config::Config) {
+    match args.command {
+        AgentCommands::Start(args) => agent_start(args, config),
+        AgentCommands::Unlock(args) => agent_unlock(args, config),
+        AgentCommands::Lock(args) => {
+            agent::lock(&args.socket).unwrap_or_else(|e| panic!("{e}"));
+        }
+        AgentCommands::List(args) => {
+            print!(
+                "{}",
+                agent::list(&args.socket).unwrap_or_else(|e| panic!("{e}"))
+            );
+        }
+    }
+}
+
+fn agent_start(args: AgentStart, config: &config::Config) {
+    let ca = config.ca(&args.ca);
+    let base_dir = path::store_dir(
+        &ca.base_dir(&args.base_dir),
+        &args.store.clone().or(ca.store.clone()),
+    );
+    let socket = args
+        .socket
+        .map(|socket| shellexpand::tilde(&socket).to_string())
+        .unwrap_or_else(|| path::agent_socket(&base_dir));
+    if let Some(uid) = args
+        .allow_revocation
+        .iter()
+        .find(|uid| !args.allow.iter().any(|allow| allow.uid == **uid))
+    {
+        panic!("uid {uid} is given --allow-revocation but not --allow");
+    }
+    agent::serve(
+        &socket,
+        &base_dir,
+        args.allow,
+        agent::Policy {
+            max_lifetime: args.max_lifetime,
+            revokers: args.allow_revocation,
+        },
+        std::time::Duration::from_secs(args.unlock_hours * 3600),
+    );
+}
+
+fn agent_unlock(args: AgentUnlock, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+    let (pkey_path, key_type) = existing_pkey(&base_dir, &args.intermediate, &None, &args.key_type);
+    let password = match pkey::is_encrypted(&pkey_path) {
+        true => args
+            .password
+            .get()
+            .or_else(|| password::prompt(&format!("Password for {pkey_path}"), false))
+            .unwrap_or_else(|| panic!("{pkey_path} is encrypted and no password was given")),
+        false => String::new(),
+    };
+    agent::unlock(&args.socket, key_type, &args.intermediate, &password)
+        .unwrap_or_else(|e| panic!("{e}"));
+    println!("{pkey_path}");
+}
+
+pub fn backup(args: Backup, config: &config::Config) {
+    match args.command {
+        BackupCommands::Split(args) => backup_split(args, config),
+        BackupCommands::Combine(args) => backup_combine(args, config),
+    }
+}
+
+fn backup_split(args: BackupSplit, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+    let (pkey_path, _) = existing_pkey(&base_dir, &None, &None, &args.key_type);
+    if let Some(uri) = signer::key_uri(&pkey_path) {
+        panic!("{pkey_path} refers to {uri}, back the key up with its own tools");
+    }
+
+    let secret = fs::read(&pkey_path).unwrap();
+    let shares =
+        shamir::split(&secret, args.shares, args.threshold).unwrap_or_else(|e| panic!("{e}"));
+    let label = Path::new(&pkey_path)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    for share in shares {
+        let text = share.to_text(&label);
+        match args.out_dir {
+            Some(ref out_dir) => {
+                let share_path = format!(
+                    "{}/{label}.share-{}-of-{}.txt",
+                    shellexpand::tilde(out_dir),
+                    share.x,
+                    share.shares
+                );
+                path::ensure_dir(&share_path);
+                file::write_private(&share_path, text.as_bytes());
+                println!("{share_path}");
+            }
+            None => println!("{text}"),
+        }
+    }
+}
+
+fn backup_combine(args: BackupCombine, config: &config::Config) {
+    let (_, base_dir, _lock) = args.store.open(config);
+
+    let text = match args.files.is_empty() {
+        true => {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut text).unwrap();
+            text
+        }
+        false => args
+            .files
+            .iter()
+            .map(|file| {
+                fs::read_to_string(shellexpand::tilde(file).to_string())
+                    .unwrap_or_else(|e| panic!("Unable to read {file}: {e}"))
+            })
+            .collect(),
+    };
+    let shares = shamir::parse(&text).unwrap_or_else(|e| panic!("{e}"));
+    let secret = shamir::combine(&shares).unwrap_or_else(|e| panic!("{e}"));
+
+    // The key is only put back once it is known to be the root's
+    let password = match String::from_utf8_lossy(&secret).contains("ENCRYPTED") {
+        true => args
+            .password
+            .get()
+            .or_else(|| password::prompt("Password for the restored key", false)),
+        false => None,
+    };
+    let pkey = pkey::decode_pkey(&secret, &password)
+        .unwrap_or_else(|e| panic!("Unable to read the restored key: {e}"));
+    let key_type = match pkey.id() {
+        openssl::pkey::Id::EC => KeyType::Ecdsa,
+        _ => KeyType::Rsa(0),
+    };
+    let cert_path = path::ca_crt(&base_dir, key_type);
+    let cert = cert::read_cert(&cert_path);
+    if !cert.public_key().unwrap().public_eq(&pkey) {
+        panic!("The restored key does not match {cert_path}");
+    }
+    println!("The restored key matches {cert_path}");
+
+    let pkey_path = match args.out {
+        Some(ref out) => shellexpand::tilde(out).to_string(),
+        None => path::ca_pkey(&base_dir, key_type),
+    };
+    match fs::read(&pkey_path) {
+        Ok(existing) if existing == secret => println!("{pkey_path} is already in place"),
+        Ok(_) => panic!("{pkey_path} already exists, restore elsewhere with --out"),
+        Err(_) => {
+            file::write_private(&pkey_path, &secret);
+            println!("{pkey_path}");
+        }
+    }
+}
+
+pub fn request(args: Request, config: &config::Config) {
+    match args.command {
+        RequestCommands::Intermediate(args) => request_intermediate(*args, config),
+        RequestCommands::Crl(args) => request_crl(args, config),
+    }
+}
+
+fn request_intermediate(args: RequestIntermediate, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+    let key_type = key_type(
+        args.key_type.as_ref().or(ca.key_type.as_ref()),
+        args.key_length.or(ca.key_length).unwrap_or(2048),
+    );
+    let name = &args.intermediate;
+
+    let cert_path = path::intermediate_crt(&base_dir, name, key_type);
+    if Path::new(&cert_path).exists() {
+        panic!("{cert_path} already exists");
+    }
+
+    // Only the root's certificate is needed here, its key stays offline
+    let ca_cert = cert::read_cert(&path::ca_crt(&base_dir, key_type));
+    let ca_policy = issuer_policy(&ca, &path::ca_policy(&base_dir, key_type));
+    let subject = match args.no_inherit {
+        true => args
+            .subject
+            .to_subject(&Some(name.clone()), &Subject::new()),
+        false => args
+            .subject
+            .to_subject(&Some(name.clone()), &ca_policy.defaults),
+    };
+    if let Err(e) = ca_policy.check(&subject, ca_cert.subject_name()) {
+        panic!("{e}");
+    }
+
+    let mut transaction = file::Transaction::new();
+    let pkey_path = path::intermediate_pkey(&base_dir, name, key_type);
+    let pkey: Box<dyn Signer> = match (Path::new(&pkey_path).exists(), &args.key) {
+        (true, Some(uri)) if signer::key_uri(&pkey_path) != Some(signer::stored_uri(uri)) => {
+            panic!("{pkey_path} already exists and does not refer to {uri}")
+        }
+        (true, _) => signer::load(
+            &pkey_path,
+            password::for_key(args.password.get(), &pkey_path),
+        ),
+        (false, Some(uri)) => {
+            let pkey = signer::open_or_generate(
+                uri,
+                password::for_pin(args.password.get(), uri),
+                key_type,
+            );
+            println!("{}", pkey_path);
+            transaction.write(&pkey_path, &signer::encode_key_uri(uri));
+            pkey
+        }
+        (false, None) => {
+            let pkey = pkey::generate_pkey(key_type);
+            println!("{}", pkey_path);
+            transaction.write(
+                &pkey_path,
+                &pkey::encode_pkey(
+                    &pkey,
+                    password::for_new_key(args.password.get(), "intermediate"),
+                    &args.encryption.to_encryption(&ca),
+                ),
+            );
+            Box::new(pkey)
+        }
+    };
+
+    let req = req::generate_req(&subject, args.subject.printable, &[], pkey.as_ref());
+    let csr_path = path::intermediate_csr(&base_dir, name, key_type);
+    println!("{}", csr_path);
+    transaction.write(&csr_path, &req.to_pem().unwrap());
+
+    let bundle_path = match args.out {
+        Some(ref out) => shellexpand::tilde(out).to_string(),
+        None => path::intermediate_request(&base_dir, name, key_type),
+    };
+    let request = bundle::Request {
+        kind: bundle::REQUEST.to_string(),
+        name: name.clone(),
+        key_type: key_type.to_string(),
+        lifetime: args
+            .lifetime
+            .or(ca.intermediate_lifetime)
+            .unwrap_or(Duration::days(365 * 2)),
+        profile: args.profile.clone(),
+        csr: String::from_utf8(req.to_pem().unwrap()).unwrap(),
+    };
+    println!("{}", bundle_path);
+    transaction.write(&bundle_path, toml::to_string(&request).unwrap().as_bytes());
+    transaction.commit();
+
+    let policy_path = path::intermediate_policy(&base_dir, name, key_type);
+    if !Path::new(&policy_path).exists() {
+        let rules = match args.policy.is_empty() {
+            true => ca_policy.rules.clone(),
+            false => args.policy.clone(),
+        };
+        policy::save_policy(&policy_path, &policy::Policy::capture(&subject, &rules));
+    }
+    eprintln!("Sign {bundle_path} on the offline root with sign-bundle, then install the result with import-signed");
+}
+
+/// Bundle the root's revocations for the offline root to sign as its next CRL
+fn request_crl(args: RequestCrl, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+    let key_type = key_type(args.key_type.as_ref().or(ca.key_type.as_ref()), 0);
+
+    let ca_cert_path = path::ca_crt(&base_dir, key_type);
+    if !Path::new(&ca_cert_path).is_file() {
+        panic!("{ca_cert_path} does not exist");
+    }
+    let bundle_path = match args.out {
+        Some(ref out) => shellexpand::tilde(out).to_string(),
+        None => path::crl_request(&base_dir, key_type),
+    };
+    save_crl_request(
+        &base_dir,
+        key_type,
+        args.lifetime
+            .or(ca.crl_lifetime)
+            .unwrap_or(Duration::days(7)),
+        &bundle_path,
+    );
+    eprintln!("Sign {bundle_path} on the offline root with sign-bundle, then install the result with import-signed");
+}
+
+/// Ask for the root's next CRL, listing everything revoked so far
+fn save_crl_request(base_dir: &str, key_type: KeyType, lifetime: Duration, bundle_path: &str) {
+    let revocations = crl::read_revocations(&path::ca_revoked(base_dir, key_type));
+    bundle::save(
+        bundle_path,
+        &bundle::CrlRequest {
+            kind: bundle::CRL_REQUEST.to_string(),
+            key_type: key_type.to_string(),
+            crl_number: revocations.crl_number + 1,
+            lifetime,
+            revoked: revocations.revoked,
+        },
+    );
+}
+
+pub fn sign_bundle(args: SignBundle, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+
+    match bundle::kind(&args.bundle).as_str() {
+        bundle::REQUEST => sign_intermediate_request(&args, config, &ca, &base_dir),
+        bundle::CRL_REQUEST => sign_crl_request(&args, &ca, &base_dir),
+        kind => panic!(
+            "{} is a {kind} bundle, which sign-bundle does not sign",
+            args.bundle
+        ),
+    }
+}
+
+fn sign_intermediate_request(
+    args: &SignBundle,
+    config: &config::Config,
+    ca: &config::CaConfig,
+    base_dir: &str,
+) {
+    let request = bundle::read_request(&args.bundle);
+    let key_type = key_type(Some(&request.key_type), 0);
+    let req = openssl::x509::X509Req::from_pem(request.csr.as_bytes())
+        .unwrap_or_else(|e| panic!("{} holds an invalid request: {e}", args.bundle));
+    if !req.verify(&req.public_key().unwrap()).unwrap_or(false) {
+        panic!(
+            "The signature on the request in {} does not verify",
+            args.bundle
+        );
+    }
+
+    let ca_cert_path = path::ca_crt(base_dir, key_type);
+    let ca_cert = cert::read_cert(&ca_cert_path);
+    let subject = Subject::from_name(req.subject_name());
+    let ca_policy = issuer_policy(ca, &path::ca_policy(base_dir, key_type));
+    if let Err(e) = ca_policy.check(&subject, ca_cert.subject_name()) {
+        panic!("{e}");
+    }
+
+    println!("Intermediate: {}", request.name);
+    println!("Subject: {subject}");
+    println!("Key: {key_type}");
+    if request.lifetime <= Duration::ZERO {
+        panic!(
+            "{} asks for a lifetime of {}",
+            args.bundle, request.lifetime
+        );
+    }
+    println!("Lifetime: {}", request.lifetime.describe());
+    // The profile must be configured on the root, which is checked before
+    // anything is signed
+    let profile = match request.profile {
+        Some(ref name) => config.profile(name),
+        None => config::ProfileConfig::default(),
+    };
+    println!("Profile: {}", request.profile.as_deref().unwrap_or("none"));
+    println!("Issuer: {ca_cert_path}");
+    if !args.yes && !password::confirm("Sign it with the root key?") {
+        panic!("Not signing {}, confirm with --yes", args.bundle);
+    }
+
+    let ca_pkey_path = path::ca_pkey(base_dir, key_type);
+    let ca_pkey = signer::load(
+        &ca_pkey_path,
+        password::for_key(args.password.get(), &ca_pkey_path),
+    );
+    let cert = cert::generate_cert(
+        &Validity::from_now(request.lifetime, ca.backdate()).unwrap_or_else(|e| panic!("{e}")),
+        &req,
+        true,
+        &ca.profile_extensions(&profile),
+        &ca_cert,
+        ca_pkey.as_ref(),
+    );
+
+    bundle::save(
+        &signed_bundle_path(args),
+        &bundle::Signed {
+            kind: bundle::SIGNED.to_string(),
+            name: request.name,
+            key_type: request.key_type,
+            certificate: String::from_utf8(cert.to_pem().unwrap()).unwrap(),
+            issuer: String::from_utf8(ca_cert.to_pem().unwrap()).unwrap(),
+        },
+    );
+}
+
+fn sign_crl_request(args: &SignBundle, ca: &config::CaConfig, base_dir: &str) {
+    let request = bundle::read_crl_request(&args.bundle);
+    let key_type = key_type(Some(&request.key_type), 0);
+    for revoked in &request.revoked {
+        history::parse_serial(&revoked.serial).unwrap_or_else(|e| panic!("{}: {e}", args.bundle));
+    }
+    if request.lifetime <= Duration::ZERO {
+        panic!(
+            "{} asks for a lifetime of {}",
+            args.bundle, request.lifetime
+        );
+    }
+
+    // CRL numbers only ever go up, and the root remembers the last it signed
+    let revoked_path = path::ca_revoked(base_dir, key_type);
+    let revocations = crl::read_revocations(&revoked_path);
+    if request.crl_number <= revocations.crl_number {
+        panic!(
+            "{} asks for CRL number {}, but number {} was already signed",
+            args.bundle, request.crl_number, revocations.crl_number
+        );
+    }
+
+    let ca_cert_path = path::ca_crt(base_dir, key_type);
+    let ca_cert = cert::read_cert(&ca_cert_path);
+    println!("CRL number: {}", request.crl_number);
+    println!("Revoked: {}", request.revoked.len());
+    for revoked in &request.revoked {
+        println!(
+            "  {} {} {} {}",
+            revoked.serial,
+            revoked.name.as_deref().unwrap_or("-"),
+            revoked.revoked,
+            revoked.reason.as_str()
+        );
+    }
+    println!("Next update: in {}", request.lifetime.describe());
+    println!("Issuer: {ca_cert_path}");
+    if !args.yes && !password::confirm("Sign it with the root key?") {
+        panic!("Not signing {}, confirm with --yes", args.bundle);
+    }
+
+    let ca_pkey_path = path::ca_pkey(base_dir, key_type);
+    let ca_pkey = signer::load(
+        &ca_pkey_path,
+        password::for_key(args.password.get(), &ca_pkey_path),
+    );
+    let crl = crl::generate_crl(
+        &ca_cert,
+        &request.revoked,
+        request.crl_number,
+        &Validity::from_now(request.lifetime, ca.backdate()).unwrap_or_else(|e| panic!("{e}")),
+        ca_pkey.as_ref(),
+    );
+
+    // The root keeps what it signed, so the next request is checked against it
+    let mut transaction = file::Transaction::new();
+    let crl_path = path::ca_crl(base_dir, key_type);
+    println!("{}", crl_path);
+    transaction.write(&crl_path, &crl.to_pem().unwrap());
+    transaction.write(
+        &revoked_path,
+        &crl::encode_revocations(&crl::Revocations {
+            crl_number: request.crl_number,
+            revoked: request.revoked,
+        }),
+    );
+    transaction.commit();
+
+    bundle::save(
+        &signed_bundle_path(args),
+        &bundle::CrlSigned {
+            kind: bundle::CRL_SIGNED.to_string(),
+            key_type: request.key_type,
+            crl: String::from_utf8(crl.to_pem().unwrap()).unwrap(),
+            issuer: String::from_utf8(ca_cert.to_pem().unwrap()).unwrap(),
+        },
+    );
+}
+
+/// Where to write what sign-bundle signed: the request's name ending in
+/// .signed.toml unless --out is given
+fn signed_bundle_path(args: &SignBundle) -> String {
+    match args.out {
+        Some(ref out) => shellexpand::tilde(out).to_string(),
+        None => match args.bundle.strip_suffix(".request.toml") {
+            Some(stem) => format!("{stem}.signed.toml"),
+            None => format!("{}.signed.toml", args.bundle),
+        },
+    }
+}
+
+pub fn import_signed(args: ImportSigned, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+
+    match bundle::kind(&args.bundle).as_str() {
+        bundle::SIGNED => import_intermediate(&args, &ca, &base_dir),
+        bundle::CRL_SIGNED => import_crl(&args, &base_dir),
+        kind => panic!(
+            "{} is a {kind} bundle, which import-signed does not install",
+            args.bundle
+        ),
+    }
+}
+
+fn import_intermediate(args: &ImportSigned, ca: &config::CaConfig, base_dir: &str) {
+    let signed = bundle::read_signed(&args.bundle);
+    path::validate_name(&signed.name).unwrap_or_else(|e| panic!("{e}"));
+    let key_type = key_type(Some(&signed.key_type), 0);
+    let cert = openssl::x509::X509::from_pem(signed.certificate.as_bytes())
+        .unwrap_or_else(|e| panic!("{} holds an invalid certificate: {e}", args.bundle));
+    let issuer = openssl::x509::X509::from_pem(signed.issuer.as_bytes())
+        .unwrap_or_else(|e| panic!("{} holds an invalid issuer: {e}", args.bundle));
+
+    let ca_cert_path = path::ca_crt(base_dir, key_type);
+    let ca_cert = cert::read_cert(&ca_cert_path);
+    if issuer.to_der().unwrap() != ca_cert.to_der().unwrap() {
+        panic!(
+            "{} was signed by a different root than {ca_cert_path}",
+            args.bundle
+        );
+    }
+    if !cert.verify(&ca_cert.public_key().unwrap()).unwrap_or(false) {
+        panic!(
+            "The certificate in {} is not signed by {ca_cert_path}",
+            args.bundle
+        );
+    }
+    let csr_path = path::intermediate_csr(base_dir, &signed.name, key_type);
+    let req = req::read_req(&csr_path);
+    if !cert
+        .public_key()
+        .unwrap()
+        .public_eq(&req.public_key().unwrap())
+    {
+        panic!(
+            "The certificate in {} is not for the key requested in {csr_path}",
+            args.bundle
+        );
+    }
+
+    let cert_path = path::intermediate_crt(base_dir, &signed.name, key_type);
+    match fs::read(&cert_path) {
+        Ok(existing) if existing == cert.to_pem().unwrap() => {
+            println!("{cert_path} is already installed")
+        }
+        Ok(_) => panic!("{cert_path} already exists"),
+        Err(_) => {
+            cert::save_cert(&cert_path, &cert);
+            println!("{cert_path}");
+            let issuers = chain::issuers(base_dir, key_type, None);
+            if !issuers.is_empty() {
+                let chain_path = path::intermediate_chain(base_dir, &signed.name, key_type);
+                file::write_private(&chain_path, &chain::encode(&cert, &issuers));
+                println!("{chain_path}");
+            }
+            config::run_hooks(
+                &ca.hooks.post_issue,
+                &[
+                    ("HANCOCK_EVENT", "issue"),
+                    ("HANCOCK_NAME", &signed.name),
+                    ("HANCOCK_CERT", &cert_path),
+                    (
+                        "HANCOCK_KEY",
+                        &path::intermediate_pkey(base_dir, &signed.name, key_type),
+                    ),
+                    ("HANCOCK_CSR", &csr_path),
+                ],
+            );
+        }
+    }
+}
+
+/// Install a CRL the offline root signed, as long as it is newer than the
+/// last one installed. The store's revoked list stays the record of
+/// everything revoked, so anything revoked after the CRL was requested is not
+/// in it, and a request for the next CRL listing those is written straight
+/// away
+fn import_crl(args: &ImportSigned, base_dir: &str) {
+    let signed = bundle::read_crl_signed(&args.bundle);
+    let key_type = key_type(Some(&signed.key_type), 0);
+    let crl = openssl::x509::X509Crl::from_pem(signed.crl.as_bytes())
+        .unwrap_or_else(|e| panic!("{} holds an invalid CRL: {e}", args.bundle));
+    let issuer = openssl::x509::X509::from_pem(signed.issuer.as_bytes())
+        .unwrap_or_else(|e| panic!("{} holds an invalid issuer: {e}", args.bundle));
+
+    let ca_cert_path = path::ca_crt(base_dir, key_type);
+    let ca_cert = cert::read_cert(&ca_cert_path);
+    if issuer.to_der().unwrap() != ca_cert.to_der().unwrap() {
+        panic!(
+            "{} was signed by a different root than {ca_cert_path}",
+            args.bundle
+        );
+    }
+    if !crl.verify(&ca_cert.public_key().unwrap()).unwrap_or(false) {
+        panic!("The CRL in {} is not signed by {ca_cert_path}", args.bundle);
+    }
+    let number =
+        crl::number(&crl).unwrap_or_else(|| panic!("The CRL in {} has no CRL number", args.bundle));
+
+    let crl_path = path::ca_crl(base_dir, key_type);
+    if fs::read(&crl_path).is_ok_and(|existing| existing == crl.to_pem().unwrap()) {
+        println!("{crl_path} is already installed");
+        return;
+    }
+    let revoked_path = path::ca_revoked(base_dir, key_type);
+    let mut revocations = crl::read_revocations(&revoked_path);
+    if number <= revocations.crl_number {
+        panic!(
+            "The CRL in {} is number {number}, but number {} is already installed",
+            args.bundle, revocations.crl_number
+        );
+    }
+    revocations.crl_number = number;
+
+    let mut transaction = file::Transaction::new();
+    println!("{}", crl_path);
+    transaction.write(&crl_path, &crl.to_pem().unwrap());
+    transaction.write(&revoked_path, &crl::encode_revocations(&revocations));
+    transaction.commit();
+
+    let listed: Vec<String> = crl
+        .get_revoked()
+        .map(|revoked| {
+            revoked
+                .iter()
+                .map(|entry| {
+                    entry
+                        .serial_number()
+                        .to_bn()
+                        .unwrap()
+                        .to_hex_str()
+                        .unwrap()
+                        .to_lowercase()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let missing: Vec<&str> = revocations
+        .revoked
+        .iter()
+        .filter(|revoked| !listed.contains(&revoked.serial))
+        .map(|revoked| revoked.serial.as_str())
+        .collect();
+    if !missing.is_empty() {
+        let lifetime = crl
+            .next_update()
+            .map(|next| Duration(validity::unix(next) - validity::unix(crl.last_update())))
+            .unwrap_or(Duration::days(7));
+        let request_path = path::crl_request(base_dir, key_type);
+        save_crl_request(base_dir, key_type, lifetime, &request_path);
+        eprintln!(
+            "{} revoked since the CRL was requested are not in it, sign {request_path} on the offline root to publish CRL {} listing them too",
+            missing.join(", "),
+            number + 1
+        );
+    }
+}
+
+/// Install a subordinate root's certificate once the external root signed the
+/// request init --subordinate wrote
+fn import_ca_cert(
+    base_dir: &str,
+    key_type: KeyType,
+    cert_path: &str,
+    chain_path: &Option<String>,
+    rules: &[(Nid, policy::Rule)],
+) {
+    let certs = chain::read_certs(cert_path);
+    let cert = &certs[0];
+    let csr_path = path::ca_csr(base_dir, key_type);
+    if !Path::new(&csr_path).exists() {
+        panic!("{csr_path} does not exist, create it with init --subordinate first");
+    }
+    let req = req::read_req(&csr_path);
+    if !cert
+        .public_key()
+        .unwrap()
+        .public_eq(&req.public_key().unwrap())
+    {
+        panic!("{cert_path} is not for the key requested in {csr_path}");
+    }
+    if !chain::is_ca(cert) {
+        panic!("{cert_path} is not a CA certificate, the external root must sign it with CA:TRUE");
+    }
+
+    // The chain may also follow the certificate in the same file
+    let mut issuers: Vec<openssl::x509::X509> = certs[1..].to_vec();
+    if let Some(chain_path) = chain_path {
+        issuers.extend(chain::read_certs(chain_path));
+    }
+    let mut full = vec![cert.clone()];
+    full.extend(issuers.iter().cloned());
+    chain::verify(&full).unwrap_or_else(|e| panic!("{e}"));
+    match issuers.last() {
+        None => eprintln!("No chain was given, issued chains will end at the subordinate root"),
+        Some(last) if !chain::is_self_signed(last) => {
+            eprintln!("The chain does not end at a self-signed root, clients need the rest of it")
+        }
+        Some(_) => {}
+    }
+
+    let ca_cert_path = path::ca_crt(base_dir, key_type);
+    match fs::read(&ca_cert_path) {
+        Ok(existing) if existing == cert.to_pem().unwrap() => {
+            println!("{ca_cert_path} is already installed")
+        }
+        Ok(_) => panic!("{ca_cert_path} already exists"),
+        Err(_) => {
+            println!("{}", ca_cert_path);
+            cert::save_cert(&ca_cert_path, cert);
+        }
+    }
+    if !issuers.is_empty() {
+        let ca_chain_path = path::ca_chain(base_dir, key_type);
+        println!("{}", ca_chain_path);
+        file::write_private(&ca_chain_path, &chain::encode_all(&issuers));
+    }
+
+    let policy_path = path::ca_policy(base_dir, key_type);
+    if !Path::new(&policy_path).exists() {
+        let policy = policy::Policy::capture(&Subject::from_name(cert.subject_name()), rules);
+        policy::save_policy(&policy_path, &policy);
+    }
+}
+
+pub fn root(args: Root, config: &config::Config) {
+    match args.command {
+        RootCommands::Rollover(args) => root_rollover(*args, config),
+        RootCommands::CrossSign(args) => root_cross_sign(args, config),
+        RootCommands::Renew(args) => root_renew(args, config),
+    }
+}
+
+fn root_renew(args: RootRenew, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+
+    let key_type = key_type(args.key_type.as_ref().or(ca.key_type.as_ref()), 0);
+    let pkey_path = path::ca_pkey(&base_dir, key_type);
+    let cert_path = path::ca_crt(&base_dir, key_type);
+    let old_cert = cert::read_cert(&cert_path);
+    if !chain::is_self_signed(&old_cert) {
+        panic!("{cert_path} is signed by an external root, have it sign a new one instead");
+    }
+    let pkey = signer::load(
+        &pkey_path,
+        password::for_key(args.password.get(), &pkey_path),
+    );
+    if !old_cert.public_key().unwrap().public_eq(&pkey.public_key()) {
+        panic!("{pkey_path} is not the key of {cert_path}");
+    }
+
+    let validity = match args.lifetime {
+        Some(lifetime) => Validity::from_now(lifetime, ca.backdate()),
+        None => Validity::renewing(&old_cert, ca.backdate()),
+    }
+    .unwrap_or_else(|e| panic!("{e}"));
+    let cert = reissue_root(&base_dir, key_type, &old_cert, &validity, pkey.as_ref());
+    println!("{}", cert_info(cert));
+}
+
+/// Sign the root again with its key and write it in place of the old
+/// certificate, which is kept though the new one validates everything it
+/// signed
+fn reissue_root(
+    base_dir: &str,
+    key_type: KeyType,
+    old_cert: &openssl::x509::X509,
+    validity: &Validity,
+    signer: &dyn Signer,
+) -> openssl::x509::X509 {
+    let cert = root::renew_root_cert(old_cert, validity, signer);
+    let mut transaction = file::Transaction::new();
+    let retired_crt_path = path::retired_root_crt(base_dir, &history::serial(old_cert), key_type);
+    println!("{}", retired_crt_path);
+    transaction.write(&retired_crt_path, &old_cert.to_pem().unwrap());
+    let cert_path = path::ca_crt(base_dir, key_type);
+    println!("{}", cert_path);
+    transaction.write(&cert_path, &cert.to_pem().unwrap());
+    transaction.commit();
+    cert
+}
+
+/// Renew the root, or an intermediate the root signs again, with its
+/// existing key for its original lifetime, so it no longer expires before a
+/// certificate it is about to sign
+fn renew_issuer(
+    ca: &config::CaConfig,
+    base_dir: &str,
+    key_type: KeyType,
+    intermediate: Option<&str>,
+    root_signer: &dyn Signer,
+) -> openssl::x509::X509 {
+    let root_path = path::ca_crt(base_dir, key_type);
+    let root_cert = cert::read_cert(&root_path);
+    let name = match intermediate {
+        Some(name) => name,
+        None if !chain::is_self_signed(&root_cert) => {
+            panic!("{root_path} is signed by an external root, have it sign a new one instead")
+        }
+        None => {
+            let cert = reissue_root(
+                base_dir,
+                key_type,
+                &root_cert,
+                &Validity::renewing(&root_cert, ca.backdate()).unwrap_or_else(|e| panic!("{e}")),
+                root_signer,
+            );
+            println!("Renewed the root: {}", cert_info(cert.clone()));
+            return cert;
+        }
+    };
+
+    let crt_path = path::intermediate_crt(base_dir, name, key_type);
+    let csr_path = path::intermediate_csr(base_dir, name, key_type);
+    let cert = cert::generate_cert(
+        &Validity::renewing(&cert::read_cert(&crt_path), ca.backdate())
+            .unwrap_or_else(|e| panic!("{e}")),
+        &req::read_req(&csr_path),
+        true,
+        &ca.profile_extensions(&config::ProfileConfig::default()),
+        &root_cert,
+        root_signer,
+    );
+    let mut transaction = file::Transaction::new();
+    history::stage_archive_intermediate(
+        &mut transaction,
+        base_dir,
+        name,
+        key_type,
+        &cert::read_cert(&crt_path),
+    );
+    println!("{}", crt_path);
+    transaction.write(&crt_path, &cert.to_pem().unwrap());
+    let issuers = chain::issuers(base_dir, key_type, None);
+    if !issuers.is_empty() {
+        let chain_path = path::intermediate_chain(base_dir, name, key_type);
+        println!("{}", chain_path);
+        transaction.write(&chain_path, &chain::encode(&cert, &issuers));
+    }
+    transaction.commit();
+    println!("Renewed intermediate {name}: {}", cert_info(cert.clone()));
+    cert
 }
 
-pub fn init(args: Init) {
-    let base_dir = path::base_dir(&args.base_dir);
+fn root_cross_sign(args: RootCrossSign, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
 
-    let key_type = match args.key_type.to_uppercase().as_str() {
-        "RSA" => KeyType::Rsa(args.key_length),
-        "ECDSA" => KeyType::Ecdsa,
-        _ => panic!("key_type not ECDSA or RSA after validation. This should never happen"),
+    let subject_type = key_type(Some(&args.key_type), 0);
+    let issuer_type = match subject_type {
+        KeyType::Rsa(_) => KeyType::Ecdsa,
+        KeyType::Ecdsa => KeyType::Rsa(0),
     };
+    for key_type in [subject_type, issuer_type] {
+        let cert_path = path::ca_crt(&base_dir, key_type);
+        if !Path::new(&cert_path).is_file() {
+            panic!("{cert_path} does not exist, create the {key_type} root with init -t {key_type} first");
+        }
+    }
+    let subject = cert::read_cert(&path::ca_crt(&base_dir, subject_type));
+    let issuer = cert::read_cert(&path::ca_crt(&base_dir, issuer_type));
+    let issuer_pkey_path = path::ca_pkey(&base_dir, issuer_type);
+    let issuer_pkey = signer::load(
+        &issuer_pkey_path,
+        password::for_key(args.password.get(), &issuer_pkey_path),
+    );
+
+    let cross = root::cross_sign(&subject, &issuer, ca.backdate(), issuer_pkey.as_ref());
+    let cross_path = path::cross_crt(
+        &base_dir,
+        &history::serial(&subject),
+        &history::serial(&issuer),
+    );
+    println!("{}", cross_path);
+    let mut transaction = file::Transaction::new();
+    transaction.write(&cross_path, &cross.to_pem().unwrap());
+    stage_chains(
+        &mut transaction,
+        &base_dir,
+        subject_type,
+        &Pending {
+            cross: vec![cross],
+            ..Pending::default()
+        },
+    );
+    transaction.commit();
+}
 
+fn root_rollover(args: RootRollover, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
+    let new_password = args
+        .new_password
+        .read(args.password.allow_password_argument);
+
+    let key_type = key_type(
+        args.key_type.as_ref().or(ca.key_type.as_ref()),
+        args.key_length.or(ca.key_length).unwrap_or(4096),
+    );
     let pkey_path = path::ca_pkey(&base_dir, key_type);
+    let cert_path = path::ca_crt(&base_dir, key_type);
+    let old_cert = cert::read_cert(&cert_path);
+    if !chain::is_self_signed(&old_cert) {
+        panic!("{cert_path} is signed by an external root, have it sign a new one instead");
+    }
+    // An agent holds keys by path, and would keep signing with the old one
+    if agent::holds(&pkey_path) {
+        panic!("An agent holds {pkey_path}, lock it with agent lock first");
+    }
+    if let Some(ref uri) = args.key {
+        if signer::key_uri(&pkey_path) == Some(signer::stored_uri(uri)) {
+            panic!("{pkey_path} already refers to {uri}, the new root needs a new key");
+        }
+    }
+    let old_pkey = signer::load(
+        &pkey_path,
+        password::for_key(args.password.get(), &pkey_path),
+    );
 
-    let pkey = match Path::new(&pkey_path).exists() {
-        true => pkey::read_pkey(&pkey_path, args.password),
-        false => {
-            let pkey = pkey::generate_pkey(key_type);
-            pkey::save_pkey(&pkey_path, &pkey, args.password);
+    // The old root is kept under its serial, and everything written until
+    // the commit replaces it together
+    let mut transaction = file::Transaction::new();
+    let old_serial = history::serial(&old_cert);
+    let retired_pkey_path = path::retired_root_pkey(&base_dir, &old_serial, key_type);
+    println!("{}", retired_pkey_path);
+    transaction.write(&retired_pkey_path, &fs::read(&pkey_path).unwrap());
+    let retired_crt_path = path::retired_root_crt(&base_dir, &old_serial, key_type);
+    println!("{}", retired_crt_path);
+    transaction.write(&retired_crt_path, &old_cert.to_pem().unwrap());
+
+    let new_pkey: Box<dyn Signer> = match args.key {
+        Some(ref uri) => {
+            let pkey =
+                signer::open_or_generate(uri, password::for_pin(new_password, uri), key_type);
+            transaction.write(&pkey_path, &signer::encode_key_uri(uri));
             pkey
         }
+        None => {
+            let pkey = pkey::generate_pkey(key_type);
+            transaction.write(
+                &pkey_path,
+                &pkey::encode_pkey(
+                    &pkey,
+                    password::for_new_key(new_password, "root"),
+                    &args.encryption.to_encryption(&ca),
+                ),
+            );
+            Box::new(pkey)
+        }
     };
+    println!("{}", pkey_path);
 
-    let cert_path = path::ca_crt(&base_dir, key_type);
-    if !Path::new(&cert_path).exists() {
-        let cert = root::generate_root_cert(
-            args.lifetime,
-            &args.common_name,
-            &args.country,
-            &args.state,
-            &args.locality,
-            &args.organization,
-            &args.organizational_unit,
-            &pkey,
+    let new_cert = root::generate_root_cert(
+        &Validity::from_now(
+            args.lifetime
+                .or(ca.root_lifetime)
+                .unwrap_or(Duration::days(365 * 10)),
+            ca.backdate(),
+        )
+        .unwrap_or_else(|e| panic!("{e}")),
+        &args.subject.to_subject(
+            &args.common_name.clone().or(get_cn(&old_cert)),
+            &Subject::from_name(old_cert.subject_name()),
+        ),
+        args.subject.printable,
+        new_pkey.as_ref(),
+    );
+    println!("{}", cert_path);
+    transaction.write(&cert_path, &new_cert.to_pem().unwrap());
+
+    // Clients trusting only one of the roots reach it through the other's
+    // certificate signed by it
+    let new_serial = history::serial(&new_cert);
+    let mut pending = Pending {
+        root: Some(new_cert.clone()),
+        retired: vec![old_cert.clone()],
+        ..Pending::default()
+    };
+    for (subject, issuer, signer) in [
+        (&new_cert, &old_cert, old_pkey.as_ref()),
+        (&old_cert, &new_cert, new_pkey.as_ref()),
+    ] {
+        let cross_path = path::cross_crt(
+            &base_dir,
+            &history::serial(subject),
+            &history::serial(issuer),
         );
-        cert::save_cert(&cert_path, &cert);
+        println!("{}", cross_path);
+        let cross = root::cross_sign(subject, issuer, ca.backdate(), signer);
+        transaction.write(&cross_path, &cross.to_pem().unwrap());
+        pending.cross.push(cross);
+    }
+
+    // Intermediates keep their keys, so what they issued before stays valid
+    // under their certificates from the new root
+    for name in intermediate_names(&base_dir) {
+        let crt_path = path::intermediate_crt(&base_dir, &name, key_type);
+        if !Path::new(&crt_path).is_file() {
+            continue;
+        }
+        let crt = cert::read_cert(&crt_path);
+        if old_cert.issued(&crt) != openssl::x509::X509VerifyResult::OK {
+            continue;
+        }
+        let csr_path = path::intermediate_csr(&base_dir, &name, key_type);
+        if !Path::new(&csr_path).is_file() {
+            eprintln!("{csr_path} does not exist, {name} was left under the old root");
+            continue;
+        }
+        let cert = cert::generate_cert(
+            &Validity::renewing(&crt, ca.backdate()).unwrap_or_else(|e| panic!("{e}")),
+            &req::read_req(&csr_path),
+            true,
+            &ca.profile_extensions(&config::ProfileConfig::default()),
+            &new_cert,
+            new_pkey.as_ref(),
+        );
+        history::stage_archive_intermediate(&mut transaction, &base_dir, &name, key_type, &crt);
+        println!("{}", crt_path);
+        transaction.write(&crt_path, &cert.to_pem().unwrap());
+        pending.intermediates.insert(name, cert);
     }
+    // Chains are written with the rest, so none is left pointing at the old
+    // root if anything fails
+    stage_chains(&mut transaction, &base_dir, key_type, &pending);
+    transaction.commit();
+    println!("{old_serial} -> {new_serial}");
 }
 
-pub fn issue(args: Issue) {
-    let base_dir = path::base_dir(&args.base_dir);
+/// Certificates written in a transaction that has not been committed yet,
+/// which the chains staged alongside them must already use
+#[derive(Default)]
+struct Pending {
+    root: Option<openssl::x509::X509>,
+    intermediates: HashMap<String, openssl::x509::X509>,
+    cross: Vec<openssl::x509::X509>,
+    retired: Vec<openssl::x509::X509>,
+}
 
-    let key_type = match args.key_type.to_uppercase().as_str() {
-        "RSA" => KeyType::Rsa(args.key_length),
-        "ECDSA" => KeyType::Ecdsa,
-        _ => panic!("key_type not ECDSA or RSA after validation. This should never happen"),
+/// Stage the chain files of what a root and its intermediates issued, after
+/// the root or the certificates cross-signing it changed. Certificates still
+/// signed by a retired root reach the current one through its cross
+/// certificate
+fn stage_chains(
+    transaction: &mut file::Transaction,
+    base_dir: &str,
+    key_type: KeyType,
+    pending: &Pending,
+) {
+    let root = match pending.root {
+        Some(ref root) => root.clone(),
+        None => cert::read_cert(&path::ca_crt(base_dir, key_type)),
     };
-
-    let cn = match args.common_name {
-        Some(ref cn) => cn.clone(),
-        None => match args.intermediate {
-            Some(ref i) => i.clone(),
-            None => panic!("At least one of common-name or intermediate must be set"),
-        },
+    let issued_by_root =
+        |crt: &openssl::x509::X509| root.issued(crt) == openssl::x509::X509VerifyResult::OK;
+    let cross_certs = |subject: &openssl::x509::X509| {
+        let mut certs = chain::cross_certs(base_dir, subject);
+        certs.extend(
+            pending
+                .cross
+                .iter()
+                .filter(|cross| chain::is_cross_cert(cross, subject))
+                .cloned(),
+        );
+        certs
+    };
+    let intermediate = |name: &str| match pending.intermediates.get(name) {
+        Some(crt) => crt.clone(),
+        None => cert::read_cert(&path::intermediate_crt(base_dir, name, key_type)),
     };
 
-    // If both CN and Int are set, use the specified Int CA
-    // If only one is set, use the Root CA
-    let ca_pkey_path = if args.common_name.is_some() && args.intermediate.is_some() {
-        path::intermediate_pkey(&base_dir, &args.intermediate.clone().unwrap(), key_type)
-    } else {
-        path::ca_pkey(&base_dir, key_type)
+    let root_issuers = match chain::is_self_signed(&root) {
+        true => cross_certs(&root),
+        false => chain::issuers(base_dir, key_type, None),
     };
+    for name in intermediate_names(base_dir) {
+        let crt_path = path::intermediate_crt(base_dir, &name, key_type);
+        if !Path::new(&crt_path).is_file() {
+            continue;
+        }
+        let crt = intermediate(&name);
+        if issued_by_root(&crt) && !root_issuers.is_empty() {
+            let chain_path = path::intermediate_chain(base_dir, &name, key_type);
+            println!("{}", chain_path);
+            transaction.write(&chain_path, &chain::encode(&crt, &root_issuers));
+        }
+    }
 
-    let ca_pkey = match Path::new(&ca_pkey_path).exists() {
-        true => pkey::read_pkey(&ca_pkey_path, args.password),
-        false => {
-            let pkey = pkey::generate_pkey(key_type);
-            pkey::save_pkey(&ca_pkey_path, &pkey, args.password);
-            pkey
+    for name in fs::read_dir(base_dir).unwrap() {
+        let name = name.unwrap();
+        if !name.file_type().unwrap().is_dir() {
+            continue;
         }
-    };
+        let name = name.file_name().to_string_lossy().to_string();
+        let crt_path = path::cert_crt(base_dir, &name, key_type);
+        if !Path::new(&crt_path).is_file() {
+            continue;
+        }
+        let crt = cert::read_cert(&crt_path);
+        let issuers = match issuing_intermediate(base_dir, &crt, key_type) {
+            Some(ref name) => {
+                let intermediate = intermediate(name);
+                match issued_by_root(&intermediate) {
+                    true => [vec![intermediate], root_issuers.clone()].concat(),
+                    false => continue,
+                }
+            }
+            None if issued_by_root(&crt) => root_issuers.clone(),
+            None => match pending
+                .retired
+                .iter()
+                .find(|retired| retired.issued(&crt) == openssl::x509::X509VerifyResult::OK)
+                .cloned()
+                .or_else(|| retired_root(base_dir, &crt))
+            {
+                Some(retired) => cross_certs(&retired),
+                None => continue,
+            },
+        };
+        if !issuers.is_empty() {
+            let chain_path = path::cert_chain(base_dir, &name, key_type);
+            println!("{}", chain_path);
+            transaction.write(&chain_path, &chain::encode(&crt, &issuers));
+        }
+    }
+}
 
-    // If both CN and Int are set, use the specified Int CA
-    // If only one is set, use the Root CA
-    let ca_cert_path = if args.common_name.is_some() && args.intermediate.is_some() {
-        path::intermediate_crt(&base_dir, &args.intermediate.clone().unwrap(), key_type)
-    } else {
-        path::ca_crt(&base_dir, key_type)
-    };
-    let ca_cert = cert::read_cert(&ca_cert_path);
+/// The root a rollover retired that issued a certificate
+fn retired_root(base_dir: &str, crt: &openssl::x509::X509) -> Option<openssl::x509::X509> {
+    fs::read_dir(format!("{base_dir}/roots"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .filter(|path| path.ends_with(".crt"))
+        .map(|path| cert::read_cert(&path))
+        .find(|root| root.issued(crt) == openssl::x509::X509VerifyResult::OK)
+}
 
-    // If Int is set but CN is not set, generate a new Int PKey
-    // Else If CN is set, generate a new Cert PKey
-    let pkey_path = if args.intermediate.is_some() && args.common_name.is_none() {
-        path::intermediate_pkey(&base_dir, &args.intermediate.clone().unwrap(), key_type)
-    } else if args.common_name.is_some() {
-        path::cert_pkey(&base_dir, &args.common_name.clone().unwrap(), key_type)
-    } else {
-        panic!("unexpected case");
+/// Names of a store's intermediates, sorted
+fn intermediate_names(base_dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(format!("{base_dir}/intermediates")) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
     };
+    names.sort();
+    names
+}
+
+fn key_passwd(args: KeyPasswd, config: &config::Config) {
+    let (ca, base_dir, _lock) = args.store.open(config);
 
-    let pkey = match Path::new(&pkey_path).exists() {
-        true => pkey::read_pkey(&pkey_path, None),
+    let id = args
+        .common_name
+        .as_ref()
+        .map(|name| index::resolve(&base_dir, name));
+    let (pkey_path, _) = existing_pkey(&base_dir, &args.intermediate, &id, &args.key_type);
+    if let Some(uri) = signer::key_uri(&pkey_path) {
+        panic!("{pkey_path} refers to {uri}, which manages its own key protection");
+    }
+
+    let pkey = pkey::read_pkey(
+        &pkey_path,
+        password::for_key(args.password.get(), &pkey_path),
+    );
+
+    let new_password = match args.remove {
+        true => {
+            if !args.yes && !password::confirm(&format!("Store {pkey_path} without a password?")) {
+                panic!("Not removing the password of {pkey_path}, confirm with --yes");
+            }
+            None
+        }
         false => {
-            let pkey = pkey::generate_pkey(key_type);
-            pkey::save_pkey(&pkey_path, &pkey, None);
-            pkey
+            match args
+                .new_password
+                .read(args.password.allow_password_argument)
+                .or_else(|| password::prompt("New password", true))
+            {
+                Some(new_password) => Some(new_password),
+                None => panic!("No new password given, use --remove to store the key without one"),
+            }
         }
     };
 
-    // If Int is set but CN is not set, generate a new Int CSR
-    // Else If CN is set, generate a new Cert CSR
-    let x509_req_path = if args.intermediate.is_some() && args.common_name.is_none() {
-        path::intermediate_csr(&base_dir, &args.intermediate.clone().unwrap(), key_type)
-    } else if args.common_name.is_some() {
-        path::cert_csr(&base_dir, &args.common_name.clone().unwrap(), key_type)
-    } else {
-        panic!("unexpected case");
-    };
-    let x509_req = {
-        let req = req::generate_req(
-            &Some(cn.clone()),
-            &args.country,
-            &args.state,
-            &args.locality,
-            &args.organization,
-            &args.organizational_unit,
-            &args.subject_alt_names,
-            &pkey,
-        );
-        req::save_req(&x509_req_path, &req);
-        req
-    };
+    pkey::reencrypt_pkey(
+        &pkey_path,
+        &pkey,
+        &new_password,
+        &args.encryption.to_encryption(&ca),
+    );
 
-    // If Int is set but CN is not set, generate a new Int Cert
-    // Else If CN is set, generate a new Cert
-    let cert_path = if args.intermediate.is_some() && args.common_name.is_none() {
-        path::intermediate_crt(&base_dir, &args.intermediate.clone().unwrap(), key_type)
-    } else if args.common_name.is_some() {
-        path::cert_crt(&base_dir, &args.common_name.clone().unwrap(), key_type)
-    } else {
-        panic!("unexpected case");
-    };
-    let cert = cert::generate_cert(
-        match args.lifetime {
-            Some(d) => d,
-            None => {
-                if args.intermediate.is_some() && args.common_name.is_none() {
-                    365 * 2
-                } else {
-                    90
+    match new_password {
+        Some(_) => println!("{pkey_path} encrypted with the new password"),
+        None => println!("{pkey_path} is no longer encrypted"),
+    }
+    if let Some(ref id) = id {
+        if Path::new(&path::cert_history(&base_dir, id)).is_dir() {
+            eprintln!(
+                "Copies of the key archived in the history of {id} keep their previous password"
+            );
+        }
+    }
+}
+
+pub fn config(args: Config, config_path: &Option<String>) {
+    match args.command {
+        ConfigCommands::Check => {
+            let path = match config::find(config_path) {
+                Some(path) => path,
+                None => {
+                    println!("No configuration file found");
+                    return;
                 }
+            };
+            let errors = match config::Config::load_from(&path) {
+                Ok(config) => config.check(),
+                Err(e) => vec![e],
+            };
+            if errors.is_empty() {
+                println!("{} is valid", path.display());
+                return;
             }
-        },
-        &x509_req,
-        args.intermediate.is_some() && args.common_name.is_none(),
-        &ca_cert,
-        &ca_pkey,
-    );
-    cert::save_cert(&cert_path, &cert);
+            for error in &errors {
+                eprintln!("{}: {error}", path.display());
+            }
+            std::process::exit(1);
+        }
+    }
 }
 
-pub fn list(args: List) {
-    let base_dir = path::base_dir(&args.base_dir);
+/// An issuer's policy, with defaults from the configured CA for attributes
+/// the issuer has none for
+fn issuer_policy(ca: &config::CaConfig, policy_path: &str) -> policy::Policy {
+    let mut policy = policy::read_policy(policy_path).unwrap_or_default();
+    for (nid, value) in ca.subject().entries() {
+        if !policy.defaults.contains(*nid) {
+            policy.defaults.push(*nid, value).unwrap();
+        }
+    }
+    policy
+}
 
-    let rsa_ca_crt_path = path::ca_crt(&base_dir, KeyType::Rsa(0));
-    if Path::new(&rsa_ca_crt_path).is_file() {
-        let crt = cert::read_cert(&rsa_ca_crt_path);
-        println!("{}", cert_info(crt));
+/// The root's, an intermediate's or a certificate's key, of the given type or
+/// whichever type exists
+fn existing_pkey(
+    base_dir: &str,
+    intermediate: &Option<String>,
+    id: &Option<String>,
+    key_type: &Option<String>,
+) -> (String, KeyType) {
+    let key_types = match key_type {
+        Some(key_type) => vec![self::key_type(Some(key_type), 0)],
+        None => vec![KeyType::Rsa(0), KeyType::Ecdsa],
+    };
+    let pkey_paths: Vec<(String, KeyType)> = key_types
+        .into_iter()
+        .map(|key_type| match (intermediate, id) {
+            (Some(intermediate), _) => (
+                path::intermediate_pkey(base_dir, intermediate, key_type),
+                key_type,
+            ),
+            (None, Some(id)) => (path::cert_pkey(base_dir, id, key_type), key_type),
+            (None, None) => (path::ca_pkey(base_dir, key_type), key_type),
+        })
+        .filter(|(pkey_path, _)| Path::new(pkey_path).is_file())
+        .collect();
+    match pkey_paths.len() {
+        0 => panic!("No private key found"),
+        1 => pkey_paths[0].clone(),
+        _ => panic!("There is both an RSA and an ECDSA key, choose one with --key-type"),
     }
+}
 
-    let ecda_ca_crt_path = path::ca_crt(&base_dir, KeyType::Ecdsa);
-    if Path::new(&ecda_ca_crt_path).is_file() {
-        let crt = cert::read_cert(&ecda_ca_crt_path);
-        println!("{}", cert_info(crt));
+fn key_type(key_type: Option<&String>, key_length: u32) -> KeyType {
+    match key_type.map(|t| t.to_uppercase()).as_deref() {
+        None | Some("RSA") => KeyType::Rsa(key_length),
+        Some("ECDSA") => KeyType::Ecdsa,
+        Some(t) => panic!("{t} is not a valid key type ['RSA', 'ECDSA']"),
     }
+}
 
-    for name in fs::read_dir(&base_dir).unwrap() {
-        let name = name.unwrap();
-        if name.file_type().unwrap().is_dir() {
-            let name = format!("{}", name.file_name().to_string_lossy());
-            for key_type in [KeyType::Rsa(0), KeyType::Ecdsa] {
-                let crt_path = path::cert_crt(&base_dir, &name, key_type);
-                if Path::new(&crt_path).is_file() {
-                    let crt = cert::read_cert(&crt_path);
-                    println!("{}", cert_info(crt));
+/// Serials of every certificate in the store a CA signed, current or
+/// archived. Files that are not certificates are reported and skipped
+fn issued_serials(base_dir: &str, ca_cert: &openssl::x509::X509) -> HashSet<String> {
+    let ca_key = ca_cert.public_key().unwrap();
+    let mut serials = HashSet::new();
+    let mut dirs = vec![base_dir.to_string()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path().to_string_lossy().to_string();
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                dirs.push(path);
+            } else if path.ends_with(".crt") {
+                let crt = match fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|pem| openssl::x509::X509::from_pem(&pem).map_err(|e| e.to_string()))
+                {
+                    Ok(crt) => crt,
+                    Err(e) => {
+                        eprintln!("Skipping {path}, which is not a readable certificate: {e}");
+                        continue;
+                    }
+                };
+                if ca_cert.issued(&crt) == openssl::x509::X509VerifyResult::OK
+                    && crt.verify(&ca_key).unwrap_or(false)
+                {
+                    serials.insert(history::serial(&crt));
                 }
             }
         }
     }
+    serials
 }
 
-pub fn renew(args: Renew) {
-    let base_dir = path::base_dir(&args.base_dir);
+/// The intermediate in a store that issued a certificate, if any did
+fn issuing_intermediate(
+    base_dir: &str,
+    crt: &openssl::x509::X509,
+    key_type: KeyType,
+) -> Option<String> {
+    let entries = fs::read_dir(format!("{base_dir}/intermediates")).ok()?;
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .find(|name| {
+            let crt_path = path::intermediate_crt(base_dir, name, key_type);
+            Path::new(&crt_path).is_file()
+                && cert::read_cert(&crt_path).issued(crt) == openssl::x509::X509VerifyResult::OK
+        })
+}
 
-    let rsa_ca_crt_path = path::ca_crt(&base_dir, KeyType::Rsa(0));
-    if Path::new(&rsa_ca_crt_path).is_file() {
-        let crt = cert::read_cert(&rsa_ca_crt_path);
-        println!("{}", cert_info(crt));
-    }
+/// Intermediates a root signed that outlive it, and so stop validating
+/// before they expire
+fn root_warnings(base_dir: &str, key_type: KeyType, root: &openssl::x509::X509) -> Vec<String> {
+    intermediate_names(base_dir)
+        .into_iter()
+        .filter(|name| {
+            let crt_path = path::intermediate_crt(base_dir, name, key_type);
+            Path::new(&crt_path).is_file() && {
+                let crt = cert::read_cert(&crt_path);
+                root.issued(&crt) == openssl::x509::X509VerifyResult::OK
+                    && crt.not_after() > root.not_after()
+            }
+        })
+        .map(|name| {
+            format!("expires before intermediate {name} it signed, renew it with root renew")
+        })
+        .collect()
+}
 
-    let ecda_ca_crt_path = path::ca_crt(&base_dir, KeyType::Ecdsa);
-    if Path::new(&ecda_ca_crt_path).is_file() {
-        let crt = cert::read_cert(&ecda_ca_crt_path);
-        println!("{}", cert_info(crt));
+/// A warning if a certificate above one in its chain, its intermediate, the
+/// root or an external root's chain, expires before it does
+fn chain_warning(base_dir: &str, key_type: KeyType, crt: &openssl::x509::X509) -> Option<String> {
+    let root_path = path::ca_crt(base_dir, key_type);
+    if !Path::new(&root_path).is_file() {
+        return None;
     }
+    let root = cert::read_cert(&root_path);
 
-    for name in fs::read_dir(&base_dir).unwrap() {
-        let name = name.unwrap();
-        if name.file_type().unwrap().is_dir() {
-            let name = format!("{}", name.file_name().to_string_lossy());
-            for key_type in [KeyType::Rsa(0), KeyType::Ecdsa] {
-                let crt_path = path::cert_crt(&base_dir, &name, key_type);
-                if Path::new(&crt_path).is_file() {
-                    let crt = cert::read_cert(&crt_path);
-                    let now = Asn1Time::days_from_now(0).unwrap();
-                    let original_lifetime = crt.not_before().diff(crt.not_after()).unwrap().days;
-
-                    if now.diff(crt.not_after()).unwrap().days < 30 {
-                        // TODO: handle expirations in the past
-                        println!(
-                            "{} expires in {} days, renewing for {} days",
-                            get_cn(&crt).unwrap_or_else(|| String::from("Unknown CN")),
-                            now.diff(crt.not_after()).unwrap().days,
-                            original_lifetime
-                        );
-
-                        let ca_pkey_path = path::ca_pkey(&base_dir, key_type);
-
-                        let ca_pkey = match Path::new(&ca_pkey_path).exists() {
-                            true => pkey::read_pkey(&ca_pkey_path, args.password.clone()),
-                            false => {
-                                panic!("No private key for type {} found", key_type.to_string());
-                            }
-                        };
-
-                        let ca_cert_path = path::ca_crt(&base_dir, key_type);
-                        let ca_cert = cert::read_cert(&ca_cert_path);
-
-                        let x509_req = req::read_req(&path::cert_csr(
-                            &base_dir,
-                            &get_cn(&crt).unwrap(),
-                            key_type,
-                        ));
-                        let cert = cert::generate_cert(
-                            original_lifetime as u32,
-                            &x509_req,
-                            false,
-                            &ca_cert,
-                            &ca_pkey,
-                        );
-                        cert::save_cert(
-                            &path::cert_crt(&base_dir, &get_cn(&crt).unwrap(), key_type),
-                            &cert,
-                        );
-                    }
-                }
+    let mut chain: Vec<(String, openssl::x509::X509)> = Vec::new();
+    match issuing_intermediate(base_dir, crt, key_type) {
+        Some(name) => chain.push((
+            format!("intermediate {name}"),
+            cert::read_cert(&path::intermediate_crt(base_dir, &name, key_type)),
+        )),
+        None if root.issued(crt) == openssl::x509::X509VerifyResult::OK => {}
+        None => return None,
+    }
+    if !chain::is_self_signed(&root) {
+        let chain_path = path::ca_chain(base_dir, key_type);
+        if Path::new(&chain_path).is_file() {
+            for issuer in chain::read_certs(&chain_path) {
+                let name = format!(
+                    "{} from the external chain",
+                    Subject::from_name(issuer.subject_name())
+                );
+                chain.push((name, issuer));
             }
         }
     }
+    chain.push((String::from("the root"), root));
+
+    let (name, first) = chain
+        .iter()
+        .filter(|(_, issuer)| issuer.not_after() < crt.not_after())
+        .reduce(
+            |first, next| match next.1.not_after() < first.1.not_after() {
+                true => next,
+                false => first,
+            },
+        )?;
+    Some(format!(
+        "{name} in its chain expires {}, before it does",
+        expires(first)
+    ))
 }
 
-fn cert_info(crt: openssl::x509::X509) -> String {
-    let now = Asn1Time::days_from_now(0).unwrap();
+/// Which root signed each certificate cross-signing a root, and when it expires
+fn cross_info(base_dir: &str, root: &openssl::x509::X509) -> Vec<String> {
+    chain::cross_certs(base_dir, root)
+        .iter()
+        .map(|cross| {
+            let signer = [KeyType::Rsa(0), KeyType::Ecdsa]
+                .iter()
+                .map(|key_type| (key_type, path::ca_crt(base_dir, *key_type)))
+                .filter(|(_, path)| Path::new(path).is_file())
+                .find(|(_, path)| {
+                    let issuer = cert::read_cert(path);
+                    issuer.issued(cross) == openssl::x509::X509VerifyResult::OK
+                        && cross.verify(&issuer.public_key().unwrap()).unwrap_or(false)
+                });
+            let signer = match signer {
+                Some((key_type, _)) => format!("the {} root", key_type.to_string().to_uppercase()),
+                None => match retired_root(base_dir, cross) {
+                    Some(retired) => format!("retired root {}", history::serial(&retired)),
+                    None => Subject::from_name(cross.issuer_name()).to_string(),
+                },
+            };
+            format!("cross-signed by {signer} - expires {}", expires(cross))
+        })
+        .collect()
+}
 
+fn cert_info(crt: openssl::x509::X509) -> String {
     let cn = get_cn(&crt).unwrap_or_else(|| String::from("Unknown CN"));
-    let orig = crt.not_before().diff(crt.not_after()).unwrap().days;
-    let ex = match now.compare(crt.not_after()).unwrap() {
-        Ordering::Greater => match now.diff(crt.not_after()).unwrap().days {
-            1 => String::from("1 day ago"),
-            d => {
-                format!("{} days ago", d)
-            }
-        },
-        Ordering::Less => match now.diff(crt.not_after()).unwrap().days {
-            1 => String::from("in 1 day"),
-            d => {
-                format!("in {} days", d)
-            }
-        },
+    let orig = Validity::of(&crt).duration().describe();
+    format!("{cn} - expires {} (originally {orig})", expires(&crt))
+}
+
+fn expires(crt: &openssl::x509::X509) -> String {
+    let remaining = Validity::of(crt).not_after - validity::now();
+    match remaining.cmp(&0) {
+        Ordering::Less => format!("{} ago", validity::describe(remaining, 1)),
+        Ordering::Greater => format!("in {}", validity::describe(remaining, 1)),
         Ordering::Equal => String::from("right now"),
-    };
-    format!("{cn} - expires {ex} (originally {orig} days)")
+    }
 }
 
 fn type_parser(input: &str) -> Result<String, String> {
@@ -420,6 +3661,81 @@ fn type_parser(input: &str) -> Result<String, String> {
     }
 }
 
+fn serial_parser(input: &str) -> Result<String, String> {
+    history::parse_serial(input)
+}
+
+fn name_parser(input: &str) -> Result<String, String> {
+    path::validate_name(input).map(|_| input.to_string())
+}
+
+fn store_parser(input: &str) -> Result<String, String> {
+    path::validate_store_name(input).map(|_| input.to_string())
+}
+
+fn key_uri_parser(input: &str) -> Result<String, String> {
+    signer::validate_uri(input).map(|_| input.to_string())
+}
+
+fn allow_parser(input: &str) -> Result<agent::Allow, String> {
+    input.parse()
+}
+
+fn uid_parser(input: &str) -> Result<u32, String> {
+    agent::uid(input)
+}
+
+fn subject_parser(input: &str) -> Result<Subject, String> {
+    input.parse()
+}
+
+fn policy_parser(input: &str) -> Result<(Nid, policy::Rule), String> {
+    let (attribute, rule) = input
+        .split_once('=')
+        .ok_or_else(|| format!("{input} is not of the form ATTR=RULE"))?;
+    Ok((subject::attribute_nid(attribute)?, rule.parse()?))
+}
+
+fn cipher_parser(input: &str) -> Result<pkey::Cipher, String> {
+    input.parse()
+}
+
+fn issuer_expiry_parser(input: &str) -> Result<IssuerExpiry, String> {
+    input.parse()
+}
+
+fn reason_parser(input: &str) -> Result<crl::Reason, String> {
+    input.parse()
+}
+
+fn lifetime_parser(input: &str) -> Result<Duration, String> {
+    let lifetime: Duration = input.parse()?;
+    match lifetime > Duration::ZERO {
+        true => Ok(lifetime),
+        false => Err(format!("{input} is not a positive lifetime")),
+    }
+}
+
+fn backdate_parser(input: &str) -> Result<Duration, String> {
+    input.parse()
+}
+
+fn timestamp_parser(input: &str) -> Result<Timestamp, String> {
+    input.parse()
+}
+
+fn kdf_parser(input: &str) -> Result<pkey::Kdf, String> {
+    input.parse()
+}
+
+fn san_parser(input: &str) -> Result<AltName, String> {
+    input.parse()
+}
+
+fn san_list_parser(input: &str) -> Result<Vec<AltName>, String> {
+    san::parse_list(input)
+}
+
 fn get_cn(crt: &openssl::x509::X509) -> Option<String> {
     let mut cn = crt.subject_name().entries_by_nid(Nid::COMMONNAME);
     if let Some(entry) = cn.next() {
@@ -427,3 +3743,419 @@ fn get_cn(crt: &openssl::x509::X509) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509StoreContext, X509};
+
+    /// A store in a directory of its own, with passwords in files so nothing
+    /// is prompted for
+    struct Store {
+        dir: TestDir,
+    }
+
+    impl Store {
+        fn new(name: &str) -> Store {
+            let dir = TestDir::new(name);
+            fs::write(format!("{}/password", dir.0), "root secret\n").unwrap();
+            fs::write(format!("{}/intermediate", dir.0), "intermediate secret\n").unwrap();
+            fs::write(format!("{}/new", dir.0), "new secret\n").unwrap();
+            Store { dir }
+        }
+
+        fn base_dir(&self) -> String {
+            path::store_dir(&self.dir.0, &None)
+        }
+
+        /// Parse a command's arguments, adding the base directory, ECDSA keys,
+        /// a quick KDF and whichever password files it takes
+        fn args<T: clap::Args + clap::FromArgMatches>(&self, args: &[&str]) -> T {
+            let command = T::augment_args(clap::Command::new("hancock"));
+            let dir = &self.dir.0;
+            let mut argv = vec![String::from("hancock"), format!("--base-dir={dir}")];
+            let takes = |flag: &str| command.get_arguments().any(|arg| arg.get_id() == flag);
+            if takes("key_type") && !args.iter().any(|arg| arg.starts_with("-t")) {
+                argv.push(String::from("-tecdsa"));
+            }
+            // The default iterations are slow in unoptimized builds
+            if takes("kdf") {
+                argv.push(String::from("--kdf=pbkdf2:iterations=1000"));
+            }
+            for (flag, file) in [
+                ("password-file", "password"),
+                ("intermediate-password-file", "intermediate"),
+                ("new-password-file", "new"),
+            ] {
+                let given = args.iter().any(|arg| arg.starts_with(&format!("--{flag}")));
+                // Removing a key's password takes no new one
+                let removing = flag == "new-password-file" && args.contains(&"--remove");
+                if takes(flag) && !given && !removing {
+                    argv.push(format!("--{flag}={dir}/{file}"));
+                }
+            }
+            argv.extend(args.iter().map(|arg| arg.to_string()));
+            let matches = command.try_get_matches_from(argv).unwrap();
+            T::from_arg_matches(&matches).unwrap()
+        }
+
+        fn init(&self, args: &[&str]) {
+            init(self.args(args), &config::Config::default());
+        }
+
+        fn issue(&self, args: &[&str]) {
+            issue(self.args(args), &config::Config::default());
+        }
+
+        fn root(&self) -> X509 {
+            cert::read_cert(&path::ca_crt(&self.base_dir(), KeyType::Ecdsa))
+        }
+
+        fn intermediate(&self, name: &str) -> X509 {
+            cert::read_cert(&path::intermediate_crt(
+                &self.base_dir(),
+                name,
+                KeyType::Ecdsa,
+            ))
+        }
+
+        /// A certificate issued under a name, and the issuers written with it
+        fn leaf(&self, name: &str) -> (X509, Vec<X509>) {
+            let base_dir = self.base_dir();
+            let id = index::resolve(&base_dir, name);
+            let chain_path = path::cert_chain(&base_dir, &id, KeyType::Ecdsa);
+            match Path::new(&chain_path).is_file() {
+                true => {
+                    let chain = chain::read_certs(&chain_path);
+                    (chain[0].clone(), chain[1..].to_vec())
+                }
+                false => (
+                    cert::read_cert(&path::cert_crt(&base_dir, &id, KeyType::Ecdsa)),
+                    Vec::new(),
+                ),
+            }
+        }
+    }
+
+    /// Whether a client trusting only the anchor accepts a certificate sent
+    /// with the chain
+    fn verifies(cert: &X509, chain: &[X509], anchor: &X509) -> bool {
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(anchor.clone()).unwrap();
+        let store = store.build();
+        let mut untrusted = Stack::new().unwrap();
+        for cert in chain {
+            untrusted.push(cert.clone()).unwrap();
+        }
+        X509StoreContext::new()
+            .unwrap()
+            .init(&store, cert, &untrusted, |context| context.verify_cert())
+            .unwrap()
+    }
+
+    fn same_subject(a: &X509, b: &X509) -> bool {
+        a.subject_name().try_cmp(b.subject_name()).unwrap() == Ordering::Equal
+    }
+
+    #[test]
+    fn keeps_passwords_out_of_debug_output() {
+        let command = PasswordArgs::<KeyPassword>::augment_args(clap::Command::new("hancock"));
+        let matches = command
+            .try_get_matches_from(["hancock", "--key-password=hunter2"])
+            .unwrap();
+        let args = <PasswordArgs<KeyPassword> as clap::FromArgMatches>::from_arg_matches(&matches)
+            .unwrap();
+        assert_eq!(args.value.as_deref(), Some("hunter2"));
+        let shown = format!("{args:?}");
+        assert!(!shown.contains("hunter2"), "{shown}");
+        assert!(shown.contains("<redacted>"), "{shown}");
+    }
+
+    #[test]
+    fn rolls_over_the_root() {
+        let store = Store::new("ops-rollover");
+        store.init(&["-n", "Test Root"]);
+        store.issue(&["-i", "web"]);
+        store.issue(&["-i", "web", "-n", "www.example.com"]);
+        store.issue(&["-n", "direct.example.com"]);
+        let old_root = store.root();
+        let old_intermediate = store.intermediate("web");
+        let (leaf, chain) = store.leaf("www.example.com");
+        assert!(verifies(&leaf, &chain, &old_root));
+
+        root_rollover(store.args(&[]), &config::Config::default());
+        let new_root = store.root();
+        assert!(!new_root
+            .public_key()
+            .unwrap()
+            .public_eq(&old_root.public_key().unwrap()));
+        assert!(same_subject(&new_root, &old_root));
+
+        // The intermediate is signed again by the new root, keeping its key and
+        // subject so what it issued before stays valid
+        let intermediate = store.intermediate("web");
+        assert_ne!(
+            history::serial(&intermediate),
+            history::serial(&old_intermediate)
+        );
+        assert!(intermediate
+            .public_key()
+            .unwrap()
+            .public_eq(&old_intermediate.public_key().unwrap()));
+        assert!(same_subject(&intermediate, &old_intermediate));
+        assert!(intermediate
+            .verify(&new_root.public_key().unwrap())
+            .unwrap());
+
+        // The leaf's chain reaches the new root through the intermediate, and
+        // the old root through the link certificate it signed
+        let (rolled_leaf, chain) = store.leaf("www.example.com");
+        assert_eq!(rolled_leaf.to_der().unwrap(), leaf.to_der().unwrap());
+        assert_eq!(history::serial(&chain[0]), history::serial(&intermediate));
+        assert!(verifies(&leaf, &chain, &new_root));
+        assert!(verifies(&leaf, &chain, &old_root));
+        assert!(!verifies(&leaf, &chain[..1], &old_root));
+
+        // One the old root signed itself reaches the new root through the old
+        // root's certificate signed by the new one
+        let (direct, chain) = store.leaf("direct.example.com");
+        assert!(verifies(&direct, &chain, &old_root));
+        assert!(verifies(&direct, &chain, &new_root));
+
+        // Both roots are kept, and each signed the other
+        let base_dir = store.base_dir();
+        let old_serial = history::serial(&old_root);
+        let new_serial = history::serial(&new_root);
+        assert!(Path::new(&path::retired_root_crt(
+            &base_dir,
+            &old_serial,
+            KeyType::Ecdsa
+        ))
+        .is_file());
+        for (subject, issuer) in [(&new_root, &old_root), (&old_root, &new_root)] {
+            let link = cert::read_cert(&path::cross_crt(
+                &base_dir,
+                &history::serial(subject),
+                &history::serial(issuer),
+            ));
+            assert!(link
+                .public_key()
+                .unwrap()
+                .public_eq(&subject.public_key().unwrap()));
+            assert!(link.verify(&issuer.public_key().unwrap()).unwrap());
+        }
+        assert_ne!(old_serial, new_serial);
+
+        // What the new root issues is still accepted by clients of the old one
+        store.issue(&["-i", "web", "-n", "api.example.com"]);
+        let (leaf, chain) = store.leaf("api.example.com");
+        assert!(verifies(&leaf, &chain, &new_root));
+        assert!(verifies(&leaf, &chain, &old_root));
+    }
+
+    #[test]
+    fn renews_the_root_with_its_key() {
+        let store = Store::new("ops-root-renew");
+        store.init(&["-n", "Test Root", "-d", "30d"]);
+        store.issue(&["-i", "web"]);
+        let old_root = store.root();
+
+        root_renew(store.args(&["-d", "60d"]), &config::Config::default());
+        let root = store.root();
+        assert_ne!(history::serial(&root), history::serial(&old_root));
+        assert_eq!(
+            root.subject_key_id().unwrap().as_slice(),
+            old_root.subject_key_id().unwrap().as_slice()
+        );
+        assert!(root
+            .public_key()
+            .unwrap()
+            .public_eq(&old_root.public_key().unwrap()));
+        assert!(same_subject(&root, &old_root));
+        assert!(root.not_after() > old_root.not_after());
+        assert!(Path::new(&path::retired_root_crt(
+            &store.base_dir(),
+            &history::serial(&old_root),
+            KeyType::Ecdsa
+        ))
+        .is_file());
+
+        // What the old certificate signed verifies under the new one
+        let intermediate = store.intermediate("web");
+        assert!(verifies(&intermediate, &[], &root));
+    }
+
+    #[test]
+    fn cross_signs_the_other_root() {
+        let store = Store::new("ops-cross-sign");
+        store.init(&["-n", "ECDSA Root"]);
+        store.init(&["-t", "rsa", "-b", "2048", "-n", "RSA Root"]);
+        store.issue(&["-t", "rsa", "-n", "www.example.com"]);
+        let base_dir = store.base_dir();
+        let rsa_root = cert::read_cert(&path::ca_crt(&base_dir, KeyType::Rsa(0)));
+        let ecdsa_root = store.root();
+
+        root_cross_sign(store.args(&["-t", "rsa"]), &config::Config::default());
+        let cross = cert::read_cert(&path::cross_crt(
+            &base_dir,
+            &history::serial(&rsa_root),
+            &history::serial(&ecdsa_root),
+        ));
+        assert!(same_subject(&cross, &rsa_root));
+        assert!(cross
+            .public_key()
+            .unwrap()
+            .public_eq(&rsa_root.public_key().unwrap()));
+        assert!(cross.verify(&ecdsa_root.public_key().unwrap()).unwrap());
+
+        // Certificates the RSA root issued are sent with the cross certificate
+        let id = index::resolve(&base_dir, "www.example.com");
+        let chain = chain::read_certs(&path::cert_chain(&base_dir, &id, KeyType::Rsa(0)));
+        assert!(verifies(&chain[0], &chain[1..], &rsa_root));
+        assert!(verifies(&chain[0], &chain[1..], &ecdsa_root));
+    }
+
+    #[test]
+    fn changes_the_root_key_password() {
+        let store = Store::new("ops-key-passwd");
+        store.init(&["-n", "Test Root"]);
+        let pkey_path = path::ca_pkey(&store.base_dir(), KeyType::Ecdsa);
+        let key = pkey::read_pkey(&pkey_path, Some(String::from("root secret")));
+        let key_passwd = |args: &[&str]| key_passwd(store.args(args), &config::Config::default());
+
+        key_passwd(&[]);
+        let new = Some(String::from("new secret"));
+        assert!(pkey::verify_pkey(&pkey_path, &new, &key));
+        assert!(!pkey::verify_pkey(
+            &pkey_path,
+            &Some(String::from("root secret")),
+            &key
+        ));
+        assert!(!Path::new(&format!("{pkey_path}.bak")).exists());
+
+        // A backup left by an interrupted change is kept, and so is the key
+        fs::write(format!("{pkey_path}.bak"), "left behind").unwrap();
+        let encrypted = fs::read(&pkey_path).unwrap();
+        let refused = std::panic::catch_unwind(|| {
+            key_passwd(&["--password-file", &format!("{}/new", store.dir.0)])
+        });
+        assert!(refused.is_err());
+        assert_eq!(
+            fs::read(format!("{pkey_path}.bak")).unwrap(),
+            b"left behind"
+        );
+        assert_eq!(fs::read(&pkey_path).unwrap(), encrypted);
+        fs::remove_file(format!("{pkey_path}.bak")).unwrap();
+
+        key_passwd(&[
+            "--password-file",
+            &format!("{}/new", store.dir.0),
+            "--remove",
+            "--yes",
+        ]);
+        assert!(pkey::verify_pkey(&pkey_path, &None, &key));
+    }
+
+    #[test]
+    fn keeps_certificates_within_their_issuer() {
+        let store = Store::new("ops-issuer-expiry");
+        store.init(&["-n", "Test Root", "-d", "30d"]);
+        let root = store.root();
+
+        store.issue(&["-n", "truncated.example.com"]);
+        let (leaf, _) = store.leaf("truncated.example.com");
+        assert_eq!(leaf.not_after(), root.not_after());
+
+        let refused = std::panic::catch_unwind(|| {
+            store.issue(&["-n", "failed.example.com", "--issuer-expiry", "fail"])
+        });
+        assert!(refused.is_err());
+        assert!(index::lookup(&store.base_dir(), "failed.example.com").is_empty());
+
+        store.issue(&["-n", "renewed.example.com", "--issuer-expiry", "renew"]);
+        let renewed = store.root();
+        assert_ne!(history::serial(&renewed), history::serial(&root));
+        assert!(renewed
+            .public_key()
+            .unwrap()
+            .public_eq(&root.public_key().unwrap()));
+        let (leaf, chain) = store.leaf("renewed.example.com");
+        assert!(verifies(&leaf, &chain, &renewed));
+    }
+
+    #[test]
+    fn signs_intermediates_on_an_offline_root() {
+        let offline = Store::new("ops-offline-root");
+        let online = Store::new("ops-online");
+        offline.init(&["-n", "Offline Root"]);
+        let root = offline.root();
+        let (offline_dir, online_dir) = (offline.base_dir(), online.base_dir());
+        fs::create_dir_all(&online_dir).unwrap();
+        for path in [path::ca_crt, path::ca_policy] {
+            fs::copy(
+                path(&offline_dir, KeyType::Ecdsa),
+                path(&online_dir, KeyType::Ecdsa),
+            )
+            .unwrap();
+        }
+
+        request_intermediate(online.args(&["-i", "web"]), &config::Config::default());
+        let request = path::intermediate_request(&online_dir, "web", KeyType::Ecdsa);
+        sign_bundle(
+            offline.args(&["--yes", &request]),
+            &config::Config::default(),
+        );
+        let signed = request.replace(".request.toml", ".signed.toml");
+        import_signed(online.args(&[&signed]), &config::Config::default());
+
+        let intermediate = online.intermediate("web");
+        assert!(verifies(&intermediate, &[], &root));
+        // The intermediate's key was encrypted with the password given to
+        // request intermediate
+        let password = format!("--intermediate-password-file={}/password", online.dir.0);
+        online.issue(&["-i", "web", "-n", "www.example.com", &password]);
+        let (leaf, chain) = online.leaf("www.example.com");
+        assert!(verifies(&leaf, &chain, &root));
+    }
+
+    #[test]
+    fn installs_a_subordinate_root() {
+        let external = Store::new("ops-external-root");
+        let subordinate = Store::new("ops-subordinate");
+        external.init(&["-n", "External Root"]);
+        subordinate.init(&["-n", "Subordinate Root", "--subordinate"]);
+        let base_dir = subordinate.base_dir();
+
+        // The external root signs the request as it would any intermediate's
+        let external_dir = external.base_dir();
+        let external_root = external.root();
+        let external_pkey = signer::load(
+            &path::ca_pkey(&external_dir, KeyType::Ecdsa),
+            Some(String::from("root secret")),
+        );
+        let cert = cert::generate_cert(
+            &Validity::from_now(Duration::days(365), Duration::ZERO).unwrap(),
+            &req::read_req(&path::ca_csr(&base_dir, KeyType::Ecdsa)),
+            true,
+            &cert::Profile::default(),
+            &external_root,
+            external_pkey.as_ref(),
+        );
+        let cert_path = format!("{}/subordinate.crt", subordinate.dir.0);
+        cert::save_cert(&cert_path, &cert);
+        subordinate.init(&[
+            "--import-cert",
+            &cert_path,
+            "--chain",
+            &path::ca_crt(&external_dir, KeyType::Ecdsa),
+        ]);
+
+        subordinate.issue(&["-n", "www.example.com"]);
+        let (leaf, chain) = subordinate.leaf("www.example.com");
+        assert_eq!(history::serial(&chain[0]), history::serial(&cert));
+        assert!(verifies(&leaf, &chain, &external_root));
+    }
+}
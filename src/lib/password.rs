@@ -0,0 +1,266 @@
+use std::fs::{read_to_string, File};
+use std::io::{ErrorKind, IsTerminal, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+use std::process::{Command, Stdio};
+
+use crate::agent;
+use crate::pkcs11;
+use crate::pkey;
+use crate::signer;
+
+/// Where a private key password is read from
+#[derive(Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Given directly, with `-p` or in the environment
+    Value(String),
+    /// The first line of a file
+    File(String),
+    /// The first line read from an inherited file descriptor
+    Fd(i32),
+    /// The first line a shell command prints, such as `pass show ca`
+    Command(String),
+}
+
+impl std::fmt::Debug for Source {
+    /// Never shows a password given directly
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Value(_) => f.write_str("Value(<redacted>)"),
+            Source::File(path) => f.debug_tuple("File").field(path).finish(),
+            Source::Fd(fd) => f.debug_tuple("Fd").field(fd).finish(),
+            Source::Command(command) => f.debug_tuple("Command").field(command).finish(),
+        }
+    }
+}
+
+impl Source {
+    pub fn read(&self) -> String {
+        let contents = match self {
+            Source::Value(value) => return value.clone(),
+            Source::File(path) => read_to_string(shellexpand::tilde(path).to_string())
+                .unwrap_or_else(|e| panic!("Unable to read password from {path}: {e}")),
+            Source::Fd(fd) => {
+                // Safety: the descriptor was handed to us to read from. It is
+                // left open, as the writer may still hold the other end
+                let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+                // Read a byte at a time up to the newline, so a writer that
+                // keeps the pipe open does not leave us waiting for EOF
+                let mut line = Vec::new();
+                let mut byte = [0; 1];
+                loop {
+                    match file.read(&mut byte) {
+                        Ok(0) => break,
+                        Ok(_) if byte[0] == b'\n' => break,
+                        Ok(_) => line.push(byte[0]),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => panic!("Unable to read password from fd {fd}: {e}"),
+                    }
+                }
+                String::from_utf8(line)
+                    .unwrap_or_else(|_| panic!("The password from fd {fd} is not UTF-8"))
+            }
+            Source::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .unwrap_or_else(|e| panic!("Unable to run {command}: {e}"));
+                if !output.status.success() {
+                    panic!("{command} exited with {}", output.status);
+                }
+                String::from_utf8(output.stdout)
+                    .unwrap_or_else(|_| panic!("{command} printed a password that is not UTF-8"))
+            }
+        };
+        first_line(&contents)
+    }
+}
+
+/// Ask for a password on the terminal without echoing it, optionally asking
+/// again to confirm. Gives None if there is no terminal or nothing is entered
+pub fn prompt(prompt: &str, confirm: bool) -> Option<String> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    loop {
+        let password = rpassword::prompt_password(format!("{prompt}: ")).unwrap();
+        if password.is_empty() {
+            return None;
+        }
+        if !confirm {
+            return Some(password);
+        }
+        let confirmation = rpassword::prompt_password(format!("{prompt} (again): ")).unwrap();
+        if password == confirmation {
+            return Some(password);
+        }
+        eprintln!("Passwords do not match, try again");
+    }
+}
+
+/// Ask a yes or no question on the terminal. Without a terminal the answer
+/// is no
+pub fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// The password for an existing key, asked for on the terminal if the key is
+/// encrypted, none was given and no agent holds it
+pub fn for_key(given: Option<String>, path: &str) -> Option<String> {
+    match given {
+        Some(password) => Some(password),
+        None if agent::holds(path) => None,
+        None if pkey::is_encrypted(path) => prompt(&format!("Password for {path}"), false),
+        None => match signer::key_uri(path) {
+            Some(uri) => for_pin(None, &uri),
+            None => None,
+        },
+    }
+}
+
+/// The PIN for a token key, asked for on the terminal if none was given and
+/// the URI does not carry one. External signers take no PIN
+pub fn for_pin(given: Option<String>, uri: &str) -> Option<String> {
+    match given {
+        Some(pin) => Some(pin),
+        None if !uri.starts_with(pkcs11::SCHEME) => None,
+        None if uri.parse::<pkcs11::Uri>().is_ok_and(|uri| uri.has_pin()) => None,
+        None => prompt(&format!("PIN for {uri}"), false),
+    }
+}
+
+/// The password to encrypt a new CA key with, asked for with confirmation on
+/// the terminal if none was given. An empty password only leaves the key
+/// unencrypted once that is confirmed, and without a terminal it is warned of
+pub fn for_new_key(given: Option<String>, what: &str) -> Option<String> {
+    if given.is_some() {
+        return given;
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!("No password was given, so the new {what} key is stored unencrypted");
+        return None;
+    }
+    loop {
+        if let Some(password) = prompt(&format!("Password for the new {what} key"), true) {
+            return Some(password);
+        }
+        if confirm(&format!("Store the new {what} key without a password?")) {
+            return None;
+        }
+    }
+}
+
+/// Passwords given as arguments show up in process listings and shell
+/// history, so they are only accepted from the environment unless allowed
+pub fn check_argument(on_command_line: bool, env: &str, flag: &str, allowed: bool) {
+    if on_command_line && !allowed {
+        panic!(
+            "Refusing a password given with {flag} on the command line, use {env}, \
+             --password-file, --password-fd, --password-command or the prompt instead, \
+             or pass --allow-password-argument"
+        );
+    }
+}
+
+fn first_line(contents: &str) -> String {
+    contents.lines().next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn hides_given_values_from_debug_output() {
+        let shown = format!("{:?}", Source::Value("hunter2".to_string()));
+        assert_eq!(shown, "Value(<redacted>)");
+        let shown = format!("{:?}", Source::File("/run/ca".to_string()));
+        assert_eq!(shown, "File(\"/run/ca\")");
+    }
+
+    #[test]
+    fn reads_the_first_line_of_each_source() {
+        let dir = TestDir::new("password-sources");
+        let path = format!("{}/password", dir.0);
+        std::fs::write(&path, "from a file\nsecond line\n").unwrap();
+
+        assert_eq!(Source::Value("given".to_string()).read(), "given");
+        assert_eq!(Source::File(path.clone()).read(), "from a file");
+        let fd = File::open(&path).unwrap().into_raw_fd();
+        assert_eq!(Source::Fd(fd).read(), "from a file");
+        assert_eq!(
+            Source::Command("printf 'from a command\\nsecond line\\n'".to_string()).read(),
+            "from a command"
+        );
+        assert_eq!(Source::Command("true".to_string()).read(), "");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to read password from")]
+    fn refuses_a_missing_file() {
+        Source::File("/nonexistent/password".to_string()).read();
+    }
+
+    #[test]
+    #[should_panic(expected = "exited with")]
+    fn refuses_a_failing_command() {
+        Source::Command("exit 3".to_string()).read();
+    }
+
+    #[test]
+    fn allows_passwords_from_the_environment_or_when_allowed() {
+        check_argument(false, "CA_PASSWORD", "--password", false);
+        check_argument(true, "CA_PASSWORD", "--password", true);
+    }
+
+    #[test]
+    fn tells_arguments_from_the_environment() {
+        use crate::ops::{NewPassword, PasswordArgs};
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            password: PasswordArgs,
+            #[command(flatten)]
+            new_password: PasswordArgs<NewPassword>,
+        }
+
+        std::env::set_var("HANCOCK_NEW_PASSWORD", "secret");
+        let cli = Cli::parse_from(["hancock"]);
+        assert_eq!(cli.new_password.read(false), Some("secret".to_string()));
+        // The same value is still refused when it is given as an argument
+        let cli = Cli::parse_from(["hancock", "--new-password", "secret"]);
+        assert!(cli.new_password.value_on_command_line);
+        let cli = Cli::parse_from([
+            "hancock",
+            "--new-password",
+            "secret",
+            "--allow-password-argument",
+        ]);
+        assert_eq!(
+            cli.new_password.read(cli.password.allow_password_argument),
+            Some("secret".to_string())
+        );
+        std::env::remove_var("HANCOCK_NEW_PASSWORD");
+        assert_eq!(cli.password.get(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Refusing a password given with --password")]
+    fn refuses_passwords_on_the_command_line() {
+        check_argument(true, "CA_PASSWORD", "--password", false);
+    }
+}
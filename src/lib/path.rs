@@ -1,17 +1,100 @@
 use crate::KeyType;
 use path_absolutize::*;
 use shellexpand;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, read_dir};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 
+pub const DEFAULT_STORE: &str = "default";
+
+/// Directory holding a CA store's keys, certificates and intermediates. A base
+/// directory from before stores existed is used as is until it is migrated
+pub fn store_dir(base_dir: &str, store: &Option<String>) -> String {
+    match store {
+        Some(name) => {
+            if let Err(e) = validate_store_name(name) {
+                panic!("{e}");
+            }
+            format!("{base_dir}/cas/{name}")
+        }
+        None if is_legacy(base_dir) => base_dir.to_string(),
+        None => format!("{base_dir}/cas/{DEFAULT_STORE}"),
+    }
+}
+
+/// The store named, or every store in the base directory. Stores are paired
+/// with their names, which are None for an unmigrated base directory
+pub fn stores(base_dir: &str, store: &Option<String>) -> Vec<(Option<String>, String)> {
+    if store.is_some() {
+        return vec![(store.clone(), store_dir(base_dir, store))];
+    }
+    let mut stores: Vec<(Option<String>, String)> = match read_dir(format!("{base_dir}/cas")) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                (Some(name.clone()), format!("{base_dir}/cas/{name}"))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    stores.sort();
+    if is_legacy(base_dir) {
+        stores.insert(0, (None, base_dir.to_string()));
+    }
+    stores
+}
+
+/// Whether a CA lives directly in the base directory rather than in a store
+pub fn is_legacy(base_dir: &str) -> bool {
+    [KeyType::Rsa(0), KeyType::Ecdsa].iter().any(|key_type| {
+        Path::new(&ca_pkey(base_dir, *key_type)).exists()
+            || Path::new(&ca_crt(base_dir, *key_type)).exists()
+    })
+}
+
+pub fn validate_store_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "{name} is not a valid store name, use letters, digits, '-', '_' and '.'"
+        )),
+    }
+}
+
+/// Directories a store keeps alongside its certificates, which are never
+/// certificate IDs
+pub const STORE_DIRS: [&str; 4] = ["intermediates", "roots", "cross", "cas"];
+
+/// Reject names that are unsafe to use as a path component
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.len() > 128
+        || name.starts_with('.')
+        || name.contains(['/', '\\'])
+        || name.chars().any(|c| c.is_control())
+    {
+        return Err(format!(
+            "{name:?} is not a safe name, it must not be empty, start with '.' or contain '/'"
+        ));
+    }
+    Ok(())
+}
+
 pub fn ca_pkey(base_dir: &str, key_type: KeyType) -> String {
     match key_type {
         KeyType::Rsa(_) => {
             format!("{base_dir}/authority.pem")
         }
         _ => {
-            format!("{base_dir}/authority.{}.pem", key_type.to_string())
+            format!("{base_dir}/authority.{}.pem", key_type)
         }
     }
 }
@@ -19,18 +102,102 @@ pub fn ca_crt(base_dir: &str, key_type: KeyType) -> String {
     match key_type {
         KeyType::Rsa(_) => format!("{base_dir}/authority.crt"),
         _ => {
-            format!("{base_dir}/authority.{}.crt", key_type.to_string())
+            format!("{base_dir}/authority.{}.crt", key_type)
         }
     }
 }
 
+/// Request for a subordinate CA's certificate, to be signed by an external root
+pub fn ca_csr(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.csr"),
+        _ => format!("{base_dir}/authority.{}.csr", key_type),
+    }
+}
+
+/// Certificates above a subordinate CA, up to the external root
+pub fn ca_chain(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.chain.pem"),
+        _ => format!("{base_dir}/authority.{}.chain.pem", key_type),
+    }
+}
+
+pub fn ca_policy(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.policy.toml"),
+        _ => {
+            format!("{base_dir}/authority.{}.policy.toml", key_type)
+        }
+    }
+}
+
+/// Certificates the root has revoked, and the number of its last CRL
+pub fn ca_revoked(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.revoked.toml"),
+        _ => format!("{base_dir}/authority.{}.revoked.toml", key_type),
+    }
+}
+pub fn ca_crl(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.crl"),
+        _ => format!("{base_dir}/authority.{}.crl", key_type),
+    }
+}
+
+/// Bundle asking an offline root to sign its CRL
+pub fn crl_request(base_dir: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/authority.crl.request.toml"),
+        _ => format!("{base_dir}/authority.{}.crl.request.toml", key_type),
+    }
+}
+
+/// A root's key kept after a rollover replaced it, under its serial
+pub fn retired_root_pkey(base_dir: &str, serial: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/roots/{serial}.pem"),
+        _ => format!("{base_dir}/roots/{serial}.{}.pem", key_type),
+    }
+}
+pub fn retired_root_crt(base_dir: &str, serial: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/roots/{serial}.crt"),
+        _ => format!("{base_dir}/roots/{serial}.{}.crt", key_type),
+    }
+}
+
+/// Certificates for one root's key signed by another root
+pub fn cross_dir(base_dir: &str) -> String {
+    format!("{base_dir}/cross")
+}
+pub fn cross_crt(base_dir: &str, subject_serial: &str, issuer_serial: &str) -> String {
+    format!("{base_dir}/cross/{subject_serial}-by-{issuer_serial}.crt")
+}
+
+/// Lock file for a base directory, shared by every store within it
+pub fn lock(base_dir: &str) -> String {
+    format!("{base_dir}/.lock")
+}
+
+/// Default socket for a store's agent
+pub fn agent_socket(base_dir: &str) -> String {
+    format!("{base_dir}/agent.sock")
+}
+
+/// Maps certificate IDs to their CommonName and aliases
+pub fn cert_index(base_dir: &str) -> String {
+    format!("{base_dir}/certs.toml")
+}
+
 pub fn cert_pkey(base_dir: &str, name: &str, key_type: KeyType) -> String {
     match key_type {
         KeyType::Rsa(_) => {
             format!("{base_dir}/{name}/{name}.pem")
         }
         _ => {
-            format!("{base_dir}/{name}/{name}.{}.pem", key_type.to_string())
+            format!("{base_dir}/{name}/{name}.{}.pem", key_type)
         }
     }
 }
@@ -40,7 +207,7 @@ pub fn cert_csr(base_dir: &str, name: &str, key_type: KeyType) -> String {
             format!("{base_dir}/{name}/{name}.csr")
         }
         _ => {
-            format!("{base_dir}/{name}/{name}.{}.csr", key_type.to_string())
+            format!("{base_dir}/{name}/{name}.{}.csr", key_type)
         }
     }
 }
@@ -50,46 +217,157 @@ pub fn cert_crt(base_dir: &str, name: &str, key_type: KeyType) -> String {
             format!("{base_dir}/{name}/{name}.crt")
         }
         _ => {
-            format!("{base_dir}/{name}/{name}.{}.crt", key_type.to_string())
+            format!("{base_dir}/{name}/{name}.{}.crt", key_type)
         }
     }
 }
+/// The certificate followed by its issuers
+pub fn cert_chain(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/{name}/{name}.chain.pem"),
+        _ => format!("{base_dir}/{name}/{name}.{}.chain.pem", key_type),
+    }
+}
 
-pub fn intermediate_pkey(base_dir: &str, name: &str, key_type: KeyType) -> String {
+pub fn cert_history(base_dir: &str, name: &str) -> String {
+    format!("{base_dir}/{name}/history")
+}
+pub fn history_pkey(base_dir: &str, name: &str, serial: &str, key_type: KeyType) -> String {
     match key_type {
         KeyType::Rsa(_) => {
-            format!("{base_dir}/intermediates/{name}/{name}.pem")
+            format!("{base_dir}/{name}/history/{serial}.pem")
+        }
+        _ => {
+            format!("{base_dir}/{name}/history/{serial}.{}.pem", key_type)
+        }
+    }
+}
+pub fn history_crt(base_dir: &str, name: &str, serial: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => {
+            format!("{base_dir}/{name}/history/{serial}.crt")
+        }
+        _ => {
+            format!("{base_dir}/{name}/history/{serial}.{}.crt", key_type)
+        }
+    }
+}
+
+pub fn intermediate_history_pkey(
+    base_dir: &str,
+    name: &str,
+    serial: &str,
+    key_type: KeyType,
+) -> String {
+    match key_type {
+        KeyType::Rsa(_) => {
+            format!("{base_dir}/intermediates/{name}/history/{serial}.pem")
         }
         _ => {
             format!(
-                "{base_dir}/intermediates/{name}/{name}.{}.pem",
-                key_type.to_string()
+                "{base_dir}/intermediates/{name}/history/{serial}.{}.pem",
+                key_type
             )
         }
     }
 }
-pub fn intermediate_csr(base_dir: &str, name: &str, key_type: KeyType) -> String {
+pub fn intermediate_history_crt(
+    base_dir: &str,
+    name: &str,
+    serial: &str,
+    key_type: KeyType,
+) -> String {
     match key_type {
         KeyType::Rsa(_) => {
-            format!("{base_dir}/intermediates/{name}/{name}.csr")
+            format!("{base_dir}/intermediates/{name}/history/{serial}.crt")
         }
         _ => {
             format!(
-                "{base_dir}/intermediates/{name}/{name}.{}.csr",
-                key_type.to_string()
+                "{base_dir}/intermediates/{name}/history/{serial}.{}.crt",
+                key_type
             )
         }
     }
 }
+
+pub fn intermediate_pkey(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => {
+            format!("{base_dir}/intermediates/{name}/{name}.pem")
+        }
+        _ => {
+            format!("{base_dir}/intermediates/{name}/{name}.{}.pem", key_type)
+        }
+    }
+}
+pub fn intermediate_csr(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => {
+            format!("{base_dir}/intermediates/{name}/{name}.csr")
+        }
+        _ => {
+            format!("{base_dir}/intermediates/{name}/{name}.{}.csr", key_type)
+        }
+    }
+}
 pub fn intermediate_crt(base_dir: &str, name: &str, key_type: KeyType) -> String {
     match key_type {
         KeyType::Rsa(_) => {
             format!("{base_dir}/intermediates/{name}/{name}.crt")
         }
+        _ => {
+            format!("{base_dir}/intermediates/{name}/{name}.{}.crt", key_type)
+        }
+    }
+}
+/// The intermediate's certificate followed by its issuers
+pub fn intermediate_chain(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/intermediates/{name}/{name}.chain.pem"),
+        _ => format!(
+            "{base_dir}/intermediates/{name}/{name}.{}.chain.pem",
+            key_type
+        ),
+    }
+}
+
+/// Bundle asking an offline root to sign a new intermediate
+pub fn intermediate_request(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/intermediates/{name}/{name}.request.toml"),
+        _ => format!(
+            "{base_dir}/intermediates/{name}/{name}.{}.request.toml",
+            key_type
+        ),
+    }
+}
+
+/// Certificates the intermediate has revoked, and the number of its last CRL
+pub fn intermediate_revoked(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/intermediates/{name}/{name}.revoked.toml"),
+        _ => format!(
+            "{base_dir}/intermediates/{name}/{name}.{}.revoked.toml",
+            key_type
+        ),
+    }
+}
+pub fn intermediate_crl(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => format!("{base_dir}/intermediates/{name}/{name}.crl"),
+        _ => format!("{base_dir}/intermediates/{name}/{name}.{}.crl", key_type),
+    }
+}
+
+pub fn intermediate_policy(base_dir: &str, name: &str, key_type: KeyType) -> String {
+    match key_type {
+        KeyType::Rsa(_) => {
+            format!("{base_dir}/intermediates/{name}/{name}.policy.toml")
+        }
         _ => {
             format!(
-                "{base_dir}/intermediates/{name}/{name}.{}.crt",
-                key_type.to_string()
+                "{base_dir}/intermediates/{name}/{name}.{}.policy.toml",
+                key_type
             )
         }
     }
@@ -113,9 +391,108 @@ pub fn ensure_dir(path: &str) {
             .to_str()
             .unwrap_or("/"),
     };
+    // A bare file name is in the current directory, which is left alone
+    if dir.is_empty() {
+        return;
+    }
 
     create_dir_all(dir).unwrap();
     let mut permissions = std::fs::metadata(dir).unwrap().permissions();
     permissions.set_mode(0o700);
     std::fs::set_permissions(dir, permissions).unwrap();
 }
+
+/// An empty directory of a test's own, removed again when it is dropped
+#[cfg(test)]
+pub struct TestDir(pub String);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("hancock-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        create_dir_all(&dir).unwrap();
+        TestDir(dir.to_string_lossy().to_string())
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_store_names() {
+        for valid in ["default", "web-2", "acme_internal", "v1.0"] {
+            assert_eq!(validate_store_name(valid), Ok(()), "{valid}");
+        }
+        for invalid in [
+            "",
+            ".hidden",
+            "..",
+            "a/b",
+            "with space",
+            "ünï",
+            &"a".repeat(65),
+        ] {
+            assert!(validate_store_name(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn finds_stores_and_legacy_base_directories() {
+        let dir = TestDir::new("path-stores");
+        let base_dir = dir.0.as_str();
+        assert_eq!(
+            store_dir(base_dir, &None),
+            format!("{base_dir}/cas/default")
+        );
+        assert_eq!(
+            store_dir(base_dir, &Some("web".to_string())),
+            format!("{base_dir}/cas/web")
+        );
+        assert!(stores(base_dir, &None).is_empty());
+
+        for name in ["web", "default"] {
+            create_dir_all(format!("{base_dir}/cas/{name}")).unwrap();
+        }
+        assert_eq!(
+            stores(base_dir, &None),
+            [
+                (
+                    Some("default".to_string()),
+                    format!("{base_dir}/cas/default")
+                ),
+                (Some("web".to_string()), format!("{base_dir}/cas/web")),
+            ]
+        );
+
+        // A CA directly in the base directory is used until it is migrated
+        std::fs::write(ca_crt(base_dir, KeyType::Ecdsa), "").unwrap();
+        assert!(is_legacy(base_dir));
+        assert_eq!(store_dir(base_dir, &None), base_dir);
+        assert_eq!(stores(base_dir, &None)[0], (None, base_dir.to_string()));
+    }
+
+    #[test]
+    fn validates_names() {
+        for valid in ["www.example.com", "John Smith", "*.example.com"] {
+            assert_eq!(validate_name(valid), Ok(()), "{valid}");
+        }
+        for invalid in ["", ".", "..", "a/b", "a\\b", "tab\tname", &"a".repeat(129)] {
+            assert!(validate_name(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a valid store name")]
+    fn refuses_an_invalid_store() {
+        store_dir("/tmp", &Some("../other".to_string()));
+    }
+}
@@ -0,0 +1,563 @@
+//! CA keys kept on a PKCS#11 token, named by an RFC 7512 URI such as
+//! `pkcs11:token=ca;object=root?module-path=/usr/lib/softhsm/libsofthsm2.so`
+use cryptoki::context::{CInitializeArgs, CInitializeFlags, Pkcs11};
+use cryptoki::error::{Error, RvError};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{
+    Attribute, AttributeType, KeyType as TokenKeyType, ObjectClass, ObjectHandle,
+};
+use cryptoki::session::{Session, UserType};
+use cryptoki::slot::Slot;
+use cryptoki::types::AuthPin;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rand::rand_bytes;
+use openssl::rsa::Rsa;
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+use crate::der;
+use crate::signer::Signer;
+use crate::KeyType;
+
+pub const SCHEME: &str = "pkcs11:";
+
+/// Module used when the URI has no `module-path`
+pub const MODULE_ENV: &str = "HANCOCK_PKCS11_MODULE";
+
+/// The parts of a PKCS#11 URI hancock uses. Other attributes are ignored
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Uri {
+    pub token: Option<String>,
+    pub serial: Option<String>,
+    pub object: Option<String>,
+    pub id: Option<Vec<u8>>,
+    pub module_path: Option<String>,
+    pub pin_value: Option<String>,
+    pub pin_source: Option<String>,
+}
+
+impl FromStr for Uri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("{s} is not a PKCS#11 URI, it must start with {SCHEME}"))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut uri = Uri::default();
+        for attribute in path.split(';').filter(|a| !a.is_empty()) {
+            let (key, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| format!("{attribute} is not of the form key=value"))?;
+            let value = percent_decode(value)?;
+            match key {
+                "token" => uri.token = Some(utf8(value, key)?),
+                "serial" => uri.serial = Some(utf8(value, key)?),
+                "object" => uri.object = Some(utf8(value, key)?),
+                "id" => uri.id = Some(value),
+                _ => {}
+            }
+        }
+        for attribute in query.split('&').filter(|a| !a.is_empty()) {
+            let (key, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| format!("{attribute} is not of the form key=value"))?;
+            let value = percent_decode(value)?;
+            match key {
+                "module-path" => uri.module_path = Some(utf8(value, key)?),
+                "pin-value" => uri.pin_value = Some(utf8(value, key)?),
+                "pin-source" => uri.pin_source = Some(utf8(value, key)?),
+                _ => {}
+            }
+        }
+
+        if uri.object.is_none() && uri.id.is_none() {
+            return Err(format!("{s} must name a key with object= or id="));
+        }
+        Ok(uri)
+    }
+}
+
+/// The URI without any `pin-value`, so the PIN is never written to a key file
+pub fn without_pin(uri: &str) -> String {
+    let Some((path, query)) = uri.split_once('?') else {
+        return uri.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|attribute| !attribute.is_empty() && !attribute.starts_with("pin-value="))
+        .collect();
+    match query.is_empty() {
+        true => path.to_string(),
+        false => format!("{path}?{}", query.join("&")),
+    }
+}
+
+impl Uri {
+    /// Whether the URI carries the PIN itself, so none needs to be asked for
+    pub fn has_pin(&self) -> bool {
+        self.pin_value.is_some() || self.pin_source.is_some()
+    }
+
+    fn pin(&self, given: Option<String>) -> Option<String> {
+        if let Some(pin) = &self.pin_value {
+            return Some(pin.clone());
+        }
+        if let Some(source) = &self.pin_source {
+            let path = source.strip_prefix("file:").unwrap_or(source);
+            let contents = read_to_string(path)
+                .unwrap_or_else(|e| panic!("Unable to read PIN from {path}: {e}"));
+            return Some(contents.lines().next().unwrap_or_default().to_string());
+        }
+        given
+    }
+
+    fn module(&self) -> String {
+        self.module_path
+            .clone()
+            .or_else(|| std::env::var(MODULE_ENV).ok())
+            .unwrap_or_else(|| {
+                panic!("No PKCS#11 module, give module-path in the URI or set {MODULE_ENV}")
+            })
+    }
+
+    /// Attributes matching the key's objects on the token
+    fn template(&self, class: ObjectClass) -> Vec<Attribute> {
+        let mut template = vec![Attribute::Class(class)];
+        if let Some(object) = &self.object {
+            template.push(Attribute::Label(object.as_bytes().to_vec()));
+        }
+        if let Some(id) = &self.id {
+            template.push(Attribute::Id(id.clone()));
+        }
+        template
+    }
+}
+
+/// A key pair on a token, used through a logged in session
+pub struct Key {
+    session: Session,
+    private: ObjectHandle,
+    public: PKey<Public>,
+}
+
+impl Key {
+    pub fn open(uri: &str, pin: Option<String>) -> Key {
+        let parsed = parse(uri);
+        let session = login(&parsed, pin);
+        match find(&session, &parsed) {
+            Some((private, public)) => Key {
+                session,
+                private,
+                public,
+            },
+            None => panic!("No key matching {uri} was found on the token"),
+        }
+    }
+
+    /// Open the key, generating it on the token first if it does not exist.
+    /// Generated private keys are sensitive and cannot be extracted
+    pub fn open_or_generate(uri: &str, pin: Option<String>, key_type: KeyType) -> Key {
+        let parsed = parse(uri);
+        let session = login(&parsed, pin);
+        if let Some((private, public)) = find(&session, &parsed) {
+            return Key {
+                session,
+                private,
+                public,
+            };
+        }
+
+        let id = parsed.id.clone().unwrap_or_else(|| {
+            let mut id = vec![0; 8];
+            rand_bytes(&mut id).unwrap();
+            id
+        });
+        let label = parsed
+            .object
+            .clone()
+            .unwrap_or_else(|| id.iter().map(|b| format!("{b:02x}")).collect());
+
+        let mut public_template = vec![
+            Attribute::Token(true),
+            Attribute::Verify(true),
+            Attribute::Label(label.as_bytes().to_vec()),
+            Attribute::Id(id.clone()),
+        ];
+        let private_template = vec![
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Sign(true),
+            Attribute::Label(label.as_bytes().to_vec()),
+            Attribute::Id(id),
+        ];
+        let mechanism = match key_type {
+            KeyType::Rsa(bits) => {
+                public_template.push(Attribute::ModulusBits(
+                    (bits as std::os::raw::c_ulong).into(),
+                ));
+                public_template.push(Attribute::PublicExponent(vec![0x01, 0x00, 0x01]));
+                Mechanism::RsaPkcsKeyPairGen
+            }
+            KeyType::Ecdsa => {
+                public_template.push(Attribute::EcParams(der::oid("1.3.132.0.34")));
+                Mechanism::EccKeyPairGen
+            }
+        };
+        let (public, private) = session
+            .generate_key_pair(&mechanism, &public_template, &private_template)
+            .unwrap_or_else(|e| panic!("Unable to generate a key for {uri} on the token: {e}"));
+        eprintln!("Generated a {key_type} key on the token for {uri}");
+        Key {
+            public: public_key(&session, public),
+            session,
+            private,
+        }
+    }
+}
+
+impl Signer for Key {
+    fn public_key(&self) -> PKey<Public> {
+        self.public.clone()
+    }
+
+    /// The digest is computed here and only the raw signing operation runs
+    /// on the token, since that is the mechanism every token supports
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> Vec<u8> {
+        let hashed = hash(digest, data).unwrap();
+        match self.public.rsa() {
+            Ok(_) => {
+                let digest_info = der::sequence(&[
+                    der::sequence(&[der::oid(&digest_oid(digest)), der::tlv(der::NULL, &[])]),
+                    der::tlv(der::OCTET_STRING, &hashed),
+                ]);
+                self.session
+                    .sign(&Mechanism::RsaPkcs, self.private, &digest_info)
+                    .unwrap_or_else(|e| panic!("The token failed to sign: {e}"))
+            }
+            Err(_) => {
+                // The token gives r and s concatenated, X.509 wants them DER
+                // encoded
+                let signature = self
+                    .session
+                    .sign(&Mechanism::Ecdsa, self.private, &hashed)
+                    .unwrap_or_else(|e| panic!("The token failed to sign: {e}"));
+                let (r, s) = signature.split_at(signature.len() / 2);
+                EcdsaSig::from_private_components(
+                    BigNum::from_slice(r).unwrap(),
+                    BigNum::from_slice(s).unwrap(),
+                )
+                .unwrap()
+                .to_der()
+                .unwrap()
+            }
+        }
+    }
+}
+
+fn parse(uri: &str) -> Uri {
+    uri.parse().unwrap_or_else(|e: String| panic!("{e}"))
+}
+
+fn login(uri: &Uri, pin: Option<String>) -> Session {
+    let module = uri.module();
+    let pkcs11 = Pkcs11::new(&module)
+        .unwrap_or_else(|e| panic!("Unable to load PKCS#11 module {module}: {e}"));
+    match pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)) {
+        Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+        Err(e) => panic!("Unable to initialize PKCS#11 module {module}: {e}"),
+    }
+
+    let slot = find_slot(&pkcs11, uri);
+    let session = pkcs11
+        .open_rw_session(slot)
+        .unwrap_or_else(|e| panic!("Unable to open a session on the token: {e}"));
+    let pin = uri.pin(pin).map(AuthPin::from);
+    match session.login(UserType::User, pin.as_ref()) {
+        Ok(()) | Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => {}
+        Err(e) => panic!("Unable to log in to the token: {e}"),
+    }
+    session
+}
+
+fn find_slot(pkcs11: &Pkcs11, uri: &Uri) -> Slot {
+    let slots = pkcs11
+        .get_slots_with_token()
+        .unwrap_or_else(|e| panic!("Unable to list PKCS#11 slots: {e}"));
+    slots
+        .into_iter()
+        .find(|slot| {
+            let info = match pkcs11.get_token_info(*slot) {
+                Ok(info) => info,
+                Err(_) => return false,
+            };
+            uri.token
+                .as_deref()
+                .is_none_or(|token| info.label().trim() == token)
+                && uri
+                    .serial
+                    .as_deref()
+                    .is_none_or(|serial| info.serial_number().trim() == serial)
+        })
+        .unwrap_or_else(|| match &uri.token {
+            Some(token) => panic!("No PKCS#11 token labelled {token} was found"),
+            None => panic!("No PKCS#11 token was found"),
+        })
+}
+
+/// The private key and public half of the key pair the URI names
+fn find(session: &Session, uri: &Uri) -> Option<(ObjectHandle, PKey<Public>)> {
+    let private = unique(session, &uri.template(ObjectClass::PRIVATE_KEY))?;
+    if let Some(public) = unique(session, &uri.template(ObjectClass::PUBLIC_KEY)) {
+        return Some((private, public_key(session, public)));
+    }
+    // Some tokens keep only the private object. From PKCS#11 v2.40 it gives
+    // the public half as CKA_PUBLIC_KEY_INFO, and an RSA one always has the
+    // modulus and exponent, but an EC one has no point
+    let public_key = match public_key_info(session, private) {
+        Some(public_key) => public_key,
+        None if key_type(session, private) == TokenKeyType::RSA => public_key(session, private),
+        None => panic!(
+            "The token has no public key object for the key, and does not give the private \
+             key's CKA_PUBLIC_KEY_INFO, import the public key onto the token too"
+        ),
+    };
+    Some((private, public_key))
+}
+
+/// The SubjectPublicKeyInfo of a private key object, if the token gives it
+fn public_key_info(session: &Session, private: ObjectHandle) -> Option<PKey<Public>> {
+    let info = session
+        .get_attributes(private, &[AttributeType::PublicKeyInfo])
+        .ok()?
+        .into_iter()
+        .find_map(|attribute| match attribute {
+            Attribute::PublicKeyInfo(info) if !info.is_empty() => Some(info),
+            _ => None,
+        })?;
+    Some(
+        PKey::public_key_from_der(&info)
+            .unwrap_or_else(|e| panic!("The token gave an invalid CKA_PUBLIC_KEY_INFO: {e}")),
+    )
+}
+
+fn key_type(session: &Session, object: ObjectHandle) -> TokenKeyType {
+    session
+        .get_attributes(object, &[AttributeType::KeyType])
+        .unwrap()
+        .into_iter()
+        .find_map(|attribute| match attribute {
+            Attribute::KeyType(key_type) => Some(key_type),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn unique(session: &Session, template: &[Attribute]) -> Option<ObjectHandle> {
+    let objects = session
+        .find_objects(template)
+        .unwrap_or_else(|e| panic!("Unable to search the token: {e}"));
+    match objects.as_slice() {
+        [] => None,
+        [object] => Some(*object),
+        _ => panic!("More than one key on the token matches, add id= to the URI"),
+    }
+}
+
+/// The public key from the attributes of a public key object, or of an RSA
+/// private key object, which has the same ones
+fn public_key(session: &Session, public: ObjectHandle) -> PKey<Public> {
+    let key_type = key_type(session, public);
+
+    if key_type == TokenKeyType::RSA {
+        let mut modulus = None;
+        let mut exponent = None;
+        for attribute in session
+            .get_attributes(
+                public,
+                &[AttributeType::Modulus, AttributeType::PublicExponent],
+            )
+            .unwrap()
+        {
+            match attribute {
+                Attribute::Modulus(n) => modulus = Some(n),
+                Attribute::PublicExponent(e) => exponent = Some(e),
+                _ => {}
+            }
+        }
+        let (modulus, exponent) = modulus
+            .zip(exponent)
+            .unwrap_or_else(|| panic!("The token does not give the key's modulus and exponent"));
+        let rsa = Rsa::from_public_components(
+            BigNum::from_slice(&modulus).unwrap(),
+            BigNum::from_slice(&exponent).unwrap(),
+        )
+        .unwrap();
+        PKey::from_rsa(rsa).unwrap()
+    } else if key_type == TokenKeyType::EC {
+        let mut params = None;
+        let mut point = None;
+        for attribute in session
+            .get_attributes(public, &[AttributeType::EcParams, AttributeType::EcPoint])
+            .unwrap()
+        {
+            match attribute {
+                Attribute::EcParams(p) => params = Some(p),
+                Attribute::EcPoint(p) => point = Some(p),
+                _ => {}
+            }
+        }
+        let params = params.unwrap_or_else(|| panic!("The token does not give the key's curve"));
+        let curve = [
+            ("1.2.840.10045.3.1.7", Nid::X9_62_PRIME256V1),
+            ("1.3.132.0.34", Nid::SECP384R1),
+            ("1.3.132.0.35", Nid::SECP521R1),
+        ]
+        .into_iter()
+        .find(|(oid, _)| der::oid(oid) == params)
+        .map(|(_, nid)| nid)
+        .unwrap_or_else(|| panic!("The key on the token uses an unsupported curve"));
+        let group = EcGroup::from_curve_name(curve).unwrap();
+
+        // The point should be a DER OCTET STRING, but some tokens give it bare
+        let point = point.unwrap_or_else(|| panic!("The token does not give the key's EC point"));
+        let mut ctx = openssl::bn::BigNumContext::new().unwrap();
+        let ec_point = der::split(&point)
+            .filter(|(element, rest)| element.tag == der::OCTET_STRING && rest.is_empty())
+            .and_then(|(element, _)| EcPoint::from_bytes(&group, element.content, &mut ctx).ok())
+            .or_else(|| EcPoint::from_bytes(&group, &point, &mut ctx).ok())
+            .unwrap_or_else(|| panic!("The token gave an invalid EC point"));
+        PKey::from_ec_key(EcKey::from_public_key(&group, &ec_point).unwrap()).unwrap()
+    } else {
+        panic!("The key on the token is neither RSA nor EC");
+    }
+}
+
+fn digest_oid(digest: MessageDigest) -> String {
+    match digest.type_() {
+        Nid::SHA384 => "2.16.840.1.101.3.4.2.2",
+        Nid::SHA512 => "2.16.840.1.101.3.4.2.3",
+        _ => "2.16.840.1.101.3.4.2.1",
+    }
+    .to_string()
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("{value} has an invalid percent escape"))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+fn utf8(value: Vec<u8>, key: &str) -> Result<String, String> {
+    String::from_utf8(value).map_err(|_| format!("{key} in the PKCS#11 URI is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uris() {
+        let uri: Uri = "pkcs11:token=My%20CA;id=%01%ff;serial=42?module-path=/usr/lib/p11.so&pin-source=file:/run/pin&other=x"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            uri,
+            Uri {
+                token: Some("My CA".to_string()),
+                serial: Some("42".to_string()),
+                id: Some(vec![0x01, 0xff]),
+                module_path: Some("/usr/lib/p11.so".to_string()),
+                pin_source: Some("file:/run/pin".to_string()),
+                ..Uri::default()
+            }
+        );
+        assert!(uri.has_pin());
+
+        let uri: Uri = "pkcs11:object=root;type=private".parse().unwrap();
+        assert_eq!(uri.object.as_deref(), Some("root"));
+        assert!(!uri.has_pin());
+
+        assert!("file:root".parse::<Uri>().is_err());
+        assert!("pkcs11:token=ca".parse::<Uri>().is_err());
+        assert!("pkcs11:object".parse::<Uri>().is_err());
+        assert!("pkcs11:object=%zz".parse::<Uri>().is_err());
+        assert!("pkcs11:object=%ff".parse::<Uri>().is_err());
+    }
+
+    #[test]
+    fn drops_the_pin_value() {
+        assert_eq!(
+            without_pin("pkcs11:object=root?pin-value=1234&module-path=/p11.so"),
+            "pkcs11:object=root?module-path=/p11.so"
+        );
+        assert_eq!(
+            without_pin("pkcs11:object=root?pin-value=1234"),
+            "pkcs11:object=root"
+        );
+        assert_eq!(without_pin("pkcs11:object=root"), "pkcs11:object=root");
+        assert_eq!(
+            without_pin("pkcs11:object=root?pin-source=file:/run/pin"),
+            "pkcs11:object=root?pin-source=file:/run/pin"
+        );
+    }
+
+    /// Needs SoftHSM2, with SOFTHSM2_CONF set and a token labelled
+    /// hancock-test whose user PIN is 1234. The module is
+    /// HANCOCK_PKCS11_MODULE, or SoftHSM2's usual path. Run it with
+    ///
+    /// ```text
+    /// softhsm2-util --init-token --free --label hancock-test --pin 1234 --so-pin 1234
+    /// cargo test opens_keys_without_a_public_object -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs a SoftHSM2 token"]
+    fn opens_keys_without_a_public_object() {
+        let module = std::env::var(MODULE_ENV)
+            .unwrap_or_else(|_| String::from("/usr/lib/softhsm/libsofthsm2.so"));
+        for (name, key_type) in [("rsa", KeyType::Rsa(2048)), ("ecdsa", KeyType::Ecdsa)] {
+            let uri = format!(
+                "pkcs11:token=hancock-test;object=test-{name}-{}?module-path={module}&pin-value=1234",
+                std::process::id()
+            );
+            let generated = Key::open_or_generate(&uri, None, key_type);
+            let signature = generated.sign(MessageDigest::sha256(), b"data");
+            let mut verifier =
+                openssl::sign::Verifier::new(MessageDigest::sha256(), &generated.public).unwrap();
+            assert!(verifier.verify_oneshot(&signature, b"data").unwrap());
+
+            let parsed = parse(&uri);
+            let public = unique(
+                &generated.session,
+                &parsed.template(ObjectClass::PUBLIC_KEY),
+            )
+            .unwrap();
+            generated.session.destroy_object(public).unwrap();
+            let opened = Key::open(&uri, None);
+            assert!(opened.public.public_eq(&generated.public));
+            opened.session.destroy_object(opened.private).unwrap();
+        }
+    }
+}
@@ -1,12 +1,177 @@
-use crate::path;
+use crate::file;
 use crate::KeyType;
 use openssl::ec::{EcGroup, EcKey};
 use openssl::nid::Nid;
 use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
 use openssl::rsa::Rsa;
-use openssl::symm::Cipher;
-use std::fs::{read, write, File};
-use std::os::unix::fs::PermissionsExt;
+use pkcs8::pkcs5::{pbes2, scrypt};
+use pkcs8::{EncryptedPrivateKeyInfoRef, LineEnding, PrivateKeyInfoRef, SecretDocument};
+use std::fmt;
+use std::fs::read;
+use std::str::FromStr;
+
+/// Cipher used to encrypt a private key. The GCM modes are read back by
+/// hancock but not by the OpenSSL command line tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    Aes128Cbc,
+    Aes192Cbc,
+    #[default]
+    Aes256Cbc,
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aes-128-cbc" => Ok(Cipher::Aes128Cbc),
+            "aes-192-cbc" => Ok(Cipher::Aes192Cbc),
+            "aes-256-cbc" => Ok(Cipher::Aes256Cbc),
+            "aes-128-gcm" => Ok(Cipher::Aes128Gcm),
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            _ => Err(format!(
+                "{s} is not a valid cipher ['aes-128-cbc', 'aes-192-cbc', 'aes-256-cbc', 'aes-128-gcm', 'aes-256-gcm']"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cipher::Aes128Cbc => write!(f, "aes-128-cbc"),
+            Cipher::Aes192Cbc => write!(f, "aes-192-cbc"),
+            Cipher::Aes256Cbc => write!(f, "aes-256-cbc"),
+            Cipher::Aes128Gcm => write!(f, "aes-128-gcm"),
+            Cipher::Aes256Gcm => write!(f, "aes-256-gcm"),
+        }
+    }
+}
+
+/// Function deriving the cipher's key from the password, with its cost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2 { iterations: u32 },
+    /// scrypt with a cost of `2^log_n`, block size `r` and parallelism `p`.
+    /// The OpenSSL command line tools only read keys needing up to 32MiB,
+    /// which is exceeded at `log_n` 15 with `r` 8
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+impl Kdf {
+    pub const PBKDF2_ITERATIONS: u32 = 600_000;
+    pub const SCRYPT_LOG_N: u8 = 14;
+    pub const SCRYPT_R: u32 = 8;
+    pub const SCRYPT_P: u32 = 1;
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Kdf::Pbkdf2 { iterations: 0 } => {
+                Err(String::from("pbkdf2 needs at least one iteration"))
+            }
+            Kdf::Pbkdf2 { .. } => Ok(()),
+            Kdf::Scrypt { log_n, r, p } => scrypt::Params::new(*log_n, *r, *p)
+                .map(|_| ())
+                .map_err(|e| format!("invalid scrypt parameters: {e}")),
+        }
+    }
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Pbkdf2 {
+            iterations: Kdf::PBKDF2_ITERATIONS,
+        }
+    }
+}
+
+/// Parsed from `pbkdf2[:iterations=N]` or `scrypt[:n=N,r=R,p=P]`, with any
+/// parameter left out taking its default
+impl FromStr for Kdf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+        let mut values: Vec<(&str, u64)> = Vec::new();
+        for param in params.split(',').filter(|param| !param.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("{param} is not of the form key=value"))?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("{value} is not a valid number for {key}"))?;
+            values.push((key.trim(), value));
+        }
+
+        let kdf = match name.trim().to_lowercase().as_str() {
+            "pbkdf2" => {
+                let mut iterations = Kdf::PBKDF2_ITERATIONS as u64;
+                for (key, value) in values {
+                    match key {
+                        "iterations" => iterations = value,
+                        _ => return Err(format!("{key} is not a pbkdf2 parameter ['iterations']")),
+                    }
+                }
+                Kdf::Pbkdf2 {
+                    iterations: u32::try_from(iterations)
+                        .map_err(|_| format!("{iterations} iterations is too many"))?,
+                }
+            }
+            "scrypt" => {
+                let mut n = 1u64 << Kdf::SCRYPT_LOG_N;
+                let mut r = Kdf::SCRYPT_R as u64;
+                let mut p = Kdf::SCRYPT_P as u64;
+                for (key, value) in values {
+                    match key {
+                        "n" => n = value,
+                        "r" => r = value,
+                        "p" => p = value,
+                        _ => {
+                            return Err(format!("{key} is not a scrypt parameter ['n', 'r', 'p']"))
+                        }
+                    }
+                }
+                if n < 2 || !n.is_power_of_two() {
+                    return Err(format!("scrypt n must be a power of two, not {n}"));
+                }
+                Kdf::Scrypt {
+                    log_n: n.trailing_zeros() as u8,
+                    r: u32::try_from(r).map_err(|_| format!("scrypt r {r} is too large"))?,
+                    p: u32::try_from(p).map_err(|_| format!("scrypt p {p} is too large"))?,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "{name} is not a valid KDF ['pbkdf2[:iterations=N]', 'scrypt[:n=N,r=R,p=P]']"
+                ))
+            }
+        };
+        kdf.validate()?;
+        Ok(kdf)
+    }
+}
+
+impl fmt::Display for Kdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kdf::Pbkdf2 { iterations } => write!(f, "pbkdf2:iterations={iterations}"),
+            Kdf::Scrypt { log_n, r, p } => write!(f, "scrypt:n={},r={r},p={p}", 1u64 << log_n),
+        }
+    }
+}
+
+/// How private keys are encrypted when a password is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Encryption {
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+}
 
 pub fn generate_pkey(key_type: KeyType) -> PKey<Private> {
     match key_type {
@@ -18,32 +183,261 @@ pub fn generate_pkey(key_type: KeyType) -> PKey<Private> {
     }
 }
 
-pub fn save_pkey(path: &str, key: &PKey<Private>, password: Option<String>) {
+pub fn save_pkey(
+    path: &str,
+    key: &PKey<Private>,
+    password: Option<String>,
+    encryption: &Encryption,
+) {
     println!("{}", path);
-    path::ensure_dir(path);
+    file::write_private(path, &encode_pkey(key, password, encryption));
+}
 
-    let pem_encoded = match password {
-        Some(pass) => {
-            // AES-256-GCM is recommended by this StackOverflow answer, but not supported in
-            // this function. AES-256-CBC is the alternative reccomendation and is supported
-            // https://stackoverflow.com/a/22958889
-            key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), pass.as_bytes())
-                .unwrap()
-        }
-        None => key.private_key_to_pem_pkcs8().unwrap(),
+/// Encode a key as PKCS#8, encrypted with PBES2 if a password is given
+pub fn encode_pkey(
+    key: &PKey<Private>,
+    password: Option<String>,
+    encryption: &Encryption,
+) -> Vec<u8> {
+    let password = match password {
+        Some(password) => password,
+        None => return key.private_key_to_pem_pkcs8().unwrap(),
+    };
+
+    let mut salt = [0; 16];
+    rand_bytes(&mut salt).unwrap();
+    let mut iv = [0; 16];
+    rand_bytes(&mut iv).unwrap();
+    let mut nonce = [0; 12];
+    rand_bytes(&mut nonce).unwrap();
+
+    let kdf: pbes2::Kdf = match encryption.kdf {
+        Kdf::Pbkdf2 { iterations } => pbes2::Pbkdf2Params::hmac_sha256(iterations, &salt)
+            .unwrap()
+            .into(),
+        Kdf::Scrypt { log_n, r, p } => pbes2::ScryptParams::from_params_and_salt(
+            scrypt::Params::new(log_n, r, p).unwrap(),
+            &salt,
+        )
+        .unwrap()
+        .into(),
+    };
+    let scheme = match encryption.cipher {
+        Cipher::Aes128Cbc => pbes2::EncryptionScheme::Aes128Cbc { iv },
+        Cipher::Aes192Cbc => pbes2::EncryptionScheme::Aes192Cbc { iv },
+        Cipher::Aes256Cbc => pbes2::EncryptionScheme::Aes256Cbc { iv },
+        Cipher::Aes128Gcm => pbes2::EncryptionScheme::Aes128Gcm { nonce },
+        Cipher::Aes256Gcm => pbes2::EncryptionScheme::Aes256Gcm { nonce },
     };
-    let file = File::create(path).unwrap();
-    let mut permissions = file.metadata().unwrap().permissions();
-    permissions.set_mode(0o600);
-    std::fs::set_permissions(path, permissions).unwrap();
-    write(path, pem_encoded).unwrap();
+
+    let der = key.private_key_to_pkcs8().unwrap();
+    PrivateKeyInfoRef::try_from(der.as_slice())
+        .unwrap()
+        .encrypt_with_params(
+            pbes2::Parameters {
+                kdf,
+                encryption: scheme,
+            },
+            password,
+        )
+        .unwrap()
+        .to_pem("ENCRYPTED PRIVATE KEY", LineEnding::LF)
+        .unwrap()
+        .as_bytes()
+        .to_vec()
+}
+
+pub fn is_encrypted(path: &str) -> bool {
+    read(path)
+        .map(|pem| String::from_utf8_lossy(&pem).contains("ENCRYPTED"))
+        .unwrap_or(false)
 }
 
 pub fn read_pkey(path: &str, password: Option<String>) -> PKey<Private> {
-    match password {
-        Some(pass) => {
-            PKey::private_key_from_pem_passphrase(&read(path).unwrap(), pass.as_bytes()).unwrap()
+    if password.is_none() && is_encrypted(path) {
+        panic!("{path} is encrypted and no password was given");
+    }
+    decode_pkey(&read(path).unwrap(), &password)
+        .unwrap_or_else(|e| panic!("Unable to read {path}: {e}"))
+}
+
+/// Decode a key written by hancock or OpenSSL. Keys OpenSSL cannot decrypt,
+/// such as those using AES-GCM or scrypt beyond its memory limit, are
+/// decrypted with the PKCS#8 implementation instead
+pub fn decode_pkey(pem: &[u8], password: &Option<String>) -> Result<PKey<Private>, String> {
+    let password = match password {
+        Some(password) => password,
+        None => return PKey::private_key_from_pem(pem).map_err(|e| e.to_string()),
+    };
+    let openssl_error = match PKey::private_key_from_pem_passphrase(pem, password.as_bytes()) {
+        Ok(key) => return Ok(key),
+        Err(e) => e.to_string(),
+    };
+
+    let document = std::str::from_utf8(pem)
+        .ok()
+        .and_then(|pem| SecretDocument::from_pem(pem).ok())
+        .filter(|(label, _)| *label == "ENCRYPTED PRIVATE KEY");
+    let (_, document) = match document {
+        Some(document) => document,
+        None => return Err(openssl_error),
+    };
+    let der = EncryptedPrivateKeyInfoRef::try_from(document.as_bytes())
+        .map_err(|e| e.to_string())?
+        .decrypt(password)
+        .map_err(|_| String::from("wrong password"))?;
+    PKey::private_key_from_pkcs8(der.as_bytes()).map_err(|e| e.to_string())
+}
+
+/// Whether a key file can be read with a password and holds the given key
+pub fn verify_pkey(path: &str, password: &Option<String>, key: &PKey<Private>) -> bool {
+    let pem = match read(path) {
+        Ok(pem) => pem,
+        Err(_) => return false,
+    };
+    match decode_pkey(&pem, password) {
+        Ok(read_back) => {
+            read_back.private_key_to_der().unwrap() == key.private_key_to_der().unwrap()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Write a key file again with a new password, or none. The current file is
+/// kept as `.bak` until the new one has been read back with the new password,
+/// and put back if that fails. An existing `.bak` is refused rather than
+/// overwritten, as it may be the only copy left by an interrupted change
+pub fn reencrypt_pkey(
+    path: &str,
+    key: &PKey<Private>,
+    password: &Option<String>,
+    encryption: &Encryption,
+) {
+    let backup_path = format!("{path}.bak");
+    if std::fs::symlink_metadata(&backup_path).is_ok() {
+        panic!(
+            "{backup_path} already exists, perhaps left by an interrupted password change. \
+             Check which key and password it has and move it away first"
+        );
+    }
+    file::write_private(&backup_path, &read(path).unwrap());
+    file::write_private(path, &encode_pkey(key, password.clone(), encryption));
+    if !verify_pkey(path, password, key) {
+        std::fs::rename(&backup_path, path).unwrap();
+        panic!("Unable to read {path} back with the new password, it has been restored");
+    }
+    std::fs::remove_file(&backup_path).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::TestDir;
+
+    #[test]
+    fn parses_ciphers() {
+        for cipher in [
+            Cipher::Aes128Cbc,
+            Cipher::Aes192Cbc,
+            Cipher::Aes256Cbc,
+            Cipher::Aes128Gcm,
+            Cipher::Aes256Gcm,
+        ] {
+            assert_eq!(cipher.to_string().parse(), Ok(cipher));
+        }
+        assert_eq!("AES-256-GCM".parse(), Ok(Cipher::Aes256Gcm));
+        assert!("aes-256-ctr".parse::<Cipher>().is_err());
+    }
+
+    #[test]
+    fn parses_kdfs() {
+        assert_eq!("pbkdf2".parse(), Ok(Kdf::default()));
+        assert_eq!(
+            "PBKDF2:iterations=1000".parse(),
+            Ok(Kdf::Pbkdf2 { iterations: 1000 })
+        );
+        assert_eq!(
+            "scrypt".parse(),
+            Ok(Kdf::Scrypt {
+                log_n: Kdf::SCRYPT_LOG_N,
+                r: Kdf::SCRYPT_R,
+                p: Kdf::SCRYPT_P
+            })
+        );
+        assert_eq!(
+            "scrypt:n=1024, r=4,p=2".parse(),
+            Ok(Kdf::Scrypt {
+                log_n: 10,
+                r: 4,
+                p: 2
+            })
+        );
+        for kdf in ["pbkdf2:iterations=1000", "scrypt:n=32768,r=8,p=1"] {
+            assert_eq!(kdf.parse::<Kdf>().unwrap().to_string(), kdf);
         }
-        None => PKey::private_key_from_pem(&read(path).unwrap()).unwrap(),
+        for invalid in [
+            "argon2",
+            "pbkdf2:iterations=0",
+            "pbkdf2:iterations=5000000000",
+            "pbkdf2:rounds=10",
+            "pbkdf2:iterations",
+            "scrypt:n=1000",
+            "scrypt:n=1",
+            "scrypt:n=1024,r=0",
+            "scrypt:n=-2",
+        ] {
+            assert!(invalid.parse::<Kdf>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn reads_back_encrypted_keys() {
+        let key = generate_pkey(KeyType::Ecdsa);
+        let password = Some(String::from("correct horse"));
+        for (cipher, kdf) in [
+            (Cipher::Aes256Cbc, "pbkdf2:iterations=1000"),
+            (Cipher::Aes128Gcm, "scrypt:n=1024"),
+            (Cipher::Aes192Cbc, "scrypt:n=1024,r=1"),
+        ] {
+            let encryption = Encryption {
+                cipher,
+                kdf: kdf.parse().unwrap(),
+            };
+            let pem = encode_pkey(&key, password.clone(), &encryption);
+            assert!(String::from_utf8_lossy(&pem).contains("ENCRYPTED"));
+            let decoded = decode_pkey(&pem, &password).unwrap();
+            assert!(decoded.public_eq(&key));
+            assert!(decode_pkey(&pem, &Some(String::from("wrong"))).is_err());
+        }
+
+        let pem = encode_pkey(&key, None, &Encryption::default());
+        assert!(decode_pkey(&pem, &None).unwrap().public_eq(&key));
+    }
+
+    #[test]
+    fn changes_passwords() {
+        let dir = TestDir::new("pkey-reencrypt");
+        let path = format!("{}/key.pem", dir.0);
+        let key = generate_pkey(KeyType::Ecdsa);
+        let old = Some(String::from("old"));
+        let new = Some(String::from("new"));
+        let encryption = Encryption {
+            cipher: Cipher::Aes256Cbc,
+            kdf: "pbkdf2:iterations=1000".parse().unwrap(),
+        };
+        file::write_private(&path, &encode_pkey(&key, old.clone(), &encryption));
+
+        reencrypt_pkey(&path, &key, &new, &encryption);
+        let pem = read(&path).unwrap();
+        assert!(decode_pkey(&pem, &old).is_err());
+        assert!(decode_pkey(&pem, &new).unwrap().public_eq(&key));
+        assert!(!std::path::Path::new(&format!("{path}.bak")).exists());
+
+        // A backup left behind is neither overwritten nor replaced
+        std::fs::write(format!("{path}.bak"), b"left behind").unwrap();
+        let refused = std::panic::catch_unwind(|| reencrypt_pkey(&path, &key, &old, &encryption));
+        assert!(refused.is_err());
+        assert_eq!(read(format!("{path}.bak")).unwrap(), b"left behind");
+        assert_eq!(read(&path).unwrap(), pem);
     }
 }
@@ -0,0 +1,301 @@
+use openssl::nid::Nid;
+use openssl::x509::X509NameRef;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+use toml::{Table, Value};
+
+use crate::file;
+use crate::subject::{self, Subject};
+
+/// How an attribute of an issued certificate's subject is checked against
+/// the issuing CA, in the style of OpenSSL's `policy_match` section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The attribute must be present
+    Supplied,
+    /// The attribute must be present and equal to the issuer's value
+    Match,
+    /// The attribute may be present or absent
+    Optional,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "supplied" => Ok(Rule::Supplied),
+            "match" => Ok(Rule::Match),
+            "optional" => Ok(Rule::Optional),
+            _ => Err(format!(
+                "{s} is not a valid policy ['supplied', 'match', 'optional']"
+            )),
+        }
+    }
+}
+
+impl Rule {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rule::Supplied => "supplied",
+            Rule::Match => "match",
+            Rule::Optional => "optional",
+        }
+    }
+}
+
+/// Default subject attributes and policy rules stored alongside a CA
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub defaults: Subject,
+    pub rules: Vec<(Nid, Rule)>,
+}
+
+impl Policy {
+    /// Capture the defaults from a CA's subject, leaving out its CommonName
+    pub fn capture(ca_subject: &Subject, rules: &[(Nid, Rule)]) -> Policy {
+        let mut defaults = Subject::new();
+        for (nid, value) in ca_subject.entries() {
+            if *nid != Nid::COMMONNAME {
+                defaults.push(*nid, value).unwrap();
+            }
+        }
+        Policy {
+            defaults,
+            rules: rules.to_vec(),
+        }
+    }
+
+    /// Check an issued certificate's subject against the rules
+    pub fn check(&self, subject: &Subject, issuer: &X509NameRef) -> Result<(), String> {
+        let issuer = Subject::from_name(issuer);
+        for (nid, rule) in &self.rules {
+            let name = nid.short_name().unwrap_or("attribute");
+            let value = subject.get(*nid);
+            match rule {
+                Rule::Optional => {}
+                Rule::Supplied if value.is_none() => {
+                    return Err(format!("policy requires {name} to be supplied"))
+                }
+                Rule::Supplied => {}
+                Rule::Match => {
+                    let expected = issuer.get(*nid).or_else(|| self.defaults.get(*nid));
+                    if value.is_none() || value != expected {
+                        return Err(format!(
+                            "policy requires {name} to match the issuer ({}), got {}",
+                            expected.unwrap_or("unset"),
+                            value.unwrap_or("unset")
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn read_policy(path: &str) -> Option<Policy> {
+    if !Path::new(path).is_file() {
+        return None;
+    }
+    let table: Table = read_to_string(path)
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|e| panic!("Unable to parse {path}: {e}"));
+
+    let mut policy = Policy::default();
+    if let Some(defaults) = table.get("defaults").and_then(Value::as_table) {
+        policy.defaults = subject_from_table(defaults).unwrap_or_else(|e| panic!("{path}: {e}"));
+    }
+    if let Some(rules) = table.get("policy").and_then(Value::as_table) {
+        for (key, rule) in rules {
+            let nid = subject::attribute_nid(key).unwrap_or_else(|e| panic!("{path}: {e}"));
+            let rule = rule
+                .as_str()
+                .ok_or_else(|| format!("policy for {key} must be a string"))
+                .and_then(Rule::from_str)
+                .unwrap_or_else(|e| panic!("{path}: {e}"));
+            policy.rules.push((nid, rule));
+        }
+    }
+    Some(policy)
+}
+
+pub fn save_policy(path: &str, policy: &Policy) {
+    println!("{}", path);
+    let mut rules = Table::new();
+    for (nid, rule) in &policy.rules {
+        rules.insert(
+            nid.short_name().unwrap().to_string(),
+            Value::String(rule.as_str().to_string()),
+        );
+    }
+    let mut table = Table::new();
+    table.insert(
+        String::from("defaults"),
+        Value::Table(subject_to_table(&policy.defaults)),
+    );
+    table.insert(String::from("policy"), Value::Table(rules));
+
+    file::write_private(path, toml::to_string(&table).unwrap().as_bytes());
+}
+
+/// Attributes are keyed by short name, with repeated attributes such as OU
+/// stored as arrays
+pub fn subject_from_table(table: &Table) -> Result<Subject, String> {
+    let mut subject = Subject::new();
+    for (key, value) in table {
+        let nid = subject::attribute_nid(key)?;
+        match value {
+            Value::String(value) => subject.push(nid, value)?,
+            Value::Array(values) => {
+                for value in values {
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| format!("values of {key} must be strings"))?;
+                    subject.push(nid, value)?;
+                }
+            }
+            _ => return Err(format!("value of {key} must be a string or array")),
+        }
+    }
+    Ok(subject)
+}
+
+pub fn subject_to_table(subject: &Subject) -> Table {
+    let mut table = Table::new();
+    for (nid, value) in subject.entries() {
+        let key = nid.short_name().unwrap().to_string();
+        match table.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(Value::String(value.clone())),
+            Some(existing) => {
+                let first = existing.clone();
+                *existing = Value::Array(vec![first, Value::String(value.clone())]);
+            }
+            None => {
+                table.insert(key, Value::String(value.clone()));
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject(entries: &[(Nid, &str)]) -> Subject {
+        let mut subject = Subject::new();
+        for (nid, value) in entries {
+            subject.push(*nid, value).unwrap();
+        }
+        subject
+    }
+
+    fn policy(rules: &[(Nid, Rule)]) -> Policy {
+        Policy {
+            defaults: Subject::new(),
+            rules: rules.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(" Match ".parse(), Ok(Rule::Match));
+        assert_eq!("supplied".parse(), Ok(Rule::Supplied));
+        assert_eq!("OPTIONAL".parse(), Ok(Rule::Optional));
+        assert!("required".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn checks_match_against_the_issuer() {
+        let issuer = subject(&[
+            (Nid::COUNTRYNAME, "US"),
+            (Nid::ORGANIZATIONNAME, "Acme"),
+            (Nid::COMMONNAME, "Acme Root"),
+        ])
+        .build(false);
+        let policy = policy(&[(Nid::ORGANIZATIONNAME, Rule::Match)]);
+
+        let same = subject(&[(Nid::ORGANIZATIONNAME, "Acme"), (Nid::COMMONNAME, "foo")]);
+        assert_eq!(policy.check(&same, &issuer), Ok(()));
+        let other = subject(&[(Nid::ORGANIZATIONNAME, "Other"), (Nid::COMMONNAME, "foo")]);
+        assert!(policy.check(&other, &issuer).is_err());
+        let missing = subject(&[(Nid::COMMONNAME, "foo")]);
+        assert!(policy.check(&missing, &issuer).is_err());
+    }
+
+    #[test]
+    fn matches_the_defaults_when_the_issuer_has_no_value() {
+        let issuer = subject(&[(Nid::COMMONNAME, "Acme Root")]).build(false);
+        let mut policy = policy(&[(Nid::ORGANIZATIONNAME, Rule::Match)]);
+        policy.defaults = subject(&[(Nid::ORGANIZATIONNAME, "Acme")]);
+
+        let same = subject(&[(Nid::ORGANIZATIONNAME, "Acme"), (Nid::COMMONNAME, "foo")]);
+        assert_eq!(policy.check(&same, &issuer), Ok(()));
+        let other = subject(&[(Nid::ORGANIZATIONNAME, "Other"), (Nid::COMMONNAME, "foo")]);
+        assert!(policy.check(&other, &issuer).is_err());
+    }
+
+    #[test]
+    fn checks_supplied_and_optional() {
+        let issuer = subject(&[(Nid::COMMONNAME, "Acme Root")]).build(false);
+        let policy = policy(&[
+            (Nid::ORGANIZATIONALUNITNAME, Rule::Supplied),
+            (Nid::LOCALITYNAME, Rule::Optional),
+        ]);
+
+        let supplied = subject(&[
+            (Nid::ORGANIZATIONALUNITNAME, "Web"),
+            (Nid::COMMONNAME, "foo"),
+        ]);
+        assert_eq!(policy.check(&supplied, &issuer), Ok(()));
+        let with_optional = subject(&[
+            (Nid::LOCALITYNAME, "Boston"),
+            (Nid::ORGANIZATIONALUNITNAME, "Web"),
+            (Nid::COMMONNAME, "foo"),
+        ]);
+        assert_eq!(policy.check(&with_optional, &issuer), Ok(()));
+        let missing = subject(&[(Nid::LOCALITYNAME, "Boston"), (Nid::COMMONNAME, "foo")]);
+        assert!(policy.check(&missing, &issuer).is_err());
+    }
+
+    #[test]
+    fn captures_defaults_without_the_common_name() {
+        let ca_subject = subject(&[
+            (Nid::COUNTRYNAME, "US"),
+            (Nid::ORGANIZATIONNAME, "Acme"),
+            (Nid::COMMONNAME, "Acme Root"),
+        ]);
+        let policy = Policy::capture(&ca_subject, &[(Nid::COUNTRYNAME, Rule::Match)]);
+        assert_eq!(policy.defaults.get(Nid::COUNTRYNAME), Some("US"));
+        assert_eq!(policy.defaults.get(Nid::COMMONNAME), None);
+        assert_eq!(policy.rules, [(Nid::COUNTRYNAME, Rule::Match)]);
+    }
+
+    #[test]
+    fn keeps_repeated_attributes_in_tables() {
+        let defaults = subject(&[
+            (Nid::ORGANIZATIONNAME, "Acme"),
+            (Nid::ORGANIZATIONALUNITNAME, "Web"),
+            (Nid::ORGANIZATIONALUNITNAME, "Ops"),
+        ]);
+        let table = subject_to_table(&defaults);
+        assert_eq!(
+            table.get("OU"),
+            Some(&Value::Array(vec![
+                Value::String("Web".to_string()),
+                Value::String("Ops".to_string())
+            ]))
+        );
+        assert_eq!(
+            subject_from_table(&table).unwrap().entries(),
+            defaults.entries()
+        );
+
+        let mut invalid = Table::new();
+        invalid.insert("O".to_string(), Value::Integer(1));
+        assert!(subject_from_table(&invalid).is_err());
+    }
+}
@@ -1,30 +1,31 @@
-use openssl::asn1::Asn1Time;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
 use openssl::bn::{BigNum, MsbOption};
 use openssl::hash::MessageDigest;
-use openssl::nid::Nid;
-use openssl::pkey::{Id, PKey, Private};
+use openssl::pkey::Id;
+use openssl::stack::Stack;
 use openssl::x509::extension::*;
 use openssl::x509::*;
 
-#[allow(clippy::too_many_arguments)]
+use crate::der;
+use crate::signer::{self, Signer};
+use crate::subject::Subject;
+use crate::validity::{self, Duration, Validity};
+
 pub fn generate_root_cert(
-    lifetime_days: u32,
-    common_name: &Option<String>,
-    country: &Option<String>,
-    state: &Option<String>,
-    locality: &Option<String>,
-    organization: &Option<String>,
-    organizational_unit: &Option<String>,
-    pkey: &PKey<Private>,
+    validity: &Validity,
+    subject: &Subject,
+    printable: bool,
+    signer: &dyn Signer,
 ) -> X509 {
+    let public_key = signer.public_key();
     let mut x509_builder = X509::builder().unwrap();
     x509_builder.set_version(2).unwrap();
 
     x509_builder
-        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .set_not_before(&validity.not_before_time())
         .unwrap();
     x509_builder
-        .set_not_after(&Asn1Time::days_from_now(lifetime_days).unwrap())
+        .set_not_after(&validity.not_after_time())
         .unwrap();
 
     let mut serial = BigNum::new().unwrap();
@@ -33,43 +34,12 @@ pub fn generate_root_cert(
         .set_serial_number(&serial.to_asn1_integer().unwrap())
         .unwrap();
 
-    let mut x509_name_builder = X509Name::builder().unwrap();
-    if let Some(cn) = common_name {
-        x509_name_builder
-            .append_entry_by_nid(Nid::COMMONNAME, cn)
-            .unwrap();
-    }
-    if let Some(c) = country {
-        x509_name_builder
-            .append_entry_by_nid(Nid::COUNTRYNAME, c)
-            .unwrap();
-    }
-    if let Some(s) = state {
-        x509_name_builder
-            .append_entry_by_nid(Nid::STATEORPROVINCENAME, s)
-            .unwrap();
-    }
-    if let Some(l) = locality {
-        x509_name_builder
-            .append_entry_by_nid(Nid::LOCALITYNAME, l)
-            .unwrap();
-    }
-    if let Some(o) = organization {
-        x509_name_builder
-            .append_entry_by_nid(Nid::ORGANIZATIONNAME, o)
-            .unwrap();
-    }
-    if let Some(ou) = organizational_unit {
-        x509_name_builder
-            .append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, ou)
-            .unwrap();
-    }
-    let x509_name = x509_name_builder.build();
+    let x509_name = subject.build(printable);
 
     x509_builder.set_issuer_name(&x509_name).unwrap();
     x509_builder.set_subject_name(&x509_name).unwrap();
 
-    x509_builder.set_pubkey(pkey).unwrap();
+    x509_builder.set_pubkey(&public_key).unwrap();
 
     let basic_constraints = BasicConstraints::new().critical().ca().build().unwrap();
     x509_builder.append_extension(basic_constraints).unwrap();
@@ -89,13 +59,172 @@ pub fn generate_root_cert(
         .append_extension(subject_key_identifier)
         .unwrap();
 
-    let digest_algorithm = match pkey.id() {
+    let digest_algorithm = match public_key.id() {
+        Id::RSA => MessageDigest::sha256(),
+        Id::EC => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    };
+
+    signer::sign_cert(x509_builder, signer, digest_algorithm)
+}
+
+/// Request for a subordinate CA's certificate, asking the external root for
+/// the same constraints and usages a self-signed root would have
+pub fn generate_root_req(subject: &Subject, printable: bool, signer: &dyn Signer) -> X509Req {
+    let public_key = signer.public_key();
+    let mut x509req_builder = X509Req::builder().unwrap();
+    x509req_builder.set_pubkey(&public_key).unwrap();
+    x509req_builder.set_version(0).unwrap();
+    x509req_builder
+        .set_subject_name(&subject.build(printable))
+        .unwrap();
+
+    let mut extensions = Stack::new().unwrap();
+    extensions
+        .push(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    extensions
+        .push(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    x509req_builder.add_extensions(&extensions).unwrap();
+
+    let digest_algorithm = match public_key.id() {
+        Id::RSA => MessageDigest::sha256(),
+        Id::EC => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    };
+
+    signer::sign_req(x509req_builder, signer, digest_algorithm)
+}
+
+/// Certificate for another root's subject and key signed by this root, so
+/// clients trusting only this root also trust what the other one issues. It
+/// is valid no longer than either root
+pub fn cross_sign(subject: &X509, issuer: &X509, backdate: Duration, signer: &dyn Signer) -> X509 {
+    let mut x509_builder = X509::builder().unwrap();
+    x509_builder.set_version(2).unwrap();
+
+    x509_builder
+        .set_not_before(
+            &Asn1Time::from_unix((validity::now() - backdate.seconds()) as libc::time_t).unwrap(),
+        )
+        .unwrap();
+    let not_after = match subject.not_after() < issuer.not_after() {
+        true => subject.not_after(),
+        false => issuer.not_after(),
+    };
+    x509_builder.set_not_after(not_after).unwrap();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(128, MsbOption::MAYBE_ZERO, false).unwrap();
+    x509_builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+
+    x509_builder.set_issuer_name(issuer.subject_name()).unwrap();
+    x509_builder
+        .set_subject_name(subject.subject_name())
+        .unwrap();
+    x509_builder
+        .set_pubkey(&subject.public_key().unwrap())
+        .unwrap();
+
+    let basic_constraints = BasicConstraints::new().critical().ca().build().unwrap();
+    x509_builder.append_extension(basic_constraints).unwrap();
+
+    let key_usage = KeyUsage::new()
+        .critical()
+        .key_cert_sign()
+        .crl_sign()
+        .build()
+        .unwrap();
+    x509_builder.append_extension(key_usage).unwrap();
+
+    let subject_key_identifier = SubjectKeyIdentifier::new()
+        .build(&x509_builder.x509v3_context(Some(issuer), None))
+        .unwrap();
+    x509_builder
+        .append_extension(subject_key_identifier)
+        .unwrap();
+
+    let authority_key_identifier = AuthorityKeyIdentifier::new()
+        .keyid(false)
+        .build(&x509_builder.x509v3_context(Some(issuer), None))
+        .unwrap();
+    x509_builder
+        .append_extension(authority_key_identifier)
+        .unwrap();
+
+    let digest_algorithm = match signer.public_key().id() {
         Id::RSA => MessageDigest::sha256(),
         Id::EC => MessageDigest::sha384(),
         _ => MessageDigest::sha256(),
     };
 
-    x509_builder.sign(pkey, digest_algorithm).unwrap();
+    signer::sign_cert(x509_builder, signer, digest_algorithm)
+}
+
+/// The root certificate again for a new validity period, with the same key,
+/// subject and SubjectKeyIdentifier, so what it signed keeps validating
+pub fn renew_root_cert(root: &X509, validity: &Validity, signer: &dyn Signer) -> X509 {
+    let mut x509_builder = X509::builder().unwrap();
+    x509_builder.set_version(2).unwrap();
+
+    x509_builder
+        .set_not_before(&validity.not_before_time())
+        .unwrap();
+    x509_builder
+        .set_not_after(&validity.not_after_time())
+        .unwrap();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(128, MsbOption::MAYBE_ZERO, false).unwrap();
+    x509_builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+
+    x509_builder.set_issuer_name(root.subject_name()).unwrap();
+    x509_builder.set_subject_name(root.subject_name()).unwrap();
+    x509_builder.set_pubkey(&signer.public_key()).unwrap();
+
+    let basic_constraints = BasicConstraints::new().critical().ca().build().unwrap();
+    x509_builder.append_extension(basic_constraints).unwrap();
+
+    let key_usage = KeyUsage::new()
+        .critical()
+        .key_cert_sign()
+        .crl_sign()
+        .build()
+        .unwrap();
+    x509_builder.append_extension(key_usage).unwrap();
+
+    let subject_key_identifier = match root.subject_key_id() {
+        Some(id) => X509Extension::new_from_der(
+            &Asn1Object::from_str("2.5.29.14").unwrap(),
+            false,
+            &Asn1OctetString::new_from_bytes(&der::tlv(der::OCTET_STRING, id.as_slice())).unwrap(),
+        )
+        .unwrap(),
+        None => SubjectKeyIdentifier::new()
+            .build(&x509_builder.x509v3_context(None, None))
+            .unwrap(),
+    };
+    x509_builder
+        .append_extension(subject_key_identifier)
+        .unwrap();
+
+    let digest_algorithm = match signer.public_key().id() {
+        Id::RSA => MessageDigest::sha256(),
+        Id::EC => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    };
 
-    x509_builder.build()
+    signer::sign_cert(x509_builder, signer, digest_algorithm)
 }
@@ -0,0 +1,451 @@
+use openssl::asn1::Asn1Object;
+use openssl::x509::extension::SubjectAlternativeName;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::der;
+
+/// Microsoft User Principal Name, carried as an otherName
+const UPN_OID: &str = "1.3.6.1.4.1.311.20.2.3";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AltName {
+    Dns(String),
+    Ip(IpAddr),
+    Email(String),
+    Uri(String),
+    Upn(String),
+    Rid(String),
+}
+
+impl AltName {
+    /// Guess the type of an entry given without a `type:` prefix
+    pub fn infer(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        if let Ok(ip) = IpAddr::from_str(value) {
+            Ok(AltName::Ip(ip))
+        } else if value.contains("://") {
+            AltName::uri(value)
+        } else if value.contains('@') {
+            AltName::email(value)
+        } else {
+            AltName::dns(value)
+        }
+    }
+
+    /// Internationalized names are stored as punycode A-labels
+    pub fn dns(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(String::from("DNS name is empty"));
+        }
+        dns_to_ascii(value).map(AltName::Dns)
+    }
+
+    pub fn ip(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        IpAddr::from_str(value)
+            .map(AltName::Ip)
+            .map_err(|_| format!("{value} is not a valid IP address"))
+    }
+
+    pub fn email(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        match value.split_once('@') {
+            // An rfc822Name is an IA5String, and only the domain has an ASCII
+            // form, so internationalized local parts cannot be encoded
+            Some((local, _)) if !local.is_ascii() => Err(format!(
+                "{value} has a non-ASCII local part, which an email SAN cannot hold"
+            )),
+            Some((local, domain))
+                if !local.is_empty()
+                    && !local.chars().any(|c| c.is_whitespace() || c.is_control()) =>
+            {
+                match dns_to_ascii(domain) {
+                    Ok(domain) if !domain.starts_with('*') => {
+                        Ok(AltName::Email(format!("{local}@{domain}")))
+                    }
+                    _ => Err(format!("{value} is not a valid email address")),
+                }
+            }
+            _ => Err(format!("{value} is not a valid email address")),
+        }
+    }
+
+    pub fn uri(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        let invalid = || format!("{value} is not a valid URI");
+        let (scheme, rest) = value.split_once(':').ok_or_else(invalid)?;
+
+        let mut scheme_chars = scheme.chars();
+        if !scheme_chars
+            .next()
+            .map(|c| c.is_ascii_alphabetic())
+            .unwrap_or(false)
+            || !scheme_chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+            || rest.is_empty()
+            || value.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(invalid());
+        }
+
+        if scheme.eq_ignore_ascii_case("spiffe") {
+            validate_spiffe_id(value)?;
+        }
+        Ok(AltName::Uri(value.to_string()))
+    }
+
+    pub fn upn(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        match value.split_once('@') {
+            Some((user, domain))
+                if !user.is_empty()
+                    && !domain.is_empty()
+                    && !value.chars().any(|c| c.is_whitespace()) =>
+            {
+                Ok(AltName::Upn(value.to_string()))
+            }
+            _ => Err(format!("{value} is not a valid UPN, expected user@domain")),
+        }
+    }
+
+    pub fn rid(value: &str) -> Result<AltName, String> {
+        let value = value.trim();
+        let arcs: Vec<&str> = value.split('.').collect();
+        let valid = arcs.len() >= 2
+            && arcs
+                .iter()
+                .all(|a| !a.is_empty() && a.chars().all(|c| c.is_ascii_digit()))
+            && ["0", "1", "2"].contains(&arcs[0])
+            && Asn1Object::from_str(value).is_ok();
+        match valid {
+            true => Ok(AltName::Rid(value.to_string())),
+            false => Err(format!("{value} is not a valid dotted OID")),
+        }
+    }
+
+    /// The SAN implied by a certificate's CommonName, if it is a DNS name,
+    /// an IP address or an email address. Other CommonNames, such as a
+    /// person's name, imply none
+    pub fn from_common_name(cn: &str) -> Option<AltName> {
+        let cn = cn.trim();
+        if let Ok(ip) = IpAddr::from_str(cn) {
+            Some(AltName::Ip(ip))
+        } else if cn.contains('@') {
+            AltName::email(cn).ok()
+        } else {
+            AltName::dns(cn).ok()
+        }
+    }
+
+    pub fn add_to(&self, builder: &mut SubjectAlternativeName) {
+        match self {
+            AltName::Dns(name) => builder.dns(name),
+            AltName::Ip(ip) => builder.ip(&ip.to_string()),
+            AltName::Email(email) => builder.email(email),
+            AltName::Uri(uri) => builder.uri(uri),
+            AltName::Upn(upn) => builder.other_name2(
+                Asn1Object::from_str(UPN_OID).unwrap(),
+                &der::utf8_string(upn),
+            ),
+            AltName::Rid(oid) => builder.rid(oid),
+        };
+    }
+
+    fn same_as(&self, other: &AltName) -> bool {
+        match (self, other) {
+            (AltName::Dns(a), AltName::Dns(b)) => a.eq_ignore_ascii_case(b),
+            (AltName::Email(a), AltName::Email(b)) => a.eq_ignore_ascii_case(b),
+            (AltName::Upn(a), AltName::Upn(b)) => a.eq_ignore_ascii_case(b),
+            _ => self == other,
+        }
+    }
+}
+
+impl FromStr for AltName {
+    type Err = String;
+
+    /// Parse a single entry such as `dns:example.com`, `ip:10.0.0.1` or
+    /// `uri:spiffe://cluster/ns/foo`. Entries without a known prefix are inferred
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((kind, value)) = s.split_once(':') {
+            match kind.trim().to_lowercase().as_str() {
+                "dns" => return AltName::dns(value),
+                "ip" => return AltName::ip(value),
+                "email" => return AltName::email(value),
+                "uri" => return AltName::uri(value),
+                "upn" => return AltName::upn(value),
+                "rid" | "registeredid" => return AltName::rid(value),
+                _ => {}
+            }
+        }
+        AltName::infer(s)
+    }
+}
+
+impl fmt::Display for AltName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AltName::Dns(name) => match idna::domain_to_unicode(name) {
+                (unicode, Ok(())) if unicode != *name => write!(f, "DNS:{unicode} ({name})"),
+                _ => write!(f, "DNS:{name}"),
+            },
+            AltName::Ip(ip) => write!(f, "IP:{ip}"),
+            AltName::Email(email) => write!(f, "email:{email}"),
+            AltName::Uri(uri) => write!(f, "URI:{uri}"),
+            AltName::Upn(upn) => write!(f, "UPN:{upn}"),
+            AltName::Rid(oid) => write!(f, "RID:{oid}"),
+        }
+    }
+}
+
+/// Parse a comma separated list of entries, skipping empty ones
+pub fn parse_list(list: &str) -> Result<Vec<AltName>, String> {
+    list.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(AltName::from_str)
+        .collect()
+}
+
+/// Combine the requested names with the one implied by the CommonName, if it
+/// implies one, dropping duplicates while keeping the order they were given in
+pub fn collect(names: &[AltName], common_name: Option<&str>) -> Vec<AltName> {
+    let mut alt_names: Vec<AltName> = Vec::new();
+    let from_cn = common_name.and_then(AltName::from_common_name);
+    for name in names.iter().chain(from_cn.iter()) {
+        if !alt_names.iter().any(|n| n.same_as(name)) {
+            alt_names.push(name.clone());
+        }
+    }
+    alt_names
+}
+
+/// Convert a DNS name to its A-label form, checking label syntax, lengths
+/// and wildcard placement
+fn dns_to_ascii(name: &str) -> Result<String, String> {
+    let invalid = |reason: &str| format!("{name} is not a valid DNS name: {reason}");
+
+    let (wildcard, rest) = match name.strip_prefix("*.") {
+        Some(rest) => (true, rest),
+        None => (false, name),
+    };
+    if rest.contains('*') {
+        return Err(invalid("a wildcard may only be the entire leftmost label"));
+    }
+
+    let ascii = idna::domain_to_ascii(rest).map_err(|_| invalid("not a valid IDN"))?;
+    if ascii.is_empty() {
+        return Err(invalid("name is empty"));
+    }
+    for label in ascii.split('.') {
+        if label.is_empty() {
+            return Err(invalid("labels must not be empty"));
+        }
+        if label.len() > 63 {
+            return Err(invalid(&format!("label {label} is longer than 63 octets")));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid(&format!("label {label} starts or ends with '-'")));
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(invalid(&format!(
+                "label {label} may only contain letters, digits and '-'"
+            )));
+        }
+    }
+
+    if wildcard {
+        let is_public_suffix = psl::suffix_str(&ascii)
+            .map(|suffix| suffix == ascii)
+            .unwrap_or(true);
+        if !ascii.contains('.') || is_public_suffix {
+            return Err(invalid(
+                "wildcards are not allowed directly below a public suffix",
+            ));
+        }
+    }
+
+    let ascii = match wildcard {
+        true => format!("*.{ascii}"),
+        false => ascii,
+    };
+    if ascii.len() > 253 {
+        return Err(invalid("name is longer than 253 octets"));
+    }
+    Ok(ascii)
+}
+
+fn validate_spiffe_id(uri: &str) -> Result<(), String> {
+    let invalid = |reason: &str| format!("{uri} is not a valid SPIFFE ID: {reason}");
+    let rest = match uri.get(..9) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("spiffe://") => &uri[9..],
+        _ => return Err(invalid("expected spiffe://<trust-domain>/<path>")),
+    };
+    if rest.contains(['?', '#']) {
+        return Err(invalid("query and fragment are not allowed"));
+    }
+    let (trust_domain, path) = match rest.split_once('/') {
+        Some((trust_domain, path)) => (trust_domain, Some(path)),
+        None => (rest, None),
+    };
+    if trust_domain.is_empty()
+        || !trust_domain
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-._".contains(c))
+    {
+        return Err(invalid(
+            "trust domain must be lowercase letters, digits, '-', '.' or '_'",
+        ));
+    }
+    if let Some(path) = path {
+        for segment in path.split('/') {
+            if segment.is_empty() || segment == "." || segment == ".." {
+                return Err(invalid("path segments must not be empty, '.' or '..'"));
+            }
+            if !segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c))
+            {
+                return Err(invalid(
+                    "path segments must be letters, digits, '-', '.' or '_'",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_typed_entries() {
+        assert_eq!(
+            "dns:example.com".parse(),
+            Ok(AltName::Dns("example.com".to_string()))
+        );
+        assert_eq!(
+            "IP:10.0.0.1".parse(),
+            Ok(AltName::Ip("10.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            "email:john@example.com".parse(),
+            Ok(AltName::Email("john@example.com".to_string()))
+        );
+        assert_eq!(
+            "upn:john@corp.example".parse(),
+            Ok(AltName::Upn("john@corp.example".to_string()))
+        );
+        assert_eq!(
+            "rid:1.2.3.4".parse(),
+            Ok(AltName::Rid("1.2.3.4".to_string()))
+        );
+        assert!("rid:3.2".parse::<AltName>().is_err());
+        assert!("ip:example.com".parse::<AltName>().is_err());
+        assert!("email:john".parse::<AltName>().is_err());
+    }
+
+    #[test]
+    fn infers_untyped_entries() {
+        assert_eq!(
+            parse_list("example.com, ::1,,john@example.com,https://example.com/").unwrap(),
+            [
+                AltName::Dns("example.com".to_string()),
+                AltName::Ip("::1".parse().unwrap()),
+                AltName::Email("john@example.com".to_string()),
+                AltName::Uri("https://example.com/".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_idns_to_a_labels() {
+        assert_eq!(
+            AltName::dns("bücher.example"),
+            Ok(AltName::Dns("xn--bcher-kva.example".to_string()))
+        );
+        assert_eq!(
+            AltName::email("juergen@bücher.example"),
+            Ok(AltName::Email("juergen@xn--bcher-kva.example".to_string()))
+        );
+        assert!(AltName::email("jürgen@bücher.example").is_err());
+        assert!(AltName::email("jürgen@example.com").is_err());
+        assert_eq!(
+            AltName::Dns("xn--bcher-kva.example".to_string()).to_string(),
+            "DNS:bücher.example (xn--bcher-kva.example)"
+        );
+    }
+
+    #[test]
+    fn checks_dns_names() {
+        assert!(AltName::dns("-bad.example").is_err());
+        assert!(AltName::dns("a..example").is_err());
+        assert!(AltName::dns("under_score.example").is_err());
+        assert!(AltName::dns(&format!("{}.example", "a".repeat(64))).is_err());
+        assert!(AltName::dns(&format!("{}.example", "a".repeat(63))).is_ok());
+    }
+
+    #[test]
+    fn checks_wildcards() {
+        assert_eq!(
+            AltName::dns("*.example.com"),
+            Ok(AltName::Dns("*.example.com".to_string()))
+        );
+        assert_eq!(
+            AltName::dns("*.bücher.example"),
+            Ok(AltName::Dns("*.xn--bcher-kva.example".to_string()))
+        );
+        assert!(AltName::dns("*.com").is_err());
+        assert!(AltName::dns("*.co.uk").is_err());
+        assert!(AltName::dns("a.*.example.com").is_err());
+        assert!(AltName::dns("f*.example.com").is_err());
+        assert!(AltName::email("john@*.example.com").is_err());
+    }
+
+    #[test]
+    fn checks_spiffe_ids() {
+        assert!(AltName::uri("spiffe://cluster.local/ns/foo/sa/bar").is_ok());
+        assert!(AltName::uri("spiffe://Cluster/ns").is_err());
+        assert!(AltName::uri("spiffe://cluster/ns//foo").is_err());
+        assert!(AltName::uri("spiffe://cluster/ns?x=1").is_err());
+    }
+
+    #[test]
+    fn derives_a_san_only_from_host_like_common_names() {
+        assert_eq!(
+            AltName::from_common_name("example.com"),
+            Some(AltName::Dns("example.com".to_string()))
+        );
+        assert_eq!(
+            AltName::from_common_name("10.0.0.1"),
+            Some(AltName::Ip("10.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            AltName::from_common_name("john@example.com"),
+            Some(AltName::Email("john@example.com".to_string()))
+        );
+        assert_eq!(AltName::from_common_name("John Smith"), None);
+        assert_eq!(AltName::from_common_name("my_int"), None);
+    }
+
+    #[test]
+    fn adds_the_common_name_once() {
+        let names = [
+            AltName::Email("john@example.com".to_string()),
+            AltName::Dns("Example.com".to_string()),
+        ];
+        assert_eq!(collect(&names, Some("example.com")), names);
+        assert_eq!(
+            collect(&names[..1], Some("example.com")),
+            [
+                AltName::Email("john@example.com".to_string()),
+                AltName::Dns("example.com".to_string()),
+            ]
+        );
+        assert_eq!(collect(&names[..1], Some("John Smith")), names[..1]);
+        assert!(collect(&[], Some("John Smith")).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509Builder, X509Req, X509ReqBuilder, X509};
+use std::fs::read_to_string;
+
+use crate::agent;
+use crate::der;
+use crate::external;
+use crate::file;
+use crate::pkcs11;
+use crate::pkey;
+use crate::KeyType;
+
+/// A private key that can sign certificates and requests, wherever it lives
+pub trait Signer {
+    fn public_key(&self) -> PKey<Public>;
+
+    /// Sign some data, giving the signature as X.509 carries it: PKCS#1 v1.5
+    /// for RSA keys and a DER ECDSA-Sig-Value for EC keys
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> Vec<u8>;
+
+    /// The key itself, if it is held in this process
+    fn private_key(&self) -> Option<&PKey<Private>> {
+        None
+    }
+}
+
+impl Signer for PKey<Private> {
+    fn public_key(&self) -> PKey<Public> {
+        PKey::public_key_from_der(&self.public_key_to_der().unwrap()).unwrap()
+    }
+
+    fn sign(&self, digest: MessageDigest, data: &[u8]) -> Vec<u8> {
+        openssl::sign::Signer::new(digest, self)
+            .unwrap()
+            .sign_oneshot_to_vec(data)
+            .unwrap()
+    }
+
+    fn private_key(&self) -> Option<&PKey<Private>> {
+        Some(self)
+    }
+}
+
+/// The URI in a key file that refers to a key kept elsewhere instead of
+/// holding it
+pub fn key_uri(path: &str) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+        .filter(|contents| {
+            [pkcs11::SCHEME, external::EXEC_SCHEME, external::UNIX_SCHEME]
+                .iter()
+                .any(|scheme| contents.starts_with(scheme))
+        })
+}
+
+/// Check a key URI names a PKCS#11 token key or an external signer
+pub fn validate_uri(uri: &str) -> Result<(), String> {
+    if uri.starts_with(external::EXEC_SCHEME) || uri.starts_with(external::UNIX_SCHEME) {
+        return Ok(());
+    }
+    uri.parse::<pkcs11::Uri>().map(|_| ())
+}
+
+/// Whether a key file refers to a key kept elsewhere
+pub fn is_external(path: &str) -> bool {
+    key_uri(path).is_some()
+}
+
+/// The URI as a key file holds it. A `pin-value` is left out, so the PIN has
+/// to be given again or come from a `pin-source`
+pub fn stored_uri(uri: &str) -> String {
+    match uri.starts_with(pkcs11::SCHEME) {
+        true => pkcs11::without_pin(uri),
+        false => uri.to_string(),
+    }
+}
+
+pub fn encode_key_uri(uri: &str) -> Vec<u8> {
+    format!("{}\n", stored_uri(uri)).into_bytes()
+}
+
+/// Write a key file referring to a key kept elsewhere
+pub fn save_key_uri(path: &str, uri: &str) {
+    println!("{}", path);
+    file::write_private(path, &encode_key_uri(uri));
+}
+
+/// Load the key in a key file, which is either a PEM key decrypted with the
+/// password, a reference to a token key logged in to with it as the PIN, or a
+/// reference to an external signer. A running agent holding the key signs
+/// instead
+pub fn load(path: &str, password: Option<String>) -> Box<dyn Signer> {
+    if let Some(key) = agent::key(path) {
+        return Box::new(key);
+    }
+    match key_uri(path) {
+        Some(uri) if uri.starts_with(pkcs11::SCHEME) => Box::new(pkcs11::Key::open(&uri, password)),
+        Some(uri) => Box::new(external::Key::open(&uri)),
+        None => Box::new(pkey::read_pkey(path, password)),
+    }
+}
+
+/// Use the key named by a URI. A token key is generated first if it does not
+/// exist, while an external signer is expected to hold its key already
+pub fn open_or_generate(uri: &str, password: Option<String>, key_type: KeyType) -> Box<dyn Signer> {
+    match uri.starts_with(pkcs11::SCHEME) {
+        true => Box::new(pkcs11::Key::open_or_generate(uri, password, key_type)),
+        false => Box::new(external::Key::open(uri)),
+    }
+}
+
+pub fn sign_cert(mut builder: X509Builder, signer: &dyn Signer, digest: MessageDigest) -> X509 {
+    if let Some(key) = signer.private_key() {
+        builder.sign(key, digest).unwrap();
+        return builder.build();
+    }
+
+    let public_key = signer.public_key();
+    builder.sign(&placeholder(public_key.id()), digest).unwrap();
+    let der = resign(&builder.build().to_der().unwrap(), signer, digest);
+    let cert = X509::from_der(&der).unwrap();
+    if !cert.verify(&public_key).unwrap() {
+        panic!("The signature on the certificate does not verify with the signing key");
+    }
+    cert
+}
+
+pub fn sign_req(
+    mut builder: X509ReqBuilder,
+    signer: &dyn Signer,
+    digest: MessageDigest,
+) -> X509Req {
+    if let Some(key) = signer.private_key() {
+        builder.sign(key, digest).unwrap();
+        return builder.build();
+    }
+
+    let public_key = signer.public_key();
+    builder.sign(&placeholder(public_key.id()), digest).unwrap();
+    let der = resign(&builder.build().to_der().unwrap(), signer, digest);
+    let req = X509Req::from_der(&der).unwrap();
+    if !req.verify(&public_key).unwrap() {
+        panic!("The signature on the request does not verify with the signing key");
+    }
+    req
+}
+
+/// A throwaway key of the same algorithm as the real one, so OpenSSL fills in
+/// the right signature algorithm before the signature is replaced
+fn placeholder(id: Id) -> PKey<Private> {
+    match id {
+        Id::EC => PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap(),
+        _ => PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap(),
+    }
+}
+
+/// Replace the signature on a certificate or request, both of which are a
+/// SEQUENCE of the signed data, the signature algorithm and the signature
+fn resign(signed: &[u8], signer: &dyn Signer, digest: MessageDigest) -> Vec<u8> {
+    let (outer, _) = der::split(signed).unwrap();
+    let (tbs, rest) = der::split(outer.content).unwrap();
+    let (algorithm, _) = der::split(rest).unwrap();
+    sign_der(tbs.encoded, algorithm.encoded, signer, digest)
+}
+
+/// The digest and DER AlgorithmIdentifier for structures OpenSSL cannot sign
+/// itself, such as CRLs and OCSP responses: SHA-256 for RSA keys and SHA-384
+/// for EC keys
+pub fn algorithm(public_key: &PKey<Public>) -> (MessageDigest, Vec<u8>) {
+    match public_key.id() {
+        // ecdsa-with-SHA384, which has no parameters
+        Id::EC => (
+            MessageDigest::sha384(),
+            der::sequence(&[der::oid("1.2.840.10045.4.3.3")]),
+        ),
+        // sha256WithRSAEncryption
+        _ => (
+            MessageDigest::sha256(),
+            der::sequence(&[der::oid("1.2.840.113549.1.1.11"), der::tlv(der::NULL, &[])]),
+        ),
+    }
+}
+
+/// Sign some DER, giving the SEQUENCE of it, the signature algorithm and the
+/// signature that certificates, CRLs and OCSP responses all share
+pub fn sign_der(
+    tbs: &[u8],
+    algorithm: &[u8],
+    signer: &dyn Signer,
+    digest: MessageDigest,
+) -> Vec<u8> {
+    // A signature is a BIT STRING with no unused bits
+    let mut signature = vec![0];
+    signature.extend(signer.sign(digest, tbs));
+    der::sequence(&[
+        tbs.to_vec(),
+        algorithm.to_vec(),
+        der::tlv(der::BIT_STRING, &signature),
+    ])
+}
@@ -0,0 +1,394 @@
+use openssl::asn1::{Asn1Object, Asn1Type};
+use openssl::nid::Nid;
+use openssl::x509::{X509Name, X509NameEntryRef, X509NameRef};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default)]
+pub struct Subject {
+    entries: Vec<(Nid, String)>,
+    /// For each entry copied from an existing name, its position there, so
+    /// it is rebuilt with the same string type and bytes
+    copied: Vec<Option<usize>>,
+    /// The DER of the name entries were copied from
+    source: Option<Vec<u8>>,
+}
+
+impl Subject {
+    pub fn new() -> Subject {
+        Subject::default()
+    }
+
+    /// Parse either an OpenSSL style `/C=US/O=Acme/CN=foo` string, which lists
+    /// the most significant attribute first, or an RFC 4514 `CN=foo,O=Acme,C=US`
+    /// string, which lists it last
+    pub fn parse(subject: &str) -> Result<Subject, String> {
+        let subject = subject.trim();
+        let entries = match subject.starts_with('/') {
+            true => parse_slash(subject)?,
+            false => {
+                let mut entries = parse_rfc4514(subject)?;
+                entries.reverse();
+                entries
+            }
+        };
+        let mut parsed = Subject::new();
+        for (key, value) in entries {
+            parsed.push(attribute_nid(&key)?, &value)?;
+        }
+        Ok(parsed)
+    }
+
+    /// Copy the attributes of an existing name, keeping their order and how
+    /// each is encoded. Panics on a value that cannot be decoded rather than
+    /// leaving it out
+    pub fn from_name(name: &X509NameRef) -> Subject {
+        let entries: Vec<(Nid, String)> = name
+            .entries()
+            .map(|entry| {
+                let nid = entry.object().nid();
+                let value = entry.data().as_utf8().unwrap_or_else(|e| {
+                    panic!(
+                        "Unable to decode the {} attribute of a name: {e}",
+                        entry.object()
+                    )
+                });
+                (nid, value.to_string())
+            })
+            .collect();
+        Subject {
+            copied: (0..entries.len()).map(Some).collect(),
+            entries,
+            source: Some(name.to_der().unwrap()),
+        }
+    }
+
+    pub fn push(&mut self, nid: Nid, value: &str) -> Result<(), String> {
+        // Country codes are uppercase, but often typed in lowercase
+        let value = match nid {
+            Nid::COUNTRYNAME if value.len() == 2 => value.to_ascii_uppercase(),
+            _ => value.to_string(),
+        };
+        validate_value(nid, &value)?;
+        self.entries.push((nid, value));
+        self.copied.push(None);
+        Ok(())
+    }
+
+    pub fn get(&self, nid: Nid) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| *n == nid)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, nid: Nid) -> bool {
+        self.get(nid).is_some()
+    }
+
+    pub fn common_name(&self) -> Option<String> {
+        self.get(Nid::COMMONNAME).map(String::from)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[(Nid, String)] {
+        &self.entries
+    }
+
+    /// Build the name in the order the attributes were added. Attributes copied
+    /// from an existing name keep their encoding. With `printable` set, any
+    /// other value that fits the PrintableString alphabet is encoded as one
+    pub fn build(&self, printable: bool) -> X509Name {
+        let source = self
+            .source
+            .as_ref()
+            .map(|der| X509Name::from_der(der).unwrap());
+        let source_entries: Vec<&X509NameEntryRef> = match source {
+            Some(ref name) => name.entries().collect(),
+            None => Vec::new(),
+        };
+        let mut x509_name_builder = X509Name::builder().unwrap();
+        for ((nid, value), copied) in self.entries.iter().zip(&self.copied) {
+            match copied {
+                Some(index) => x509_name_builder.append_entry(source_entries[*index]),
+                None => x509_name_builder.append_entry_by_nid_with_type(
+                    *nid,
+                    value,
+                    string_type(*nid, value, printable),
+                ),
+            }
+            .unwrap();
+        }
+        x509_name_builder.build()
+    }
+}
+
+/// Shown in the OpenSSL style `/C=US/O=Acme/CN=foo` form
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (nid, value) in &self.entries {
+            write!(f, "/{}={}", nid.short_name().unwrap_or("?"), value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Subject {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Subject::parse(s)
+    }
+}
+
+/// Resolve an attribute name such as `CN`, `organizationName` or `2.5.4.3`
+pub fn attribute_nid(key: &str) -> Result<Nid, String> {
+    let key = key.trim();
+    let alias = match key.to_lowercase().as_str() {
+        "e" | "email" | "emailaddress" => Some(Nid::PKCS9_EMAILADDRESS),
+        "s" => Some(Nid::STATEORPROVINCENAME),
+        "g" | "givenname" => Some(Nid::GIVENNAME),
+        "surname" => Some(Nid::SURNAME),
+        "serialnumber" => Some(Nid::SERIALNUMBER),
+        "dc" | "domaincomponent" => Some(Nid::DOMAINCOMPONENT),
+        _ => None,
+    };
+    if let Some(nid) = alias {
+        return Ok(nid);
+    }
+    match Asn1Object::from_str(key).map(|object| object.nid()) {
+        Ok(nid) if nid != Nid::UNDEF => Ok(nid),
+        _ => Err(format!("{key} is not a known subject attribute")),
+    }
+}
+
+fn validate_value(nid: Nid, value: &str) -> Result<(), String> {
+    let name = nid.short_name().unwrap_or("attribute");
+    if value.is_empty() {
+        return Err(format!("{name} must not be empty"));
+    }
+    match nid {
+        Nid::COUNTRYNAME if value.len() != 2 || !value.chars().all(|c| c.is_ascii_uppercase()) => {
+            Err(format!(
+                "{name} must be a two letter uppercase country code, got {value}"
+            ))
+        }
+        Nid::SERIALNUMBER if !is_printable(value) => Err(format!(
+            "{name} may only contain PrintableString characters, got {value}"
+        )),
+        Nid::PKCS9_EMAILADDRESS | Nid::DOMAINCOMPONENT if !value.is_ascii() => {
+            Err(format!("{name} must be ASCII, got {value}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn string_type(nid: Nid, value: &str, printable: bool) -> Asn1Type {
+    match nid {
+        Nid::COUNTRYNAME | Nid::SERIALNUMBER | Nid::DNQUALIFIER => Asn1Type::PRINTABLESTRING,
+        Nid::PKCS9_EMAILADDRESS | Nid::DOMAINCOMPONENT => Asn1Type::IA5STRING,
+        _ if printable && is_printable(value) => Asn1Type::PRINTABLESTRING,
+        _ => Asn1Type::UTF8STRING,
+    }
+}
+
+fn is_printable(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || " '()+,-./:=?".contains(c))
+}
+
+fn parse_slash(subject: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    for component in split_unescaped(&subject[1..], &['/'])? {
+        if component.trim().is_empty() {
+            continue;
+        }
+        entries.push(split_attribute(&component, subject)?);
+    }
+    Ok(entries)
+}
+
+fn parse_rfc4514(subject: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    for component in split_unescaped(subject, &[',', ';'])? {
+        if component.trim().is_empty() {
+            return Err(format!("{subject} contains an empty RDN"));
+        }
+        if split_unescaped(&component, &['+'])?.len() != 1 {
+            return Err(format!(
+                "{subject} contains a multi-valued RDN, which is not supported"
+            ));
+        }
+        entries.push(split_attribute(&component, subject)?);
+    }
+    Ok(entries)
+}
+
+fn split_attribute(component: &str, subject: &str) -> Result<(String, String), String> {
+    let (key, value) = component
+        .split_once('=')
+        .ok_or_else(|| format!("{subject} is missing '=' in {component}"))?;
+    let value = value.trim();
+    if value.starts_with('#') {
+        return Err(format!(
+            "{subject} uses a hex encoded value, which is not supported"
+        ));
+    }
+    Ok((key.trim().to_string(), unescape(value)?))
+}
+
+/// Split on unescaped separators, leaving escape sequences in place
+fn split_unescaped(input: &str, separators: &[char]) -> Result<Vec<String>, String> {
+    let mut parts = vec![String::new()];
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escaped = chars
+                .next()
+                .ok_or_else(|| format!("{input} ends with a dangling '\\'"))?;
+            let current = parts.last_mut().unwrap();
+            current.push(c);
+            current.push(escaped);
+        } else if separators.contains(&c) {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    Ok(parts)
+}
+
+/// Resolve `\,` style escapes and `\c3\bc` style hex pairs
+fn unescape(value: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let first = chars
+            .next()
+            .ok_or_else(|| format!("{value} ends with a dangling '\\'"))?;
+        match (
+            first.to_digit(16),
+            chars.peek().and_then(|c| c.to_digit(16)),
+        ) {
+            (Some(high), Some(low)) => {
+                chars.next();
+                bytes.push((high * 16 + low) as u8);
+            }
+            _ => {
+                let mut buf = [0; 4];
+                bytes.extend(first.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("{value} is not valid UTF-8 after unescaping"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_names(subject: &Subject) -> Vec<String> {
+        subject
+            .entries()
+            .iter()
+            .map(|(nid, value)| format!("{}={value}", nid.short_name().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_both_orders_most_significant_first() {
+        let slash: Subject = "/C=US/O=Acme/OU=Web/CN=foo".parse().unwrap();
+        let rfc4514: Subject = "CN=foo,OU=Web,O=Acme,C=US".parse().unwrap();
+        assert_eq!(short_names(&slash), ["C=US", "O=Acme", "OU=Web", "CN=foo"]);
+        assert_eq!(short_names(&rfc4514), short_names(&slash));
+        assert_eq!(slash.to_string(), "/C=US/O=Acme/OU=Web/CN=foo");
+    }
+
+    #[test]
+    fn builds_names_in_the_given_order() {
+        let subject: Subject = "/DC=com/DC=example/CN=foo".parse().unwrap();
+        let name = subject.build(false);
+        let built: Vec<String> = name
+            .entries()
+            .map(|entry| entry.data().as_utf8().unwrap().to_string())
+            .collect();
+        assert_eq!(built, ["com", "example", "foo"]);
+        assert_eq!(
+            short_names(&Subject::from_name(&name)),
+            short_names(&subject)
+        );
+    }
+
+    #[test]
+    fn copies_names_with_their_encoding() {
+        let mut builder = X509Name::builder().unwrap();
+        builder
+            .append_entry_by_nid_with_type(Nid::COUNTRYNAME, "US", Asn1Type::PRINTABLESTRING)
+            .unwrap();
+        builder
+            .append_entry_by_nid_with_type(Nid::ORGANIZATIONNAME, "Acme", Asn1Type::UTF8STRING)
+            .unwrap();
+        // Two bytes make one BMPString character
+        builder
+            .append_entry_by_nid_with_type(Nid::COMMONNAME, "ab", Asn1Type::BMPSTRING)
+            .unwrap();
+        let name = builder.build();
+
+        let subject = Subject::from_name(&name);
+        assert_eq!(subject.entries().len(), 3);
+        assert_eq!(
+            subject.build(true).to_der().unwrap(),
+            name.to_der().unwrap()
+        );
+
+        let mut extended = subject.clone();
+        extended.push(Nid::ORGANIZATIONALUNITNAME, "Web").unwrap();
+        let rebuilt = extended.build(true);
+        assert_eq!(rebuilt.entries().count(), 4);
+        let common_name = rebuilt.entries().nth(2).unwrap().data();
+        assert_eq!(common_name.as_slice(), b"ab");
+        assert_eq!(common_name.as_utf8().unwrap().to_string(), "\u{6162}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to decode the commonName attribute")]
+    fn refuses_names_it_cannot_decode() {
+        // A commonName whose value is an empty SEQUENCE rather than a string
+        let der = [
+            0x30, 0x0b, 0x31, 0x09, 0x30, 0x07, 0x06, 0x03, 0x55, 0x04, 0x03, 0x30, 0x00,
+        ];
+        Subject::from_name(&X509Name::from_der(&der).unwrap());
+    }
+
+    #[test]
+    fn unescapes_values() {
+        let subject: Subject = r"CN=Smith\, John,O=B\c3\bccher".parse().unwrap();
+        assert_eq!(subject.get(Nid::ORGANIZATIONNAME), Some("Bücher"));
+        assert_eq!(subject.common_name().as_deref(), Some("Smith, John"));
+        assert!("CN=a+O=b".parse::<Subject>().is_err());
+        assert!("CN=#0403".parse::<Subject>().is_err());
+        assert!("CN=foo,,O=bar".parse::<Subject>().is_err());
+        assert!("/XX=foo".parse::<Subject>().is_err());
+    }
+
+    #[test]
+    fn uppercases_country_codes() {
+        let mut subject = Subject::new();
+        subject.push(Nid::COUNTRYNAME, "us").unwrap();
+        assert_eq!(subject.get(Nid::COUNTRYNAME), Some("US"));
+        let parsed: Subject = "/C=de/CN=foo".parse().unwrap();
+        assert_eq!(parsed.get(Nid::COUNTRYNAME), Some("DE"));
+        assert!(subject.push(Nid::COUNTRYNAME, "usa").is_err());
+        assert!(subject.push(Nid::COUNTRYNAME, "u1").is_err());
+        assert!(subject.push(Nid::COUNTRYNAME, "").is_err());
+    }
+}
@@ -0,0 +1,553 @@
+//! When certificates are valid: lifetimes written as durations like `15m`,
+//! `8h` or `30d`, explicit timestamps, and a backdate so hosts with a slightly
+//! slow clock accept a certificate as soon as it is issued
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509Ref;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+/// 9999-12-31T23:59:59Z, the latest time X.509 can encode
+pub const LATEST: i64 = 253_402_300_799;
+
+/// A length of time in seconds. A number without a unit is a number of days,
+/// as lifetimes always were
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "RawDuration", into = "String")]
+pub struct Duration(pub i64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+
+    pub fn days(days: i64) -> Duration {
+        Duration(days * DAY)
+    }
+
+    pub fn seconds(self) -> i64 {
+        self.0
+    }
+
+    /// Roughly how long this is, in at most two units, such as "90 days" or
+    /// "1 day 12 hours"
+    pub fn describe(self) -> String {
+        describe(self.0, 2)
+    }
+
+    /// Refuse a lifetime that, starting now, would end after the latest time
+    /// a certificate can hold
+    fn encodable(self) -> Result<Duration, String> {
+        match self.0 <= LATEST - now() {
+            true => Ok(self),
+            false => Err(format!(
+                "{} is too long, certificates cannot be valid after 9999-12-31T23:59:59Z",
+                self.describe()
+            )),
+        }
+    }
+}
+
+impl FromStr for Duration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not a valid duration, such as 15m, 8h, 30d or 1d12h");
+        let trimmed = s.trim();
+        if !trimmed.is_empty() && trimmed.bytes().all(|b| b.is_ascii_digit()) {
+            let days: i64 = trimmed.parse().map_err(|_| invalid())?;
+            return days
+                .checked_mul(DAY)
+                .map(Duration)
+                .ok_or_else(invalid)
+                .and_then(Duration::encodable);
+        }
+
+        let mut seconds: i64 = 0;
+        let mut rest = trimmed;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+            if digits == 0 {
+                return Err(invalid());
+            }
+            let number: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+            let unit_len = rest[digits..]
+                .bytes()
+                .take_while(|b| b.is_ascii_alphabetic())
+                .count();
+            let unit = match &rest[digits..digits + unit_len] {
+                "s" => 1,
+                "m" => MINUTE,
+                "h" => HOUR,
+                "d" => DAY,
+                "w" => WEEK,
+                _ => return Err(invalid()),
+            };
+            seconds = number
+                .checked_mul(unit)
+                .and_then(|n| seconds.checked_add(n))
+                .ok_or_else(invalid)?;
+            rest = &rest[digits + unit_len..];
+        }
+        match trimmed.is_empty() {
+            true => Err(invalid()),
+            false => Duration(seconds).encodable(),
+        }
+    }
+}
+
+/// The shortest form that parses back to the same duration, such as `90d`
+/// or `1d12h`
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0s");
+        }
+        let mut rest = self.0;
+        for (unit, suffix) in [(DAY, "d"), (HOUR, "h"), (MINUTE, "m"), (1, "s")] {
+            if rest >= unit {
+                write!(f, "{}{suffix}", rest / unit)?;
+                rest %= unit;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Durations in TOML are either a number of days or a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Days(u32),
+    Text(String),
+}
+
+impl TryFrom<RawDuration> for Duration {
+    type Error = String;
+
+    fn try_from(raw: RawDuration) -> Result<Self, Self::Error> {
+        match raw {
+            RawDuration::Days(days) => Duration::days(days as i64).encodable(),
+            RawDuration::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<Duration> for String {
+    fn from(duration: Duration) -> String {
+        duration.to_string()
+    }
+}
+
+/// A moment in seconds since the Unix epoch, written as an RFC 3339 timestamp
+/// such as `2024-06-01T12:00:00Z` or `2024-06-01 12:00+02:00`, or as a date
+/// meaning midnight UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp(now())
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_timestamp(s.trim()) {
+            Some(timestamp) if timestamp.0 > LATEST => Err(format!(
+                "{s} is too late, certificates cannot be valid after 9999-12-31T23:59:59Z"
+            )),
+            Some(timestamp) => Ok(timestamp),
+            None => Err(format!(
+                "{s} is not a valid timestamp, such as 2024-06-01 or 2024-06-01T12:00:00Z"
+            )),
+        }
+    }
+}
+
+/// Always in UTC, such as `2024-06-01T12:00:00Z`
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day, hour, minute, second) = utc(self.0);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"
+        )
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> String {
+        timestamp.to_string()
+    }
+}
+
+fn parse_timestamp(s: &str) -> Option<Timestamp> {
+    let number = |s: &str| -> Option<i64> {
+        match !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            true => s.parse().ok(),
+            false => None,
+        }
+    };
+
+    let (date, time) = match s.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (
+        number(parts.next()?)?,
+        number(parts.next()?)?,
+        number(parts.next()?)?,
+    );
+    // Years past what a certificate can hold would overflow the arithmetic
+    if year > 9999 || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * DAY;
+
+    if let Some(time) = time {
+        let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+            Some(i) => time.split_at(i),
+            None => return None,
+        };
+        let mut fields = clock.split(':');
+        let hour = number(fields.next()?)?;
+        let minute = number(fields.next()?)?;
+        let second = match fields.next() {
+            // Fractions of a second are dropped
+            Some(second) => number(second.split_once('.').map_or(second, |(s, _)| s))?,
+            None => 0,
+        };
+        if fields.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        seconds += hour * HOUR + minute * MINUTE + second;
+
+        if !offset.eq_ignore_ascii_case("z") {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let (hours, minutes) = (number(hours)?, number(minutes)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            seconds -= sign * (hours * HOUR + minutes * MINUTE);
+        }
+    }
+    Some(Timestamp(seconds))
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of a number of days since 1970-01-01, the inverse of
+/// days_from_civil
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// The year, month, day, hour, minute and second in UTC of a moment in
+/// seconds since the Unix epoch
+pub fn utc(seconds: i64) -> (i64, i64, i64, i64, i64, i64) {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(DAY));
+    let time = seconds.rem_euclid(DAY);
+    (
+        year,
+        month,
+        day,
+        time / HOUR,
+        time % HOUR / MINUTE,
+        time % MINUTE,
+    )
+}
+
+/// A certificate's notBefore and notAfter, in seconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl Validity {
+    /// Valid for a lifetime from now, starting a backdate earlier so clients
+    /// whose clocks are behind accept it straight away
+    pub fn from_now(lifetime: Duration, backdate: Duration) -> Result<Validity, String> {
+        let now = now();
+        Validity {
+            not_before: now - backdate.0,
+            not_after: now.saturating_add(lifetime.0),
+        }
+        .check()
+    }
+
+    /// Valid between explicit timestamps, with whichever is missing filled in
+    /// from the lifetime and backdate as `from_now` would
+    pub fn between(
+        not_before: Option<Timestamp>,
+        not_after: Option<Timestamp>,
+        lifetime: Duration,
+        backdate: Duration,
+    ) -> Result<Validity, String> {
+        let validity = match (not_before, not_after) {
+            (None, None) => return Validity::from_now(lifetime, backdate),
+            (Some(start), None) => Validity {
+                not_before: start.0,
+                not_after: start.0.saturating_add(lifetime.0),
+            },
+            (None, Some(end)) => Validity {
+                not_before: now() - backdate.0,
+                not_after: end.0,
+            },
+            (Some(start), Some(end)) => Validity {
+                not_before: start.0,
+                not_after: end.0,
+            },
+        };
+        validity.check()
+    }
+
+    /// A certificate's current validity
+    pub fn of(cert: &X509Ref) -> Validity {
+        Validity {
+            not_before: unix(cert.not_before()),
+            not_after: unix(cert.not_after()),
+        }
+    }
+
+    /// A new validity exactly as long as a certificate's, for renewing it.
+    /// The backdate comes out of that length rather than adding to it, so
+    /// renewing again does not stretch it, and takes at most half of it
+    pub fn renewing(cert: &X509Ref, backdate: Duration) -> Result<Validity, String> {
+        let duration = Validity::of(cert).duration().0;
+        let not_before = now() - backdate.0.min(duration / 2);
+        Validity {
+            not_before,
+            not_after: not_before.saturating_add(duration),
+        }
+        .check()
+    }
+
+    /// Cut short to end when a CA certificate does, as a certificate stops
+    /// validating once its issuer expires. Nothing is left of a window that
+    /// only starts once the issuer has expired
+    pub fn within(&self, ca_cert: &X509Ref) -> Result<Validity, String> {
+        Validity {
+            not_before: self.not_before,
+            not_after: self.not_after.min(unix(ca_cert.not_after())),
+        }
+        .check()
+        .map_err(|_| String::from("The certificate would only start once its issuer has expired"))
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration(self.not_after - self.not_before)
+    }
+
+    /// Whether this ends after a CA certificate does
+    pub fn outlives(&self, ca_cert: &X509Ref) -> bool {
+        self.not_after > unix(ca_cert.not_after())
+    }
+
+    pub fn not_before_time(&self) -> Asn1Time {
+        Asn1Time::from_unix(self.not_before as libc::time_t).unwrap()
+    }
+
+    pub fn not_after_time(&self) -> Asn1Time {
+        if self.not_after > LATEST {
+            panic!("notAfter is after 9999-12-31T23:59:59Z, which a certificate cannot hold");
+        }
+        Asn1Time::from_unix(self.not_after as libc::time_t).unwrap()
+    }
+
+    fn check(self) -> Result<Validity, String> {
+        if self.not_after <= self.not_before {
+            return Err(String::from("notAfter must be later than notBefore"));
+        }
+        if self.not_after > LATEST {
+            return Err(String::from(
+                "notAfter would be after 9999-12-31T23:59:59Z, the latest time a certificate can hold",
+            ));
+        }
+        Ok(self)
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Seconds since the Unix epoch of an ASN.1 time
+pub fn unix(time: &Asn1TimeRef) -> i64 {
+    let diff = Asn1Time::from_unix(0).unwrap().diff(time).unwrap();
+    diff.days as i64 * DAY + diff.secs as i64
+}
+
+/// A number of seconds in words, in at most the given number of units
+pub fn describe(seconds: i64, units: usize) -> String {
+    let mut rest = seconds.abs();
+    let mut words = Vec::new();
+    for (unit, name) in [
+        (DAY, "day"),
+        (HOUR, "hour"),
+        (MINUTE, "minute"),
+        (1, "second"),
+    ] {
+        if words.len() == units {
+            break;
+        }
+        let count = rest / unit;
+        // Stop at the first empty unit after the largest, so 1 day 0 hours
+        // 5 minutes reads as "1 day" rather than "1 day 5 minutes"
+        if count == 0 && !words.is_empty() {
+            break;
+        }
+        if count > 0 {
+            words.push(format!(
+                "{count} {name}{}",
+                if count == 1 { "" } else { "s" }
+            ));
+            rest %= unit;
+        }
+    }
+    match words.is_empty() {
+        true => String::from("0 seconds"),
+        false => words.join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!("90".parse(), Ok(Duration::days(90)));
+        assert_eq!("15m".parse(), Ok(Duration(15 * MINUTE)));
+        assert_eq!("8h".parse(), Ok(Duration(8 * HOUR)));
+        assert_eq!("2w".parse(), Ok(Duration::days(14)));
+        assert_eq!(" 1d12h ".parse(), Ok(Duration(DAY + 12 * HOUR)));
+        for invalid in ["", "d", "1x", "1.5d", "-1d", "12 h"] {
+            assert!(invalid.parse::<Duration>().is_err(), "{invalid}");
+        }
+        // Lifetimes must fit before the latest time X.509 can hold
+        assert!("3000000d".parse::<Duration>().is_err());
+        assert!("99999999999999999999".parse::<Duration>().is_err());
+    }
+
+    #[test]
+    fn writes_durations_back() {
+        for text in ["90d", "1d12h", "15m", "1h0m30s", "0s"] {
+            let duration: Duration = text.parse().unwrap();
+            assert_eq!(duration.to_string().parse(), Ok(duration));
+        }
+        assert_eq!(Duration(DAY + 12 * HOUR).to_string(), "1d12h");
+        assert_eq!(Duration(DAY + 12 * HOUR).describe(), "1 day 12 hours");
+        assert_eq!(describe(DAY + 5 * MINUTE, 2), "1 day");
+        assert_eq!(describe(0, 2), "0 seconds");
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!("1970-01-01".parse(), Ok(Timestamp(0)));
+        assert_eq!("2024-06-01T12:00:00Z".parse(), Ok(Timestamp(1_717_243_200)));
+        assert_eq!(
+            "2024-06-01 14:00+02:00".parse(),
+            Ok(Timestamp(1_717_243_200))
+        );
+        assert_eq!(
+            "2024-02-29T00:00:00.5z".parse(),
+            Ok(Timestamp(1_709_164_800))
+        );
+        assert_eq!(Timestamp(1_717_243_200).to_string(), "2024-06-01T12:00:00Z");
+        for invalid in [
+            "2023-02-29",
+            "2024-13-01",
+            "2024-06-01T12:00",
+            "2024-06-01T24:00:00Z",
+            "10000-01-01",
+            "99999999999999-01-01",
+            "9223372036854775807-01-01T00:00:00Z",
+            "yesterday",
+        ] {
+            assert!(invalid.parse::<Timestamp>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn converts_dates_both_ways() {
+        for seconds in [0, -DAY, 951_782_400, 4_107_542_399, LATEST] {
+            let (year, month, day, hour, minute, second) = utc(seconds);
+            assert_eq!(
+                days_from_civil(year, month, day) * DAY + hour * HOUR + minute * MINUTE + second,
+                seconds
+            );
+        }
+        assert_eq!(utc(LATEST), (9999, 12, 31, 23, 59, 59));
+    }
+
+    #[test]
+    fn builds_validity_windows() {
+        let validity = Validity::from_now(Duration::days(30), Duration(HOUR)).unwrap();
+        assert_eq!(validity.duration(), Duration(30 * DAY + HOUR));
+
+        let start = Timestamp(1_717_243_200);
+        let validity =
+            Validity::between(Some(start), None, Duration::days(1), Duration::ZERO).unwrap();
+        assert_eq!(validity.not_after, start.0 + DAY);
+        assert!(Validity::between(
+            Some(start),
+            Some(Timestamp(start.0 - 1)),
+            Duration::days(1),
+            Duration::ZERO
+        )
+        .is_err());
+    }
+}